@@ -25,4 +25,11 @@ fn main() {
             &[proto_dir],
         )
         .expect("failed to compile protobuf definitions");
+
+    // Build the Hermes gRPC service definitions. Only the server is needed, clients are expected
+    // to generate their own code from `proto/hermes`. `api/grpc.rs` includes the generated code.
+    tonic_build::configure()
+        .build_client(false)
+        .compile(&[PathBuf::from("proto/hermes/v1/hermes.proto")], &["proto"])
+        .expect("failed to compile hermes protobuf definitions");
 }
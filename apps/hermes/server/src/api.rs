@@ -1,19 +1,24 @@
 use {
     crate::{
-        config::RunOptions,
-        state::{Aggregates, Benchmarks, Cache, Metrics},
+        config::{self, rpc::Options},
+        state::{Aggregates, Benchmarks, Cache, DerivedFeeds, Metrics, Publishers, Wormhole},
     },
-    anyhow::Result,
+    anyhow::{Context, Result},
     axum::{extract::Extension, middleware::from_fn_with_state, routing::get, Router},
     ipnet::IpNet,
     serde_qs::axum::QsQueryConfig,
-    std::sync::Arc,
+    std::{num::NonZeroU32, sync::Arc},
+    tokio::sync::watch,
     tower_http::cors::CorsLayer,
     utoipa::OpenApi,
     utoipa_swagger_ui::SwaggerUi,
 };
 
+mod admin;
+mod api_keys;
+mod binary_update;
 mod doc_examples;
+mod grpc;
 mod metrics_middleware;
 mod rest;
 pub mod types;
@@ -23,6 +28,7 @@ pub struct ApiState<S> {
     pub state: Arc<S>,
     pub ws: Arc<ws::WsState>,
     pub metrics: Arc<metrics_middleware::ApiMetrics>,
+    pub api_keys: Arc<api_keys::ApiKeysState>,
 }
 
 /// Manually implement `Clone` as the derive macro will try and slap `Clone` on
@@ -33,12 +39,20 @@ impl<S> Clone for ApiState<S> {
             state: self.state.clone(),
             ws: self.ws.clone(),
             metrics: self.metrics.clone(),
+            api_keys: self.api_keys.clone(),
         }
     }
 }
 
 impl<S> ApiState<S> {
-    pub fn new(state: Arc<S>, ws_whitelist: Vec<IpNet>, requester_ip_header_name: String) -> Self
+    pub fn new(
+        state: Arc<S>,
+        ws_whitelist: Vec<IpNet>,
+        ws_bytes_limit_per_ip_per_second: NonZeroU32,
+        requester_ip_header_name: String,
+        api_keys: Vec<api_keys::ApiKeyConfig>,
+        api_key_required: bool,
+    ) -> Self
     where
         S: Metrics,
         S: Send + Sync + 'static,
@@ -47,33 +61,121 @@ impl<S> ApiState<S> {
             metrics: Arc::new(metrics_middleware::ApiMetrics::new(state.clone())),
             ws: Arc::new(ws::WsState::new(
                 ws_whitelist,
+                ws_bytes_limit_per_ip_per_second,
                 requester_ip_header_name,
                 state.clone(),
             )),
+            api_keys: Arc::new(api_keys::ApiKeysState::new(
+                api_keys,
+                api_key_required,
+                state.clone(),
+            )),
             state,
         }
     }
 }
 
-#[tracing::instrument(skip(opts, state))]
-pub async fn spawn<S>(opts: RunOptions, state: Arc<S>) -> Result<()>
+#[tracing::instrument(skip(opts, state, reloaded_options))]
+pub async fn spawn<S>(
+    opts: Options,
+    state: Arc<S>,
+    reloaded_options: watch::Receiver<config::Options>,
+) -> Result<()>
 where
     S: Aggregates,
     S: Benchmarks,
     S: Cache,
+    S: DerivedFeeds,
     S: Metrics,
+    S: Publishers,
+    S: Wormhole,
     S: Send + Sync + 'static,
 {
+    let api_keys = match &opts.api_keys_file {
+        Some(path) => api_keys::load_api_keys(path)?,
+        None => vec![],
+    };
+    tracing::info!(count = api_keys.len(), "Loaded API keys.");
+
     let state = {
         let opts = opts.clone();
         ApiState::new(
             state,
-            opts.rpc.ws_whitelist,
-            opts.rpc.requester_ip_header_name,
+            opts.ws_whitelist,
+            opts.ws_bytes_limit_per_ip_per_second,
+            opts.requester_ip_header_name,
+            api_keys,
+            opts.api_key_required,
         )
     };
 
-    run(opts, state.clone()).await
+    // The gRPC server shares the same state as the REST server but is only started when it has
+    // been given an address to bind to.
+    let grpc = {
+        let listen_addr = opts.grpc_listen_addr;
+        let api_keys = state.api_keys.clone();
+        let state = state.state.clone();
+        async move {
+            match listen_addr {
+                Some(listen_addr) => grpc::run(listen_addr, state, api_keys).await,
+                None => Ok(()),
+            }
+        }
+    };
+
+    // The admin server is only started when it has been given an address to bind to, and it
+    // always requires a token.
+    let admin = {
+        let listen_addr = opts.admin_listen_addr;
+        let token = opts.admin_token.clone();
+        let state = state.clone();
+        async move {
+            match listen_addr {
+                Some(listen_addr) => {
+                    let token = token.context("The admin server requires an admin token")?;
+                    admin::run(listen_addr, token, state).await
+                }
+                None => Ok(()),
+            }
+        }
+    };
+
+    tokio::try_join!(
+        run(opts, state.clone()),
+        grpc,
+        admin,
+        apply_reloaded_options(reloaded_options, state.clone())
+    )?;
+    Ok(())
+}
+
+/// Apply the options reloaded while running that the API depends on: the websocket byte limit
+/// and whitelist, and the benchmarks endpoint.
+async fn apply_reloaded_options<S>(
+    mut reloaded_options: watch::Receiver<config::Options>,
+    state: ApiState<S>,
+) -> Result<()>
+where
+    S: Benchmarks,
+{
+    let mut exit = crate::EXIT.subscribe();
+    loop {
+        tokio::select! {
+            _ = exit.changed() => break,
+            Ok(()) = reloaded_options.changed() => {
+                let options = reloaded_options.borrow_and_update().clone();
+                if let Some(rpc) = options.rpc() {
+                    state
+                        .ws
+                        .set_bytes_limit(rpc.ws_whitelist.clone(), rpc.ws_bytes_limit_per_ip_per_second);
+                }
+                if let Some(benchmarks) = options.benchmarks() {
+                    Benchmarks::set_benchmarks_endpoint(&*state.state, benchmarks.endpoint.clone());
+                }
+            }
+        }
+    }
+    Ok(())
 }
 
 /// This method provides a background service that responds to REST requests
@@ -81,15 +183,17 @@ where
 /// Currently this is based on Axum due to the simplicity and strong ecosystem support for the
 /// packages they are based on (tokio & hyper).
 #[tracing::instrument(skip(opts, state))]
-pub async fn run<S>(opts: RunOptions, state: ApiState<S>) -> Result<()>
+pub async fn run<S>(opts: Options, state: ApiState<S>) -> Result<()>
 where
     S: Aggregates,
     S: Benchmarks,
     S: Cache,
+    S: DerivedFeeds,
     S: Metrics,
+    S: Publishers,
     S: Send + Sync + 'static,
 {
-    tracing::info!(endpoint = %opts.rpc.listen_addr, "Starting RPC Server.");
+    tracing::info!(endpoint = %opts.listen_addr, "Starting RPC Server.");
 
     #[derive(OpenApi)]
     #[openapi(
@@ -102,16 +206,24 @@ where
             rest::price_feed_ids,
             rest::latest_price_updates,
             rest::latest_twaps,
+            rest::timestamp_twaps,
             rest::latest_publisher_stake_caps,
             rest::timestamp_price_updates,
+            rest::ccip_price_updates,
+            rest::range_price_updates,
             rest::price_feeds_metadata,
+            rest::price_feed_publishers,
+            rest::price_feed_publishers_stream_sse_handler,
+            rest::price_feed_stats,
             rest::price_stream_sse_handler,
+            rest::twap_stream_sse_handler,
         ),
         components(
             schemas(
                 rest::GetVaaCcipInput,
                 rest::GetVaaCcipResponse,
                 rest::GetVaaResponse,
+                rest::CcipPriceUpdatesResponse,
                 types::PriceIdInput,
                 types::RpcPrice,
                 types::RpcPriceFeed,
@@ -119,15 +231,25 @@ where
                 types::RpcPriceIdentifier,
                 types::EncodingType,
                 types::PriceUpdate,
+                types::PriceUpdateRange,
+                rest::RangeType,
                 types::BinaryUpdate,
                 types::ParsedPriceUpdate,
+                types::ParsedDerivedPriceUpdate,
                 types::RpcPriceFeedMetadataV2,
                 types::PriceFeedMetadata,
+                types::PriceFeedMetadataWithPrice,
                 types::LatestPublisherStakeCapsUpdateDataResponse,
                 types::ParsedPublisherStakeCapsUpdate,
                 types::ParsedPublisherStakeCap,
                 types::AssetType,
                 types::TwapsResponse,
+                types::PriceFeedPublishersResponse,
+                types::RpcPublisherPrice,
+                types::RpcPublisherStatus,
+                types::PriceFeedStatsResponse,
+                types::RpcFeedStats,
+                types::RpcPublishTimeGapStats,
                 types::ParsedPriceFeedTwap,
             )
         ),
@@ -142,8 +264,6 @@ where
     let app = Router::new();
     #[allow(deprecated)]
     let app = app
-        .merge(SwaggerUi::new("/docs").url("/docs/openapi.json", ApiDoc::openapi()))
-        .route("/", get(rest::index))
         .route("/api/get_price_feed", get(rest::get_price_feed))
         .route("/api/get_vaa", get(rest::get_vaa))
         .route("/api/get_vaa_ccip", get(rest::get_vaa_ccip))
@@ -159,23 +279,41 @@ where
             "/v2/updates/twap/:window_seconds/latest",
             get(rest::latest_twaps),
         )
-        // TODO(Tejas)
-        // .route(
-        //     "/v2/updates/twap/:window_seconds/:publish_time",
-        //     get(rest::latest_twaps),
-        // )
+        .route(
+            "/v2/updates/twap/:window_seconds/stream",
+            get(rest::twap_stream_sse_handler),
+        )
+        .route(
+            "/v2/updates/twap/:window_seconds/:publish_time",
+            get(rest::timestamp_twaps),
+        )
         .route(
             "/v2/updates/publisher_stake_caps/latest",
             get(rest::latest_publisher_stake_caps),
         )
+        .route("/v2/updates/price/range", get(rest::range_price_updates))
+        .route("/v2/updates/price/ccip", get(rest::ccip_price_updates))
         .route(
             "/v2/updates/price/:publish_time",
             get(rest::timestamp_price_updates),
         )
         .route("/v2/price_feeds", get(rest::price_feeds_metadata))
+        .route(
+            "/v2/price_feeds/:id/publishers",
+            get(rest::price_feed_publishers),
+        )
+        .route(
+            "/v2/price_feeds/:id/publishers/stream",
+            get(rest::price_feed_publishers_stream_sse_handler),
+        )
+        .route("/v2/price_feeds/:id/stats", get(rest::price_feed_stats))
+        .route("/ws", get(ws::ws_route_handler))
+        .route_layer(from_fn_with_state(state.clone(), api_keys::authenticate))
+        // The index, documentation and health checks are left out of authentication.
+        .merge(SwaggerUi::new("/docs").url("/docs/openapi.json", ApiDoc::openapi()))
+        .route("/", get(rest::index))
         .route("/live", get(rest::live))
         .route("/ready", get(rest::ready))
-        .route("/ws", get(ws::ws_route_handler))
         .route_layer(from_fn_with_state(
             state.clone(),
             metrics_middleware::track_metrics,
@@ -189,7 +327,7 @@ where
 
     // Binds the axum's server to the configured address and port. This is a blocking call and will
     // not return until the server is shutdown.
-    axum::Server::try_bind(&opts.rpc.listen_addr)?
+    axum::Server::try_bind(&opts.listen_addr)?
         .serve(app.into_make_service())
         .with_graceful_shutdown(async {
             let _ = crate::EXIT.subscribe().changed().await;
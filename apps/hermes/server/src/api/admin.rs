@@ -0,0 +1,282 @@
+//! Admin server for runtime operations.
+//!
+//! It listens on its own address, separate from the public API, and every request must carry the
+//! admin token as a bearer `Authorization` header. It allows operators to:
+//!
+//! - `GET /cache`: inspect the size and occupancy of the caches, per message state key.
+//! - `GET /subscribers`: count the websocket connections and the aggregation event subscribers.
+//! - `GET /rejected_vaas`: list the most recently rejected VAAs, oldest first.
+//! - `POST /price_feeds_metadata/reload`: fetch the price feeds metadata without waiting for the
+//!   next scheduled update.
+//! - `PUT /guardian_sets/:index`: add a guardian set newer than the known ones.
+//! - `GET /api_keys` and `PUT /api_keys/:name/quotas`: inspect and change the API key quotas.
+
+use {
+    super::{api_keys::ApiKeyQuotas, types::RpcPriceIdentifier, ApiState},
+    crate::{
+        network::wormhole::GuardianSet,
+        state::{
+            aggregate::{Slot, UnixTimestamp},
+            cache::{CacheStats, MessageStateKeyStats, MessageStateTime},
+            price_feeds_metadata::PriceFeedMeta,
+            wormhole::{RejectedVaa, Wormhole},
+            Aggregates, Cache,
+        },
+    },
+    anyhow::{ensure, Result},
+    axum::{
+        extract::{Path, State},
+        http::{header::AUTHORIZATION, Request, StatusCode},
+        middleware::{from_fn_with_state, Next},
+        response::{IntoResponse, Response},
+        routing::{get, post, put},
+        Json, Router,
+    },
+    pythnet_sdk::messages::MessageType,
+    serde::{Deserialize, Serialize},
+    std::{
+        collections::HashMap,
+        net::SocketAddr,
+        sync::{atomic::Ordering, Arc},
+    },
+};
+
+#[derive(Debug, Serialize)]
+pub struct MessageStateTimeResponse {
+    pub slot: Slot,
+    pub publish_time: UnixTimestamp,
+}
+
+impl From<MessageStateTime> for MessageStateTimeResponse {
+    fn from(time: MessageStateTime) -> Self {
+        Self {
+            slot: time.slot,
+            publish_time: time.publish_time,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct MessageStateKeyStatsResponse {
+    pub feed_id: RpcPriceIdentifier,
+    pub message_type: MessageType,
+    pub len: usize,
+    /// Share of the cache size in use, between 0 and 1.
+    pub occupancy: f64,
+    pub oldest: Option<MessageStateTimeResponse>,
+    pub newest: Option<MessageStateTimeResponse>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CacheStatsResponse {
+    pub cache_size: u64,
+    pub accumulator_messages: usize,
+    pub wormhole_merkle_states: usize,
+    pub message_states: Vec<MessageStateKeyStatsResponse>,
+}
+
+impl From<CacheStats> for CacheStatsResponse {
+    fn from(stats: CacheStats) -> Self {
+        let cache_size = stats.cache_size;
+        let mut key_stats = stats.message_states;
+        key_stats.sort_by_key(|key_stats| {
+            (
+                key_stats.key.feed_id,
+                <&'static str>::from(key_stats.key.type_),
+            )
+        });
+        let message_states = key_stats
+            .into_iter()
+            .map(
+                |key_stats: MessageStateKeyStats| MessageStateKeyStatsResponse {
+                    feed_id: RpcPriceIdentifier::new(key_stats.key.feed_id),
+                    message_type: key_stats.key.type_,
+                    len: key_stats.len,
+                    occupancy: key_stats.len as f64 / cache_size.max(1) as f64,
+                    oldest: key_stats.oldest.map(Into::into),
+                    newest: key_stats.newest.map(Into::into),
+                },
+            )
+            .collect();
+
+        Self {
+            cache_size,
+            accumulator_messages: stats.accumulator_messages,
+            wormhole_merkle_states: stats.wormhole_merkle_states,
+            message_states,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct SubscribersResponse {
+    /// Number of open websocket connections.
+    pub websocket_connections: usize,
+    /// Number of subscribers to the aggregation events, which include every streaming client
+    /// (websocket, SSE and gRPC) and the sinks.
+    pub aggregation_event_subscribers: usize,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GuardianKey(#[serde(with = "crate::serde::hex")] [u8; 20]);
+
+#[derive(Debug, Deserialize)]
+pub struct GuardianSetRequest {
+    /// The addresses of the guardians, as hex strings.
+    pub keys: Vec<GuardianKey>,
+}
+
+/// Check the bearer token of a request against the admin token, in constant time. An empty admin
+/// token never authorizes a request.
+fn is_authorized(authorization: Option<&str>, token: &str) -> bool {
+    if token.is_empty() {
+        return false;
+    }
+    let Some(provided) = authorization.and_then(|value| value.strip_prefix("Bearer ")) else {
+        return false;
+    };
+    let provided = provided.trim().as_bytes();
+    provided.len() == token.len()
+        && provided
+            .iter()
+            .zip(token.as_bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+async fn authenticate<B>(
+    State(token): State<Arc<String>>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let authorization = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok());
+    if !is_authorized(authorization, &token) {
+        return (StatusCode::UNAUTHORIZED, "Missing or invalid admin token").into_response();
+    }
+    next.run(req).await
+}
+
+async fn cache_stats<S>(State(state): State<ApiState<S>>) -> Json<CacheStatsResponse>
+where
+    S: Cache,
+{
+    Json(Cache::cache_stats(&*state.state).await.into())
+}
+
+async fn subscribers<S>(State(state): State<ApiState<S>>) -> Json<SubscribersResponse>
+where
+    S: Aggregates,
+{
+    Json(SubscribersResponse {
+        websocket_connections: state.ws.active_connections.load(Ordering::SeqCst),
+        aggregation_event_subscribers: Aggregates::subscriber_count(&*state.state),
+    })
+}
+
+async fn rejected_vaas<S>(State(state): State<ApiState<S>>) -> Json<Vec<RejectedVaa>>
+where
+    S: Wormhole,
+{
+    Json(Wormhole::get_rejected_vaas(&*state.state).await)
+}
+
+async fn reload_price_feeds_metadata<S>(State(state): State<ApiState<S>>) -> StatusCode
+where
+    S: PriceFeedMeta,
+{
+    PriceFeedMeta::request_price_feeds_metadata_reload(&*state.state);
+    StatusCode::ACCEPTED
+}
+
+async fn update_guardian_set<S>(
+    State(state): State<ApiState<S>>,
+    Path(index): Path<u32>,
+    Json(request): Json<GuardianSetRequest>,
+) -> Result<StatusCode, (StatusCode, String)>
+where
+    S: Wormhole,
+{
+    let keys = request.keys.into_iter().map(|key| key.0).collect();
+    tracing::info!(index, "Adding guardian set from the admin server.");
+    Wormhole::add_guardian_set(&*state.state, index, GuardianSet { keys })
+        .await
+        .map_err(|e| (StatusCode::CONFLICT, e.to_string()))?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn api_key_quotas<S>(
+    State(state): State<ApiState<S>>,
+) -> Json<HashMap<String, ApiKeyQuotas>> {
+    Json(state.api_keys.quotas())
+}
+
+async fn update_api_key_quotas<S>(
+    State(state): State<ApiState<S>>,
+    Path(name): Path<String>,
+    Json(quotas): Json<ApiKeyQuotas>,
+) -> Result<Json<ApiKeyQuotas>, StatusCode> {
+    let api_key = state
+        .api_keys
+        .get_by_name(&name)
+        .ok_or(StatusCode::NOT_FOUND)?;
+    tracing::info!(
+        key = name,
+        ?quotas,
+        "Updating API key quotas from the admin server."
+    );
+    api_key.set_quotas(quotas);
+    Ok(Json(api_key.quotas()))
+}
+
+#[tracing::instrument(skip(token, state))]
+pub async fn run<S>(listen_addr: SocketAddr, token: String, state: ApiState<S>) -> Result<()>
+where
+    S: Aggregates,
+    S: Wormhole,
+    S: Send + Sync + 'static,
+{
+    ensure!(!token.is_empty(), "The admin token must not be empty");
+    tracing::info!(endpoint = %listen_addr, "Starting Admin Server.");
+
+    let app = Router::new()
+        .route("/cache", get(cache_stats))
+        .route("/subscribers", get(subscribers))
+        .route("/rejected_vaas", get(rejected_vaas))
+        .route(
+            "/price_feeds_metadata/reload",
+            post(reload_price_feeds_metadata),
+        )
+        .route("/guardian_sets/:index", put(update_guardian_set))
+        .route("/api_keys", get(api_key_quotas))
+        .route("/api_keys/:name/quotas", put(update_api_key_quotas))
+        .route_layer(from_fn_with_state(Arc::new(token), authenticate))
+        .with_state(state);
+
+    axum::Server::try_bind(&listen_addr)?
+        .serve(app.into_make_service())
+        .with_graceful_shutdown(async {
+            let _ = crate::EXIT.subscribe().changed().await;
+            tracing::info!("Shutting down admin server...");
+        })
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_admin_token_is_checked() {
+        assert!(is_authorized(Some("Bearer secret"), "secret"));
+        assert!(!is_authorized(Some("Bearer secrets"), "secret"));
+        assert!(!is_authorized(Some("Bearer secreT"), "secret"));
+        assert!(!is_authorized(Some("secret"), "secret"));
+        assert!(!is_authorized(None, "secret"));
+        assert!(!is_authorized(Some("Bearer "), ""));
+    }
+}
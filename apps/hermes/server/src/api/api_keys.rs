@@ -0,0 +1,673 @@
+//! API key authentication and per-key quotas.
+//!
+//! Keys are loaded from a JSON file given at startup, each key having an optional request quota
+//! and an optional byte quota per second. The byte quota is shared by every endpoint a key uses:
+//! REST responses, SSE streams, websocket and gRPC messages all draw from the same budget.
+
+use {
+    super::{rest::RestError, ApiState},
+    crate::state::metrics::Metrics,
+    anyhow::{ensure, Context, Result},
+    axum::{
+        body::{boxed, Bytes, HttpBody, StreamBody},
+        extract::State,
+        http::{
+            header::{AUTHORIZATION, CONTENT_TYPE},
+            HeaderMap, Request,
+        },
+        middleware::Next,
+        response::{IntoResponse, Response},
+    },
+    futures::{future, stream, StreamExt},
+    governor::{DefaultDirectRateLimiter, Quota, RateLimiter},
+    nonzero_ext::nonzero,
+    prometheus_client::{
+        encoding::{EncodeLabelSet, EncodeLabelValue},
+        metrics::{counter::Counter, family::Family},
+    },
+    serde::{Deserialize, Serialize},
+    std::{
+        borrow::Cow,
+        collections::{HashMap, HashSet},
+        num::NonZeroU32,
+        path::Path,
+        sync::{Arc, PoisonError, RwLock, RwLockReadGuard},
+    },
+};
+
+/// Header carrying the API key. Clients that cannot set headers, such as browser `EventSource` and
+/// `WebSocket`, can pass the key in the `api_key` query parameter instead.
+const API_KEY_HEADER: &str = "x-api-key";
+
+/// Number of seconds of quota a key can spend at once. A single response or message can't be
+/// split, so the byte quotas allow bursts larger than a second worth of bytes for the largest
+/// ones, such as every price feed at once. The quota still limits the average rate.
+const BYTES_BURST_SECONDS: NonZeroU32 = nonzero!(10u32);
+
+/// The burst of a byte quota, the largest response a key can receive at once.
+pub(super) fn bytes_burst(bytes_per_second: NonZeroU32) -> NonZeroU32 {
+    bytes_per_second.saturating_mul(BYTES_BURST_SECONDS)
+}
+
+/// The event sent at the end of an SSE stream cut for exceeding the byte quota, so that clients
+/// can tell it apart from a network failure.
+const QUOTA_EXCEEDED_EVENT: &[u8] = b"event: error\ndata: API key quota exceeded\n\n";
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct ApiKeyConfig {
+    /// The secret sent by the client.
+    pub key: String,
+    /// A name identifying the owner of the key. It is used in logs and metrics so the key itself
+    /// is never exposed.
+    pub name: String,
+    /// Maximum number of requests per second. Unlimited if not set.
+    #[serde(default)]
+    pub requests_per_second: Option<NonZeroU32>,
+    /// Maximum number of response bytes per second. Unlimited if not set. Responses larger than
+    /// 10 seconds of quota are always rejected.
+    #[serde(default)]
+    pub bytes_per_second: Option<NonZeroU32>,
+}
+
+/// Load the API keys from a JSON file containing a list of `ApiKeyConfig`.
+pub fn load_api_keys(path: &Path) -> Result<Vec<ApiKeyConfig>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read API keys file {}", path.display()))?;
+    let api_keys: Vec<ApiKeyConfig> = serde_json::from_str(&contents)
+        .with_context(|| format!("Failed to parse API keys file {}", path.display()))?;
+
+    let mut names = HashSet::new();
+    let mut keys = HashSet::new();
+    for api_key in &api_keys {
+        ensure!(
+            names.insert(api_key.name.as_str()),
+            "Duplicate API key name {}",
+            api_key.name
+        );
+        // The key itself is not logged, only the name of the entry reusing it.
+        ensure!(
+            keys.insert(api_key.key.as_str()),
+            "API key {} reuses the key of another entry",
+            api_key.name
+        );
+    }
+
+    Ok(api_keys)
+}
+
+/// The quotas of an API key. They can be changed at runtime through the admin server.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ApiKeyQuotas {
+    /// Maximum number of requests per second. Unlimited if not set.
+    #[serde(default)]
+    pub requests_per_second: Option<NonZeroU32>,
+    /// Maximum number of response bytes per second. Unlimited if not set.
+    #[serde(default)]
+    pub bytes_per_second: Option<NonZeroU32>,
+}
+
+struct RateLimiters {
+    quotas: ApiKeyQuotas,
+    request_limiter: Option<DefaultDirectRateLimiter>,
+    byte_limiter: Option<DefaultDirectRateLimiter>,
+}
+
+impl RateLimiters {
+    fn new(quotas: ApiKeyQuotas) -> Self {
+        Self {
+            quotas,
+            request_limiter: quotas
+                .requests_per_second
+                .map(|limit| RateLimiter::direct(Quota::per_second(limit))),
+            byte_limiter: quotas.bytes_per_second.map(|limit| {
+                RateLimiter::direct(Quota::per_second(limit).allow_burst(bytes_burst(limit)))
+            }),
+        }
+    }
+}
+
+pub struct ApiKey {
+    pub name: String,
+    limiters: RwLock<RateLimiters>,
+    bytes: Counter,
+    bytes_rate_limited: Counter,
+}
+
+impl ApiKey {
+    fn new(config: ApiKeyConfig, metrics: &ApiKeyMetrics) -> Self {
+        let labels = KeyLabels {
+            key: config.name.clone(),
+        };
+        Self {
+            name: config.name,
+            limiters: RwLock::new(RateLimiters::new(ApiKeyQuotas {
+                requests_per_second: config.requests_per_second,
+                bytes_per_second: config.bytes_per_second,
+            })),
+            bytes: metrics.bytes.get_or_create(&labels).clone(),
+            bytes_rate_limited: metrics.bytes_rate_limited.get_or_create(&labels).clone(),
+        }
+    }
+
+    fn limiters(&self) -> RwLockReadGuard<'_, RateLimiters> {
+        self.limiters.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn quotas(&self) -> ApiKeyQuotas {
+        self.limiters().quotas
+    }
+
+    /// Replace the quotas of the key. The budgets start over from full.
+    pub fn set_quotas(&self, quotas: ApiKeyQuotas) {
+        *self
+            .limiters
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = RateLimiters::new(quotas);
+    }
+
+    fn check_request(&self) -> bool {
+        self.limiters()
+            .request_limiter
+            .as_ref()
+            .is_none_or(|limiter| limiter.check().is_ok())
+    }
+
+    /// Draw `len` bytes from the byte quota of the key. Returns `false` if the quota is exceeded,
+    /// in which case the bytes should not be sent.
+    pub fn check_bytes(&self, len: usize) -> bool {
+        let allowed = match (
+            &self.limiters().byte_limiter,
+            u32::try_from(len).ok().and_then(NonZeroU32::new),
+        ) {
+            (None, _) => true,
+            (Some(limiter), Some(len)) => limiter.check_n(len) == Ok(Ok(())),
+            // Empty messages are free and anything larger than a u32 can never fit in the burst.
+            (Some(_), None) => len == 0,
+        };
+
+        if allowed {
+            self.bytes.inc_by(len as u64);
+        } else {
+            self.bytes_rate_limited.inc();
+        }
+        allowed
+    }
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelValue)]
+pub enum RequestStatus {
+    Allowed,
+    RateLimited,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, EncodeLabelSet)]
+pub struct RequestLabels {
+    pub key: String,
+    pub status: RequestStatus,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, EncodeLabelSet)]
+pub struct KeyLabels {
+    pub key: String,
+}
+
+pub struct ApiKeyMetrics {
+    pub requests: Family<RequestLabels, Counter>,
+    pub bytes: Family<KeyLabels, Counter>,
+    pub bytes_rate_limited: Family<KeyLabels, Counter>,
+    pub unauthorized: Counter,
+}
+
+impl ApiKeyMetrics {
+    pub fn new<S>(state: Arc<S>) -> Self
+    where
+        S: Metrics,
+        S: Send + Sync + 'static,
+    {
+        let new = Self {
+            requests: Family::default(),
+            bytes: Family::default(),
+            bytes_rate_limited: Family::default(),
+            unauthorized: Counter::default(),
+        };
+
+        {
+            let requests = new.requests.clone();
+            let bytes = new.bytes.clone();
+            let bytes_rate_limited = new.bytes_rate_limited.clone();
+            let unauthorized = new.unauthorized.clone();
+
+            tokio::spawn(async move {
+                Metrics::register(
+                    &*state,
+                    (
+                        "api_key_requests",
+                        "Total number of requests made with each API key",
+                        requests,
+                    ),
+                )
+                .await;
+                Metrics::register(
+                    &*state,
+                    (
+                        "api_key_bytes",
+                        "Total number of bytes sent to each API key",
+                        bytes,
+                    ),
+                )
+                .await;
+                Metrics::register(
+                    &*state,
+                    (
+                        "api_key_bytes_rate_limited",
+                        "Total number of responses and messages dropped for exceeding the byte \
+                         quota of an API key",
+                        bytes_rate_limited,
+                    ),
+                )
+                .await;
+                Metrics::register(
+                    &*state,
+                    (
+                        "api_key_unauthorized_requests",
+                        "Total number of requests rejected for a missing or unknown API key",
+                        unauthorized,
+                    ),
+                )
+                .await;
+            });
+        }
+
+        new
+    }
+}
+
+pub struct ApiKeysState {
+    keys: HashMap<String, Arc<ApiKey>>,
+    /// If set, requests without an API key are rejected. Otherwise they are served without quotas
+    /// other than the per-IP websocket limit.
+    required: bool,
+    pub metrics: ApiKeyMetrics,
+}
+
+impl ApiKeysState {
+    pub fn new<S>(api_keys: Vec<ApiKeyConfig>, required: bool, state: Arc<S>) -> Self
+    where
+        S: Metrics,
+        S: Send + Sync + 'static,
+    {
+        let metrics = ApiKeyMetrics::new(state);
+        Self {
+            keys: api_keys
+                .into_iter()
+                .map(|config| (config.key.clone(), Arc::new(ApiKey::new(config, &metrics))))
+                .collect(),
+            required,
+            metrics,
+        }
+    }
+
+    /// The quotas of every key, by key name.
+    pub fn quotas(&self) -> HashMap<String, ApiKeyQuotas> {
+        self.keys
+            .values()
+            .map(|api_key| (api_key.name.clone(), api_key.quotas()))
+            .collect()
+    }
+
+    /// Find a key by its name, as the secret is never exposed.
+    pub fn get_by_name(&self, name: &str) -> Option<Arc<ApiKey>> {
+        self.keys
+            .values()
+            .find(|api_key| api_key.name == name)
+            .cloned()
+    }
+
+    /// Resolve the API key of a request.
+    fn authenticate(&self, key: Option<Cow<'_, str>>) -> Result<Option<Arc<ApiKey>>, RestError> {
+        match key {
+            Some(key) => self
+                .keys
+                .get(key.as_ref())
+                .cloned()
+                .map(Some)
+                .ok_or(RestError::Unauthorized),
+            None if self.required => Err(RestError::Unauthorized),
+            None => Ok(None),
+        }
+    }
+
+    /// Resolve the API key of a request from its headers or query string, and charge the request
+    /// to the request quota of the key. This is shared by the REST and gRPC servers.
+    pub fn authorize(
+        &self,
+        headers: &HeaderMap,
+        query: Option<&str>,
+    ) -> Result<Option<Arc<ApiKey>>, RestError> {
+        let api_key = match self.authenticate(extract_api_key(headers, query)) {
+            Ok(Some(api_key)) => api_key,
+            Ok(None) => return Ok(None),
+            Err(e) => {
+                self.metrics.unauthorized.inc();
+                return Err(e);
+            }
+        };
+
+        let allowed = api_key.check_request();
+        self.metrics
+            .requests
+            .get_or_create(&RequestLabels {
+                key: api_key.name.clone(),
+                status: if allowed {
+                    RequestStatus::Allowed
+                } else {
+                    RequestStatus::RateLimited
+                },
+            })
+            .inc();
+        if !allowed {
+            tracing::debug!(key = api_key.name, "API key request quota exceeded.");
+            return Err(RestError::TooManyRequests);
+        }
+
+        Ok(Some(api_key))
+    }
+}
+
+#[derive(Deserialize)]
+struct ApiKeyQuery {
+    api_key: Option<String>,
+}
+
+/// Extract the API key from the `x-api-key` header, a bearer `Authorization` header or the
+/// URL encoded `api_key` query parameter, in this order.
+fn extract_api_key<'a>(headers: &'a HeaderMap, query: Option<&str>) -> Option<Cow<'a, str>> {
+    let header_key = headers
+        .get(API_KEY_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .or_else(|| {
+            headers
+                .get(AUTHORIZATION)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.strip_prefix("Bearer "))
+        });
+    match header_key {
+        Some(key) => Some(Cow::Borrowed(key.trim())),
+        None => serde_qs::from_str::<ApiKeyQuery>(query?)
+            .ok()?
+            .api_key
+            .map(|key| Cow::Owned(key.trim().to_string())),
+    }
+}
+
+/// Authenticate requests and enforce the quotas of their API key.
+///
+/// The resolved key is added to the request extensions so handlers that send data outside of the
+/// response body, such as websockets, can charge it to the byte quota. Responses of a known size
+/// are rejected if they don't fit in the byte quota, while streamed responses are cut once it is
+/// exhausted.
+pub async fn authenticate<B, S>(
+    State(api_state): State<ApiState<S>>,
+    mut req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let api_key = match api_state
+        .api_keys
+        .authorize(req.headers(), req.uri().query())
+    {
+        Ok(Some(api_key)) => api_key,
+        Ok(None) => return next.run(req).await,
+        Err(e) => return e.into_response(),
+    };
+
+    req.extensions_mut().insert(api_key.clone());
+    let response = next.run(req).await;
+
+    if let Some(len) = response.body().size_hint().exact() {
+        if !api_key.check_bytes(len as usize) {
+            tracing::debug!(key = api_key.name, "API key byte quota exceeded.");
+            return RestError::TooManyRequests.into_response();
+        }
+        return response;
+    }
+
+    // A streamed response is cut at the first chunk exceeding the quota. SSE streams are ended
+    // with an error event rather than silently.
+    let (parts, body) = response.into_parts();
+    let is_event_stream = parts
+        .headers
+        .get(CONTENT_TYPE)
+        .is_some_and(|value| value.as_bytes().starts_with(b"text/event-stream"));
+    let chunks = stream::unfold(body, |mut body| async move {
+        body.data().await.map(|chunk| (chunk, body))
+    })
+    .scan(false, move |quota_exceeded, chunk| {
+        if *quota_exceeded {
+            return future::ready(None);
+        }
+        let chunk = match chunk {
+            Ok(bytes) if !api_key.check_bytes(bytes.len()) => {
+                tracing::debug!(key = api_key.name, "API key byte quota exceeded.");
+                *quota_exceeded = true;
+                if !is_event_stream {
+                    return future::ready(None);
+                }
+                Ok(Bytes::from_static(QUOTA_EXCEEDED_EVENT))
+            }
+            chunk => chunk,
+        };
+        future::ready(Some(chunk))
+    });
+    Response::from_parts(parts, boxed(StreamBody::new(chunks)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn api_key(requests_per_second: Option<u32>, bytes_per_second: Option<u32>) -> ApiKey {
+        let metrics = ApiKeyMetrics {
+            requests: Family::default(),
+            bytes: Family::default(),
+            bytes_rate_limited: Family::default(),
+            unauthorized: Counter::default(),
+        };
+        ApiKey::new(
+            ApiKeyConfig {
+                key: "secret".to_string(),
+                name: "test".to_string(),
+                requests_per_second: requests_per_second.and_then(NonZeroU32::new),
+                bytes_per_second: bytes_per_second.and_then(NonZeroU32::new),
+            },
+            &metrics,
+        )
+    }
+
+    #[test]
+    fn test_extract_api_key_works() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(extract_api_key(&headers, None), None);
+        assert_eq!(
+            extract_api_key(&headers, Some("ids[]=abc&api_key=from_query")).as_deref(),
+            Some("from_query")
+        );
+        assert_eq!(
+            extract_api_key(&headers, Some("api_key=a%2Bb%3D%3D")).as_deref(),
+            Some("a+b==")
+        );
+        assert_eq!(extract_api_key(&headers, Some("api_keys=abc")), None);
+
+        headers.insert(AUTHORIZATION, "Bearer from_bearer".parse().unwrap());
+        assert_eq!(
+            extract_api_key(&headers, Some("api_key=from_query")).as_deref(),
+            Some("from_bearer")
+        );
+
+        headers.insert(API_KEY_HEADER, "from_header".parse().unwrap());
+        assert_eq!(
+            extract_api_key(&headers, Some("api_key=from_query")).as_deref(),
+            Some("from_header")
+        );
+    }
+
+    #[test]
+    fn test_api_key_quotas_work() {
+        let unlimited = api_key(None, None);
+        for _ in 0..100 {
+            assert!(unlimited.check_request());
+            assert!(unlimited.check_bytes(1024 * 1024));
+        }
+
+        let limited = api_key(Some(2), Some(100));
+        assert!(limited.check_request());
+        assert!(limited.check_request());
+        assert!(!limited.check_request());
+
+        // A response larger than the quota fits in the burst, which then refills at the quota
+        // rate.
+        let burst = bytes_burst(nonzero!(100u32)).get() as usize;
+        assert!(limited.check_bytes(0));
+        assert!(limited.check_bytes(60));
+        assert!(limited.check_bytes(burst - 60));
+        assert!(!limited.check_bytes(1000));
+        assert!(!limited.check_bytes(burst + 1));
+        assert_eq!(limited.bytes.get(), burst as u64);
+        assert_eq!(limited.bytes_rate_limited.get(), 2);
+    }
+
+    #[test]
+    fn test_api_key_quotas_can_be_changed() {
+        let api_key = api_key(Some(1), None);
+        assert!(api_key.check_request());
+        assert!(!api_key.check_request());
+
+        let quotas = ApiKeyQuotas {
+            requests_per_second: None,
+            bytes_per_second: NonZeroU32::new(10),
+        };
+        api_key.set_quotas(quotas);
+        assert_eq!(api_key.quotas(), quotas);
+        assert!(api_key.check_request());
+        assert!(api_key.check_request());
+        assert!(!api_key.check_bytes(bytes_burst(nonzero!(10u32)).get() as usize + 1));
+    }
+
+    #[tokio::test]
+    async fn test_sse_stream_ends_with_an_error_when_the_byte_quota_is_exceeded() {
+        use {
+            axum::{
+                middleware::from_fn_with_state,
+                response::sse::{Event, Sse},
+                routing::get,
+                Router,
+            },
+            nonzero_ext::nonzero,
+            std::convert::Infallible,
+        };
+
+        let (state, _) = crate::state::test::setup_state(10).await;
+        let api_state = ApiState::new(
+            state,
+            vec![],
+            nonzero!(1u32),
+            String::new(),
+            vec![ApiKeyConfig {
+                key: "a+b".to_string(),
+                name: "test".to_string(),
+                requests_per_second: None,
+                bytes_per_second: NonZeroU32::new(2),
+            }],
+            true,
+        );
+        let app = Router::new()
+            .route(
+                "/stream",
+                get(|| async {
+                    Sse::new(stream::iter(
+                        [
+                            "small".to_string(),
+                            "x".repeat(bytes_burst(nonzero!(2u32)).get() as usize),
+                        ]
+                        .map(|data| Ok::<_, Infallible>(Event::default().data(data))),
+                    ))
+                }),
+            )
+            .route_layer(from_fn_with_state(api_state.clone(), authenticate))
+            .with_state(api_state);
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+
+        let response = reqwest::get(format!("http://{}/stream", addr))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 401);
+
+        let body = reqwest::get(format!("http://{}/stream?api_key=a%2Bb", addr))
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert_eq!(
+            body,
+            format!(
+                "data: small\n\n{}",
+                std::str::from_utf8(QUOTA_EXCEEDED_EVENT).unwrap()
+            )
+        );
+    }
+
+    #[test]
+    fn test_load_api_keys_works() {
+        let dir = std::env::temp_dir().join(format!("hermes-api-keys-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join("keys.json");
+        std::fs::write(
+            &path,
+            r#"[
+                {"key": "a", "name": "alice", "requests_per_second": 10},
+                {"key": "b", "name": "bob", "bytes_per_second": 1024}
+            ]"#,
+        )
+        .unwrap();
+        assert_eq!(
+            load_api_keys(&path).unwrap(),
+            vec![
+                ApiKeyConfig {
+                    key: "a".to_string(),
+                    name: "alice".to_string(),
+                    requests_per_second: NonZeroU32::new(10),
+                    bytes_per_second: None,
+                },
+                ApiKeyConfig {
+                    key: "b".to_string(),
+                    name: "bob".to_string(),
+                    requests_per_second: None,
+                    bytes_per_second: NonZeroU32::new(1024),
+                },
+            ]
+        );
+
+        std::fs::write(
+            &path,
+            r#"[{"key": "a", "name": "alice"}, {"key": "b", "name": "alice"}]"#,
+        )
+        .unwrap();
+        assert!(load_api_keys(&path).is_err());
+
+        std::fs::write(
+            &path,
+            r#"[{"key": "a", "name": "alice"}, {"key": "a", "name": "bob"}]"#,
+        )
+        .unwrap();
+        assert!(load_api_keys(&path).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,191 @@
+//! Compact binary framing of the websocket price updates, for clients subscribing with
+//! `"framing": "binary"`. It follows the framing of the Lazer `BinaryWsUpdate`: all the updates of
+//! a slot are sent in a single binary message made of
+//!
+//! - `u32` (LE): `BINARY_UPDATE_FORMAT_MAGIC`
+//! - `u64` (BE): the slot of the updates
+//! - for each price feed, a `u16` (BE) length followed by a `BinaryPriceUpdate`:
+//!   - `[u8; 32]`: the price feed id
+//!   - `i64`, `u64`, `i32`, `i64` (BE): the price, confidence, exponent and publish time
+//!   - `i64`, `u64` (BE): the EMA price and confidence
+//!   - a `u16` (BE) length followed by the `AccumulatorUpdateData` of the feed, which is empty
+//!     unless `binary` is requested
+
+use {
+    crate::state::aggregate::{PriceFeedUpdate, Slot},
+    anyhow::Result,
+    byteorder::{WriteBytesExt, BE, LE},
+    pyth_sdk::{PriceIdentifier, UnixTimestamp},
+};
+
+/// First bytes (LE) of a binary websocket message, spelling `PHWS`.
+pub const BINARY_UPDATE_FORMAT_MAGIC: u32 = u32::from_le_bytes(*b"PHWS");
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BinaryPriceUpdate {
+    pub id: PriceIdentifier,
+    pub price: i64,
+    pub conf: u64,
+    pub expo: i32,
+    pub publish_time: UnixTimestamp,
+    pub ema_price: i64,
+    pub ema_conf: u64,
+    pub update_data: Vec<u8>,
+}
+
+impl BinaryPriceUpdate {
+    pub fn from_price_feed_update(price_feed_update: PriceFeedUpdate, binary: bool) -> Self {
+        let price = price_feed_update.price_feed.get_price_unchecked();
+        let ema_price = price_feed_update.price_feed.get_ema_price_unchecked();
+        Self {
+            id: price_feed_update.price_feed.id,
+            price: price.price,
+            conf: price.conf,
+            expo: price.expo,
+            publish_time: price.publish_time,
+            ema_price: ema_price.price,
+            ema_conf: ema_price.conf,
+            update_data: match binary {
+                false => vec![],
+                true => price_feed_update.update_data.unwrap_or_default(),
+            },
+        }
+    }
+
+    fn serialize(&self, buf: &mut Vec<u8>) -> Result<()> {
+        buf.extend_from_slice(&self.id.to_bytes());
+        buf.write_i64::<BE>(self.price)?;
+        buf.write_u64::<BE>(self.conf)?;
+        buf.write_i32::<BE>(self.expo)?;
+        buf.write_i64::<BE>(self.publish_time)?;
+        buf.write_i64::<BE>(self.ema_price)?;
+        buf.write_u64::<BE>(self.ema_conf)?;
+        buf.write_u16::<BE>(self.update_data.len().try_into()?)?;
+        buf.extend_from_slice(&self.update_data);
+        Ok(())
+    }
+}
+
+/// Content of a binary websocket message with the updates of a slot.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BinaryWsUpdate {
+    pub slot: Slot,
+    pub updates: Vec<BinaryPriceUpdate>,
+}
+
+impl BinaryWsUpdate {
+    pub fn serialize(&self, buf: &mut Vec<u8>) -> Result<()> {
+        buf.write_u32::<LE>(BINARY_UPDATE_FORMAT_MAGIC)?;
+        buf.write_u64::<BE>(self.slot)?;
+
+        for update in &self.updates {
+            // Reserve the length header and fill it in once the update is written.
+            let len_index = buf.len();
+            buf.extend_from_slice(&[0, 0]);
+            update.serialize(buf)?;
+            let len: u16 = (buf.len() - len_index - 2).try_into()?;
+            buf[len_index..len_index + 2].copy_from_slice(&len.to_be_bytes());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use {
+        super::*,
+        anyhow::bail,
+        pyth_sdk::{Price, PriceFeed},
+    };
+
+    fn take<'a>(data: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+        if data.len() < len {
+            bail!("data too short");
+        }
+        let (bytes, rest) = data.split_at(len);
+        *data = rest;
+        Ok(bytes)
+    }
+
+    /// Parse a binary websocket message like a client would.
+    fn deserialize(mut data: &[u8]) -> Result<BinaryWsUpdate> {
+        if u32::from_le_bytes(take(&mut data, 4)?.try_into()?) != BINARY_UPDATE_FORMAT_MAGIC {
+            bail!("binary update format magic mismatch");
+        }
+        let slot = u64::from_be_bytes(take(&mut data, 8)?.try_into()?);
+
+        let mut updates = vec![];
+        while !data.is_empty() {
+            let len = u16::from_be_bytes(take(&mut data, 2)?.try_into()?);
+            let mut update = take(&mut data, len.into())?;
+            updates.push(BinaryPriceUpdate {
+                id: PriceIdentifier::new(take(&mut update, 32)?.try_into()?),
+                price: i64::from_be_bytes(take(&mut update, 8)?.try_into()?),
+                conf: u64::from_be_bytes(take(&mut update, 8)?.try_into()?),
+                expo: i32::from_be_bytes(take(&mut update, 4)?.try_into()?),
+                publish_time: i64::from_be_bytes(take(&mut update, 8)?.try_into()?),
+                ema_price: i64::from_be_bytes(take(&mut update, 8)?.try_into()?),
+                ema_conf: u64::from_be_bytes(take(&mut update, 8)?.try_into()?),
+                update_data: {
+                    let len = u16::from_be_bytes(take(&mut update, 2)?.try_into()?);
+                    take(&mut update, len.into())?.to_vec()
+                },
+            });
+            if !update.is_empty() {
+                bail!("unexpected data after price update");
+            }
+        }
+
+        Ok(BinaryWsUpdate { slot, updates })
+    }
+
+    #[test]
+    fn test_binary_ws_update_roundtrips() {
+        let price_feed_update = |id: u8, update_data: Option<Vec<u8>>| PriceFeedUpdate {
+            price_feed: PriceFeed::new(
+                PriceIdentifier::new([id; 32]),
+                Price {
+                    price: -100,
+                    conf: 5,
+                    expo: -8,
+                    publish_time: 1_700_000_000,
+                },
+                Price {
+                    price: 98,
+                    conf: 4,
+                    expo: -8,
+                    publish_time: 1_700_000_000,
+                },
+            ),
+            slot: Some(10),
+            received_at: None,
+            update_data,
+            prev_publish_time: None,
+        };
+
+        let update = BinaryWsUpdate {
+            slot: 10,
+            updates: vec![
+                BinaryPriceUpdate::from_price_feed_update(
+                    price_feed_update(2, Some(vec![1, 2, 3])),
+                    true,
+                ),
+                BinaryPriceUpdate::from_price_feed_update(
+                    price_feed_update(3, Some(vec![1, 2, 3])),
+                    false,
+                ),
+            ],
+        };
+        assert_eq!(update.updates[0].update_data, vec![1, 2, 3]);
+        assert!(update.updates[1].update_data.is_empty());
+
+        let mut buf = vec![];
+        update.serialize(&mut buf).unwrap();
+        assert_eq!(&buf[..4], b"PHWS");
+        // The header, then each update with its length header, fixed fields and update data.
+        assert_eq!(buf.len(), 12 + (2 + 78 + 3) + (2 + 78));
+        assert_eq!(deserialize(&buf).unwrap(), update);
+
+        assert!(deserialize(&buf[..buf.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,747 @@
+use {
+    self::proto::{
+        hermes_server::{Hermes, HermesServer},
+        GetLatestPriceUpdatesRequest, GetLatestTwapsRequest, GetPriceFeedsMetadataRequest,
+        GetPriceFeedsMetadataResponse, GetPriceUpdatesByTimeRequest, ParsedPriceFeedTwap,
+        ParsedPriceUpdate, Price, PriceFeedMetadata, PriceFeedUpdateMetadata, PriceUpdate,
+        SubscribePriceUpdatesRequest, TwapsUpdate,
+    },
+    crate::{
+        api::{
+            api_keys::{ApiKey, ApiKeysState},
+            rest::{RestError, MAX_TWAP_WINDOW_SECONDS},
+            types::AssetType,
+        },
+        state::{
+            aggregate::{
+                Aggregates, AggregationEvent, PriceFeedTwap, PriceFeedUpdate, RequestTime,
+            },
+            price_feeds_metadata::{search::PriceFeedsMetadataFilter, PriceFeedMeta},
+        },
+    },
+    anyhow::Result,
+    futures::{future, Stream, StreamExt},
+    pyth_sdk::{PriceIdentifier, UnixTimestamp},
+    serde::{de::IntoDeserializer, Deserialize},
+    std::{net::SocketAddr, pin::Pin, sync::Arc},
+    tokio_stream::wrappers::BroadcastStream,
+    tonic::{transport::Server, Request, Response, Status},
+};
+
+#[cfg(test)]
+use mock_instant::{SystemTime, UNIX_EPOCH};
+#[cfg(not(test))]
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Hermes gRPC `prost` compiled definitions, built from `proto/hermes` by build.rs.
+mod proto {
+    tonic::include_proto!("hermes.v1");
+}
+
+/// gRPC service exposing the same data as the REST API, backed by the shared `Aggregates` state.
+pub struct HermesService<S> {
+    state: Arc<S>,
+}
+
+impl<S> HermesService<S> {
+    pub fn new(state: Arc<S>) -> Self {
+        Self { state }
+    }
+}
+
+impl From<PriceFeedUpdate> for ParsedPriceUpdate {
+    fn from(price_feed_update: PriceFeedUpdate) -> Self {
+        let price_feed = price_feed_update.price_feed;
+        let price = price_feed.get_price_unchecked();
+        let ema_price = price_feed.get_ema_price_unchecked();
+
+        Self {
+            id: price_feed.id.to_bytes().to_vec(),
+            price: Some(Price {
+                price: price.price,
+                conf: price.conf,
+                expo: price.expo,
+                publish_time: price.publish_time,
+            }),
+            ema_price: Some(Price {
+                price: ema_price.price,
+                conf: ema_price.conf,
+                expo: ema_price.expo,
+                publish_time: ema_price.publish_time,
+            }),
+            metadata: Some(PriceFeedUpdateMetadata {
+                slot: price_feed_update.slot,
+                proof_available_time: price_feed_update.received_at,
+                prev_publish_time: price_feed_update.prev_publish_time,
+            }),
+        }
+    }
+}
+
+impl From<PriceFeedTwap> for ParsedPriceFeedTwap {
+    fn from(twap: PriceFeedTwap) -> Self {
+        Self {
+            id: twap.id.to_bytes().to_vec(),
+            start_timestamp: twap.start_timestamp,
+            end_timestamp: twap.end_timestamp,
+            twap: Some(Price {
+                price: twap.twap.price,
+                conf: twap.twap.conf,
+                expo: twap.twap.expo,
+                publish_time: twap.twap.publish_time,
+            }),
+            down_slots_ratio: twap.down_slots_ratio.to_string(),
+        }
+    }
+}
+
+/// Parse the requested price feed ids and check that they exist in the aggregate state.
+///
+/// Unknown ids are dropped if `remove_invalid` is set, otherwise they fail the request in the
+/// same way as `rest::validate_price_ids`.
+async fn validate_price_ids<S>(
+    state: &S,
+    ids: Vec<Vec<u8>>,
+    remove_invalid: bool,
+) -> Result<Vec<PriceIdentifier>, Status>
+where
+    S: Aggregates,
+{
+    let price_ids = ids
+        .into_iter()
+        .map(|id| {
+            <[u8; 32]>::try_from(id.as_slice())
+                .ok()
+                .map(PriceIdentifier::new)
+        })
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(|| Status::invalid_argument("Price feed ids must be 32 bytes long"))?;
+
+    let available_ids = Aggregates::get_price_feed_ids(state).await;
+    let (valid_ids, invalid_ids): (Vec<_>, Vec<_>) = price_ids
+        .into_iter()
+        .partition(|id| available_ids.contains(id));
+
+    if invalid_ids.is_empty() || remove_invalid {
+        Ok(valid_ids)
+    } else {
+        let missing_ids = invalid_ids
+            .iter()
+            .map(|id| id.to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        Err(Status::not_found(format!(
+            "Price ids not found: {}",
+            missing_ids
+        )))
+    }
+}
+
+async fn get_price_update<S>(
+    state: &S,
+    price_ids: &[PriceIdentifier],
+    request_time: RequestTime,
+) -> Result<PriceUpdate, Status>
+where
+    S: Aggregates,
+{
+    let price_feeds_with_update_data =
+        Aggregates::get_price_feeds_with_update_data(state, price_ids, request_time.clone())
+            .await
+            .map_err(|e| {
+                tracing::warn!(
+                    "Error getting price feeds {:?} at {:?} with update data: {:?}",
+                    price_ids,
+                    request_time,
+                    e
+                );
+                Status::not_found("Update data not found")
+            })?;
+
+    Ok(PriceUpdate {
+        update_data: price_feeds_with_update_data.update_data,
+        parsed: price_feeds_with_update_data
+            .price_feeds
+            .into_iter()
+            .map(Into::into)
+            .collect(),
+    })
+}
+
+/// Build the update sent to a subscriber for an aggregation event, mirroring the SSE stream.
+async fn handle_aggregation_event<S>(
+    state: &S,
+    event: AggregationEvent,
+    mut price_ids: Vec<PriceIdentifier>,
+    allow_unordered: bool,
+    benchmarks_only: bool,
+) -> Result<Option<PriceUpdate>, Status>
+where
+    S: Aggregates,
+{
+    if let AggregationEvent::OutOfOrder { .. } = event {
+        if !allow_unordered {
+            return Ok(None);
+        }
+    }
+
+    // Price feeds can be removed while subscribed, so only request the ones still available.
+    let available_price_feed_ids = Aggregates::get_price_feed_ids(state).await;
+    price_ids.retain(|price_id| available_price_feed_ids.contains(price_id));
+
+    // Only the price feeds updated in the slot are sent, the others have no update to send.
+    let mut price_ids =
+        Aggregates::get_price_feed_ids_at_slot(state, &price_ids, event.slot()).await;
+    if price_ids.is_empty() {
+        return Ok(None);
+    }
+
+    let mut update = get_price_update(state, &price_ids, RequestTime::AtSlot(event.slot())).await?;
+
+    if benchmarks_only {
+        update.parsed.retain(|parsed| {
+            let publish_time = parsed.price.as_ref().map(|price| price.publish_time);
+            parsed
+                .metadata
+                .as_ref()
+                .and_then(|metadata| metadata.prev_publish_time)
+                .is_some_and(|prev_publish_time| Some(prev_publish_time) != publish_time)
+        });
+        price_ids.retain(|price_id| {
+            update
+                .parsed
+                .iter()
+                .any(|parsed| parsed.id == price_id.to_bytes())
+        });
+        if !price_ids.is_empty() {
+            update = get_price_update(state, &price_ids, RequestTime::AtSlot(event.slot())).await?;
+        }
+    }
+
+    if price_ids.is_empty() {
+        return Ok(None);
+    }
+
+    Ok(Some(update))
+}
+
+/// Authenticate a request with the same API keys and request quotas as the REST API. The key is
+/// added to the request extensions so that the responses are charged to its byte quota.
+fn authorize(api_keys: &ApiKeysState, mut request: Request<()>) -> Result<Request<()>, Status> {
+    match api_keys.authorize(&request.metadata().clone().into_headers(), None) {
+        Ok(api_key) => {
+            if let Some(api_key) = api_key {
+                request.extensions_mut().insert(api_key);
+            }
+            Ok(request)
+        }
+        Err(RestError::TooManyRequests) => {
+            Err(Status::resource_exhausted("API key quota exceeded"))
+        }
+        Err(_) => Err(Status::unauthenticated("Missing or invalid API key")),
+    }
+}
+
+/// Charge a response to the byte quota of the API key of the request, if any.
+fn check_bytes<M: prost::Message>(api_key: Option<&ApiKey>, message: &M) -> Result<(), Status> {
+    match api_key {
+        Some(api_key) if !api_key.check_bytes(message.encoded_len()) => {
+            tracing::debug!(key = api_key.name, "API key byte quota exceeded.");
+            Err(Status::resource_exhausted("API key quota exceeded"))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn request_api_key<T>(request: &Request<T>) -> Option<Arc<ApiKey>> {
+    request.extensions().get::<Arc<ApiKey>>().cloned()
+}
+
+#[tonic::async_trait]
+impl<S> Hermes for HermesService<S>
+where
+    S: Aggregates,
+    S: Send + Sync + 'static,
+{
+    type SubscribePriceUpdatesStream =
+        Pin<Box<dyn Stream<Item = Result<PriceUpdate, Status>> + Send + 'static>>;
+
+    async fn get_latest_price_updates(
+        &self,
+        request: Request<GetLatestPriceUpdatesRequest>,
+    ) -> Result<Response<PriceUpdate>, Status> {
+        let api_key = request_api_key(&request);
+        let request = request.into_inner();
+        let price_ids =
+            validate_price_ids(&*self.state, request.ids, request.ignore_invalid_price_ids).await?;
+        let update = get_price_update(&*self.state, &price_ids, RequestTime::Latest).await?;
+        check_bytes(api_key.as_deref(), &update)?;
+        Ok(Response::new(update))
+    }
+
+    async fn get_price_updates_by_time(
+        &self,
+        request: Request<GetPriceUpdatesByTimeRequest>,
+    ) -> Result<Response<PriceUpdate>, Status> {
+        let api_key = request_api_key(&request);
+        let request = request.into_inner();
+        let price_ids =
+            validate_price_ids(&*self.state, request.ids, request.ignore_invalid_price_ids).await?;
+        let update = get_price_update(
+            &*self.state,
+            &price_ids,
+            RequestTime::FirstAfter(request.publish_time),
+        )
+        .await?;
+        check_bytes(api_key.as_deref(), &update)?;
+        Ok(Response::new(update))
+    }
+
+    async fn get_latest_twaps(
+        &self,
+        request: Request<GetLatestTwapsRequest>,
+    ) -> Result<Response<TwapsUpdate>, Status> {
+        let api_key = request_api_key(&request);
+        let request = request.into_inner();
+        if request.window_seconds == 0 || request.window_seconds > MAX_TWAP_WINDOW_SECONDS {
+            return Err(Status::invalid_argument(format!(
+                "window_seconds must be in range (0, {}]",
+                MAX_TWAP_WINDOW_SECONDS
+            )));
+        }
+
+        let price_ids =
+            validate_price_ids(&*self.state, request.ids, request.ignore_invalid_price_ids).await?;
+
+        let current_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|_| Status::internal("System time is before the unix epoch"))?
+            .as_secs() as UnixTimestamp;
+        let start_time = current_time - request.window_seconds as i64;
+
+        let twaps_with_update_data = Aggregates::get_twaps_with_update_data(
+            &*self.state,
+            &price_ids,
+            RequestTime::FirstAfter(start_time),
+            RequestTime::Latest,
+        )
+        .await
+        .map_err(|e| {
+            tracing::warn!(
+                "Error getting TWAPs for price IDs {:?} with update data: {:?}",
+                price_ids,
+                e
+            );
+            Status::not_found("Update data not found")
+        })?;
+
+        let update = TwapsUpdate {
+            update_data: twaps_with_update_data.update_data,
+            parsed: twaps_with_update_data
+                .twaps
+                .into_iter()
+                .map(Into::into)
+                .collect(),
+        };
+        check_bytes(api_key.as_deref(), &update)?;
+        Ok(Response::new(update))
+    }
+
+    async fn subscribe_price_updates(
+        &self,
+        request: Request<SubscribePriceUpdatesRequest>,
+    ) -> Result<Response<Self::SubscribePriceUpdatesStream>, Status> {
+        let api_key = request_api_key(&request);
+        let request = request.into_inner();
+        let price_ids =
+            validate_price_ids(&*self.state, request.ids, request.ignore_invalid_price_ids).await?;
+
+        let state = self.state.clone();
+        let update_rx = Aggregates::subscribe(&*self.state);
+        let mut exit = crate::EXIT.subscribe();
+
+        // An update that fails to build or is missed by a lagging subscriber is skipped rather than
+        // ending the stream. The stream ends with an error once the byte quota of its API key is
+        // exhausted. Streams are also ended on shutdown, otherwise the graceful shutdown of the
+        // server would wait for every subscriber to disconnect.
+        let stream = BroadcastStream::new(update_rx)
+            .then(move |event| {
+                let state = state.clone();
+                let price_ids = price_ids.clone();
+                async move {
+                    let event = match event {
+                        Ok(event) => event,
+                        Err(e) => {
+                            tracing::warn!(
+                                error = ?e,
+                                "gRPC subscriber lagged behind, skipping updates."
+                            );
+                            return None;
+                        }
+                    };
+                    let slot = event.slot();
+                    match handle_aggregation_event(
+                        &*state,
+                        event,
+                        price_ids,
+                        request.allow_unordered,
+                        request.benchmarks_only,
+                    )
+                    .await
+                    {
+                        Ok(Some(update)) => {
+                            Aggregates::observe_update_sent(&*state, slot).await;
+                            Some(update)
+                        }
+                        Ok(None) => None,
+                        Err(e) => {
+                            tracing::warn!(
+                                error = ?e,
+                                "Failed to build gRPC price update, skipping it."
+                            );
+                            None
+                        }
+                    }
+                }
+            })
+            .filter_map(future::ready)
+            .map(move |update| check_bytes(api_key.as_deref(), &update).map(|()| update))
+            .scan(false, |quota_exceeded, update| {
+                future::ready((!*quota_exceeded).then(|| {
+                    *quota_exceeded = update.is_err();
+                    update
+                }))
+            })
+            .take_until(async move {
+                let _ = exit.changed().await;
+            });
+
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    async fn get_price_feeds_metadata(
+        &self,
+        request: Request<GetPriceFeedsMetadataRequest>,
+    ) -> Result<Response<GetPriceFeedsMetadataResponse>, Status> {
+        let request = request.into_inner();
+        let asset_type = match request.asset_type {
+            Some(asset_type) => Some(
+                AssetType::deserialize(asset_type.as_str().into_deserializer()).map_err(
+                    |e: serde::de::value::Error| Status::invalid_argument(e.to_string()),
+                )?,
+            ),
+            None => None,
+        };
+
+        let filter = PriceFeedsMetadataFilter {
+            query: request.query,
+            asset_type,
+            ..Default::default()
+        };
+        let price_feeds_metadata = PriceFeedMeta::get_price_feeds_metadata(&*self.state, &filter)
+            .await
+            .map_err(|e| {
+                tracing::warn!("RPC connection error: {}", e);
+                Status::internal(e.to_string())
+            })?;
+
+        Ok(Response::new(GetPriceFeedsMetadataResponse {
+            price_feeds: price_feeds_metadata
+                .into_iter()
+                .map(|metadata| PriceFeedMetadata {
+                    id: PriceIdentifier::from(metadata.id).to_bytes().to_vec(),
+                    attributes: metadata.attributes.into_iter().collect(),
+                })
+                .collect(),
+        }))
+    }
+}
+
+/// This method provides a background service that responds to gRPC requests, alongside the
+/// REST server started by `api::run`. Requests are authenticated with the API keys of the REST
+/// server.
+#[tracing::instrument(skip(state, api_keys))]
+pub async fn run<S>(
+    listen_addr: SocketAddr,
+    state: Arc<S>,
+    api_keys: Arc<ApiKeysState>,
+) -> Result<()>
+where
+    S: Aggregates,
+    S: Send + Sync + 'static,
+{
+    tracing::info!(endpoint = %listen_addr, "Starting gRPC Server.");
+
+    Server::builder()
+        .add_service(HermesServer::with_interceptor(
+            HermesService::new(state),
+            move |request| authorize(&api_keys, request),
+        ))
+        .serve_with_shutdown(listen_addr, async {
+            let _ = crate::EXIT.subscribe().changed().await;
+            tracing::info!("Shutting down gRPC server...");
+        })
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use {
+        super::*,
+        crate::{
+            api::api_keys::{bytes_burst, ApiKeyConfig},
+            state::{
+                aggregate::{
+                    test::{
+                        create_dummy_price_feed_message, generate_update,
+                        store_multiple_concurrent_valid_updates,
+                    },
+                    Slot,
+                },
+                cache::test::create_and_store_dummy_price_feed_message_state,
+                test::setup_state,
+                Metrics,
+            },
+        },
+        nonzero_ext::nonzero,
+        pythnet_sdk::messages::Message,
+        std::{num::NonZeroU32, time::Duration},
+        tonic::Code,
+    };
+
+    /// Store an update of each of the feeds at the slot.
+    async fn store_slot<S>(state: &Arc<S>, feed_seeds: &[u8], slot: Slot)
+    where
+        S: Aggregates,
+        S: Send + Sync + 'static,
+    {
+        let messages = feed_seeds
+            .iter()
+            .map(|seed| {
+                Message::PriceFeedMessage(create_dummy_price_feed_message(
+                    *seed,
+                    slot as i64,
+                    slot as i64 - 1,
+                ))
+            })
+            .collect();
+        store_multiple_concurrent_valid_updates(
+            state.clone(),
+            generate_update(messages, slot, slot),
+        )
+        .await;
+    }
+
+    fn api_keys<S>(state: Arc<S>, bytes_per_second: Option<u32>) -> ApiKeysState
+    where
+        S: Metrics,
+        S: Send + Sync + 'static,
+    {
+        ApiKeysState::new(
+            vec![ApiKeyConfig {
+                key: "secret".to_string(),
+                name: "test".to_string(),
+                requests_per_second: NonZeroU32::new(1),
+                bytes_per_second: bytes_per_second.and_then(NonZeroU32::new),
+            }],
+            true,
+            state,
+        )
+    }
+
+    async fn next_update(
+        stream: &mut Pin<Box<dyn Stream<Item = Result<PriceUpdate, Status>> + Send>>,
+    ) -> Option<Result<PriceUpdate, Status>> {
+        tokio::time::timeout(Duration::from_secs(5), stream.next())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_get_latest_price_updates_works() {
+        let (state, _) = setup_state(10).await;
+        create_and_store_dummy_price_feed_message_state(&*state, [2; 32], 10, 5).await;
+        let service = HermesService::new(state);
+
+        let update = service
+            .get_latest_price_updates(Request::new(GetLatestPriceUpdatesRequest {
+                ids: vec![vec![2; 32]],
+                ignore_invalid_price_ids: false,
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+
+        assert_eq!(update.update_data.len(), 1);
+        assert_eq!(update.parsed.len(), 1);
+        assert_eq!(update.parsed[0].id, vec![2; 32]);
+        assert_eq!(update.parsed[0].price.as_ref().unwrap().publish_time, 10);
+        assert_eq!(update.parsed[0].metadata.as_ref().unwrap().slot, Some(5));
+    }
+
+    #[tokio::test]
+    async fn test_get_latest_price_updates_rejects_invalid_ids() {
+        let (state, _) = setup_state(10).await;
+        create_and_store_dummy_price_feed_message_state(&*state, [2; 32], 10, 5).await;
+        let service = HermesService::new(state);
+
+        let status = service
+            .get_latest_price_updates(Request::new(GetLatestPriceUpdatesRequest {
+                ids: vec![vec![1; 31]],
+                ignore_invalid_price_ids: false,
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+
+        let status = service
+            .get_latest_price_updates(Request::new(GetLatestPriceUpdatesRequest {
+                ids: vec![vec![2; 32], vec![3; 32]],
+                ignore_invalid_price_ids: false,
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+
+        let update = service
+            .get_latest_price_updates(Request::new(GetLatestPriceUpdatesRequest {
+                ids: vec![vec![2; 32], vec![3; 32]],
+                ignore_invalid_price_ids: true,
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(update.parsed.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_get_price_updates_by_time_works() {
+        let (state, _) = setup_state(10).await;
+        create_and_store_dummy_price_feed_message_state(&*state, [2; 32], 10, 5).await;
+        create_and_store_dummy_price_feed_message_state(&*state, [2; 32], 20, 6).await;
+        let service = HermesService::new(state);
+
+        let update = service
+            .get_price_updates_by_time(Request::new(GetPriceUpdatesByTimeRequest {
+                ids: vec![vec![2; 32]],
+                publish_time: 15,
+                ignore_invalid_price_ids: false,
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(update.parsed.len(), 1);
+        assert_eq!(update.parsed[0].price.as_ref().unwrap().publish_time, 20);
+        assert_eq!(update.parsed[0].metadata.as_ref().unwrap().slot, Some(6));
+    }
+
+    #[tokio::test]
+    async fn test_get_latest_twaps_rejects_invalid_windows() {
+        let (state, _) = setup_state(10).await;
+        let service = HermesService::new(state);
+
+        for window_seconds in [0, MAX_TWAP_WINDOW_SECONDS + 1] {
+            let status = service
+                .get_latest_twaps(Request::new(GetLatestTwapsRequest {
+                    ids: vec![vec![2; 32]],
+                    window_seconds,
+                    ignore_invalid_price_ids: true,
+                }))
+                .await
+                .unwrap_err();
+            assert_eq!(status.code(), Code::InvalidArgument);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_price_updates_streams_the_feeds_updated_in_each_slot() {
+        let (state, _) = setup_state(10).await;
+        store_slot(&state, &[1, 2], 10).await;
+        let service = HermesService::new(state.clone());
+
+        let mut stream = service
+            .subscribe_price_updates(Request::new(SubscribePriceUpdatesRequest {
+                ids: vec![vec![1; 32], vec![2; 32]],
+                ignore_invalid_price_ids: false,
+                allow_unordered: false,
+                benchmarks_only: false,
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+
+        // Each slot only updates one of the feeds, the stream carries on with the updated one.
+        store_slot(&state, &[1], 11).await;
+        store_slot(&state, &[2], 12).await;
+        for (slot, feed_id) in [(11, vec![1; 32]), (12, vec![2; 32])] {
+            let update = next_update(&mut stream).await.unwrap().unwrap();
+            assert_eq!(update.update_data.len(), 1);
+            assert_eq!(update.parsed.len(), 1);
+            assert_eq!(update.parsed[0].id, feed_id);
+            assert_eq!(update.parsed[0].metadata.as_ref().unwrap().slot, Some(slot));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_authorize_enforces_api_keys() {
+        let (state, _) = setup_state(10).await;
+        let api_keys = api_keys(state.clone(), None);
+
+        let status = authorize(&api_keys, Request::new(())).unwrap_err();
+        assert_eq!(status.code(), Code::Unauthenticated);
+
+        let mut request = Request::new(());
+        request
+            .metadata_mut()
+            .insert("x-api-key", "unknown".parse().unwrap());
+        let status = authorize(&api_keys, request).unwrap_err();
+        assert_eq!(status.code(), Code::Unauthenticated);
+
+        let request = || {
+            let mut request = Request::new(());
+            request
+                .metadata_mut()
+                .insert("authorization", "Bearer secret".parse().unwrap());
+            request
+        };
+        let request = authorize(&api_keys, request()).unwrap();
+        assert_eq!(request_api_key(&request).unwrap().name, "test");
+
+        // The key allows a single request per second.
+        let status = authorize(&api_keys, request()).unwrap_err();
+        assert_eq!(status.code(), Code::ResourceExhausted);
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_price_updates_ends_when_the_byte_quota_is_exhausted() {
+        let (state, _) = setup_state(10).await;
+        store_slot(&state, &[1], 10).await;
+        let api_key = api_keys(state.clone(), Some(1))
+            .get_by_name("test")
+            .unwrap();
+        // Drain the burst so that the first update exceeds the quota.
+        assert!(api_key.check_bytes(bytes_burst(nonzero!(1u32)).get() as usize));
+        let service = HermesService::new(state.clone());
+
+        let mut request = Request::new(SubscribePriceUpdatesRequest {
+            ids: vec![vec![1; 32]],
+            ignore_invalid_price_ids: false,
+            allow_unordered: false,
+            benchmarks_only: false,
+        });
+        request.extensions_mut().insert(api_key);
+        let mut stream = service
+            .subscribe_price_updates(request)
+            .await
+            .unwrap()
+            .into_inner();
+
+        store_slot(&state, &[1], 11).await;
+        store_slot(&state, &[1], 12).await;
+        let status = next_update(&mut stream).await.unwrap().unwrap_err();
+        assert_eq!(status.code(), Code::ResourceExhausted);
+        assert!(next_update(&mut stream).await.is_none());
+    }
+}
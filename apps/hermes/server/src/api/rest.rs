@@ -1,6 +1,6 @@
 use {
     super::ApiState,
-    crate::state::aggregate::Aggregates,
+    crate::state::{aggregate::Aggregates, derived_feeds::DerivedFeeds},
     axum::{
         http::StatusCode,
         response::{IntoResponse, Response},
@@ -30,8 +30,9 @@ pub use {
     price_feed_ids::*,
     ready::*,
     v2::{
-        latest_price_updates::*, latest_publisher_stake_caps::*, latest_twaps::*,
-        price_feeds_metadata::*, sse::*, timestamp_price_updates::*,
+        ccip_price_updates::*, latest_price_updates::*, latest_publisher_stake_caps::*,
+        latest_twaps::*, price_feed_publishers::*, price_feed_stats::*, price_feeds_metadata::*,
+        range_price_updates::*, sse::*, timestamp_price_updates::*, timestamp_twaps::*,
     },
 };
 
@@ -41,8 +42,12 @@ pub enum RestError {
     UpdateDataNotFound,
     CcipUpdateDataNotFound,
     InvalidCCIPInput,
+    InvalidRange,
+    InvalidRangeCursor,
     PriceIdsNotFound { missing_ids: Vec<PriceIdentifier> },
     RpcConnectionError { message: String },
+    Unauthorized,
+    TooManyRequests,
 }
 
 impl IntoResponse for RestError {
@@ -67,6 +72,14 @@ impl IntoResponse for RestError {
             RestError::InvalidCCIPInput => {
                 (StatusCode::BAD_REQUEST, "Invalid CCIP input").into_response()
             }
+            RestError::InvalidRange => (
+                StatusCode::BAD_REQUEST,
+                "Invalid range, start must not be greater than end and times must fit in an i64",
+            )
+                .into_response(),
+            RestError::InvalidRangeCursor => {
+                (StatusCode::BAD_REQUEST, "Invalid range cursor").into_response()
+            }
             RestError::PriceIdsNotFound { missing_ids } => {
                 let missing_ids = missing_ids
                     .into_iter()
@@ -83,6 +96,12 @@ impl IntoResponse for RestError {
             RestError::RpcConnectionError { message } => {
                 (StatusCode::INTERNAL_SERVER_ERROR, message).into_response()
             }
+            RestError::Unauthorized => {
+                (StatusCode::UNAUTHORIZED, "Missing or invalid API key").into_response()
+            }
+            RestError::TooManyRequests => {
+                (StatusCode::TOO_MANY_REQUESTS, "API key quota exceeded").into_response()
+            }
         }
     }
 }
@@ -118,23 +137,44 @@ where
         })
     }
 }
+
+/// Split the passed in price ids into the ids of derived price feeds and the remaining ids. Derived
+/// feeds are computed by Hermes, so they are not known to the aggregate state and must be taken
+/// out before validating the price ids.
+/// # Returns
+/// A tuple of the derived price ids and the other price ids, in the order they were passed in.
+pub fn partition_derived_price_ids<S>(
+    state: &ApiState<S>,
+    price_ids: Vec<PriceIdentifier>,
+) -> (Vec<PriceIdentifier>, Vec<PriceIdentifier>)
+where
+    S: DerivedFeeds,
+{
+    price_ids
+        .into_iter()
+        .partition(|id| DerivedFeeds::is_derived_feed(&*state.state, id))
+}
+
 #[cfg(test)]
 mod tests {
     use {
         super::*,
         crate::state::{
             aggregate::{
-                AggregationEvent, PriceFeedsWithUpdateData, PublisherStakeCapsWithUpdateData,
-                ReadinessMetadata, RequestTime, TwapsWithUpdateData, Update,
+                feed_stats::PublishTimeStats, AggregationEvent, CompletedSlot,
+                PriceFeedsWithUpdateData, PublisherStakeCapsWithUpdateData, RangeCursor,
+                ReadinessMetadata, RequestRange, RequestTime, Slot, TwapsWithUpdateData, Update,
             },
             benchmarks::BenchmarksState,
-            cache::CacheState,
+            cache::{CacheState, MessageStateKey},
             metrics::MetricsState,
             price_feeds_metadata::PriceFeedMetaState,
         },
         anyhow::Result,
+        nonzero_ext::nonzero,
+        pyth_sdk::DurationInSeconds,
         std::{collections::HashSet, sync::Arc},
-        tokio::sync::broadcast::Receiver,
+        tokio::{sync::broadcast::Receiver, time::Instant},
     };
 
     // Simplified mock that only contains what we need
@@ -173,14 +213,46 @@ mod tests {
             self.available_ids.clone()
         }
 
+        async fn get_price_feed_ids_at_slot(
+            &self,
+            _price_ids: &[PriceIdentifier],
+            _slot: Slot,
+        ) -> Vec<PriceIdentifier> {
+            unimplemented!("Not needed for this test")
+        }
+
         fn subscribe(&self) -> Receiver<AggregationEvent> {
             unimplemented!("Not needed for this test")
         }
 
+        fn subscriber_count(&self) -> usize {
+            unimplemented!("Not needed for this test")
+        }
+
+        fn subscribe_completed_slots(&self) -> Receiver<Arc<CompletedSlot>> {
+            unimplemented!("Not needed for this test")
+        }
+
+        async fn store_completed_slot(&self, _completed_slot: CompletedSlot) -> Result<()> {
+            unimplemented!("Not needed for this test")
+        }
+
         async fn is_ready(&self) -> (bool, ReadinessMetadata) {
             unimplemented!("Not needed for this test")
         }
 
+        async fn update_pythnet_endpoint_health(&self, _endpoint: &str, _received_update: bool) {
+            unimplemented!("Not needed for this test")
+        }
+
+        async fn observe_pythnet_slot(&self, _slot: Slot, _observed_at: Instant) {
+            unimplemented!("Not needed for this test")
+        }
+
+        async fn observe_update_sent(&self, _slot: Slot) {
+            unimplemented!("Not needed for this test")
+        }
+
         async fn store_update(&self, _update: Update) -> Result<()> {
             unimplemented!("Not needed for this test")
         }
@@ -193,6 +265,28 @@ mod tests {
             unimplemented!("Not needed for this test")
         }
 
+        async fn get_price_feeds_with_merged_update_data(
+            &self,
+            _price_ids: &[PriceIdentifier],
+            _max_staleness: DurationInSeconds,
+        ) -> Result<PriceFeedsWithUpdateData> {
+            unimplemented!("Not needed for this test")
+        }
+
+        async fn get_price_feeds_range_with_update_data(
+            &self,
+            _price_ids: &[PriceIdentifier],
+            _range: RequestRange,
+            _after: Option<RangeCursor>,
+            _limit: usize,
+        ) -> Result<PriceFeedsWithUpdateData> {
+            unimplemented!("Not needed for this test")
+        }
+
+        async fn get_feed_stats(&self, _key: MessageStateKey) -> Option<PublishTimeStats> {
+            unimplemented!("Not needed for this test")
+        }
+
         async fn get_latest_publisher_stake_caps_with_update_data(
             &self,
         ) -> Result<PublisherStakeCapsWithUpdateData> {
@@ -206,6 +300,15 @@ mod tests {
         ) -> Result<TwapsWithUpdateData> {
             unimplemented!("Not needed for this test")
         }
+
+        async fn get_rolling_twaps_with_update_data(
+            &self,
+            _price_ids: &[PriceIdentifier],
+            _window_seconds: DurationInSeconds,
+            _end_slot: Slot,
+        ) -> Result<TwapsWithUpdateData> {
+            unimplemented!("Not needed for this test")
+        }
     }
 
     #[tokio::test]
@@ -218,7 +321,14 @@ mod tests {
         available_ids.insert(id2);
 
         let mock_state = MockAggregates { available_ids };
-        let api_state = ApiState::new(Arc::new(mock_state), vec![], String::new());
+        let api_state = ApiState::new(
+            Arc::new(mock_state),
+            vec![],
+            nonzero!(1u32),
+            String::new(),
+            vec![],
+            false,
+        );
 
         let input_ids = vec![id1, id2];
         let result = validate_price_ids(&api_state, &input_ids, false).await;
@@ -237,7 +347,14 @@ mod tests {
         available_ids.insert(id2);
 
         let mock_state = MockAggregates { available_ids };
-        let api_state = ApiState::new(Arc::new(mock_state), vec![], String::new());
+        let api_state = ApiState::new(
+            Arc::new(mock_state),
+            vec![],
+            nonzero!(1u32),
+            String::new(),
+            vec![],
+            false,
+        );
 
         let input_ids = vec![id1, id2, id3];
         let result = validate_price_ids(&api_state, &input_ids, true).await;
@@ -256,7 +373,14 @@ mod tests {
         available_ids.insert(id2);
 
         let mock_state = MockAggregates { available_ids };
-        let api_state = ApiState::new(Arc::new(mock_state), vec![], String::new());
+        let api_state = ApiState::new(
+            Arc::new(mock_state),
+            vec![],
+            nonzero!(1u32),
+            String::new(),
+            vec![],
+            false,
+        );
 
         let input_ids = vec![id1, id2, id3];
         let result = validate_price_ids(&api_state, &input_ids, false).await;
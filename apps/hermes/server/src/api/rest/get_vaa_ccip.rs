@@ -27,7 +27,7 @@ pub struct GetVaaCcipResponse {
     data: String, // TODO: Use a typed wrapper for the hex output with leading 0x.
 }
 
-/// **Deprecated: use /v2/updates/price/{publish_time} instead**
+/// **Deprecated: use /v2/updates/price/ccip instead**
 ///
 /// Get a VAA for a price feed using CCIP
 ///
@@ -0,0 +1,366 @@
+use {
+    crate::{
+        api::{
+            rest::{validate_price_ids, RestError},
+            ApiState,
+        },
+        state::aggregate::{Aggregates, RequestTime, UnixTimestamp},
+    },
+    anyhow::{anyhow, ensure, Result},
+    axum::{extract::State, Json},
+    pyth_sdk::PriceIdentifier,
+    serde::{Deserialize, Serialize},
+    serde_qs::axum::QsQuery,
+    utoipa::{IntoParams, ToSchema},
+};
+
+/// The size of an ABI word.
+const WORD: usize = 32;
+
+/// The maximum number of price feeds of a request, to bound the work of a single lookup.
+const MAX_PRICE_IDS: usize = 256;
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in=Query)]
+pub struct CcipPriceUpdatesQueryParams {
+    /// The hex-encoded `callData` of the `OffchainLookup` revert, i.e.
+    /// `abi.encode(bytes32[] ids, uint64 minPublishTime, uint64 maxPublishTime)`.
+    #[param(example = "0x...")]
+    data: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CcipPriceUpdatesResponse {
+    /// The hex-encoded `abi.encode(bytes[] updateData)`, with a leading `0x`.
+    data: String,
+}
+
+#[derive(Debug, PartialEq)]
+struct CcipRequest {
+    price_ids: Vec<PriceIdentifier>,
+    min_publish_time: UnixTimestamp,
+    max_publish_time: UnixTimestamp,
+}
+
+/// Get the price updates of a CCIP-read (EIP-3668) request.
+///
+/// This endpoint serves as the gateway of an `OffchainLookup` whose `callData` is
+/// `abi.encode(bytes32[] ids, uint64 minPublishTime, uint64 maxPublishTime)`. It returns the first
+/// price update of each price feed published at or after `minPublishTime` as
+/// `abi.encode(bytes[] updateData)`, where each element is an accumulator update data that can be
+/// passed to `parsePriceFeedUpdates` along with the same publish time bounds.
+///
+/// A price update that is not available yet results in a 5xx error, so that the client retries.
+#[utoipa::path(
+    get,
+    path = "/v2/updates/price/ccip",
+    responses(
+        (status = 200, description = "Price updates retrieved successfully", body = CcipPriceUpdatesResponse),
+        (status = 400, description = "Invalid CCIP input", body = String),
+        (status = 404, description = "Price ids or update data not found", body = String),
+        (status = 502, description = "Price updates not available yet", body = String)
+    ),
+    params(
+        CcipPriceUpdatesQueryParams
+    )
+)]
+pub async fn ccip_price_updates<S>(
+    State(state): State<ApiState<S>>,
+    QsQuery(params): QsQuery<CcipPriceUpdatesQueryParams>,
+) -> Result<Json<CcipPriceUpdatesResponse>, RestError>
+where
+    S: Aggregates,
+{
+    let request = decode_request(&params.data).map_err(|e| {
+        tracing::debug!("Invalid CCIP request {}: {:?}", params.data, e);
+        RestError::InvalidCCIPInput
+    })?;
+    let price_ids = validate_price_ids(&state, &request.price_ids, false).await?;
+
+    let state = &*state.state;
+    let price_feeds_with_update_data = Aggregates::get_price_feeds_with_update_data(
+        state,
+        &price_ids,
+        RequestTime::FirstAfter(request.min_publish_time),
+    )
+    .await
+    .map_err(|e| {
+        tracing::warn!(
+            "Error getting price feeds {:?} with update data: {:?}",
+            price_ids,
+            e
+        );
+        RestError::CcipUpdateDataNotFound
+    })?;
+
+    // The first update after the minimum publish time is past the maximum one, so no update in
+    // the requested range will ever be available.
+    if price_feeds_with_update_data
+        .price_feeds
+        .iter()
+        .any(|price_feed| {
+            price_feed.price_feed.get_price_unchecked().publish_time > request.max_publish_time
+        })
+    {
+        return Err(RestError::UpdateDataNotFound);
+    }
+
+    Ok(Json(CcipPriceUpdatesResponse {
+        data: format!(
+            "0x{}",
+            hex::encode(encode_bytes_array(
+                &price_feeds_with_update_data.update_data
+            ))
+        ),
+    }))
+}
+
+/// Read the word at `offset` as a `usize`, failing if it does not fit.
+fn read_usize(data: &[u8], offset: usize) -> Result<usize> {
+    let end = offset
+        .checked_add(WORD)
+        .ok_or_else(|| anyhow!("Word at {offset} overflows the offsets"))?;
+    let word = data
+        .get(offset..end)
+        .ok_or_else(|| anyhow!("Word at {offset} is out of bounds"))?;
+    let (high, low) = word.split_at(WORD - 8);
+    ensure!(high.iter().all(|b| *b == 0), "Word at {offset} overflows");
+    Ok(u64::from_be_bytes(low.try_into()?).try_into()?)
+}
+
+/// Read the word at `offset` as a `uint64`.
+fn read_u64(data: &[u8], offset: usize) -> Result<u64> {
+    Ok(read_usize(data, offset)? as u64)
+}
+
+fn decode_request(data: &str) -> Result<CcipRequest> {
+    let data = data
+        .strip_prefix("0x")
+        .or_else(|| data.strip_prefix("0X"))
+        .unwrap_or(data);
+    let data = hex::decode(data)?;
+
+    let ids_offset = read_usize(&data, 0)?;
+    let min_publish_time = read_u64(&data, WORD)?;
+    let max_publish_time = read_u64(&data, 2 * WORD)?;
+    ensure!(
+        min_publish_time <= max_publish_time,
+        "The minimum publish time is greater than the maximum one"
+    );
+
+    let ids_len = read_usize(&data, ids_offset)?;
+    ensure!(ids_len > 0, "No price ids");
+    ensure!(ids_len <= MAX_PRICE_IDS, "Too many price ids");
+    let price_ids = (0..ids_len)
+        .map(|i| {
+            let (start, end) = WORD
+                .checked_mul(i + 1)
+                .and_then(|offset| ids_offset.checked_add(offset))
+                .and_then(|start| Some((start, start.checked_add(WORD)?)))
+                .ok_or_else(|| anyhow!("Price id {i} overflows the offsets"))?;
+            let id = data
+                .get(start..end)
+                .ok_or_else(|| anyhow!("Price id {i} is out of bounds"))?;
+            Ok(PriceIdentifier::new(id.try_into()?))
+        })
+        .collect::<Result<_>>()?;
+
+    Ok(CcipRequest {
+        price_ids,
+        min_publish_time: min_publish_time.try_into()?,
+        max_publish_time: max_publish_time.try_into()?,
+    })
+}
+
+fn encode_usize(encoded: &mut Vec<u8>, value: usize) {
+    encoded.extend_from_slice(&[0; WORD - 8]);
+    encoded.extend_from_slice(&(value as u64).to_be_bytes());
+}
+
+/// `abi.encode(bytes[] values)`.
+fn encode_bytes_array(values: &[Vec<u8>]) -> Vec<u8> {
+    let padded_len = |value: &Vec<u8>| value.len().div_ceil(WORD) * WORD;
+
+    let mut encoded = vec![];
+    // The offset of the array, which is the only parameter.
+    encode_usize(&mut encoded, WORD);
+    encode_usize(&mut encoded, values.len());
+    // The offsets of the elements, relative to the start of the offsets.
+    let mut offset = WORD * values.len();
+    for value in values {
+        encode_usize(&mut encoded, offset);
+        offset += WORD + padded_len(value);
+    }
+    for value in values {
+        encode_usize(&mut encoded, value.len());
+        encoded.extend_from_slice(value);
+        encoded.resize(encoded.len() + padded_len(value) - value.len(), 0);
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod test {
+    use {
+        super::*,
+        crate::state::{
+            aggregate::test::{
+                create_dummy_price_feed_message, generate_update,
+                store_multiple_concurrent_valid_updates,
+            },
+            test::setup_state,
+        },
+        axum::{http::StatusCode, response::IntoResponse},
+        nonzero_ext::nonzero,
+        pythnet_sdk::messages::Message,
+    };
+
+    fn word(value: u64) -> String {
+        format!("{value:064x}")
+    }
+
+    #[test]
+    fn test_decode_request() {
+        let btc = PriceIdentifier::new([2; 32]);
+        let eth = PriceIdentifier::new([3; 32]);
+        let data = format!(
+            "0x{}{}{}{}{}{}",
+            word(0x60),
+            word(10),
+            word(20),
+            word(2),
+            hex::encode(btc.to_bytes()),
+            hex::encode(eth.to_bytes()),
+        );
+        assert_eq!(
+            decode_request(&data).unwrap(),
+            CcipRequest {
+                price_ids: vec![btc, eth],
+                min_publish_time: 10,
+                max_publish_time: 20,
+            }
+        );
+
+        // Truncated ids.
+        assert!(decode_request(&data[..data.len() - 2]).is_err());
+        // No ids.
+        assert!(decode_request(&format!(
+            "{}{}{}{}",
+            word(0x60),
+            word(10),
+            word(20),
+            word(0)
+        ))
+        .is_err());
+        // Minimum publish time after the maximum one.
+        assert!(decode_request(&data.replace(&word(10), &word(30))).is_err());
+        // A publish time that does not fit in a uint64.
+        assert!(
+            decode_request(&data.replacen(&word(20), &format!("{:064x}", 1u128 << 64), 1)).is_err()
+        );
+        // A publish time that does not fit in a timestamp.
+        assert!(decode_request(&data.replacen(&word(20), &word(u64::MAX), 1)).is_err());
+        // An offset that overflows.
+        assert!(decode_request(&data.replacen(&word(0x60), &word(u64::MAX), 1)).is_err());
+    }
+
+    fn request(id: PriceIdentifier, min_publish_time: u64, max_publish_time: u64) -> String {
+        format!(
+            "data=0x{}{}{}{}{}",
+            word(0x60),
+            word(min_publish_time),
+            word(max_publish_time),
+            word(1),
+            hex::encode(id.to_bytes()),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_ccip_price_updates() {
+        let (state, _) = setup_state(10).await;
+        // The update of each slot is published at the slot.
+        for slot in [10, 20] {
+            store_multiple_concurrent_valid_updates(
+                state.clone(),
+                generate_update(
+                    vec![Message::PriceFeedMessage(create_dummy_price_feed_message(
+                        1,
+                        slot as i64,
+                        slot as i64 - 1,
+                    ))],
+                    slot,
+                    slot,
+                ),
+            )
+            .await;
+        }
+        let id = PriceIdentifier::new([1; 32]);
+        let api_state = ApiState::new(
+            state.clone(),
+            vec![],
+            nonzero!(1u32),
+            String::new(),
+            vec![],
+            false,
+        );
+        let query = |min_publish_time, max_publish_time| {
+            QsQuery(serde_qs::from_str(&request(id, min_publish_time, max_publish_time)).unwrap())
+        };
+
+        // The first update at or after the minimum publish time is returned.
+        let Json(response) = ccip_price_updates(State(api_state.clone()), query(15, 25))
+            .await
+            .unwrap();
+        let expected = Aggregates::get_price_feeds_with_update_data(
+            &*state,
+            &[id],
+            RequestTime::FirstAfter(15),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            response.data,
+            format!(
+                "0x{}",
+                hex::encode(encode_bytes_array(&expected.update_data))
+            )
+        );
+
+        // The first update is past the maximum publish time, it will never be available.
+        let status = |result: Result<_, RestError>| result.unwrap_err().into_response().status();
+        assert_eq!(
+            status(ccip_price_updates(State(api_state.clone()), query(11, 15)).await),
+            StatusCode::NOT_FOUND
+        );
+
+        // No update is available yet, the client retries.
+        assert_eq!(
+            status(ccip_price_updates(State(api_state), query(21, 30)).await),
+            StatusCode::BAD_GATEWAY
+        );
+    }
+
+    #[test]
+    fn test_encode_bytes_array() {
+        let encoded = encode_bytes_array(&[vec![0xaa; 33], vec![0xbb]]);
+        let expected = [
+            word(0x20),
+            word(2),
+            word(0x40),
+            word(0xa0),
+            word(33),
+            "aa".repeat(33),
+            "00".repeat(31),
+            word(1),
+            "bb".to_string(),
+            "00".repeat(31),
+        ]
+        .concat();
+        assert_eq!(hex::encode(encoded), expected);
+
+        assert_eq!(
+            hex::encode(encode_bytes_array(&[])),
+            [word(0x20), word(0)].concat()
+        );
+    }
+}
@@ -1,11 +1,17 @@
 use {
     crate::{
         api::{
-            rest::{validate_price_ids, RestError},
-            types::{BinaryUpdate, EncodingType, ParsedPriceUpdate, PriceIdInput, PriceUpdate},
+            rest::{partition_derived_price_ids, validate_price_ids, RestError},
+            types::{
+                BinaryUpdate, EncodingType, ParsedDerivedPriceUpdate, ParsedPriceUpdate,
+                PriceIdInput, PriceUpdate,
+            },
             ApiState,
         },
-        state::aggregate::{Aggregates, RequestTime},
+        state::{
+            aggregate::{Aggregates, RequestTime},
+            derived_feeds::DerivedFeeds,
+        },
     },
     anyhow::Result,
     axum::{extract::State, Json},
@@ -42,6 +48,14 @@ pub struct LatestPriceUpdatesQueryParams {
     /// If true, invalid price IDs in the `ids` parameter are ignored. Only applicable to the v2 APIs. Default is `false`.
     #[serde(default)]
     ignore_invalid_price_ids: bool,
+
+    /// If set, minimize the number of update data returned for feeds updated in different slots,
+    /// which lowers the cost of submitting them on-chain. A feed may then be returned at an older
+    /// slot shared with the other feeds, as long as its price is at most this many seconds older
+    /// than its latest one. With a large enough bound, a single update data covers all the feeds.
+    #[serde(default)]
+    #[param(example = 5)]
+    merge_max_staleness: Option<u64>,
 }
 
 fn default_true() -> bool {
@@ -51,6 +65,9 @@ fn default_true() -> bool {
 /// Get the latest price updates by price feed id.
 ///
 /// Given a collection of price feed ids, retrieve the latest Pyth price for each price feed.
+///
+/// The ids of price feeds derived by Hermes from other feeds are returned in the `derived` field.
+/// Derived prices are unsigned and have no update data, so they can't be verified on-chain.
 #[utoipa::path(
     get,
     path = "/v2/updates/price/latest",
@@ -68,24 +85,33 @@ pub async fn latest_price_updates<S>(
 ) -> Result<Json<PriceUpdate>, RestError>
 where
     S: Aggregates,
+    S: DerivedFeeds,
 {
     let price_id_inputs: Vec<PriceIdentifier> =
         params.ids.into_iter().map(|id| id.into()).collect();
+    let (derived_price_ids, price_id_inputs) = partition_derived_price_ids(&state, price_id_inputs);
     let price_ids: Vec<PriceIdentifier> =
         validate_price_ids(&state, &price_id_inputs, params.ignore_invalid_price_ids).await?;
 
     let state = &*state.state;
-    let price_feeds_with_update_data =
-        Aggregates::get_price_feeds_with_update_data(state, &price_ids, RequestTime::Latest)
-            .await
-            .map_err(|e| {
-                tracing::warn!(
-                    "Error getting price feeds {:?} with update data: {:?}",
-                    price_ids,
-                    e
-                );
-                RestError::UpdateDataNotFound
-            })?;
+    let price_feeds_with_update_data = match params.merge_max_staleness {
+        Some(max_staleness) => {
+            Aggregates::get_price_feeds_with_merged_update_data(state, &price_ids, max_staleness)
+                .await
+        }
+        None => {
+            Aggregates::get_price_feeds_with_update_data(state, &price_ids, RequestTime::Latest)
+                .await
+        }
+    }
+    .map_err(|e| {
+        tracing::warn!(
+            "Error getting price feeds {:?} with update data: {:?}",
+            price_ids,
+            e
+        );
+        RestError::UpdateDataNotFound
+    })?;
 
     let price_update_data = price_feeds_with_update_data.update_data;
     let encoded_data: Vec<String> = price_update_data
@@ -111,9 +137,29 @@ where
         None
     };
 
+    let derived_price_updates: Option<Vec<ParsedDerivedPriceUpdate>> = if derived_price_ids
+        .is_empty()
+    {
+        None
+    } else {
+        let derived_price_feeds =
+            DerivedFeeds::get_derived_price_feeds(state, &derived_price_ids, RequestTime::Latest)
+                .await
+                .map_err(|e| {
+                    tracing::warn!(
+                        "Error getting derived price feeds {:?}: {:?}",
+                        derived_price_ids,
+                        e
+                    );
+                    RestError::UpdateDataNotFound
+                })?;
+        Some(derived_price_feeds.into_iter().map(Into::into).collect())
+    };
+
     let compressed_price_update = PriceUpdate {
         binary: binary_price_update,
         parsed: parsed_price_updates,
+        derived: derived_price_updates,
     };
 
     Ok(Json(compressed_price_update))
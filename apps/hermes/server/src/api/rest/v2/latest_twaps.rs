@@ -60,16 +60,20 @@ pub struct LatestTwapsQueryParams {
     ignore_invalid_price_ids: bool,
 }
 
-fn validate_twap_window<'de, D>(deserializer: D) -> Result<DurationInSeconds, D::Error>
+/// The maximum TWAP window, shared by every API serving TWAPs.
+pub const MAX_TWAP_WINDOW_SECONDS: DurationInSeconds = 600;
+
+pub(crate) fn validate_twap_window<'de, D>(deserializer: D) -> Result<DurationInSeconds, D::Error>
 where
     D: serde::Deserializer<'de>,
 {
     use serde::de::Error;
     let seconds = DurationInSeconds::deserialize(deserializer)?;
-    if seconds == 0 || seconds > 600 {
-        return Err(D::Error::custom(
-            "twap_window_seconds must be in range (0, 600]",
-        ));
+    if seconds == 0 || seconds > MAX_TWAP_WINDOW_SECONDS {
+        return Err(D::Error::custom(format!(
+            "twap_window_seconds must be in range (0, {}]",
+            MAX_TWAP_WINDOW_SECONDS
+        )));
     }
     Ok(seconds)
 }
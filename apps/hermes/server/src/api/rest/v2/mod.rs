@@ -1,6 +1,11 @@
+pub mod ccip_price_updates;
 pub mod latest_price_updates;
 pub mod latest_publisher_stake_caps;
 pub mod latest_twaps;
+pub mod price_feed_publishers;
+pub mod price_feed_stats;
 pub mod price_feeds_metadata;
+pub mod range_price_updates;
 pub mod sse;
 pub mod timestamp_price_updates;
+pub mod timestamp_twaps;
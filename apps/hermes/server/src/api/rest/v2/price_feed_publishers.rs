@@ -0,0 +1,107 @@
+use {
+    crate::{
+        api::{
+            rest::{validate_price_ids, RestError},
+            types::{
+                PriceFeedPublishersResponse, PriceIdInput, RpcPriceIdentifier, RpcPublisherPrice,
+            },
+            ApiState,
+        },
+        state::{aggregate::Aggregates, publishers::Publishers},
+    },
+    axum::{
+        extract::{Path, State},
+        Json,
+    },
+    pyth_sdk::PriceIdentifier,
+    serde::Deserialize,
+    std::collections::HashMap,
+    utoipa::IntoParams,
+};
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in=Path)]
+pub struct PriceFeedPublishersPathParams {
+    /// The id of the price feed to get the publisher prices of.
+    #[param(value_type = String)]
+    #[param(example = "e62df6c8b4a85fe1a67db44dc12de5db330f7ac66b72dc658afedf0f4a415b43")]
+    pub id: PriceIdInput,
+}
+
+/// Build the publisher prices of a price feed, joined with the stake caps of the publishers.
+pub(crate) async fn get_price_feed_publishers_response<S>(
+    state: &S,
+    price_id: PriceIdentifier,
+) -> Result<PriceFeedPublishersResponse, RestError>
+where
+    S: Aggregates,
+    S: Publishers,
+{
+    let price_feed_publishers = Publishers::get_price_feed_publishers(state, price_id)
+        .await
+        .ok_or(RestError::UpdateDataNotFound)?;
+
+    // The stake caps are published for every publisher at once, they are missing until the first
+    // publisher stake caps message is received.
+    let stake_caps: HashMap<String, u64> =
+        match Aggregates::get_latest_publisher_stake_caps_with_update_data(state).await {
+            Ok(stake_caps) => stake_caps
+                .publisher_stake_caps
+                .into_iter()
+                .flat_map(|update| update.publisher_stake_caps)
+                .map(|stake_cap| (stake_cap.publisher, stake_cap.cap))
+                .collect(),
+            Err(_) => HashMap::new(),
+        };
+
+    Ok(PriceFeedPublishersResponse {
+        id: RpcPriceIdentifier::from(price_feed_publishers.id),
+        expo: price_feed_publishers.expo,
+        slot: price_feed_publishers.slot,
+        publishers: price_feed_publishers
+            .publishers
+            .into_iter()
+            .map(|publisher_price| {
+                let publisher = publisher_price.publisher.to_string();
+                RpcPublisherPrice {
+                    stake_cap: stake_caps.get(&publisher).copied(),
+                    publisher,
+                    price: publisher_price.price,
+                    conf: publisher_price.conf,
+                    status: publisher_price.status.into(),
+                    slot: publisher_price.slot,
+                }
+            })
+            .collect(),
+    })
+}
+
+/// Get the latest price of every publisher of a price feed.
+///
+/// Each publisher price comes with its confidence interval, status and the slot it was submitted
+/// at, along with the stake cap of the publisher. The prices are read from the Pythnet price
+/// account of the feed and are not signed, they are meant for monitoring the publishers only.
+/// They are only available if the Hermes instance polls them, which is disabled by default.
+#[utoipa::path(
+    get,
+    path = "/v2/price_feeds/{id}/publishers",
+    responses(
+        (status = 200, description = "Publisher prices retrieved successfully", body = PriceFeedPublishersResponse),
+        (status = 404, description = "Price id not found", body = String)
+    ),
+    params(
+        PriceFeedPublishersPathParams
+    )
+)]
+pub async fn price_feed_publishers<S>(
+    State(state): State<ApiState<S>>,
+    Path(path_params): Path<PriceFeedPublishersPathParams>,
+) -> Result<Json<PriceFeedPublishersResponse>, RestError>
+where
+    S: Aggregates,
+    S: Publishers,
+{
+    let price_ids = validate_price_ids(&state, &[path_params.id.into()], false).await?;
+    let response = get_price_feed_publishers_response(&*state.state, price_ids[0]).await?;
+    Ok(Json(response))
+}
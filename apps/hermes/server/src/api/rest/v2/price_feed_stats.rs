@@ -0,0 +1,81 @@
+use {
+    crate::{
+        api::{
+            rest::{validate_price_ids, RestError},
+            types::{PriceFeedStatsResponse, PriceIdInput, RpcFeedStats, RpcPriceIdentifier},
+            ApiState,
+        },
+        state::{aggregate::Aggregates, cache::MessageStateKey},
+    },
+    axum::{
+        extract::{Path, State},
+        Json,
+    },
+    pythnet_sdk::messages::MessageType,
+    serde::Deserialize,
+    std::time::{SystemTime, UNIX_EPOCH},
+    utoipa::IntoParams,
+};
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in=Path)]
+pub struct PriceFeedStatsPathParams {
+    /// The id of the price feed to get the statistics of.
+    #[param(value_type = String)]
+    #[param(example = "e62df6c8b4a85fe1a67db44dc12de5db330f7ac66b72dc658afedf0f4a415b43")]
+    pub id: PriceIdInput,
+}
+
+/// Get the publish time statistics of a price feed.
+///
+/// The statistics include the distribution of the most recent gaps between consecutive publish
+/// times, the age of the latest update and the number of updates with a failed aggregation. They
+/// are collected since Hermes started and help to spot feeds that update irregularly.
+#[utoipa::path(
+    get,
+    path = "/v2/price_feeds/{id}/stats",
+    responses(
+        (status = 200, description = "Statistics retrieved successfully", body = PriceFeedStatsResponse),
+        (status = 404, description = "Price id not found", body = String)
+    ),
+    params(
+        PriceFeedStatsPathParams
+    )
+)]
+pub async fn price_feed_stats<S>(
+    State(state): State<ApiState<S>>,
+    Path(path_params): Path<PriceFeedStatsPathParams>,
+) -> Result<Json<PriceFeedStatsResponse>, RestError>
+where
+    S: Aggregates,
+{
+    let price_id = validate_price_ids(&state, &[path_params.id.into()], false).await?[0];
+    let state = &*state.state;
+
+    let current_time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|_| RestError::UpdateDataNotFound)?
+        .as_secs() as i64;
+    let get_stats = |type_: MessageType| async move {
+        Aggregates::get_feed_stats(
+            state,
+            MessageStateKey {
+                feed_id: price_id.to_bytes(),
+                type_,
+            },
+        )
+        .await
+        .map(|stats| RpcFeedStats::new(stats, current_time))
+    };
+
+    let price = get_stats(MessageType::PriceFeedMessage)
+        .await
+        .ok_or(RestError::UpdateDataNotFound)?;
+    let twap = get_stats(MessageType::TwapMessage).await;
+
+    Ok(Json(PriceFeedStatsResponse {
+        id: RpcPriceIdentifier::from(price_id),
+        price,
+        twap,
+    }))
+}
@@ -2,15 +2,23 @@ use {
     crate::{
         api::{
             rest::RestError,
-            types::{AssetType, PriceFeedMetadata},
+            types::{AssetType, PriceFeedMetadataWithPrice, RpcPrice},
             ApiState,
         },
-        state::price_feeds_metadata::PriceFeedMeta,
+        state::{
+            aggregate::{Aggregates, RequestTime},
+            cache::{Cache, MessageStateFilter},
+            price_feeds_metadata::{search::PriceFeedsMetadataFilter, PriceFeedMeta},
+        },
     },
     anyhow::Result,
     axum::{extract::State, Json},
+    futures::future::join_all,
+    pyth_sdk::PriceIdentifier,
+    pythnet_sdk::messages::{Message, MessageType},
     serde::Deserialize,
     serde_qs::axum::QsQuery,
+    std::collections::HashMap,
     utoipa::IntoParams,
 };
 
@@ -21,20 +29,60 @@ pub struct PriceFeedsMetadataQueryParams {
     #[param(example = "bitcoin")]
     query: Option<String>,
 
+    /// If true, the query string is matched fuzzily against the symbol, base, description and other descriptive attributes of the price feeds, tolerating typos, and the results are ranked from the best match. Default is false.
+    #[serde(default)]
+    #[param(default = false)]
+    fuzzy: bool,
+
     /// Optional query parameter. If provided, the results will be filtered by asset type. Possible values are crypto, equity, fx, metal, rates. Filter string is case insensitive.
     #[param(example = "crypto")]
     asset_type: Option<AssetType>,
+
+    /// Optional query parameter. If provided, the results will be filtered to the price feeds with this base asset. Filter string is case insensitive.
+    #[param(example = "btc")]
+    base: Option<String>,
+
+    /// Optional query parameter. If provided, the results will be filtered to the price feeds with this quote currency. Filter string is case insensitive.
+    #[param(example = "usd")]
+    quote_currency: Option<String>,
+
+    /// Optional query parameter. If provided, the results will be filtered to the price feeds of this country. Filter string is case insensitive.
+    #[param(example = "us")]
+    country: Option<String>,
+
+    /// Optional query parameter. If provided, the results will be filtered to the price feeds with this tenor. Filter string is case insensitive.
+    #[param(example = "spot")]
+    tenor: Option<String>,
+
+    /// Optional query parameter. If provided, the results will be filtered to the price feeds with this CMS symbol. Filter string is case insensitive.
+    #[param(example = "aapl")]
+    cms_symbol: Option<String>,
+
+    /// Optional number of matching price feeds to skip, to paginate the results. Default is 0.
+    #[serde(default)]
+    #[param(default = 0)]
+    offset: usize,
+
+    /// Optional maximum number of price feeds to return, to paginate the results. All the matching
+    /// price feeds are returned if not provided.
+    #[param(example = 100)]
+    limit: Option<usize>,
+
+    /// If true, include the latest price of each returned price feed. Default is false.
+    #[serde(default)]
+    #[param(default = false)]
+    include_latest_price: bool,
 }
 
 /// Get the set of price feeds.
 ///
-/// This endpoint fetches all price feeds from the Pyth network. It can be filtered by asset type
-/// and query string.
+/// This endpoint fetches all price feeds from the Pyth network. It can be filtered by query
+/// string, asset type and other product attributes, and paginated with `offset` and `limit`.
 #[utoipa::path(
     get,
     path = "/v2/price_feeds",
     responses(
-        (status = 200, description = "Price feeds metadata retrieved successfully", body = Vec<PriceFeedMetadata>)
+        (status = 200, description = "Price feeds metadata retrieved successfully", body = Vec<PriceFeedMetadataWithPrice>)
     ),
     params(
         PriceFeedsMetadataQueryParams
@@ -43,13 +91,22 @@ pub struct PriceFeedsMetadataQueryParams {
 pub async fn price_feeds_metadata<S>(
     State(state): State<ApiState<S>>,
     QsQuery(params): QsQuery<PriceFeedsMetadataQueryParams>,
-) -> Result<Json<Vec<PriceFeedMetadata>>, RestError>
+) -> Result<Json<Vec<PriceFeedMetadataWithPrice>>, RestError>
 where
-    S: PriceFeedMeta,
+    S: Aggregates,
 {
-    let state = &state.state;
-    let price_feeds_metadata = state
-        .get_price_feeds_metadata(params.query, params.asset_type)
+    let state = &*state.state;
+    let filter = PriceFeedsMetadataFilter {
+        query: params.query,
+        fuzzy: params.fuzzy,
+        asset_type: params.asset_type,
+        base: params.base,
+        quote_currency: params.quote_currency,
+        country: params.country,
+        tenor: params.tenor,
+        cms_symbol: params.cms_symbol,
+    };
+    let price_feeds_metadata = PriceFeedMeta::get_price_feeds_metadata(state, &filter)
         .await
         .map_err(|e| {
             tracing::warn!("RPC connection error: {}", e);
@@ -57,6 +114,107 @@ where
                 message: format!("RPC connection error: {}", e),
             }
         })?;
+    let price_feeds_metadata: Vec<_> = price_feeds_metadata
+        .into_iter()
+        .skip(params.offset)
+        .take(params.limit.unwrap_or(usize::MAX))
+        .collect();
+
+    // The prices are only read for the requested page, the price feeds without a cached price are
+    // returned without one.
+    let mut latest_prices: HashMap<PriceIdentifier, RpcPrice> = HashMap::new();
+    if params.include_latest_price {
+        let message_states = join_all(price_feeds_metadata.iter().map(|metadata| {
+            Cache::fetch_message_states(
+                state,
+                vec![PriceIdentifier::from(metadata.id).to_bytes()],
+                RequestTime::Latest,
+                MessageStateFilter::Only(MessageType::PriceFeedMessage),
+            )
+        }))
+        .await;
+        latest_prices.extend(
+            message_states
+                .into_iter()
+                .filter_map(Result::ok)
+                .flatten()
+                .filter_map(|message_state| match message_state.message {
+                    Message::PriceFeedMessage(message) => Some((
+                        PriceIdentifier::new(message.feed_id),
+                        RpcPrice {
+                            price: message.price,
+                            conf: message.conf,
+                            expo: message.exponent,
+                            publish_time: message.publish_time,
+                        },
+                    )),
+                    _ => None,
+                }),
+        );
+    }
+
+    Ok(Json(
+        price_feeds_metadata
+            .into_iter()
+            .map(|metadata| PriceFeedMetadataWithPrice {
+                latest_price: latest_prices.remove(&metadata.id.into()),
+                metadata,
+            })
+            .collect(),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use {
+        super::*,
+        crate::{
+            api::types::{PriceFeedMetadata, RpcPriceIdentifier},
+            state::{
+                cache::test::create_and_store_dummy_price_feed_message_state, test::setup_state,
+            },
+        },
+        nonzero_ext::nonzero,
+    };
+
+    #[tokio::test]
+    async fn test_latest_prices_are_optional() {
+        let (state, _) = setup_state(10).await;
+        PriceFeedMeta::store_price_feeds_metadata(
+            &*state,
+            &[1, 2].map(|seed| PriceFeedMetadata {
+                id: RpcPriceIdentifier::new([seed; 32]),
+                attributes: Default::default(),
+            }),
+        )
+        .await
+        .unwrap();
+        // Only the first price feed has a price.
+        create_and_store_dummy_price_feed_message_state(&*state, [1; 32], 10, 5).await;
+
+        let api_state = ApiState::new(state, vec![], nonzero!(1u32), String::new(), vec![], false);
+        let Json(price_feeds_metadata) = price_feeds_metadata(
+            State(api_state),
+            QsQuery(serde_qs::from_str("include_latest_price=true").unwrap()),
+        )
+        .await
+        .unwrap();
 
-    Ok(Json(price_feeds_metadata))
+        let latest_prices = price_feeds_metadata
+            .into_iter()
+            .map(|metadata| {
+                (
+                    metadata.metadata.id,
+                    metadata.latest_price.map(|price| price.publish_time),
+                )
+            })
+            .collect::<HashMap<_, _>>();
+        assert_eq!(
+            latest_prices,
+            HashMap::from([
+                (RpcPriceIdentifier::new([1; 32]), Some(10)),
+                (RpcPriceIdentifier::new([2; 32]), None),
+            ])
+        );
+    }
 }
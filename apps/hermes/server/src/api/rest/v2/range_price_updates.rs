@@ -0,0 +1,237 @@
+use {
+    crate::{
+        api::{
+            doc_examples,
+            rest::{validate_price_ids, RestError},
+            types::{
+                BinaryUpdate, EncodingType, ParsedPriceUpdate, PriceIdInput, PriceUpdateRange,
+            },
+            ApiState,
+        },
+        state::aggregate::{Aggregates, RangeCursor, RequestRange, UnixTimestamp},
+    },
+    anyhow::Result,
+    axum::{extract::State, Json},
+    pyth_sdk::PriceIdentifier,
+    serde::Deserialize,
+    serde_qs::axum::QsQuery,
+    utoipa::{IntoParams, ToSchema},
+};
+
+/// The maximum number of price updates returned in a single page.
+const MAX_PAGE_SIZE: usize = 1000;
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, ToSchema)]
+pub enum RangeType {
+    /// `start` and `end` are unix timestamps in seconds, compared to the publish time.
+    #[default]
+    #[serde(rename = "time")]
+    Time,
+    /// `start` and `end` are Pythnet slots.
+    #[serde(rename = "slot")]
+    Slot,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in=Query)]
+pub struct RangePriceUpdatesQueryParams {
+    /// Get every price update within the range for this set of price feed ids.
+    ///
+    /// This parameter can be provided multiple times to retrieve multiple price updates,
+    /// for example see the following query string:
+    ///
+    /// ```
+    /// ?ids[]=a12...&ids[]=b4c...
+    /// ```
+    #[param(rename = "ids[]")]
+    #[param(example = "e62df6c8b4a85fe1a67db44dc12de5db330f7ac66b72dc658afedf0f4a415b43")]
+    ids: Vec<PriceIdInput>,
+
+    /// The inclusive start of the range.
+    #[param(example = doc_examples::timestamp_example)]
+    start: u64,
+
+    /// The inclusive end of the range.
+    #[param(example = doc_examples::timestamp_example)]
+    end: u64,
+
+    /// Whether `start` and `end` are publish times or slots. Default is `time`.
+    #[serde(default)]
+    #[param(value_type = Option<RangeType>)]
+    range_type: RangeType,
+
+    /// The `next_cursor` of the previous page, used to request the following pages. The first page
+    /// is returned if absent.
+    #[serde(default)]
+    cursor: Option<String>,
+
+    /// The maximum number of price updates to return. Default and maximum is `1000`.
+    #[serde(default = "default_limit")]
+    limit: usize,
+
+    /// If true, include the binary update data in the `binary` field of the response. Update data
+    /// is grouped per slot. Default is `false`.
+    #[serde(default)]
+    binary: bool,
+
+    /// Optional encoding type. If true, return the price update in the encoding specified by the encoding parameter. Default is `hex`.
+    #[serde(default)]
+    encoding: EncodingType,
+
+    /// If true, invalid price IDs in the `ids` parameter are ignored. Only applicable to the v2 APIs. Default is `false`.
+    #[serde(default)]
+    ignore_invalid_price_ids: bool,
+}
+
+fn default_limit() -> usize {
+    MAX_PAGE_SIZE
+}
+
+/// Get every cached price update within a time or slot range.
+///
+/// Given a collection of price feed ids and an inclusive range, retrieve every Pyth price update
+/// for each price feed within the range, ordered by publish time. Results are paginated, use
+/// `next_cursor` to request the following page.
+#[utoipa::path(
+    get,
+    path = "/v2/updates/price/range",
+    responses(
+        (status = 200, description = "Price updates retrieved successfully", body = PriceUpdateRange),
+        (status = 400, description = "Invalid range", body = String),
+        (status = 404, description = "Price ids not found", body = String)
+    ),
+    params(
+        RangePriceUpdatesQueryParams
+    )
+)]
+pub async fn range_price_updates<S>(
+    State(state): State<ApiState<S>>,
+    QsQuery(params): QsQuery<RangePriceUpdatesQueryParams>,
+) -> Result<Json<PriceUpdateRange>, RestError>
+where
+    S: Aggregates,
+{
+    if params.start > params.end {
+        return Err(RestError::InvalidRange);
+    }
+
+    let price_id_inputs: Vec<PriceIdentifier> =
+        params.ids.into_iter().map(|id| id.into()).collect();
+    let price_ids: Vec<PriceIdentifier> =
+        validate_price_ids(&state, &price_id_inputs, params.ignore_invalid_price_ids).await?;
+
+    let range = match params.range_type {
+        RangeType::Time => RequestRange::Time {
+            start: UnixTimestamp::try_from(params.start).map_err(|_| RestError::InvalidRange)?,
+            end: UnixTimestamp::try_from(params.end).map_err(|_| RestError::InvalidRange)?,
+        },
+        RangeType::Slot => RequestRange::Slot {
+            start: params.start,
+            end: params.end,
+        },
+    };
+
+    let after = params
+        .cursor
+        .as_deref()
+        .map(|cursor| parse_cursor(cursor).ok_or(RestError::InvalidRangeCursor))
+        .transpose()?;
+
+    let limit = params.limit.clamp(1, MAX_PAGE_SIZE);
+    let price_feeds_with_update_data = Aggregates::get_price_feeds_range_with_update_data(
+        &*state.state,
+        &price_ids,
+        range,
+        after,
+        limit,
+    )
+    .await
+    .map_err(|e| {
+        tracing::warn!(
+            "Error getting price feeds {:?} in range {:?} with update data: {:?}",
+            price_ids,
+            range,
+            e
+        );
+        RestError::UpdateDataNotFound
+    })?;
+
+    // A full page means there may be more updates in the range, they come after the last update.
+    let next_cursor = price_feeds_with_update_data
+        .price_feeds
+        .last()
+        .filter(|_| price_feeds_with_update_data.price_feeds.len() == limit)
+        .map(|price_feed| {
+            format_cursor(&RangeCursor {
+                publish_time: price_feed.price_feed.get_price_unchecked().publish_time,
+                slot: price_feed.slot.unwrap_or_default(),
+                feed_id: price_feed.price_feed.id.to_bytes(),
+            })
+        });
+
+    let binary = params.binary.then(|| BinaryUpdate {
+        encoding: params.encoding,
+        data: price_feeds_with_update_data
+            .update_data
+            .iter()
+            .map(|data| params.encoding.encode_str(data))
+            .collect(),
+    });
+
+    let parsed: Vec<ParsedPriceUpdate> = price_feeds_with_update_data
+        .price_feeds
+        .into_iter()
+        .map(|price_feed| price_feed.into())
+        .collect();
+
+    Ok(Json(PriceUpdateRange {
+        binary,
+        parsed,
+        next_cursor,
+    }))
+}
+
+/// Cursors are formatted as `<publish_time>_<slot>_<hex feed id>`.
+fn format_cursor(cursor: &RangeCursor) -> String {
+    format!(
+        "{}_{}_{}",
+        cursor.publish_time,
+        cursor.slot,
+        hex::encode(cursor.feed_id)
+    )
+}
+
+fn parse_cursor(cursor: &str) -> Option<RangeCursor> {
+    let mut parts = cursor.split('_');
+    let publish_time = parts.next()?.parse().ok()?;
+    let slot = parts.next()?.parse().ok()?;
+    let feed_id = hex::decode(parts.next()?).ok()?.try_into().ok()?;
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(RangeCursor {
+        publish_time,
+        slot,
+        feed_id,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_cursor_round_trips() {
+        let cursor = RangeCursor {
+            publish_time: 1_700_000_000,
+            slot: 42,
+            feed_id: [7; 32],
+        };
+        assert_eq!(parse_cursor(&format_cursor(&cursor)), Some(cursor));
+
+        assert_eq!(parse_cursor(""), None);
+        assert_eq!(parse_cursor("1_2"), None);
+        assert_eq!(parse_cursor(&format!("1_2_{}", hex::encode([7; 31]))), None);
+        assert_eq!(parse_cursor(&format!("{}_3", format_cursor(&cursor))), None);
+    }
+}
@@ -1,27 +1,48 @@
 use {
+    super::{
+        latest_twaps::validate_twap_window,
+        price_feed_publishers::{
+            get_price_feed_publishers_response, PriceFeedPublishersPathParams,
+        },
+    },
     crate::{
         api::{
-            rest::{validate_price_ids, RestError},
+            rest::{partition_derived_price_ids, validate_price_ids, RestError},
             types::{
-                BinaryUpdate, EncodingType, ParsedPriceUpdate, PriceIdInput, PriceUpdate,
-                RpcPriceIdentifier,
+                BinaryUpdate, EncodingType, ParsedDerivedPriceUpdate, ParsedPriceFeedTwap,
+                ParsedPriceUpdate, PriceIdInput, PriceUpdate, RpcPriceIdentifier, TwapsResponse,
+                UpdateFilter,
             },
             ApiState,
         },
-        state::aggregate::{Aggregates, AggregationEvent, RequestTime},
+        state::{
+            aggregate::{Aggregates, AggregationEvent, RequestTime, Slot},
+            cache::{Cache, MessageStateFilter, MessageStateSlots},
+            derived_feeds::DerivedFeeds,
+            publishers::Publishers,
+        },
     },
     anyhow::Result,
     axum::{
-        extract::State,
+        extract::{Path, State},
+        http::HeaderMap,
         response::sse::{Event, KeepAlive, Sse},
     },
     futures::Stream,
-    pyth_sdk::PriceIdentifier,
+    pyth_sdk::{DurationInSeconds, Price, PriceIdentifier},
+    pythnet_sdk::messages::MessageType,
     serde::Deserialize,
     serde_qs::axum::QsQuery,
-    std::convert::Infallible,
+    std::{
+        collections::HashMap,
+        convert::Infallible,
+        sync::{Arc, Mutex},
+    },
     tokio::sync::broadcast,
-    tokio_stream::{wrappers::BroadcastStream, StreamExt as _},
+    tokio_stream::{
+        wrappers::{errors::BroadcastStreamRecvError, BroadcastStream},
+        StreamExt as _,
+    },
     utoipa::IntoParams,
 };
 
@@ -59,8 +80,26 @@ pub struct StreamPriceUpdatesQueryParams {
     /// If true, invalid price IDs in the `ids` parameter are ignored. Only applicable to the v2 APIs. Default is `false`.
     #[serde(default)]
     ignore_invalid_price_ids: bool,
+
+    /// If set, a price update is only sent if the price moved by at least this many basis points
+    /// since the last update sent for the feed, or if another of the filters matches.
+    #[serde(default)]
+    deviation_bps: Option<u64>,
+
+    /// If set, a price update is only sent if it was published at least this many seconds after
+    /// the last update sent for the feed, or if another of the filters matches.
+    #[serde(default)]
+    heartbeat_seconds: Option<u64>,
+
+    /// If set, a price update is only sent if its confidence interval is wider than this many
+    /// basis points of the price, or if another of the filters matches.
+    #[serde(default)]
+    conf_ratio_bps: Option<u64>,
 }
 
+/// The standard SSE header sent by clients on reconnection with the id of the last event seen.
+const LAST_EVENT_ID_HEADER: &str = "last-event-id";
+
 fn default_true() -> bool {
     true
 }
@@ -75,44 +114,120 @@ fn default_true() -> bool {
     params(StreamPriceUpdatesQueryParams)
 )]
 /// SSE route handler for streaming price updates.
+///
+/// Every event is tagged with the slot of the update as its id. A client reconnecting with the
+/// standard `Last-Event-ID` header first receives the updates for every cached slot after it
+/// before switching to live updates. If some of the missed updates are no longer cached, an error
+/// event is sent first.
+///
+/// The `deviation_bps`, `heartbeat_seconds` and `conf_ratio_bps` filters limit the updates sent
+/// for each feed to the meaningful ones, like on-chain push relayers do.
+///
+/// Derived price feeds are sent in the `derived` field on every new aggregation. They are computed
+/// by Hermes and can't be verified on-chain.
 pub async fn price_stream_sse_handler<S>(
     State(state): State<ApiState<S>>,
+    headers: HeaderMap,
     QsQuery(params): QsQuery<StreamPriceUpdatesQueryParams>,
 ) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, RestError>
 where
     S: Aggregates,
+    S: DerivedFeeds,
     S: Send + Sync + 'static,
 {
     let price_id_inputs: Vec<PriceIdentifier> = params.ids.into_iter().map(Into::into).collect();
+    let (derived_price_ids, price_id_inputs) = partition_derived_price_ids(&state, price_id_inputs);
     let price_ids: Vec<PriceIdentifier> =
         validate_price_ids(&state, &price_id_inputs, params.ignore_invalid_price_ids).await?;
 
-    // Clone the update_tx receiver to listen for new price updates
+    // Clone the update_tx receiver to listen for new price updates. This happens before looking
+    // up the slots to replay so that no update can fall in between.
     let update_rx: broadcast::Receiver<AggregationEvent> = Aggregates::subscribe(&*state.state);
 
-    // Convert the broadcast receiver into a Stream
-    let stream = BroadcastStream::new(update_rx);
+    let last_event_slot: Option<Slot> = headers
+        .get(LAST_EVENT_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok());
+
+    let replay_slots = match last_event_slot {
+        Some(slot) => {
+            Cache::fetch_message_state_slots(
+                &*state.state,
+                price_ids.iter().map(|id| id.to_bytes()).collect(),
+                slot,
+                MessageStateFilter::Only(MessageType::PriceFeedMessage),
+            )
+            .await
+        }
+        None => MessageStateSlots::default(),
+    };
+    let replayed_until = replay_slots.feeds.keys().next_back().copied();
+
+    // The client is told when some of the updates it missed are no longer cached.
+    let gap_event = last_event_slot.and_then(|last_event_slot| {
+        replay_slots
+            .oldest_slot
+            .filter(|oldest_slot| last_event_slot < *oldest_slot)
+            .map(|oldest_slot| {
+                Ok(error_event(format!(
+                    "Updates after slot {} are no longer cached, the replay starts at slot {}",
+                    last_event_slot, oldest_slot
+                )))
+            })
+    });
+
+    // Replay the missed slots as new aggregations, then continue with the live updates that
+    // were not already replayed.
+    let replay_stream = tokio_stream::iter(
+        replay_slots
+            .feeds
+            .into_keys()
+            .map(|slot| Ok::<_, BroadcastStreamRecvError>(AggregationEvent::New { slot })),
+    );
+    let live_stream = BroadcastStream::new(update_rx).filter(move |message| match message {
+        Ok(AggregationEvent::New { slot }) => replayed_until.is_none_or(|until| *slot > until),
+        _ => true,
+    });
+    let stream = replay_stream.chain(live_stream);
+
+    let filter = UpdateFilter {
+        deviation_bps: params.deviation_bps,
+        heartbeat_seconds: params.heartbeat_seconds,
+        conf_ratio_bps: params.conf_ratio_bps,
+    };
+    // The last price sent for each feed, to evaluate the filter against.
+    let last_sent: Arc<Mutex<HashMap<PriceIdentifier, Price>>> = Arc::default();
 
     let sse_stream = stream.then(move |message| {
         let state_clone = state.clone(); // Clone again to use inside the async block
         let price_ids_clone = price_ids.clone(); // Clone again for use inside the async block
+        let derived_price_ids_clone = derived_price_ids.clone();
+        let last_sent = last_sent.clone();
         async move {
             match message {
                 Ok(event) => {
+                    let slot = event.slot();
                     match handle_aggregation_event(
                         event,
-                        state_clone,
+                        state_clone.clone(),
                         price_ids_clone,
+                        derived_price_ids_clone,
                         params.encoding,
                         params.parsed,
                         params.benchmarks_only,
                         params.allow_unordered,
+                        filter,
+                        &last_sent,
                     )
                     .await
                     {
-                        Ok(Some(update)) => Ok(Event::default()
-                            .json_data(update)
-                            .unwrap_or_else(error_event)),
+                        Ok(Some(update)) => {
+                            Aggregates::observe_update_sent(&*state_clone.state, slot).await;
+                            Ok(Event::default()
+                                .id(slot.to_string())
+                                .json_data(update)
+                                .unwrap_or_else(error_event))
+                        }
                         Ok(None) => Ok(Event::default().comment("No update available")),
                         Err(e) => Ok(error_event(e)),
                     }
@@ -122,20 +237,25 @@ where
         }
     });
 
-    Ok(Sse::new(sse_stream).keep_alive(KeepAlive::default()))
+    Ok(Sse::new(tokio_stream::iter(gap_event).chain(sse_stream)).keep_alive(KeepAlive::default()))
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn handle_aggregation_event<S>(
     event: AggregationEvent,
     state: ApiState<S>,
     mut price_ids: Vec<PriceIdentifier>,
+    derived_price_ids: Vec<PriceIdentifier>,
     encoding: EncodingType,
     parsed: bool,
     benchmarks_only: bool,
     allow_unordered: bool,
+    filter: UpdateFilter,
+    last_sent: &Mutex<HashMap<PriceIdentifier, Price>>,
 ) -> Result<Option<PriceUpdate>>
 where
     S: Aggregates,
+    S: DerivedFeeds,
 {
     // Handle out-of-order events
     if let AggregationEvent::OutOfOrder { .. } = event {
@@ -149,6 +269,10 @@ where
 
     price_ids.retain(|price_feed_id| available_price_feed_ids.contains(price_feed_id));
 
+    // Only the price feeds updated in the slot are sent, the others have no update to send.
+    let mut price_ids =
+        Aggregates::get_price_feed_ids_at_slot(&*state.state, &price_ids, event.slot()).await;
+
     let mut price_feeds_with_update_data = Aggregates::get_price_feeds_with_update_data(
         &*state.state,
         &price_ids,
@@ -172,6 +296,32 @@ where
                     prev_time != price_feed.price.publish_time
                 })
         });
+    }
+
+    // The sent prices are only committed once the event is built, so that a failed event doesn't
+    // hold back the next updates.
+    let mut sent_prices = vec![];
+    if !filter.is_empty() {
+        let last_sent = last_sent
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Last sent prices lock is poisoned"))?;
+        parsed_price_updates.retain(|price_feed| {
+            let id = PriceIdentifier::from(price_feed.id);
+            let price = Price {
+                price: price_feed.price.price,
+                conf: price_feed.price.conf,
+                expo: price_feed.price.expo,
+                publish_time: price_feed.price.publish_time,
+            };
+            let matches = filter.matches(&price, last_sent.get(&id));
+            if matches {
+                sent_prices.push((id, price));
+            }
+            matches
+        });
+    }
+
+    if benchmarks_only || !filter.is_empty() {
         // Retain price id in price_ids that are in parsed_price_updates
         price_ids.retain(|price_id| {
             parsed_price_updates
@@ -186,8 +336,31 @@ where
         .await?;
     }
 
-    // Check if price_ids is empty after filtering and return None if it is
-    if price_ids.is_empty() {
+    // Derived prices are only computed for new aggregations, where all of their terms are
+    // available at the same slot. The derived feeds whose prices can't be computed are skipped
+    // without affecting the other updates.
+    let mut derived_price_updates: Vec<ParsedDerivedPriceUpdate> = vec![];
+    if let AggregationEvent::New { slot } = event {
+        for derived_price_id in derived_price_ids {
+            match DerivedFeeds::get_derived_price_feeds(
+                &*state.state,
+                &[derived_price_id],
+                RequestTime::AtSlot(slot),
+            )
+            .await
+            {
+                Ok(derived_price_feeds) => {
+                    derived_price_updates.extend(derived_price_feeds.into_iter().map(Into::into))
+                }
+                Err(e) => {
+                    tracing::debug!(error = ?e, "Failed to compute derived price.");
+                }
+            }
+        }
+    }
+
+    // Check if there is nothing to send after filtering and return None if it is
+    if price_ids.is_empty() && derived_price_updates.is_empty() {
         return Ok(None);
     }
 
@@ -201,6 +374,11 @@ where
         data: encoded_data,
     };
 
+    last_sent
+        .lock()
+        .map_err(|_| anyhow::anyhow!("Last sent prices lock is poisoned"))?
+        .extend(sent_prices);
+
     Ok(Some(PriceUpdate {
         binary: binary_price_update,
         parsed: if parsed {
@@ -208,11 +386,446 @@ where
         } else {
             None
         },
+        derived: if derived_price_updates.is_empty() {
+            None
+        } else {
+            Some(derived_price_updates)
+        },
+    }))
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Path)]
+pub struct StreamTwapsPathParams {
+    /// The time window in seconds over which to calculate the TWAP, ending at the slot of each
+    /// update. Must be greater than 0 and less than or equal to 600 seconds (10 minutes).
+    #[param(example = "300")]
+    #[serde(deserialize_with = "validate_twap_window")]
+    window_seconds: DurationInSeconds,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct StreamTwapsQueryParams {
+    /// Stream the rolling TWAP (time weighted average price) for this set of price feed ids.
+    ///
+    /// This parameter can be provided multiple times to retrieve multiple TWAPs,
+    /// for example see the following query string:
+    ///
+    /// ```
+    /// ?ids[]=a12...&ids[]=b4c...
+    /// ```
+    #[param(rename = "ids[]")]
+    #[param(example = "e62df6c8b4a85fe1a67db44dc12de5db330f7ac66b72dc658afedf0f4a415b43")]
+    ids: Vec<PriceIdInput>,
+
+    /// Optional encoding type. If true, return the cumulative price updates in the encoding specified by the encoding parameter. Default is `hex`.
+    #[serde(default)]
+    encoding: EncodingType,
+
+    /// If true, include the calculated TWAP in the `parsed` field of each returned feed. Default is `true`.
+    #[serde(default = "default_true")]
+    parsed: bool,
+
+    /// If true, invalid price IDs in the `ids` parameter are ignored. Only applicable to the v2 APIs. Default is `false`.
+    #[serde(default)]
+    ignore_invalid_price_ids: bool,
+}
+
+#[utoipa::path(
+    get,
+    path = "/v2/updates/twap/{window_seconds}/stream",
+    responses(
+        (status = 200, description = "TWAPs retrieved successfully", body = TwapsResponse),
+        (status = 404, description = "Price ids not found", body = String)
+    ),
+    params(StreamTwapsPathParams, StreamTwapsQueryParams)
+)]
+/// SSE route handler for streaming rolling TWAPs.
+///
+/// On every new aggregation, the TWAP over the requested window ending at the aggregated slot is
+/// sent along with the start & end cumulative price updates needed to verify it on-chain.
+pub async fn twap_stream_sse_handler<S>(
+    State(state): State<ApiState<S>>,
+    Path(path_params): Path<StreamTwapsPathParams>,
+    QsQuery(params): QsQuery<StreamTwapsQueryParams>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, RestError>
+where
+    S: Aggregates,
+    S: Send + Sync + 'static,
+{
+    let price_id_inputs: Vec<PriceIdentifier> = params.ids.into_iter().map(Into::into).collect();
+    let price_ids: Vec<PriceIdentifier> =
+        validate_price_ids(&state, &price_id_inputs, params.ignore_invalid_price_ids).await?;
+
+    let update_rx: broadcast::Receiver<AggregationEvent> = Aggregates::subscribe(&*state.state);
+    let stream = BroadcastStream::new(update_rx);
+
+    let sse_stream = stream.then(move |message| {
+        let state_clone = state.clone();
+        let price_ids_clone = price_ids.clone();
+        async move {
+            match message {
+                Ok(event) => {
+                    match handle_twap_aggregation_event(
+                        event,
+                        state_clone,
+                        price_ids_clone,
+                        path_params.window_seconds,
+                        params.encoding,
+                        params.parsed,
+                    )
+                    .await
+                    {
+                        Ok(Some(update)) => Ok(Event::default()
+                            .json_data(update)
+                            .unwrap_or_else(error_event)),
+                        Ok(None) => Ok(Event::default().comment("No update available")),
+                        Err(e) => Ok(error_event(e)),
+                    }
+                }
+                Err(e) => Ok(error_event(e)),
+            }
+        }
+    });
+
+    Ok(Sse::new(sse_stream).keep_alive(KeepAlive::default()))
+}
+
+async fn handle_twap_aggregation_event<S>(
+    event: AggregationEvent,
+    state: ApiState<S>,
+    mut price_ids: Vec<PriceIdentifier>,
+    window_seconds: DurationInSeconds,
+    encoding: EncodingType,
+    parsed: bool,
+) -> Result<Option<TwapsResponse>>
+where
+    S: Aggregates,
+{
+    // A rolling TWAP only moves forward, so out-of-order slots are skipped.
+    let slot = match event {
+        AggregationEvent::New { slot } => slot,
+        AggregationEvent::OutOfOrder { .. } => return Ok(None),
+    };
+
+    // Price feeds can be removed while the stream is open.
+    let available_price_feed_ids = Aggregates::get_price_feed_ids(&*state.state).await;
+    price_ids.retain(|price_feed_id| available_price_feed_ids.contains(price_feed_id));
+    if price_ids.is_empty() {
+        return Ok(None);
+    }
+
+    let twaps_with_update_data = match Aggregates::get_rolling_twaps_with_update_data(
+        &*state.state,
+        &price_ids,
+        window_seconds,
+        slot,
+    )
+    .await
+    {
+        Ok(twaps_with_update_data) => twaps_with_update_data,
+        Err(e) => {
+            // The window may not be fully cached yet, e.g. shortly after startup. Like the
+            // websocket API, the update is skipped rather than reported as an error.
+            tracing::debug!(error = ?e, "Failed to calculate TWAPs.");
+            return Ok(None);
+        }
+    };
+
+    Ok(Some(TwapsResponse {
+        binary: BinaryUpdate {
+            encoding,
+            data: twaps_with_update_data
+                .update_data
+                .into_iter()
+                .map(|data| encoding.encode_str(&data))
+                .collect(),
+        },
+        parsed: if parsed {
+            Some(
+                twaps_with_update_data
+                    .twaps
+                    .into_iter()
+                    .map(ParsedPriceFeedTwap::from)
+                    .collect(),
+            )
+        } else {
+            None
+        },
     }))
 }
 
+#[utoipa::path(
+    get,
+    path = "/v2/price_feeds/{id}/publishers/stream",
+    responses(
+        (status = 200, description = "Publisher prices retrieved successfully", body = PriceFeedPublishersResponse),
+        (status = 404, description = "Price id not found", body = String)
+    ),
+    params(PriceFeedPublishersPathParams)
+)]
+/// SSE route handler for streaming the latest price of every publisher of a price feed.
+///
+/// An event is sent every time the publisher prices are refreshed from Pythnet.
+pub async fn price_feed_publishers_stream_sse_handler<S>(
+    State(state): State<ApiState<S>>,
+    Path(path_params): Path<PriceFeedPublishersPathParams>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, RestError>
+where
+    S: Aggregates,
+    S: Publishers,
+    S: Send + Sync + 'static,
+{
+    let price_ids = validate_price_ids(&state, &[path_params.id.into()], false).await?;
+    let price_id = price_ids[0];
+
+    let update_rx: broadcast::Receiver<()> = Publishers::subscribe_publishers(&*state.state);
+    let stream = BroadcastStream::new(update_rx);
+
+    let sse_stream = stream.then(move |message| {
+        let state_clone = state.clone();
+        async move {
+            match message {
+                Ok(()) => {
+                    match get_price_feed_publishers_response(&*state_clone.state, price_id).await {
+                        Ok(response) => Ok(Event::default()
+                            .id(response.slot.to_string())
+                            .json_data(response)
+                            .unwrap_or_else(error_event)),
+                        Err(e) => Ok(error_event(e)),
+                    }
+                }
+                Err(e) => Ok(error_event(e)),
+            }
+        }
+    });
+
+    Ok(Sse::new(sse_stream).keep_alive(KeepAlive::default()))
+}
+
 fn error_event<E: std::fmt::Debug>(e: E) -> Event {
     Event::default()
         .event("error")
         .data(format!("Error receiving update: {:?}", e))
 }
+
+#[cfg(test)]
+mod test {
+    use {
+        super::*,
+        crate::state::{
+            aggregate::test::{
+                create_basic_twap_message, create_dummy_price_feed_message, generate_update,
+                store_multiple_concurrent_valid_updates,
+            },
+            test::setup_state,
+            Metrics,
+        },
+        axum::{body::HttpBody, response::IntoResponse},
+        nonzero_ext::nonzero,
+        pythnet_sdk::messages::Message,
+        std::time::Duration,
+    };
+
+    fn api_state<S>(state: Arc<S>) -> ApiState<S>
+    where
+        S: Metrics,
+        S: Send + Sync + 'static,
+    {
+        ApiState::new(state, vec![], nonzero!(1u32), String::new(), vec![], false)
+    }
+
+    /// Store an update of each of the feeds at the slot.
+    async fn store_slot<S>(state: &Arc<S>, feed_seeds: &[u8], slot: Slot)
+    where
+        S: Aggregates,
+        S: Send + Sync + 'static,
+    {
+        let messages = feed_seeds
+            .iter()
+            .map(|seed| {
+                Message::PriceFeedMessage(create_dummy_price_feed_message(
+                    *seed,
+                    slot as i64,
+                    slot as i64 - 1,
+                ))
+            })
+            .collect();
+        store_multiple_concurrent_valid_updates(
+            state.clone(),
+            generate_update(messages, slot, slot),
+        )
+        .await;
+    }
+
+    /// Opens a stream of the feeds resuming after the last event id, and returns the first
+    /// events as their type, id and parsed feed ids.
+    async fn replayed_events<S>(
+        state: ApiState<S>,
+        feed_seeds: &[u8],
+        last_event_id: Slot,
+        count: usize,
+    ) -> Vec<(Option<String>, Option<Slot>, Vec<String>)>
+    where
+        S: Aggregates,
+        S: DerivedFeeds,
+        S: Send + Sync + 'static,
+    {
+        let mut headers = HeaderMap::new();
+        headers.insert(LAST_EVENT_ID_HEADER, last_event_id.into());
+        let query = feed_seeds
+            .iter()
+            .map(|seed| format!("ids[]={}", hex::encode([*seed; 32])))
+            .collect::<Vec<_>>()
+            .join("&");
+        let params: StreamPriceUpdatesQueryParams = serde_qs::from_str(&query).unwrap();
+        let mut body = price_stream_sse_handler(State(state), headers, QsQuery(params))
+            .await
+            .unwrap()
+            .into_response()
+            .into_body();
+
+        let mut text = String::new();
+        while text.matches("\n\n").count() < count {
+            let chunk = tokio::time::timeout(Duration::from_secs(5), body.data())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            text.push_str(std::str::from_utf8(&chunk).unwrap());
+        }
+
+        text.split("\n\n")
+            .take(count)
+            .map(|event| {
+                let field = |name: &str| {
+                    event
+                        .lines()
+                        .find_map(|line| line.strip_prefix(name))
+                        .map(|value| value.trim().to_string())
+                };
+                let feed_ids = field("data:")
+                    .and_then(|data| serde_json::from_str::<serde_json::Value>(&data).ok())
+                    .and_then(|data| data["parsed"].as_array().cloned())
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|feed| feed["id"].as_str().unwrap().to_string())
+                    .collect();
+                (
+                    field("event:"),
+                    field("id:").map(|id| id.parse().unwrap()),
+                    feed_ids,
+                )
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_replay_sends_the_feeds_updated_in_each_slot() {
+        let (state, _) = setup_state(3).await;
+        store_slot(&state, &[1, 2], 10).await;
+        store_slot(&state, &[1], 11).await;
+        store_slot(&state, &[2], 12).await;
+
+        // No slot after 10 has updates of both feeds, each of them is still replayed.
+        assert_eq!(
+            replayed_events(api_state(state), &[1, 2], 10, 2).await,
+            vec![
+                (None, Some(11), vec![hex::encode([1; 32])]),
+                (None, Some(12), vec![hex::encode([2; 32])]),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_replay_from_an_evicted_slot_reports_the_gap() {
+        let (state, _) = setup_state(2).await;
+        store_slot(&state, &[1], 10).await;
+        store_slot(&state, &[1], 11).await;
+        store_slot(&state, &[1], 12).await;
+
+        // The update of slot 10 is evicted, the client is told before the replay starts.
+        assert_eq!(
+            replayed_events(api_state(state), &[1], 9, 3).await,
+            vec![
+                (Some("error".to_string()), None, vec![]),
+                (None, Some(11), vec![hex::encode([1; 32])]),
+                (None, Some(12), vec![hex::encode([1; 32])]),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_twap_stream_window_ends_at_the_update() {
+        let (state, _) = setup_state(10).await;
+        let feed_id = [1u8; 32];
+        for (sequence, (cumulative_price, publish_time, slot)) in
+            [(100, 100, 1000), (200, 150, 1050), (400, 200, 1100)]
+                .into_iter()
+                .enumerate()
+        {
+            store_multiple_concurrent_valid_updates(
+                state.clone(),
+                generate_update(
+                    vec![create_basic_twap_message(
+                        feed_id,
+                        cumulative_price,
+                        0,
+                        publish_time,
+                        publish_time - 10,
+                        slot,
+                    )],
+                    slot,
+                    20 + sequence as u64,
+                ),
+            )
+            .await;
+        }
+        let state = api_state(state);
+        let price_ids = vec![PriceIdentifier::new(feed_id)];
+
+        let update = handle_twap_aggregation_event(
+            AggregationEvent::New { slot: 1100 },
+            state.clone(),
+            price_ids.clone(),
+            50,
+            EncodingType::Hex,
+            true,
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        let twaps = update.parsed.unwrap();
+        assert_eq!(twaps.len(), 1);
+        assert_eq!(twaps[0].start_timestamp, 150);
+        assert_eq!(twaps[0].end_timestamp, 200);
+        assert_eq!(update.binary.data.len(), 2);
+
+        // A window that is not cached is skipped instead of being sent as an error.
+        assert!(handle_twap_aggregation_event(
+            AggregationEvent::New { slot: 1100 },
+            state.clone(),
+            price_ids.clone(),
+            300,
+            EncodingType::Hex,
+            true,
+        )
+        .await
+        .unwrap()
+        .is_none());
+
+        // Out-of-order slots are skipped.
+        assert!(handle_twap_aggregation_event(
+            AggregationEvent::OutOfOrder { slot: 1050 },
+            state,
+            price_ids,
+            50,
+            EncodingType::Hex,
+            true,
+        )
+        .await
+        .unwrap()
+        .is_none());
+    }
+}
@@ -133,6 +133,7 @@ where
     let compressed_price_update = PriceUpdate {
         binary: binary_price_update,
         parsed: parsed_price_updates,
+        derived: None,
     };
 
     Ok(Json(compressed_price_update))
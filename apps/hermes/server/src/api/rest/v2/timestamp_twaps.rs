@@ -0,0 +1,147 @@
+use {
+    super::latest_twaps::validate_twap_window,
+    crate::{
+        api::{
+            doc_examples,
+            rest::{validate_price_ids, RestError},
+            types::{BinaryUpdate, EncodingType, ParsedPriceFeedTwap, PriceIdInput, TwapsResponse},
+            ApiState,
+        },
+        state::aggregate::{Aggregates, RequestTime, UnixTimestamp},
+    },
+    anyhow::Result,
+    axum::{
+        extract::{Path, State},
+        Json,
+    },
+    pyth_sdk::PriceIdentifier,
+    serde::Deserialize,
+    serde_qs::axum::QsQuery,
+    utoipa::IntoParams,
+};
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in=Path)]
+pub struct TimestampTwapsPathParams {
+    /// The time window in seconds over which to calculate the TWAP, ending at `publish_time`.
+    /// For example, a value of 300 would return the 5 minute TWAP ending at `publish_time`.
+    /// Must be greater than 0 and less than or equal to 600 seconds (10 minutes).
+    #[param(example = "300")]
+    #[serde(deserialize_with = "validate_twap_window")]
+    window_seconds: u64,
+
+    /// The unix timestamp in seconds at which the TWAP window ends. The window is bounded by
+    /// the first updates whose publish_time is >= the window start and end respectively.
+    #[param(value_type = i64)]
+    #[param(example = doc_examples::timestamp_example)]
+    publish_time: UnixTimestamp,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in=Query)]
+pub struct TimestampTwapsQueryParams {
+    /// Get the TWAP (time weighted average price) for this set of price feed ids.
+    /// The `binary` data contains the signed start & end cumulative price updates needed to calculate
+    /// the TWAPs on-chain. The `parsed` data contains the calculated TWAPs.
+    ///
+    /// This parameter can be provided multiple times to retrieve multiple price updates,
+    /// for example see the following query string:
+    ///
+    /// ```
+    /// ?ids[]=a12...&ids[]=b4c...
+    /// ```
+    #[param(rename = "ids[]")]
+    #[param(example = "e62df6c8b4a85fe1a67db44dc12de5db330f7ac66b72dc658afedf0f4a415b43")]
+    ids: Vec<PriceIdInput>,
+
+    /// Optional encoding type. If true, return the cumulative price updates in the encoding specified by the encoding parameter. Default is `hex`.
+    #[serde(default)]
+    encoding: EncodingType,
+
+    /// If true, include the calculated TWAP in the `parsed` field of each returned feed. Default is `true`.
+    #[serde(default = "default_true")]
+    parsed: bool,
+
+    /// If true, invalid price IDs in the `ids` parameter are ignored. Only applicable to the v2 APIs. Default is `false`.
+    #[serde(default)]
+    ignore_invalid_price_ids: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Get the TWAP by price feed id with a custom time window ending at a given timestamp.
+///
+/// Given a collection of price feed ids, retrieve the Pyth TWAP price for each price feed over
+/// the window ending at `publish_time`. Windows that are no longer cached are served from
+/// Benchmarks.
+#[utoipa::path(
+    get,
+    path = "/v2/updates/twap/{window_seconds}/{publish_time}",
+    responses(
+        (status = 200, description = "TWAPs retrieved successfully", body = TwapsResponse),
+        (status = 404, description = "Price ids not found", body = String)
+    ),
+    params(
+        TimestampTwapsPathParams,
+        TimestampTwapsQueryParams
+    )
+)]
+pub async fn timestamp_twaps<S>(
+    State(state): State<ApiState<S>>,
+    Path(path_params): Path<TimestampTwapsPathParams>,
+    QsQuery(params): QsQuery<TimestampTwapsQueryParams>,
+) -> Result<Json<TwapsResponse>, RestError>
+where
+    S: Aggregates,
+{
+    let price_id_inputs: Vec<PriceIdentifier> =
+        params.ids.into_iter().map(|id| id.into()).collect();
+    let price_ids: Vec<PriceIdentifier> =
+        validate_price_ids(&state, &price_id_inputs, params.ignore_invalid_price_ids).await?;
+
+    // Collect start and end bounds for the TWAP window
+    let end_time = path_params.publish_time;
+    let start_time = end_time - path_params.window_seconds as i64;
+
+    let twaps_with_update_data = Aggregates::get_twaps_with_update_data(
+        &*state.state,
+        &price_ids,
+        RequestTime::FirstAfter(start_time),
+        RequestTime::FirstAfter(end_time),
+    )
+    .await
+    .map_err(|e| {
+        tracing::warn!(
+            "Error getting TWAPs for price IDs {:?} at {} with update data: {:?}",
+            price_ids,
+            end_time,
+            e
+        );
+        RestError::UpdateDataNotFound
+    })?;
+
+    let binary = BinaryUpdate {
+        encoding: params.encoding,
+        data: twaps_with_update_data
+            .update_data
+            .into_iter()
+            .map(|data| params.encoding.encode_str(&data))
+            .collect(),
+    };
+
+    let parsed: Option<Vec<ParsedPriceFeedTwap>> = if params.parsed {
+        Some(
+            twaps_with_update_data
+                .twaps
+                .into_iter()
+                .map(Into::into)
+                .collect(),
+        )
+    } else {
+        None
+    };
+
+    Ok(Json(TwapsResponse { binary, parsed }))
+}
@@ -1,7 +1,12 @@
 use {
     super::doc_examples,
-    crate::state::aggregate::{
-        PriceFeedTwap, PriceFeedUpdate, PriceFeedsWithUpdateData, Slot, UnixTimestamp,
+    crate::state::{
+        aggregate::{
+            feed_stats::PublishTimeStats, PriceFeedTwap, PriceFeedUpdate, PriceFeedsWithUpdateData,
+            Slot, TwapsWithUpdateData, UnixTimestamp,
+        },
+        derived_feeds::DerivedPriceFeed,
+        publishers::PublisherStatus,
     },
     anyhow::Result,
     base64::{engine::general_purpose::STANDARD as base64_standard_engine, Engine as _},
@@ -205,6 +210,65 @@ impl EncodingType {
             EncodingType::Hex => hex::encode(data),
         }
     }
+
+    pub fn decode_str(&self, data: &str) -> Result<Vec<u8>> {
+        Ok(match self {
+            EncodingType::Base64 => base64_standard_engine.decode(data)?,
+            EncodingType::Hex => hex::decode(data)?,
+        })
+    }
+}
+
+/// Conditions for sending a price update to a subscriber, like on-chain push relayers do. An
+/// update is sent if any of the set conditions holds, and every update is sent if none is set.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+pub struct UpdateFilter {
+    /// Send the update if the price moved by at least this many basis points since the last
+    /// update sent.
+    #[serde(default)]
+    pub deviation_bps: Option<u64>,
+    /// Send the update if it was published at least this many seconds after the last update sent.
+    #[serde(default)]
+    pub heartbeat_seconds: Option<u64>,
+    /// Send the update if its confidence interval is wider than this many basis points of the
+    /// price.
+    #[serde(default)]
+    pub conf_ratio_bps: Option<u64>,
+}
+
+impl UpdateFilter {
+    pub fn is_empty(&self) -> bool {
+        self.deviation_bps.is_none()
+            && self.heartbeat_seconds.is_none()
+            && self.conf_ratio_bps.is_none()
+    }
+
+    /// Whether `price` should be sent, given the last price sent for the same feed.
+    pub fn matches(&self, price: &Price, last_sent: Option<&Price>) -> bool {
+        if self.is_empty() {
+            return true;
+        }
+        let Some(last_sent) = last_sent else {
+            return true;
+        };
+        if price.publish_time <= last_sent.publish_time {
+            return false;
+        }
+
+        // Prices of a feed share the same exponent, so they are compared without scaling.
+        let deviated = self.deviation_bps.is_some_and(|deviation_bps| {
+            let change = (price.price as i128 - last_sent.price as i128).unsigned_abs();
+            change * 10_000 >= deviation_bps as u128 * (last_sent.price as i128).unsigned_abs()
+        });
+        let heartbeat = self.heartbeat_seconds.is_some_and(|heartbeat_seconds| {
+            price.publish_time - last_sent.publish_time >= heartbeat_seconds as i64
+        });
+        let uncertain = self.conf_ratio_bps.is_some_and(|conf_ratio_bps| {
+            price.conf as u128 * 10_000
+                > conf_ratio_bps as u128 * (price.price as i128).unsigned_abs()
+        });
+        deviated || heartbeat || uncertain
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
@@ -290,6 +354,39 @@ pub struct TwapsResponse {
     pub parsed: Option<Vec<ParsedPriceFeedTwap>>,
 }
 
+impl TryFrom<TwapsResponse> for TwapsWithUpdateData {
+    type Error = anyhow::Error;
+    fn try_from(twaps_response: TwapsResponse) -> Result<Self> {
+        let twaps = match twaps_response.parsed {
+            Some(parsed_twaps) => parsed_twaps
+                .into_iter()
+                .map(|parsed_twap| PriceFeedTwap {
+                    id: parsed_twap.id.into(),
+                    start_timestamp: parsed_twap.start_timestamp,
+                    end_timestamp: parsed_twap.end_timestamp,
+                    twap: Price {
+                        price: parsed_twap.twap.price,
+                        conf: parsed_twap.twap.conf,
+                        expo: parsed_twap.twap.expo,
+                        publish_time: parsed_twap.twap.publish_time,
+                    },
+                    down_slots_ratio: parsed_twap.down_slots_ratio,
+                })
+                .collect(),
+            None => return Err(anyhow::anyhow!("No parsed TWAPs available")),
+        };
+
+        let update_data = twaps_response
+            .binary
+            .data
+            .iter()
+            .map(|data| twaps_response.binary.encoding.decode_str(data))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(TwapsWithUpdateData { twaps, update_data })
+    }
+}
+
 #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize, Clone, ToSchema)]
 pub struct ParsedPublisherStakeCapsUpdate {
     pub publisher_stake_caps: Vec<ParsedPublisherStakeCap>,
@@ -301,6 +398,134 @@ pub struct ParsedPublisherStakeCap {
     pub cap: u64,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum RpcPublisherStatus {
+    Unknown,
+    Trading,
+    Halted,
+    Auction,
+    Ignored,
+}
+
+impl From<PublisherStatus> for RpcPublisherStatus {
+    fn from(status: PublisherStatus) -> Self {
+        match status {
+            PublisherStatus::Unknown => RpcPublisherStatus::Unknown,
+            PublisherStatus::Trading => RpcPublisherStatus::Trading,
+            PublisherStatus::Halted => RpcPublisherStatus::Halted,
+            PublisherStatus::Auction => RpcPublisherStatus::Auction,
+            PublisherStatus::Ignored => RpcPublisherStatus::Ignored,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct RpcPublisherPrice {
+    /// The public key of the publisher, base58 encoded.
+    pub publisher: String,
+    /// The latest price submitted by the publisher, stored as a string to avoid precision loss
+    #[serde(with = "pyth_sdk::utils::as_string")]
+    #[schema(value_type = String, example="2920679499999")]
+    pub price: i64,
+    /// The confidence interval of the latest price, stored as a string to avoid precision loss
+    #[serde(with = "pyth_sdk::utils::as_string")]
+    #[schema(value_type = String, example="509500001")]
+    pub conf: u64,
+    pub status: RpcPublisherStatus,
+    /// The Pythnet slot at which the price was submitted.
+    #[schema(value_type = u64, example=85480034)]
+    pub slot: Slot,
+    /// The stake cap of the publisher from the latest publisher stake caps update, if any.
+    pub stake_cap: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct PriceFeedPublishersResponse {
+    pub id: RpcPriceIdentifier,
+    /// The exponent of the publisher prices and confidence intervals.
+    #[schema(example = -8)]
+    pub expo: i32,
+    /// The Pythnet slot of the latest aggregation of the price feed.
+    #[schema(value_type = u64, example=85480034)]
+    pub slot: Slot,
+    pub publishers: Vec<RpcPublisherPrice>,
+}
+
+/// Distribution of the most recent gaps, in seconds, between the publish time of the updates of
+/// a feed and their previous publish time.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct RpcPublishTimeGapStats {
+    /// Number of gaps the distribution is computed from.
+    #[schema(example = 100)]
+    pub samples: usize,
+    pub min: u64,
+    pub max: u64,
+    pub mean: f64,
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+}
+
+impl RpcPublishTimeGapStats {
+    fn new(mut gaps: Vec<u64>) -> Option<Self> {
+        if gaps.is_empty() {
+            return None;
+        }
+        gaps.sort_unstable();
+        // Nearest-rank percentile.
+        let percentile = |p: usize| gaps[(gaps.len() * p).div_ceil(100).max(1) - 1];
+        Some(Self {
+            samples: gaps.len(),
+            min: gaps[0],
+            max: gaps[gaps.len() - 1],
+            mean: gaps.iter().sum::<u64>() as f64 / gaps.len() as f64,
+            p50: percentile(50),
+            p90: percentile(90),
+            p99: percentile(99),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct RpcFeedStats {
+    /// Number of observed updates with a successful aggregation.
+    pub updates: u64,
+    /// Number of observed updates with a failed aggregation, i.e. with the same publish time as
+    /// their previous publish time.
+    pub failed_aggregations: u64,
+    #[schema(value_type = i64, example = 1717632000)]
+    pub last_publish_time: UnixTimestamp,
+    /// Seconds elapsed since the latest publish time.
+    pub last_update_age: u64,
+    /// Missing until an update with a successful aggregation is observed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub publish_time_gap: Option<RpcPublishTimeGapStats>,
+}
+
+impl RpcFeedStats {
+    pub fn new(stats: PublishTimeStats, current_time: UnixTimestamp) -> Self {
+        Self {
+            updates: stats.updates,
+            failed_aggregations: stats.failed_aggregations,
+            last_publish_time: stats.last_publish_time,
+            last_update_age: current_time.saturating_sub(stats.last_publish_time).max(0) as u64,
+            publish_time_gap: RpcPublishTimeGapStats::new(stats.gaps),
+        }
+    }
+}
+
+/// Publish time statistics of a price feed, collected since Hermes started.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct PriceFeedStatsResponse {
+    pub id: RpcPriceIdentifier,
+    /// Statistics of the price updates.
+    pub price: RpcFeedStats,
+    /// Statistics of the TWAP updates, if the feed has any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub twap: Option<RpcFeedStats>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct LatestPublisherStakeCapsUpdateDataResponse {
     pub binary: BinaryUpdate,
@@ -308,11 +533,66 @@ pub struct LatestPublisherStakeCapsUpdateDataResponse {
     pub parsed: Option<Vec<ParsedPublisherStakeCapsUpdate>>,
 }
 
+/// A price computed by Hermes from the prices of other price feeds.
+///
+/// Derived prices are not signed by the Wormhole guardians: they have no update data and can't be
+/// verified on-chain.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ParsedDerivedPriceUpdate {
+    pub id: RpcPriceIdentifier,
+    pub price: RpcPrice,
+    pub ema_price: RpcPrice,
+    /// The formula the price is derived from, multiplying and dividing price feed ids, e.g.
+    /// `<ETH/USD id> / <BTC/USD id>`.
+    pub formula: String,
+    /// The oldest Pythnet slot of the prices the feed is derived from.
+    #[schema(value_type = u64, example = 85480034)]
+    pub slot: Slot,
+}
+
+impl From<DerivedPriceFeed> for ParsedDerivedPriceUpdate {
+    fn from(derived_price_feed: DerivedPriceFeed) -> Self {
+        Self {
+            id: RpcPriceIdentifier::from(derived_price_feed.id),
+            price: RpcPrice {
+                price: derived_price_feed.price.price,
+                conf: derived_price_feed.price.conf,
+                expo: derived_price_feed.price.expo,
+                publish_time: derived_price_feed.price.publish_time,
+            },
+            ema_price: RpcPrice {
+                price: derived_price_feed.ema_price.price,
+                conf: derived_price_feed.ema_price.conf,
+                expo: derived_price_feed.ema_price.expo,
+                publish_time: derived_price_feed.ema_price.publish_time,
+            },
+            formula: derived_price_feed.formula,
+            slot: derived_price_feed.slot,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct PriceUpdate {
     pub binary: BinaryUpdate,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub parsed: Option<Vec<ParsedPriceUpdate>>,
+    /// The derived price feeds among the requested ids. They are unsigned and are not part of the
+    /// binary update data.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub derived: Option<Vec<ParsedDerivedPriceUpdate>>,
+}
+
+/// A page of price updates within a time or slot range.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct PriceUpdateRange {
+    /// The update data grouped per slot, only present if requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub binary: Option<BinaryUpdate>,
+    pub parsed: Vec<ParsedPriceUpdate>,
+    /// The cursor to request the following page with, absent if this is the last page.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
 }
 
 impl TryFrom<PriceUpdate> for PriceFeedsWithUpdateData {
@@ -362,7 +642,7 @@ impl TryFrom<PriceUpdate> for PriceFeedsWithUpdateData {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
 pub struct PriceFeedMetadata {
     pub id: RpcPriceIdentifier,
     // BTreeMap is used to automatically sort the keys to ensure consistent ordering of attributes in the JSON response.
@@ -370,7 +650,16 @@ pub struct PriceFeedMetadata {
     pub attributes: BTreeMap<String, String>,
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, ToSchema)]
+/// The metadata of a price feed, along with its latest price if it was requested.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct PriceFeedMetadataWithPrice {
+    #[serde(flatten)]
+    pub metadata: PriceFeedMetadata,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latest_price: Option<RpcPrice>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, ToSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum AssetType {
     Crypto,
@@ -438,4 +727,79 @@ mod tests {
                 .trim_matches('"')
         );
     }
+
+    #[test]
+    fn test_update_filter_works() {
+        let price = |price, conf, publish_time| Price {
+            price,
+            conf,
+            expo: -2,
+            publish_time,
+        };
+        let last_sent = price(10_000, 10, 100);
+        let filter = |deviation_bps, heartbeat_seconds, conf_ratio_bps| UpdateFilter {
+            deviation_bps,
+            heartbeat_seconds,
+            conf_ratio_bps,
+        };
+
+        // Without conditions every update is sent, even if it is not newer.
+        assert!(UpdateFilter::default().matches(&price(10_000, 10, 100), Some(&last_sent)));
+
+        // The first update is always sent, then only newer updates.
+        assert!(filter(Some(50), None, None).matches(&price(10_000, 10, 100), None));
+        assert!(!filter(Some(1), None, None).matches(&price(20_000, 10, 100), Some(&last_sent)));
+
+        // A 50 bps deviation of 10_000 is 50, in either direction.
+        let deviation = filter(Some(50), None, None);
+        assert!(!deviation.matches(&price(10_049, 10, 101), Some(&last_sent)));
+        assert!(deviation.matches(&price(10_050, 10, 101), Some(&last_sent)));
+        assert!(deviation.matches(&price(9_950, 10, 101), Some(&last_sent)));
+
+        let heartbeat = filter(None, Some(60), None);
+        assert!(!heartbeat.matches(&price(10_000, 10, 159), Some(&last_sent)));
+        assert!(heartbeat.matches(&price(10_000, 10, 160), Some(&last_sent)));
+
+        // A 20 bps confidence ratio of 10_000 is 20.
+        let conf_ratio = filter(None, None, Some(20));
+        assert!(!conf_ratio.matches(&price(10_000, 20, 101), Some(&last_sent)));
+        assert!(conf_ratio.matches(&price(10_000, 21, 101), Some(&last_sent)));
+
+        // Any condition is enough when several are set.
+        let all = filter(Some(50), Some(60), Some(20));
+        assert!(!all.matches(&price(10_010, 10, 150), Some(&last_sent)));
+        assert!(all.matches(&price(10_100, 10, 150), Some(&last_sent)));
+        assert!(all.matches(&price(10_010, 10, 160), Some(&last_sent)));
+        assert!(all.matches(&price(10_010, 30, 150), Some(&last_sent)));
+    }
+
+    #[test]
+    fn test_twaps_response_roundtrips_into_twaps_with_update_data() {
+        let twap = PriceFeedTwap {
+            id: PriceIdentifier::new([1; 32]),
+            start_timestamp: 100,
+            end_timestamp: 400,
+            twap: Price {
+                price: 42,
+                conf: 3,
+                expo: -8,
+                publish_time: 400,
+            },
+            down_slots_ratio: Decimal::new(5, 1),
+        };
+
+        for encoding in [EncodingType::Hex, EncodingType::Base64] {
+            let response = TwapsResponse {
+                binary: BinaryUpdate {
+                    encoding,
+                    data: vec![encoding.encode_str(&[1, 2, 3])],
+                },
+                parsed: Some(vec![twap.clone().into()]),
+            };
+
+            let twaps_with_update_data = TwapsWithUpdateData::try_from(response).unwrap();
+            assert_eq!(twaps_with_update_data.twaps, vec![twap.clone()]);
+            assert_eq!(twaps_with_update_data.update_data, vec![vec![1, 2, 3]]);
+        }
+    }
 }
@@ -1,60 +1,101 @@
 use {
     super::{
-        types::{PriceIdInput, RpcPriceFeed},
+        api_keys::ApiKey,
+        binary_update::BinaryPriceUpdate,
+        rest::MAX_TWAP_WINDOW_SECONDS,
+        types::{
+            ParsedDerivedPriceUpdate, ParsedPriceFeedTwap, PriceIdInput, RpcPriceFeed, UpdateFilter,
+        },
         ApiState,
     },
     crate::state::{
-        aggregate::{Aggregates, AggregationEvent, RequestTime},
+        aggregate::{Aggregates, AggregationEvent, PriceFeedUpdate, RequestTime, Slot},
+        cache::MessageStateFilter,
         metrics::Metrics,
-        Benchmarks, Cache, PriceFeedMeta,
+        Benchmarks, Cache, DerivedFeeds, PriceFeedMeta,
     },
     anyhow::{anyhow, Result},
     axum::{
         extract::{
             ws::{Message, WebSocket, WebSocketUpgrade},
-            State as AxumState,
+            Extension, State as AxumState,
         },
         http::HeaderMap,
         response::IntoResponse,
     },
+    base64::{engine::general_purpose::STANDARD as base64_standard_engine, Engine as _},
     futures::{
         stream::{SplitSink, SplitStream},
         SinkExt, StreamExt,
     },
     governor::{DefaultKeyedRateLimiter, Quota, RateLimiter},
     ipnet::IpNet,
-    nonzero_ext::nonzero,
+    outbox::{
+        in_slot_order, price_update_messages, Outbox, OutgoingMessage, PendingPriceUpdate,
+        PriceUpdateContent,
+    },
     prometheus_client::{
         encoding::{EncodeLabelSet, EncodeLabelValue},
         metrics::{counter::Counter, family::Family},
     },
-    pyth_sdk::PriceIdentifier,
+    pyth_sdk::{DurationInSeconds, PriceIdentifier},
+    pythnet_sdk::messages::MessageType,
     serde::{Deserialize, Serialize},
     std::{
-        collections::HashMap,
+        collections::{BTreeMap, BTreeSet, HashMap, HashSet},
         net::IpAddr,
         num::NonZeroU32,
         sync::{
             atomic::{AtomicUsize, Ordering},
-            Arc,
+            Arc, PoisonError, RwLock, RwLockReadGuard,
         },
         time::Duration,
     },
-    tokio::sync::{broadcast::Receiver, watch},
+    tokio::{
+        sync::{
+            broadcast::{error::RecvError, Receiver},
+            watch,
+        },
+        task::JoinHandle,
+    },
 };
 
+mod outbox;
+
 const PING_INTERVAL_DURATION: Duration = Duration::from_secs(30);
 const MAX_CLIENT_MESSAGE_SIZE: usize = 100 * 1024; // 100 KiB
 
-/// The maximum number of bytes that can be sent per second per IP address.
-/// If the limit is exceeded, the connection is closed.
-const BYTES_LIMIT_PER_IP_PER_SECOND: u32 = 256 * 1024; // 256 KiB
+/// How long the messages queued before a connection closes have to be sent.
+const WRITER_CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[derive(Clone)]
 pub struct PriceFeedClientConfig {
     verbose: bool,
     binary: bool,
     allow_out_of_order: bool,
+    /// The last slot sent as part of a replay, live updates up to this slot are skipped.
+    replayed_until: Option<Slot>,
+    /// Evaluated against the last price written to the client, kept in the outbox.
+    filter: UpdateFilter,
+    framing: Framing,
+}
+
+/// How the price updates are sent to the client.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Framing {
+    /// A JSON `price_update` message per price feed.
+    #[default]
+    Json,
+    /// A single binary message with the updates of all the price feeds of a slot, see the
+    /// `binary_update` module for the layout.
+    Binary,
+}
+
+#[derive(Clone)]
+pub struct TwapClientConfig {
+    window_seconds: DurationInSeconds,
+    binary: bool,
 }
 
 #[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelValue)]
@@ -63,6 +104,8 @@ pub enum Interaction {
     CloseConnection,
     ClientHeartbeat,
     PriceUpdate,
+    TwapUpdate,
+    DerivedPriceUpdate,
     ClientMessage,
     RateLimit,
 }
@@ -81,6 +124,8 @@ pub struct Labels {
 
 pub struct WsMetrics {
     pub interactions: Family<Labels, Counter>,
+    /// Price updates that were overwritten by a newer update of their feed before being sent.
+    pub conflated_price_updates: Counter,
 }
 
 impl WsMetrics {
@@ -91,10 +136,12 @@ impl WsMetrics {
     {
         let new = Self {
             interactions: Family::default(),
+            conflated_price_updates: Counter::default(),
         };
 
         {
             let interactions = new.interactions.clone();
+            let conflated_price_updates = new.conflated_price_updates.clone();
 
             tokio::spawn(async move {
                 Metrics::register(
@@ -106,6 +153,16 @@ impl WsMetrics {
                     ),
                 )
                 .await;
+                Metrics::register(
+                    &*state,
+                    (
+                        "ws_conflated_price_updates",
+                        "Total number of price updates not sent to slow websocket clients because \
+                         a newer update of the same feed replaced them",
+                        conflated_price_updates,
+                    ),
+                )
+                .await;
             });
         }
 
@@ -113,30 +170,90 @@ impl WsMetrics {
     }
 }
 
+/// The maximum number of bytes that can be sent per second per IP address, for the connections
+/// without an API key. If the limit is exceeded, the connection is closed.
+struct BytesLimit {
+    whitelist: Vec<IpNet>,
+    bytes_per_second: NonZeroU32,
+    rate_limiter: DefaultKeyedRateLimiter<IpAddr>,
+}
+
+impl BytesLimit {
+    fn new(whitelist: Vec<IpNet>, bytes_per_second: NonZeroU32) -> Self {
+        Self {
+            whitelist,
+            bytes_per_second,
+            rate_limiter: RateLimiter::dashmap(Quota::per_second(bytes_per_second)),
+        }
+    }
+}
+
 pub struct WsState {
     pub subscriber_counter: AtomicUsize,
-    pub bytes_limit_whitelist: Vec<IpNet>,
-    pub rate_limiter: DefaultKeyedRateLimiter<IpAddr>,
+    /// Number of currently open connections.
+    pub active_connections: AtomicUsize,
+    bytes_limit: RwLock<BytesLimit>,
     pub requester_ip_header_name: String,
     pub metrics: WsMetrics,
 }
 
 impl WsState {
-    pub fn new<S>(whitelist: Vec<IpNet>, requester_ip_header_name: String, state: Arc<S>) -> Self
+    pub fn new<S>(
+        whitelist: Vec<IpNet>,
+        bytes_limit_per_ip_per_second: NonZeroU32,
+        requester_ip_header_name: String,
+        state: Arc<S>,
+    ) -> Self
     where
         S: Metrics,
         S: Send + Sync + 'static,
     {
         Self {
             subscriber_counter: AtomicUsize::new(0),
-            rate_limiter: RateLimiter::dashmap(Quota::per_second(nonzero!(
-                BYTES_LIMIT_PER_IP_PER_SECOND
-            ))),
-            bytes_limit_whitelist: whitelist,
+            active_connections: AtomicUsize::new(0),
+            bytes_limit: RwLock::new(BytesLimit::new(whitelist, bytes_limit_per_ip_per_second)),
             requester_ip_header_name,
             metrics: WsMetrics::new(state.clone()),
         }
     }
+
+    fn bytes_limit(&self) -> RwLockReadGuard<'_, BytesLimit> {
+        self.bytes_limit
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Replace the per-IP byte limit and its whitelist. The budgets start over from full, so the
+    /// limit is only replaced if it or the whitelist changed.
+    pub fn set_bytes_limit(
+        &self,
+        whitelist: Vec<IpNet>,
+        bytes_limit_per_ip_per_second: NonZeroU32,
+    ) {
+        let mut bytes_limit = self
+            .bytes_limit
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        if bytes_limit.whitelist == whitelist
+            && bytes_limit.bytes_per_second == bytes_limit_per_ip_per_second
+        {
+            return;
+        }
+        *bytes_limit = BytesLimit::new(whitelist, bytes_limit_per_ip_per_second);
+    }
+
+    /// Whether a message can be sent to an IP address without exceeding its byte limit.
+    fn check_ip_bytes(&self, ip_addr: IpAddr, message_len: usize) -> Result<bool> {
+        let bytes_limit = self.bytes_limit();
+        Ok(bytes_limit
+            .whitelist
+            .iter()
+            .any(|ip_net| ip_net.contains(&ip_addr))
+            || bytes_limit.rate_limiter.check_key_n(
+                &ip_addr,
+                NonZeroU32::new(message_len.try_into()?).ok_or(anyhow!("Empty message"))?,
+            ) == Ok(Ok(())))
+    }
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -151,6 +268,20 @@ enum ClientMessage {
         binary: bool,
         #[serde(default)]
         allow_out_of_order: bool,
+        /// If set, the updates of every cached slot after this one are sent before live updates.
+        #[serde(default)]
+        from_slot: Option<Slot>,
+        /// If set, the ids are subscribed to rolling TWAP updates over this window rather than to
+        /// price updates.
+        #[serde(default)]
+        twap_window_seconds: Option<DurationInSeconds>,
+        /// Only send the price updates matching the filter, e.g. when the price moved by a number
+        /// of basis points since the last update sent.
+        #[serde(flatten)]
+        filter: UpdateFilter,
+        /// Whether the price updates are sent as JSON messages (default) or as binary messages.
+        #[serde(default)]
+        framing: Framing,
     },
     #[serde(rename = "unsubscribe")]
     Unsubscribe { ids: Vec<PriceIdInput> },
@@ -163,6 +294,19 @@ enum ServerMessage {
     Response(ServerResponseMessage),
     #[serde(rename = "price_update")]
     PriceUpdate { price_feed: RpcPriceFeed },
+    #[serde(rename = "twap_update")]
+    TwapUpdate {
+        window_seconds: DurationInSeconds,
+        twaps: Vec<ParsedPriceFeedTwap>,
+        /// Base64 encoded start & end cumulative price updates, present if binary is requested.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        update_data: Option<Vec<String>>,
+    },
+    /// A price computed by Hermes from other price feeds. It is unsigned and has no update data.
+    #[serde(rename = "derived_price_update")]
+    DerivedPriceUpdate {
+        derived_price_feed: ParsedDerivedPriceUpdate,
+    },
 }
 
 #[derive(Serialize, Debug, Clone)]
@@ -177,12 +321,14 @@ enum ServerResponseMessage {
 pub async fn ws_route_handler<S>(
     ws: WebSocketUpgrade,
     AxumState(state): AxumState<ApiState<S>>,
+    api_key: Option<Extension<Arc<ApiKey>>>,
     headers: HeaderMap,
 ) -> impl IntoResponse
 where
     S: Aggregates,
     S: Benchmarks,
     S: Cache,
+    S: DerivedFeeds,
     S: PriceFeedMeta,
     S: Send + Sync + 'static,
 {
@@ -192,21 +338,28 @@ where
         .and_then(|value| value.split(',').next()) // Only take the first ip if there are multiple
         .and_then(|value| value.parse().ok());
 
+    let api_key = api_key.map(|Extension(api_key)| api_key);
+
     ws.max_message_size(MAX_CLIENT_MESSAGE_SIZE)
-        .on_upgrade(move |socket| websocket_handler(socket, state, requester_ip))
+        .on_upgrade(move |socket| websocket_handler(socket, state, requester_ip, api_key))
 }
 
-#[tracing::instrument(skip(stream, state, subscriber_ip))]
-async fn websocket_handler<S>(stream: WebSocket, state: ApiState<S>, subscriber_ip: Option<IpAddr>)
-where
+#[tracing::instrument(skip(stream, state, subscriber_ip, api_key))]
+async fn websocket_handler<S>(
+    stream: WebSocket,
+    state: ApiState<S>,
+    subscriber_ip: Option<IpAddr>,
+    api_key: Option<Arc<ApiKey>>,
+) where
     S: Aggregates,
-    S: Send,
+    S: DerivedFeeds,
+    S: Send + Sync + 'static,
 {
     let ws_state = state.ws.clone();
 
     // Retain the recent rate limit data for the IP addresses to
     // prevent the rate limiter size from growing indefinitely.
-    ws_state.rate_limiter.retain_recent();
+    ws_state.bytes_limit().rate_limiter.retain_recent();
 
     let id = ws_state.subscriber_counter.fetch_add(1, Ordering::SeqCst);
 
@@ -225,6 +378,7 @@ where
     let mut subscriber = Subscriber::new(
         id,
         subscriber_ip,
+        api_key,
         state.state.clone(),
         state.ws.clone(),
         notify_receiver,
@@ -232,23 +386,28 @@ where
         sender,
     );
 
+    ws_state.active_connections.fetch_add(1, Ordering::SeqCst);
     subscriber.run().await;
+    ws_state.active_connections.fetch_sub(1, Ordering::SeqCst);
 }
 
 pub type SubscriberId = usize;
 
 /// Subscriber is an actor that handles a single websocket connection.
-/// It listens to the store for updates and sends them to the client.
+/// It listens to the store for updates and queues them in the outbox of the connection, from
+/// which its writer sends them to the client.
 pub struct Subscriber<S> {
     id: SubscriberId,
-    ip_addr: Option<IpAddr>,
     closed: bool,
     state: Arc<S>,
     ws_state: Arc<WsState>,
     notify_receiver: Receiver<AggregationEvent>,
     receiver: SplitStream<WebSocket>,
-    sender: SplitSink<WebSocket, Message>,
+    outbox: Arc<Outbox>,
+    writer: JoinHandle<()>,
     price_feeds_with_config: HashMap<PriceIdentifier, PriceFeedClientConfig>,
+    twap_feeds_with_config: HashMap<PriceIdentifier, TwapClientConfig>,
+    derived_price_feeds: HashSet<PriceIdentifier>,
     ping_interval: tokio::time::Interval,
     exit: watch::Receiver<bool>,
     responded_to_ping: bool,
@@ -257,26 +416,46 @@ pub struct Subscriber<S> {
 impl<S> Subscriber<S>
 where
     S: Aggregates,
+    S: DerivedFeeds,
+    S: Send + Sync + 'static,
 {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         id: SubscriberId,
         ip_addr: Option<IpAddr>,
+        api_key: Option<Arc<ApiKey>>,
         state: Arc<S>,
         ws_state: Arc<WsState>,
         notify_receiver: Receiver<AggregationEvent>,
         receiver: SplitStream<WebSocket>,
         sender: SplitSink<WebSocket, Message>,
     ) -> Self {
+        let outbox = Arc::new(Outbox::default());
+        let writer = tokio::spawn(
+            Writer {
+                id,
+                ip_addr,
+                api_key,
+                state: state.clone(),
+                ws_state: ws_state.clone(),
+                outbox: outbox.clone(),
+                sender,
+            }
+            .run(),
+        );
+
         Self {
             id,
-            ip_addr,
             closed: false,
             state,
             ws_state,
             notify_receiver,
             receiver,
-            sender,
+            outbox,
+            writer,
             price_feeds_with_config: HashMap::new(),
+            twap_feeds_with_config: HashMap::new(),
+            derived_price_feeds: HashSet::new(),
             ping_interval: tokio::time::interval(PING_INTERVAL_DURATION),
             exit: crate::EXIT.subscribe(),
             responded_to_ping: true, // We start with true so we don't close the connection immediately
@@ -291,13 +470,47 @@ where
                 break;
             }
         }
+
+        // Let the writer send what is already queued, without waiting on an unresponsive client.
+        self.outbox.close();
+        if !self.writer.is_finished()
+            && tokio::time::timeout(WRITER_CLOSE_TIMEOUT, &mut self.writer)
+                .await
+                .is_err()
+        {
+            self.writer.abort();
+        }
+    }
+
+    /// Queue messages for the writer, failing if the client does not keep up with them.
+    fn queue(&self, messages: impl IntoIterator<Item = OutgoingMessage>) -> Result<()> {
+        if !self.outbox.push(messages) {
+            return Err(anyhow!("Too many queued messages. Closing connection."));
+        }
+        Ok(())
+    }
+
+    fn queue_response(&self, message: &ServerMessage) -> Result<()> {
+        self.queue([OutgoingMessage::response(
+            serde_json::to_string(message)?.into(),
+        )])
     }
 
     async fn handle_next(&mut self) -> Result<()> {
         tokio::select! {
             maybe_update_feeds_event = self.notify_receiver.recv() => {
                 match maybe_update_feeds_event {
-                    Ok(event) => self.handle_price_feeds_update(event).await,
+                    Ok(event) => {
+                        self.handle_price_feeds_update(event.clone()).await?;
+                        self.handle_twaps_update(event.clone()).await?;
+                        self.handle_derived_price_feeds_update(event).await
+                    }
+                    // The next event brings the latest prices, skipping the missed ones is
+                    // equivalent to conflating them.
+                    Err(RecvError::Lagged(skipped)) => {
+                        tracing::debug!(subscriber = self.id, skipped, "Subscriber lagged behind the store.");
+                        Ok(())
+                    }
                     Err(e) => Err(anyhow!("Failed to receive update from store: {:?}", e)),
                 }
             },
@@ -320,11 +533,13 @@ where
                     return Err(anyhow!("Subscriber did not respond to ping. Closing connection."));
                 }
                 self.responded_to_ping = false;
-                self.sender.send(Message::Ping(vec![])).await?;
-                Ok(())
+                self.queue([OutgoingMessage::response(Message::Ping(vec![]))])
+            },
+            _ = &mut self.writer => {
+                self.closed = true;
+                Err(anyhow!("Connection writer exited."))
             },
             _ = self.exit.changed() => {
-                self.sender.close().await?;
                 self.closed = true;
                 Err(anyhow!("Application is shutting down. Closing connection."))
             }
@@ -338,7 +553,10 @@ where
             .cloned()
             .collect::<Vec<_>>();
 
+        // Only the price feeds updated in the slot are requested, the others have no update.
         let state = &*self.state;
+        let price_feed_ids =
+            Aggregates::get_price_feed_ids_at_slot(state, &price_feed_ids, event.slot()).await;
         let updates = match Aggregates::get_price_feeds_with_update_data(
             state,
             &price_feed_ids,
@@ -358,10 +576,9 @@ where
                 self.price_feeds_with_config
                     .retain(|price_feed_id, _| available_price_feed_ids.contains(price_feed_id));
 
-                let price_feed_ids = self
-                    .price_feeds_with_config
-                    .keys()
-                    .cloned()
+                let price_feed_ids = price_feed_ids
+                    .into_iter()
+                    .filter(|price_feed_id| available_price_feed_ids.contains(price_feed_id))
                     .collect::<Vec<_>>();
 
                 Aggregates::get_price_feeds_with_update_data(
@@ -373,88 +590,265 @@ where
             }
         };
 
-        for update in updates.price_feeds {
-            let config = self
-                .price_feeds_with_config
-                .get(&update.price_feed.id)
-                .ok_or(anyhow::anyhow!(
-                    "Config missing, price feed list was poisoned during iteration."
-                ))?;
+        self.send_price_feeds_update(event, updates.price_feeds, true)
+    }
+
+    /// Sends the cached updates of every slot after `from_slot` for the given price feeds, so
+    /// that a reconnecting client does not miss any update.
+    async fn replay_price_feeds_updates(
+        &mut self,
+        price_feed_ids: Vec<PriceIdentifier>,
+        from_slot: Slot,
+    ) -> Result<()> {
+        let slots = Cache::fetch_message_state_slots(
+            &*self.state,
+            price_feed_ids.iter().map(|id| id.to_bytes()).collect(),
+            from_slot,
+            MessageStateFilter::Only(MessageType::PriceFeedMessage),
+        )
+        .await;
 
-            if let AggregationEvent::OutOfOrder { slot: _ } = event {
-                if !config.allow_out_of_order {
-                    continue;
+        // The client is told when some of the updates it missed are no longer cached.
+        if let Some(oldest_slot) = slots
+            .oldest_slot
+            .filter(|oldest_slot| from_slot < *oldest_slot)
+        {
+            self.queue_response(&ServerMessage::Response(ServerResponseMessage::Err {
+                error: format!(
+                    "Updates after slot {} are no longer cached, the replay starts at slot {}",
+                    from_slot, oldest_slot
+                ),
+            }))?;
+        }
+
+        let mut replayed_until = None;
+        for (slot, feed_ids) in slots.feeds {
+            // Only the feeds updated in the slot are requested, as the request fails if any of
+            // them has no update in it.
+            let slot_price_feed_ids = feed_ids
+                .into_iter()
+                .map(PriceIdentifier::new)
+                .collect::<Vec<_>>();
+            match Aggregates::get_price_feeds_with_update_data(
+                &*self.state,
+                &slot_price_feed_ids,
+                RequestTime::AtSlot(slot),
+            )
+            .await
+            {
+                // Replayed updates are not conflated, as a replay is meant not to miss any of them.
+                Ok(updates) => self.send_price_feeds_update(
+                    AggregationEvent::New { slot },
+                    updates.price_feeds,
+                    false,
+                )?,
+                // The slot can only be missing if it was evicted during the replay.
+                Err(e) => {
+                    self.queue_response(&ServerMessage::Response(ServerResponseMessage::Err {
+                        error: format!("Failed to replay slot {}: {}", slot, e),
+                    }))?;
+                }
+            }
+            replayed_until = Some(slot);
+        }
+
+        for price_feed_id in price_feed_ids {
+            if let Some(config) = self.price_feeds_with_config.get_mut(&price_feed_id) {
+                config.replayed_until = replayed_until;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Queue the price updates of an event that match the subscriptions. If `conflate` is set,
+    /// an update replaces the update of its feed that is still waiting to be sent, if any.
+    fn send_price_feeds_update(
+        &mut self,
+        event: AggregationEvent,
+        price_feeds: Vec<PriceFeedUpdate>,
+        conflate: bool,
+    ) -> Result<()> {
+        let mut updates = vec![];
+        for update in price_feeds {
+            let price_feed_id = update.price_feed.id;
+            let price = update.price_feed.get_price_unchecked();
+            let config =
+                self.price_feeds_with_config
+                    .get_mut(&price_feed_id)
+                    .ok_or(anyhow::anyhow!(
+                        "Config missing, price feed list was poisoned during iteration."
+                    ))?;
+
+            match event {
+                AggregationEvent::OutOfOrder { slot: _ } => {
+                    if !config.allow_out_of_order {
+                        continue;
+                    }
+                }
+                AggregationEvent::New { slot } => {
+                    if config.replayed_until.is_some_and(|until| slot <= until) {
+                        continue;
+                    }
                 }
             }
 
-            let message = serde_json::to_string(&ServerMessage::PriceUpdate {
-                price_feed: RpcPriceFeed::from_price_feed_update(
+            if !config
+                .filter
+                .matches(&price, self.outbox.last_sent(&price_feed_id).as_ref())
+            {
+                continue;
+            }
+
+            // Binary framed updates are batched into a single message per slot, sent after the
+            // JSON ones.
+            let content = if config.framing == Framing::Binary {
+                PriceUpdateContent::Binary(BinaryPriceUpdate::from_price_feed_update(
                     update,
-                    config.verbose,
                     config.binary,
-                ),
-            })?;
+                ))
+            } else {
+                PriceUpdateContent::Json(serde_json::to_string(&ServerMessage::PriceUpdate {
+                    price_feed: RpcPriceFeed::from_price_feed_update(
+                        update,
+                        config.verbose,
+                        config.binary,
+                    ),
+                })?)
+            };
 
-            // Close the connection if rate limit is exceeded and the ip is not whitelisted.
-            // If the ip address is None no rate limiting is applied.
-            if let Some(ip_addr) = self.ip_addr {
-                if !self
-                    .ws_state
-                    .bytes_limit_whitelist
-                    .iter()
-                    .any(|ip_net| ip_net.contains(&ip_addr))
-                    && self.ws_state.rate_limiter.check_key_n(
-                        &ip_addr,
-                        NonZeroU32::new(message.len().try_into()?)
-                            .ok_or(anyhow!("Empty message"))?,
-                    ) != Ok(Ok(()))
-                {
-                    tracing::info!(
-                        self.id,
-                        ip = %ip_addr,
-                        "Rate limit exceeded. Closing connection.",
-                    );
-                    self.ws_state
-                        .metrics
-                        .interactions
-                        .get_or_create(&Labels {
-                            interaction: Interaction::RateLimit,
-                            status: Status::Error,
-                        })
-                        .inc();
+            let update = PendingPriceUpdate {
+                slot: event.slot(),
+                price_id: price_feed_id,
+                price,
+                content,
+            };
+            if !conflate {
+                updates.push(update);
+            } else if self.outbox.push_price_update(price_feed_id, update) {
+                self.ws_state.metrics.conflated_price_updates.inc();
+            }
+        }
 
-                    self.sender
-                        .send(
-                            serde_json::to_string(&ServerResponseMessage::Err {
-                                error: "Rate limit exceeded".to_string(),
-                            })?
-                            .into(),
-                        )
-                        .await?;
-                    self.sender.close().await?;
-                    self.closed = true;
-                    return Ok(());
+        if updates.is_empty() {
+            return Ok(());
+        }
+        self.queue(price_update_messages(updates)?)
+    }
+
+    async fn handle_twaps_update(&mut self, event: AggregationEvent) -> Result<()> {
+        // A rolling TWAP only moves forward, so out-of-order slots are skipped.
+        let slot = match event {
+            AggregationEvent::New { slot } => slot,
+            AggregationEvent::OutOfOrder { .. } => return Ok(()),
+        };
+
+        if self.twap_feeds_with_config.is_empty() {
+            return Ok(());
+        }
+
+        let available_price_feed_ids = Aggregates::get_price_feed_ids(&*self.state).await;
+        self.twap_feeds_with_config
+            .retain(|price_feed_id, _| available_price_feed_ids.contains(price_feed_id));
+
+        // Feeds subscribed with the same window and encoding share a single update.
+        let mut groups: BTreeMap<(DurationInSeconds, bool), Vec<PriceIdentifier>> = BTreeMap::new();
+        for (price_id, config) in &self.twap_feeds_with_config {
+            groups
+                .entry((config.window_seconds, config.binary))
+                .or_default()
+                .push(*price_id);
+        }
+
+        for ((window_seconds, binary), price_ids) in groups {
+            let twaps_with_update_data = match Aggregates::get_rolling_twaps_with_update_data(
+                &*self.state,
+                &price_ids,
+                window_seconds,
+                slot,
+            )
+            .await
+            {
+                Ok(twaps_with_update_data) => twaps_with_update_data,
+                Err(e) => {
+                    // The window may not be fully cached yet, e.g. shortly after startup.
+                    tracing::debug!(subscriber = self.id, error = ?e, "Failed to calculate TWAPs.");
+                    continue;
                 }
-            }
+            };
 
-            // `sender.feed` buffers a message to the client but does not flush it, so we can send
-            // multiple messages and flush them all at once.
-            self.sender.feed(message.into()).await?;
+            let message = serde_json::to_string(&ServerMessage::TwapUpdate {
+                window_seconds,
+                twaps: twaps_with_update_data
+                    .twaps
+                    .into_iter()
+                    .map(ParsedPriceFeedTwap::from)
+                    .collect(),
+                update_data: binary.then(|| {
+                    twaps_with_update_data
+                        .update_data
+                        .iter()
+                        .map(|data| base64_standard_engine.encode(data))
+                        .collect()
+                }),
+            })?;
 
-            self.ws_state
-                .metrics
-                .interactions
-                .get_or_create(&Labels {
-                    interaction: Interaction::PriceUpdate,
-                    status: Status::Success,
-                })
-                .inc();
+            self.queue([OutgoingMessage {
+                slot: Some(slot),
+                ..OutgoingMessage::update(message.into(), Interaction::TwapUpdate, 1)
+            }])?;
         }
 
-        self.sender.flush().await?;
         Ok(())
     }
 
+    async fn handle_derived_price_feeds_update(&mut self, event: AggregationEvent) -> Result<()> {
+        // Derived prices are only computed for new aggregations, where all of their terms are
+        // available at the same slot.
+        let slot = match event {
+            AggregationEvent::New { slot } => slot,
+            AggregationEvent::OutOfOrder { .. } => return Ok(()),
+        };
+
+        if self.derived_price_feeds.is_empty() {
+            return Ok(());
+        }
+
+        let derived_price_ids = self.derived_price_feeds.iter().copied().collect::<Vec<_>>();
+        let derived_price_feeds = match DerivedFeeds::get_derived_price_feeds(
+            &*self.state,
+            &derived_price_ids,
+            RequestTime::AtSlot(slot),
+        )
+        .await
+        {
+            Ok(derived_price_feeds) => derived_price_feeds,
+            Err(e) => {
+                tracing::debug!(subscriber = self.id, error = ?e, "Failed to compute derived prices.");
+                return Ok(());
+            }
+        };
+
+        self.queue(
+            derived_price_feeds
+                .into_iter()
+                .map(|derived_price_feed| {
+                    Ok(OutgoingMessage {
+                        slot: Some(slot),
+                        ..OutgoingMessage::update(
+                            serde_json::to_string(&ServerMessage::DerivedPriceUpdate {
+                                derived_price_feed: derived_price_feed.into(),
+                            })?
+                            .into(),
+                            Interaction::DerivedPriceUpdate,
+                            1,
+                        )
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?,
+        )
+    }
+
     #[tracing::instrument(skip(self, message))]
     async fn handle_client_message(&mut self, message: Message) -> Result<()> {
         let maybe_client_message = match message {
@@ -473,10 +867,9 @@ where
                     })
                     .inc();
 
-                // Send the close message to gracefully shut down the connection
-                // Otherwise the client might get an abnormal Websocket closure
-                // error.
-                self.sender.close().await?;
+                // The writer sends the close message once the queued messages are sent, to
+                // gracefully shut down the connection. Otherwise the client might get an abnormal
+                // Websocket closure error.
                 self.closed = true;
                 return Ok(());
             }
@@ -502,6 +895,7 @@ where
             }
         };
 
+        let mut replay = None;
         match maybe_client_message {
             Err(e) => {
                 self.ws_state
@@ -512,16 +906,9 @@ where
                         status: Status::Error,
                     })
                     .inc();
-                self.sender
-                    .send(
-                        serde_json::to_string(&ServerMessage::Response(
-                            ServerResponseMessage::Err {
-                                error: e.to_string(),
-                            },
-                        ))?
-                        .into(),
-                    )
-                    .await?;
+                self.queue_response(&ServerMessage::Response(ServerResponseMessage::Err {
+                    error: e.to_string(),
+                }))?;
                 return Ok(());
             }
 
@@ -530,8 +917,16 @@ where
                 verbose,
                 binary,
                 allow_out_of_order,
+                from_slot,
+                twap_window_seconds,
+                filter,
+                framing,
             }) => {
-                let price_ids: Vec<PriceIdentifier> = ids.into_iter().map(|id| id.into()).collect();
+                // Derived price feeds are computed by Hermes and are sent as separate messages.
+                let (derived_price_ids, price_ids): (Vec<PriceIdentifier>, Vec<PriceIdentifier>) =
+                    ids.into_iter()
+                        .map(PriceIdentifier::from)
+                        .partition(|id| DerivedFeeds::is_derived_feed(&*self.state, id));
                 let available_price_ids = Aggregates::get_price_feed_ids(&*self.state).await;
 
                 let not_found_price_ids: Vec<&PriceIdentifier> = price_ids
@@ -542,37 +937,75 @@ where
                 // If there is a single price id that is not found, we don't subscribe to any of the
                 // asked correct price feed ids and return an error to be more explicit and clear.
                 if !not_found_price_ids.is_empty() {
-                    self.sender
-                        .send(
-                            serde_json::to_string(&ServerMessage::Response(
-                                ServerResponseMessage::Err {
-                                    error: format!(
-                                        "Price feed(s) with id(s) {:?} not found",
-                                        not_found_price_ids
-                                    ),
-                                },
-                            ))?
-                            .into(),
-                        )
-                        .await?;
+                    self.queue_response(&ServerMessage::Response(ServerResponseMessage::Err {
+                        error: format!(
+                            "Price feed(s) with id(s) {:?} not found",
+                            not_found_price_ids
+                        ),
+                    }))?;
                     return Ok(());
-                } else {
+                }
+
+                if twap_window_seconds.is_some() && !derived_price_ids.is_empty() {
+                    self.queue_response(&ServerMessage::Response(ServerResponseMessage::Err {
+                        error: format!(
+                            "TWAPs are not available for derived price feed(s) {:?}",
+                            derived_price_ids
+                        ),
+                    }))?;
+                    return Ok(());
+                }
+                self.derived_price_feeds.extend(derived_price_ids);
+
+                if let Some(window_seconds) = twap_window_seconds {
+                    if window_seconds == 0 || window_seconds > MAX_TWAP_WINDOW_SECONDS {
+                        self.queue_response(&ServerMessage::Response(
+                            ServerResponseMessage::Err {
+                                error: format!(
+                                    "twap_window_seconds must be in range (0, {}]",
+                                    MAX_TWAP_WINDOW_SECONDS
+                                ),
+                            },
+                        ))?;
+                        return Ok(());
+                    }
+
                     for price_id in price_ids {
-                        self.price_feeds_with_config.insert(
+                        self.twap_feeds_with_config.insert(
                             price_id,
+                            TwapClientConfig {
+                                window_seconds,
+                                binary,
+                            },
+                        );
+                    }
+                } else {
+                    for price_id in price_ids.iter() {
+                        self.price_feeds_with_config.insert(
+                            *price_id,
                             PriceFeedClientConfig {
                                 verbose,
                                 binary,
                                 allow_out_of_order,
+                                replayed_until: None,
+                                filter,
+                                framing,
                             },
                         );
                     }
+                    // A new subscription evaluates its filter from scratch.
+                    self.outbox.forget_sent(price_ids.iter());
+                    replay = from_slot.map(|from_slot| (price_ids, from_slot));
                 }
             }
             Ok(ClientMessage::Unsubscribe { ids }) => {
                 for id in ids {
                     let price_id: PriceIdentifier = id.into();
                     self.price_feeds_with_config.remove(&price_id);
+                    self.twap_feeds_with_config.remove(&price_id);
+                    self.derived_price_feeds.remove(&price_id);
+                    self.outbox.remove_price_updates([&price_id]);
+                    self.outbox.forget_sent([&price_id]);
                 }
             }
         }
@@ -586,13 +1019,298 @@ where
             })
             .inc();
 
-        self.sender
-            .send(
-                serde_json::to_string(&ServerMessage::Response(ServerResponseMessage::Success))?
-                    .into(),
-            )
-            .await?;
+        self.queue_response(&ServerMessage::Response(ServerResponseMessage::Success))?;
+
+        if let Some((price_ids, from_slot)) = replay {
+            self.replay_price_feeds_updates(price_ids, from_slot)
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Writer sends the messages of the outbox of a connection to the client. While it waits on a
+/// slow socket, the price updates of the subscriber are conflated in the outbox.
+struct Writer<S> {
+    id: SubscriberId,
+    ip_addr: Option<IpAddr>,
+    /// The API key of the connection. Its byte quota replaces the per-IP limit.
+    api_key: Option<Arc<ApiKey>>,
+    state: Arc<S>,
+    ws_state: Arc<WsState>,
+    outbox: Arc<Outbox>,
+    sender: SplitSink<WebSocket, Message>,
+}
+
+impl<S> Writer<S>
+where
+    S: Aggregates,
+{
+    async fn run(mut self) {
+        if let Err(e) = self.write().await {
+            tracing::debug!(subscriber = self.id, error = ?e, "Error Writing Subscriber Messages.");
+        }
+        self.outbox.close();
+    }
+
+    /// Send the queued messages until the outbox is closed, then close the connection.
+    async fn write(&mut self) -> Result<()> {
+        while let Some((messages, price_updates)) = self.outbox.take().await {
+            let mut sent_prices = vec![];
+            let mut sent_slots = BTreeSet::new();
+            for message in in_slot_order(messages, price_update_messages(price_updates)?) {
+                if message.rate_limited && !self.check_rate_limit(message.size()).await? {
+                    return Ok(());
+                }
+
+                // `sender.feed` buffers a message to the client but does not flush it, so we can
+                // send multiple messages and flush them all at once.
+                self.sender.feed(message.message).await?;
+                sent_prices.extend(message.prices);
+                sent_slots.extend(message.slot);
+
+                if let Some((interaction, count)) = message.interaction {
+                    self.ws_state
+                        .metrics
+                        .interactions
+                        .get_or_create(&Labels {
+                            interaction,
+                            status: Status::Success,
+                        })
+                        .inc_by(count);
+                }
+            }
+            self.sender.flush().await?;
+            // The filters of the subscriptions are evaluated against the prices written to the
+            // client, not the ones queued.
+            self.outbox.mark_sent(sent_prices);
+            for slot in sent_slots {
+                Aggregates::observe_update_sent(&*self.state, slot).await;
+            }
+        }
 
+        self.sender.close().await?;
         Ok(())
     }
+
+    /// Close the connection if the byte quota of its API key is exceeded or, for connections
+    /// without an API key, if the rate limit is exceeded and the ip is not whitelisted. If the ip
+    /// address is None no rate limiting is applied to connections without an API key.
+    ///
+    /// Returns `false` if the connection was closed.
+    async fn check_rate_limit(&mut self, message_len: usize) -> Result<bool> {
+        let exceeded = match (&self.api_key, self.ip_addr) {
+            (Some(api_key), _) => !api_key.check_bytes(message_len),
+            (None, Some(ip_addr)) => !self.ws_state.check_ip_bytes(ip_addr, message_len)?,
+            (None, None) => false,
+        };
+
+        if exceeded {
+            tracing::info!(
+                self.id,
+                ip = ?self.ip_addr,
+                api_key = ?self.api_key.as_ref().map(|api_key| &api_key.name),
+                "Rate limit exceeded. Closing connection.",
+            );
+            self.ws_state
+                .metrics
+                .interactions
+                .get_or_create(&Labels {
+                    interaction: Interaction::RateLimit,
+                    status: Status::Error,
+                })
+                .inc();
+
+            self.sender
+                .send(
+                    serde_json::to_string(&ServerResponseMessage::Err {
+                        error: "Rate limit exceeded".to_string(),
+                    })?
+                    .into(),
+                )
+                .await?;
+            self.sender.close().await?;
+            return Ok(false);
+        }
+
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use {
+        super::*,
+        crate::state::{
+            aggregate::test::{
+                create_dummy_price_feed_message, generate_update,
+                store_multiple_concurrent_valid_updates,
+            },
+            test::setup_state,
+            Metrics,
+        },
+        axum::{routing::get, Router},
+        nonzero_ext::nonzero,
+        pythnet_sdk::messages::Message as PythnetMessage,
+        serde_json::{json, Value},
+        tokio::net::TcpStream,
+        tokio_tungstenite::{tungstenite, MaybeTlsStream, WebSocketStream},
+    };
+
+    type ClientStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+    /// Store an update of each of the feeds at the slot.
+    async fn store_slot<S>(state: &Arc<S>, feed_seeds: &[u8], slot: Slot)
+    where
+        S: Aggregates,
+        S: Send + Sync + 'static,
+    {
+        let messages = feed_seeds
+            .iter()
+            .map(|seed| {
+                PythnetMessage::PriceFeedMessage(create_dummy_price_feed_message(
+                    *seed,
+                    slot as i64,
+                    slot as i64 - 1,
+                ))
+            })
+            .collect();
+        store_multiple_concurrent_valid_updates(
+            state.clone(),
+            generate_update(messages, slot, slot),
+        )
+        .await;
+    }
+
+    async fn connect<S>(state: Arc<S>) -> ClientStream
+    where
+        S: Aggregates,
+        S: Benchmarks,
+        S: Cache,
+        S: DerivedFeeds,
+        S: Metrics,
+        S: PriceFeedMeta,
+        S: Send + Sync + 'static,
+    {
+        let state = ApiState::new(state, vec![], nonzero!(1u32), String::new(), vec![], false);
+        let app = Router::new()
+            .route("/ws", get(ws_route_handler::<S>))
+            .with_state(state);
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+
+        tokio_tungstenite::connect_async(format!("ws://{}/ws", addr))
+            .await
+            .unwrap()
+            .0
+    }
+
+    async fn subscribe(ws: &mut ClientStream, feed_seeds: &[u8], from_slot: Slot) {
+        let ids = feed_seeds
+            .iter()
+            .map(|seed| hex::encode([*seed; 32]))
+            .collect::<Vec<_>>();
+        ws.send(tungstenite::Message::Text(
+            json!({
+                "type": "subscribe",
+                "ids": ids,
+                "verbose": true,
+                "from_slot": from_slot,
+            })
+            .to_string(),
+        ))
+        .await
+        .unwrap();
+    }
+
+    async fn next_json(ws: &mut ClientStream) -> Value {
+        loop {
+            let message = tokio::time::timeout(Duration::from_secs(5), ws.next())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            if let tungstenite::Message::Text(text) = message {
+                return serde_json::from_str(&text).unwrap();
+            }
+        }
+    }
+
+    /// Read the slot and price feed id of the next price updates.
+    async fn next_price_updates(ws: &mut ClientStream, count: usize) -> Vec<(u64, String)> {
+        let mut updates = vec![];
+        for _ in 0..count {
+            let message = next_json(ws).await;
+            assert_eq!(message["type"], "price_update");
+            updates.push((
+                message["price_feed"]["metadata"]["slot"].as_u64().unwrap(),
+                message["price_feed"]["id"].as_str().unwrap().to_string(),
+            ));
+        }
+        updates
+    }
+
+    #[tokio::test]
+    async fn test_bytes_limit_is_only_replaced_when_changed() {
+        let (state, _) = setup_state(1).await;
+        let ws_state = WsState::new(vec![], nonzero!(10u32), String::new(), state);
+        let ip_addr: IpAddr = "10.0.0.1".parse().unwrap();
+        assert!(ws_state.check_ip_bytes(ip_addr, 10).unwrap());
+
+        // Reloading the same limit keeps the spent budget.
+        ws_state.set_bytes_limit(vec![], nonzero!(10u32));
+        assert!(!ws_state.check_ip_bytes(ip_addr, 1).unwrap());
+
+        // A new limit starts over from a full budget.
+        ws_state.set_bytes_limit(vec![], nonzero!(20u32));
+        assert!(ws_state.check_ip_bytes(ip_addr, 20).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_replay_sends_the_feeds_updated_in_each_slot() {
+        let (state, _) = setup_state(3).await;
+        store_slot(&state, &[1, 2], 10).await;
+        store_slot(&state, &[1], 11).await;
+        store_slot(&state, &[2], 12).await;
+
+        let mut ws = connect(state).await;
+        subscribe(&mut ws, &[1, 2], 10).await;
+        assert_eq!(next_json(&mut ws).await["status"], "success");
+
+        // No slot after 10 has updates of both feeds, each of them is still replayed.
+        assert_eq!(
+            next_price_updates(&mut ws, 2).await,
+            vec![(11, hex::encode([1; 32])), (12, hex::encode([2; 32]))]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_replay_from_an_evicted_slot_reports_the_gap() {
+        let (state, _) = setup_state(2).await;
+        store_slot(&state, &[1], 10).await;
+        store_slot(&state, &[1], 11).await;
+        store_slot(&state, &[1], 12).await;
+
+        let mut ws = connect(state).await;
+        subscribe(&mut ws, &[1], 9).await;
+        assert_eq!(next_json(&mut ws).await["status"], "success");
+
+        // The update of slot 10 is evicted, the client is told before the replay starts.
+        let gap = next_json(&mut ws).await;
+        assert_eq!(gap["status"], "error");
+        assert!(gap["error"]
+            .as_str()
+            .unwrap()
+            .contains("replay starts at slot 11"));
+        assert_eq!(
+            next_price_updates(&mut ws, 2).await,
+            vec![(11, hex::encode([1; 32])), (12, hex::encode([1; 32]))]
+        );
+    }
 }
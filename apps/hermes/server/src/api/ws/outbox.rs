@@ -0,0 +1,375 @@
+//! Messages waiting to be sent on a websocket connection.
+//!
+//! The subscriber queues its messages in the outbox and a writer task sends them, so that a slow
+//! socket does not hold up the subscriber. While the writer is blocked on the socket, each price
+//! feed keeps a single pending update that newer updates overwrite: a slow client receives the
+//! freshest prices instead of a growing backlog. The pending updates are sent in slot order with
+//! the queued messages of the same slots.
+
+use {
+    super::Interaction,
+    crate::{
+        api::binary_update::{BinaryPriceUpdate, BinaryWsUpdate},
+        state::aggregate::Slot,
+    },
+    anyhow::Result,
+    axum::extract::ws::Message,
+    pyth_sdk::{Price, PriceIdentifier},
+    std::{
+        collections::{BTreeMap, HashMap, VecDeque},
+        sync::{Mutex, MutexGuard, PoisonError},
+    },
+    tokio::sync::Notify,
+};
+
+/// The maximum number of queued messages, besides the pending price updates. A client that lets
+/// this many messages pile up does not keep up even with conflated prices and is disconnected.
+const MAX_QUEUED_MESSAGES: usize = 1000;
+
+pub struct OutgoingMessage {
+    pub message: Message,
+    /// The interaction counted once the message is sent, with the number of updates it carries.
+    pub interaction: Option<(Interaction, u64)>,
+    /// Whether the message counts towards the byte limit of the connection.
+    pub rate_limited: bool,
+    /// The slot of the updates of the message, to send it in order with the pending price
+    /// updates and to time its delivery.
+    pub slot: Option<Slot>,
+    /// The prices carried by the message, recorded as sent once it is written.
+    pub prices: Vec<(PriceIdentifier, Price)>,
+}
+
+impl OutgoingMessage {
+    pub fn response(message: Message) -> Self {
+        Self {
+            message,
+            interaction: None,
+            rate_limited: false,
+            slot: None,
+            prices: vec![],
+        }
+    }
+
+    pub fn update(message: Message, interaction: Interaction, count: u64) -> Self {
+        Self {
+            message,
+            interaction: Some((interaction, count)),
+            rate_limited: true,
+            slot: None,
+            prices: vec![],
+        }
+    }
+
+    /// The size of the message in bytes, counted towards the byte limit.
+    pub fn size(&self) -> usize {
+        match &self.message {
+            Message::Text(text) => text.len(),
+            Message::Binary(data) | Message::Ping(data) | Message::Pong(data) => data.len(),
+            Message::Close(_) => 0,
+        }
+    }
+}
+
+pub enum PriceUpdateContent {
+    /// A serialized JSON `price_update` message.
+    Json(String),
+    /// An update batched with the other binary updates of its slot.
+    Binary(BinaryPriceUpdate),
+}
+
+pub struct PendingPriceUpdate {
+    pub slot: Slot,
+    pub price_id: PriceIdentifier,
+    pub price: Price,
+    pub content: PriceUpdateContent,
+}
+
+/// Build the messages of price updates in slot order: for each slot, one per JSON update, then
+/// one for its binary updates.
+pub fn price_update_messages(
+    updates: impl IntoIterator<Item = PendingPriceUpdate>,
+) -> Result<Vec<OutgoingMessage>> {
+    #[derive(Default)]
+    struct SlotUpdates {
+        json: Vec<OutgoingMessage>,
+        binary: Vec<BinaryPriceUpdate>,
+        binary_prices: Vec<(PriceIdentifier, Price)>,
+    }
+
+    let mut slots: BTreeMap<Slot, SlotUpdates> = BTreeMap::new();
+    for update in updates {
+        let slot_updates = slots.entry(update.slot).or_default();
+        match update.content {
+            PriceUpdateContent::Json(text) => slot_updates.json.push(OutgoingMessage {
+                slot: Some(update.slot),
+                prices: vec![(update.price_id, update.price)],
+                ..OutgoingMessage::update(Message::Text(text), Interaction::PriceUpdate, 1)
+            }),
+            PriceUpdateContent::Binary(binary_update) => {
+                slot_updates.binary.push(binary_update);
+                slot_updates
+                    .binary_prices
+                    .push((update.price_id, update.price));
+            }
+        }
+    }
+
+    let mut messages = vec![];
+    for (slot, slot_updates) in slots {
+        messages.extend(slot_updates.json);
+        if slot_updates.binary.is_empty() {
+            continue;
+        }
+
+        let update_count = slot_updates.binary.len() as u64;
+        let mut message = vec![];
+        BinaryWsUpdate {
+            slot,
+            updates: slot_updates.binary,
+        }
+        .serialize(&mut message)?;
+        messages.push(OutgoingMessage {
+            slot: Some(slot),
+            prices: slot_updates.binary_prices,
+            ..OutgoingMessage::update(
+                Message::Binary(message),
+                Interaction::PriceUpdate,
+                update_count,
+            )
+        });
+    }
+
+    Ok(messages)
+}
+
+/// Merge the messages of the pending price updates, in slot order, into the queued messages. The
+/// price updates of a slot are sent before the queued messages of the same or a later slot, as
+/// the subscriber queues them in that order; the rest are sent after the queued messages.
+pub fn in_slot_order(
+    messages: Vec<OutgoingMessage>,
+    price_update_messages: Vec<OutgoingMessage>,
+) -> Vec<OutgoingMessage> {
+    let mut price_update_messages = price_update_messages.into_iter().peekable();
+    let mut ordered = vec![];
+    for message in messages {
+        if let Some(slot) = message.slot {
+            while let Some(price_update_message) = price_update_messages
+                .next_if(|price_update_message| price_update_message.slot <= Some(slot))
+            {
+                ordered.push(price_update_message);
+            }
+        }
+        ordered.push(message);
+    }
+    ordered.extend(price_update_messages);
+    ordered
+}
+
+#[derive(Default)]
+struct Pending {
+    messages: VecDeque<OutgoingMessage>,
+    price_updates: HashMap<PriceIdentifier, PendingPriceUpdate>,
+    closed: bool,
+}
+
+#[derive(Default)]
+pub struct Outbox {
+    pending: Mutex<Pending>,
+    /// Notified when messages are queued or the outbox is closed.
+    changed: Notify,
+    /// The last price written to the client for each price feed.
+    sent_prices: Mutex<HashMap<PriceIdentifier, Price>>,
+}
+
+impl Outbox {
+    fn pending(&self) -> MutexGuard<'_, Pending> {
+        self.pending.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Queue messages to be sent in order. Returns `false` if the client does not keep up.
+    pub fn push(&self, messages: impl IntoIterator<Item = OutgoingMessage>) -> bool {
+        let within_limit = {
+            let mut pending = self.pending();
+            pending.messages.extend(messages);
+            pending.messages.len() <= MAX_QUEUED_MESSAGES
+        };
+        self.changed.notify_one();
+        within_limit
+    }
+
+    /// Set the pending update of a price feed, overwriting the update not sent yet if any. An
+    /// update older than the pending one is dropped instead.
+    ///
+    /// Returns `true` if an update was conflated, i.e. will never be sent.
+    pub fn push_price_update(&self, price_id: PriceIdentifier, update: PendingPriceUpdate) -> bool {
+        let conflated = {
+            let mut pending = self.pending();
+            if let Some(pending_update) = pending.price_updates.get(&price_id) {
+                if pending_update.price.publish_time > update.price.publish_time {
+                    return true;
+                }
+            }
+            pending.price_updates.insert(price_id, update).is_some()
+        };
+        self.changed.notify_one();
+        conflated
+    }
+
+    /// Drop the pending updates of price feeds, e.g. after they are unsubscribed from.
+    pub fn remove_price_updates<'a>(
+        &self,
+        price_ids: impl IntoIterator<Item = &'a PriceIdentifier>,
+    ) {
+        let mut pending = self.pending();
+        for price_id in price_ids {
+            pending.price_updates.remove(price_id);
+        }
+    }
+
+    /// The last price of a price feed written to the client, if any.
+    pub fn last_sent(&self, price_id: &PriceIdentifier) -> Option<Price> {
+        self.sent_prices
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(price_id)
+            .copied()
+    }
+
+    /// Record the prices of messages written to the client.
+    pub fn mark_sent(&self, prices: impl IntoIterator<Item = (PriceIdentifier, Price)>) {
+        self.sent_prices
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .extend(prices);
+    }
+
+    /// Forget the last prices sent of price feeds, e.g. when they are subscribed to again.
+    pub fn forget_sent<'a>(&self, price_ids: impl IntoIterator<Item = &'a PriceIdentifier>) {
+        let mut sent_prices = self
+            .sent_prices
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        for price_id in price_ids {
+            sent_prices.remove(price_id);
+        }
+    }
+
+    /// Mark the outbox as closed: the writer sends the messages already queued, then closes the
+    /// connection.
+    pub fn close(&self) {
+        self.pending().closed = true;
+        self.changed.notify_one();
+    }
+
+    /// Wait for messages to send, and take the queued messages and the pending price updates.
+    /// Returns `None` once the outbox is closed and empty.
+    pub async fn take(&self) -> Option<(Vec<OutgoingMessage>, Vec<PendingPriceUpdate>)> {
+        loop {
+            {
+                let mut pending = self.pending();
+                if !pending.messages.is_empty() || !pending.price_updates.is_empty() {
+                    return Some((
+                        pending.messages.drain(..).collect(),
+                        pending
+                            .price_updates
+                            .drain()
+                            .map(|(_, update)| update)
+                            .collect(),
+                    ));
+                }
+                if pending.closed {
+                    return None;
+                }
+            }
+            // A notification sent while no one waits is kept for the next wait, so messages
+            // queued between the check above and this point are not missed.
+            self.changed.notified().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use {super::*, pyth_sdk::UnixTimestamp};
+
+    fn json_update(publish_time: UnixTimestamp) -> PendingPriceUpdate {
+        PendingPriceUpdate {
+            slot: publish_time as Slot,
+            price_id: PriceIdentifier::new([1; 32]),
+            price: Price {
+                price: 1,
+                conf: 1,
+                expo: -2,
+                publish_time,
+            },
+            content: PriceUpdateContent::Json(publish_time.to_string()),
+        }
+    }
+
+    fn slot_message(slot: Slot) -> OutgoingMessage {
+        OutgoingMessage {
+            slot: Some(slot),
+            ..OutgoingMessage::update(
+                Message::Text(format!("twap {}", slot)),
+                Interaction::TwapUpdate,
+                1,
+            )
+        }
+    }
+
+    fn texts(messages: Vec<OutgoingMessage>) -> Vec<String> {
+        messages
+            .into_iter()
+            .map(|message| match message.message {
+                Message::Text(text) => text,
+                _ => panic!("Unexpected message"),
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_pending_price_updates_are_conflated() {
+        let outbox = Outbox::default();
+        let btc = PriceIdentifier::new([2; 32]);
+        let eth = PriceIdentifier::new([3; 32]);
+
+        assert!(outbox.push([OutgoingMessage::response(Message::Text(
+            "response".to_string()
+        ))]));
+        assert!(!outbox.push_price_update(btc, json_update(10)));
+        assert!(!outbox.push_price_update(eth, json_update(10)));
+        // Newer updates overwrite the pending ones, older ones are dropped.
+        assert!(outbox.push_price_update(btc, json_update(11)));
+        assert!(outbox.push_price_update(btc, json_update(12)));
+        assert!(outbox.push_price_update(eth, json_update(9)));
+
+        let (messages, price_updates) = outbox.take().await.unwrap();
+        assert_eq!(texts(messages), vec!["response"]);
+        let mut price_updates = texts(price_update_messages(price_updates).unwrap());
+        price_updates.sort();
+        assert_eq!(price_updates, vec!["10", "12"]);
+
+        // Once taken, the updates of a feed are no longer conflated.
+        assert!(!outbox.push_price_update(btc, json_update(13)));
+        outbox.remove_price_updates(&[btc]);
+        outbox.close();
+        assert!(outbox.take().await.is_none());
+    }
+
+    #[test]
+    fn test_pending_price_updates_are_sent_in_slot_order() {
+        let messages = vec![
+            OutgoingMessage::response(Message::Text("response".to_string())),
+            slot_message(10),
+            slot_message(12),
+        ];
+        // Pending updates are taken from the outbox in no particular order.
+        let price_updates =
+            price_update_messages([json_update(13), json_update(11), json_update(10)]).unwrap();
+
+        assert_eq!(
+            texts(in_slot_order(messages, price_updates)),
+            vec!["response", "10", "twap 10", "11", "twap 12", "13"]
+        );
+    }
+}
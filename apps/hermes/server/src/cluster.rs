@@ -0,0 +1,787 @@
+//! Cluster mode, to run several Hermes instances behind a load balancer with consistent responses.
+//!
+//! Ingest nodes listen to Wormhole and Pythnet as usual and publish the message states of every
+//! completed slot, along with the price feeds metadata, to a pub/sub layer. API nodes do not
+//! ingest anything: they store the completed slots they consume from it, so that they serve the
+//! same slots and history as the ingest nodes and their readiness follows the shared stream.
+//!
+//! Several ingest nodes can publish the same slots for redundancy. Each slot is stored once,
+//! whichever ingest node delivers it first, and slots older than the latest stored one are
+//! stored as out of order like late updates of a single instance are.
+//!
+//! The messages are authenticated with a secret shared by the cluster, and API nodes verify the
+//! VAA and the proofs of every slot against the guardian sets they poll from Pythnet, like ingest
+//! nodes verify the updates they receive. Slots missed while an API node reconnects or falls
+//! behind are caught up from the recent slots retained by the ingest nodes.
+
+use {
+    crate::{
+        api::types::{PriceFeedMetadata, RpcPriceIdentifier},
+        config::cluster::Options,
+        network::wormhole::VaaBytes,
+        state::{
+            aggregate::{
+                wormhole_merkle::WormholeMerkleMessageProof, Aggregates, CompletedSlot, ProofSet,
+                RawMessage, Slot, UnixTimestamp,
+            },
+            cache::{Cache, MessageState, MessageStateFilter},
+            metrics::Metrics,
+            price_feeds_metadata::PriceFeedMeta,
+            wormhole::Wormhole,
+        },
+    },
+    anyhow::{anyhow, ensure, Context, Result},
+    borsh::{BorshDeserialize, BorshSerialize},
+    byteorder::BigEndian,
+    futures::{future::join_all, stream::BoxStream, StreamExt},
+    prometheus_client::{
+        encoding::{EncodeLabelSet, EncodeLabelValue},
+        metrics::{counter::Counter, family::Family},
+    },
+    pyth_sdk::PriceIdentifier,
+    pythnet_sdk::{
+        accumulators::merkle::{MerklePath, MerkleRoot},
+        hashers::keccak256_160::Keccak160,
+        messages::Message,
+        wire::from_slice,
+    },
+    std::{
+        collections::{BTreeMap, BTreeSet},
+        sync::Arc,
+        time::Duration,
+    },
+    tokio::{
+        sync::{broadcast::error::RecvError, Mutex},
+        time::Instant,
+    },
+};
+
+#[cfg(test)]
+mod loopback;
+mod tcp;
+
+/// Interval between two publications of the price feeds metadata, so that new API nodes get it
+/// without waiting for the next refresh.
+const PRICE_FEEDS_METADATA_PUBLISH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Number of the most recent stored slots remembered to skip the duplicates.
+const STORED_SLOTS_WINDOW: usize = 1000;
+
+/// Publishes the messages of an ingest node. Each message is delivered to the subscribers
+/// connected at that time. The most recent completed slots are retained, identified by their
+/// `slot`, to catch up the subscribers that reconnect or fall behind.
+#[async_trait::async_trait]
+pub trait ClusterPublisher: Send + Sync {
+    async fn publish(&self, slot: Option<Slot>, message: Vec<u8>) -> Result<()>;
+}
+
+/// Subscribes an API node to the messages of an ingest node.
+#[async_trait::async_trait]
+pub trait ClusterSubscriber: Send + Sync {
+    /// Subscribe to the messages published from now on, preceded by the retained completed slots
+    /// after the given slot, or all of them if there is none. The stream ends, or yields an error,
+    /// when the subscription is lost.
+    async fn subscribe(&self, after: Option<Slot>) -> Result<BoxStream<'static, Result<Vec<u8>>>>;
+}
+
+#[derive(Clone, Debug)]
+pub enum ClusterMessage {
+    CompletedSlot(CompletedSlot),
+    PriceFeedsMetadata(Vec<PriceFeedMetadata>),
+}
+
+/// The message is parsed from the raw message, which is what the proof is checked against.
+#[derive(BorshSerialize, BorshDeserialize)]
+struct MessageStateRecord {
+    raw_message: RawMessage,
+    proof: MerklePath<Keccak160>,
+    received_at: UnixTimestamp,
+}
+
+/// Wire format of the cluster messages. The message states of a completed slot are all proven by
+/// the same VAA, so it is only sent once.
+#[derive(BorshSerialize, BorshDeserialize)]
+enum ClusterMessageRecord {
+    CompletedSlot {
+        slot: Slot,
+        vaa: VaaBytes,
+        message_states: Vec<MessageStateRecord>,
+    },
+    PriceFeedsMetadata {
+        price_feeds: Vec<([u8; 32], BTreeMap<String, String>)>,
+    },
+}
+
+pub fn encode_completed_slot(completed_slot: &CompletedSlot) -> Result<Vec<u8>> {
+    let vaa = completed_slot
+        .message_states
+        .first()
+        .map(|message_state| message_state.proof_set.wormhole_merkle_proof.vaa.clone())
+        .unwrap_or_default();
+    let message_states = completed_slot
+        .message_states
+        .iter()
+        .map(|message_state| {
+            ensure!(
+                message_state.slot == completed_slot.slot
+                    && message_state.proof_set.wormhole_merkle_proof.vaa == vaa,
+                "Message states of slot {} are not all proven by its VAA",
+                completed_slot.slot
+            );
+            Ok(MessageStateRecord {
+                raw_message: message_state.raw_message.clone(),
+                proof: message_state.proof_set.wormhole_merkle_proof.proof.clone(),
+                received_at: message_state.received_at,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(ClusterMessageRecord::CompletedSlot {
+        slot: completed_slot.slot,
+        vaa,
+        message_states,
+    }
+    .try_to_vec()?)
+}
+
+pub fn encode_price_feeds_metadata(price_feeds_metadata: &[PriceFeedMetadata]) -> Result<Vec<u8>> {
+    Ok(ClusterMessageRecord::PriceFeedsMetadata {
+        price_feeds: price_feeds_metadata
+            .iter()
+            .map(|metadata| {
+                (
+                    PriceIdentifier::from(metadata.id).to_bytes(),
+                    metadata.attributes.clone(),
+                )
+            })
+            .collect(),
+    }
+    .try_to_vec()?)
+}
+
+pub fn decode(bytes: &[u8]) -> Result<ClusterMessage> {
+    Ok(match ClusterMessageRecord::try_from_slice(bytes)? {
+        ClusterMessageRecord::CompletedSlot {
+            slot,
+            vaa,
+            message_states,
+        } => ClusterMessage::CompletedSlot(CompletedSlot {
+            slot,
+            message_states: message_states
+                .into_iter()
+                .map(|record| {
+                    Ok(MessageState {
+                        slot,
+                        message: from_slice::<BigEndian, Message>(&record.raw_message)
+                            .map_err(|e| anyhow!("Failed to deserialize message: {:?}", e))?,
+                        raw_message: record.raw_message,
+                        proof_set: ProofSet {
+                            wormhole_merkle_proof: WormholeMerkleMessageProof {
+                                proof: record.proof,
+                                vaa: vaa.clone(),
+                            },
+                        },
+                        received_at: record.received_at,
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?,
+        }),
+        ClusterMessageRecord::PriceFeedsMetadata { price_feeds } => {
+            ClusterMessage::PriceFeedsMetadata(
+                price_feeds
+                    .into_iter()
+                    .map(|(id, attributes)| PriceFeedMetadata {
+                        id: RpcPriceIdentifier::new(id),
+                        attributes,
+                    })
+                    .collect(),
+            )
+        }
+    })
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelValue)]
+pub enum PublishStatus {
+    Success,
+    Error,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelValue)]
+pub enum ConsumeStatus {
+    Stored,
+    Duplicate,
+    Error,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, EncodeLabelSet)]
+pub struct PublishLabels {
+    pub status: PublishStatus,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, EncodeLabelSet)]
+pub struct ConsumeLabels {
+    pub status: ConsumeStatus,
+}
+
+#[derive(Default)]
+pub struct ClusterMetrics {
+    pub published_messages: Family<PublishLabels, Counter>,
+    pub consumed_messages: Family<ConsumeLabels, Counter>,
+    pub lagged_slots: Counter,
+}
+
+impl ClusterMetrics {
+    async fn register<S>(&self, state: &S)
+    where
+        S: Metrics,
+    {
+        Metrics::register(
+            state,
+            (
+                "cluster_published_messages",
+                "Total number of messages published to the API nodes by status",
+                self.published_messages.clone(),
+            ),
+        )
+        .await;
+        Metrics::register(
+            state,
+            (
+                "cluster_consumed_messages",
+                "Total number of messages consumed from the ingest nodes by status",
+                self.consumed_messages.clone(),
+            ),
+        )
+        .await;
+        Metrics::register(
+            state,
+            (
+                "cluster_lagged_slots",
+                "Total number of completed slots not published for falling behind",
+                self.lagged_slots.clone(),
+            ),
+        )
+        .await;
+    }
+
+    fn published(&self, status: PublishStatus) {
+        self.published_messages
+            .get_or_create(&PublishLabels { status })
+            .inc();
+    }
+
+    fn consumed(&self, status: ConsumeStatus) {
+        self.consumed_messages
+            .get_or_create(&ConsumeLabels { status })
+            .inc();
+    }
+}
+
+/// The most recent slots stored from the cluster, shared by the subscriptions to every ingest
+/// node.
+#[derive(Default)]
+struct StoredSlots(BTreeSet<Slot>);
+
+impl StoredSlots {
+    /// Remember a slot, returns false if it was already stored.
+    fn insert(&mut self, slot: Slot) -> bool {
+        let inserted = self.0.insert(slot);
+        if self.0.len() > STORED_SLOTS_WINDOW {
+            self.0.pop_first();
+        }
+        inserted
+    }
+
+    fn contains(&self, slot: Slot) -> bool {
+        self.0.contains(&slot)
+    }
+
+    fn remove(&mut self, slot: Slot) {
+        self.0.remove(&slot);
+    }
+
+    fn latest(&self) -> Option<Slot> {
+        self.0.last().copied()
+    }
+}
+
+/// Check that the message states of a completed slot are proven by its VAA, and that the VAA is
+/// signed by a known guardian set.
+async fn verify_completed_slot<S>(state: &S, completed_slot: &CompletedSlot) -> Result<()>
+where
+    S: Wormhole,
+{
+    let slot = completed_slot.slot;
+    let vaa = &completed_slot
+        .message_states
+        .first()
+        .with_context(|| format!("Completed slot {} has no message states", slot))?
+        .proof_set
+        .wormhole_merkle_proof
+        .vaa;
+    let root = Wormhole::verify_message(state, vaa).await?;
+    ensure!(
+        root.slot == slot,
+        "VAA of slot {} is for slot {}",
+        slot,
+        root.slot
+    );
+
+    let root = MerkleRoot::<Keccak160>::new(root.root);
+    for message_state in &completed_slot.message_states {
+        let proof = &message_state.proof_set.wormhole_merkle_proof;
+        ensure!(
+            proof.vaa == *vaa && root.check(proof.proof.clone(), &message_state.raw_message),
+            "Message state of slot {} is not proven by its VAA",
+            slot
+        );
+    }
+    Ok(())
+}
+
+/// Store a message consumed from the cluster, once its slot is verified. Returns false if its slot
+/// was already stored.
+async fn store_message<S>(
+    state: &S,
+    stored_slots: &Mutex<StoredSlots>,
+    message: &[u8],
+) -> Result<bool>
+where
+    S: Wormhole,
+{
+    match decode(message)? {
+        ClusterMessage::CompletedSlot(completed_slot) => {
+            let slot = completed_slot.slot;
+            if stored_slots.lock().await.contains(slot) {
+                return Ok(false);
+            }
+            // The slot is only marked as stored once verified, so that a copy failing the
+            // verification does not cause the genuine copies to be dropped as duplicates.
+            verify_completed_slot(state, &completed_slot).await?;
+            if !stored_slots.lock().await.insert(slot) {
+                return Ok(false);
+            }
+            if let Err(e) = Aggregates::store_completed_slot(state, completed_slot).await {
+                // Another ingest node may deliver it again.
+                stored_slots.lock().await.remove(slot);
+                return Err(e);
+            }
+        }
+        ClusterMessage::PriceFeedsMetadata(price_feeds_metadata) => {
+            PriceFeedMeta::store_price_feeds_metadata(state, &price_feeds_metadata).await?;
+        }
+    }
+    Ok(true)
+}
+
+async fn publish(
+    publisher: &dyn ClusterPublisher,
+    metrics: &ClusterMetrics,
+    slot: Option<Slot>,
+    message: Result<Vec<u8>>,
+) {
+    match message {
+        Ok(message) => match publisher.publish(slot, message).await {
+            Ok(()) => metrics.published(PublishStatus::Success),
+            Err(e) => {
+                tracing::warn!(error = ?e, "Failed to publish cluster message.");
+                metrics.published(PublishStatus::Error);
+            }
+        },
+        Err(e) => {
+            tracing::warn!(error = ?e, "Failed to encode cluster message.");
+            metrics.published(PublishStatus::Error);
+        }
+    }
+}
+
+/// Publish the slots completed after the given slot from the cache, to catch up the slots skipped
+/// by a publisher that fell behind. Returns the latest published slot.
+async fn publish_cached_slots<S>(
+    state: &S,
+    publisher: &dyn ClusterPublisher,
+    metrics: &ClusterMetrics,
+    after: Slot,
+) -> Slot
+where
+    S: Aggregates,
+{
+    let feed_ids = Cache::message_state_keys(state)
+        .await
+        .into_iter()
+        .map(|key| key.feed_id)
+        .collect();
+    let slots = Cache::fetch_message_state_slots(state, feed_ids, after, MessageStateFilter::All)
+        .await
+        .feeds
+        .into_keys()
+        .collect::<Vec<_>>();
+    for &slot in &slots {
+        let completed_slot = CompletedSlot {
+            slot,
+            message_states: Cache::fetch_message_states_at_slot(state, slot).await,
+        };
+        publish(
+            publisher,
+            metrics,
+            Some(slot),
+            encode_completed_slot(&completed_slot),
+        )
+        .await;
+    }
+    slots.last().copied().unwrap_or(after)
+}
+
+/// Publish the completed slots and, periodically, the price feeds metadata of an ingest node.
+async fn run_ingest<S>(
+    state: Arc<S>,
+    publisher: Arc<dyn ClusterPublisher>,
+    metrics: Arc<ClusterMetrics>,
+) where
+    S: Aggregates,
+{
+    let mut completed_slots = Aggregates::subscribe_completed_slots(&*state);
+    let mut metadata_interval = tokio::time::interval(PRICE_FEEDS_METADATA_PUBLISH_INTERVAL);
+    let mut exit = crate::EXIT.subscribe();
+    let mut latest_published_slot = None;
+
+    loop {
+        tokio::select! {
+            _ = exit.changed() => break,
+            completed_slot = completed_slots.recv() => match completed_slot {
+                Ok(completed_slot) => {
+                    let slot = completed_slot.slot;
+                    let message = encode_completed_slot(&completed_slot);
+                    publish(&*publisher, &metrics, Some(slot), message).await;
+                    latest_published_slot = latest_published_slot.max(Some(slot));
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    tracing::warn!(
+                        skipped,
+                        "Cluster publisher fell behind, publishing the skipped slots from the cache."
+                    );
+                    metrics.lagged_slots.inc_by(skipped);
+                    if let Some(after) = latest_published_slot {
+                        latest_published_slot =
+                            Some(publish_cached_slots(&*state, &*publisher, &metrics, after).await);
+                    }
+                }
+                Err(RecvError::Closed) => break,
+            },
+            _ = metadata_interval.tick() => {
+                let message = match PriceFeedMeta::retrieve_price_feeds_metadata(&*state).await {
+                    Ok(price_feeds_metadata) if price_feeds_metadata.is_empty() => continue,
+                    Ok(price_feeds_metadata) => encode_price_feeds_metadata(&price_feeds_metadata),
+                    Err(e) => Err(e),
+                };
+                publish(&*publisher, &metrics, None, message).await;
+            }
+        }
+    }
+
+    tracing::info!("Shutting down cluster publisher...");
+}
+
+/// Store the messages of an ingest node until the subscription is lost.
+async fn consume<S>(
+    state: &S,
+    subscriber: &dyn ClusterSubscriber,
+    stored_slots: &Mutex<StoredSlots>,
+    metrics: &ClusterMetrics,
+) -> Result<()>
+where
+    S: Wormhole,
+{
+    let after = stored_slots.lock().await.latest();
+    let mut messages = subscriber.subscribe(after).await?;
+    while let Some(message) = messages.next().await {
+        match store_message(state, stored_slots, &message?).await {
+            Ok(true) => metrics.consumed(ConsumeStatus::Stored),
+            Ok(false) => metrics.consumed(ConsumeStatus::Duplicate),
+            Err(e) => {
+                tracing::warn!(error = ?e, "Failed to store cluster message.");
+                metrics.consumed(ConsumeStatus::Error);
+            }
+        }
+    }
+    Err(anyhow!("Cluster subscription ended"))
+}
+
+/// Consume the messages of an ingest node, subscribing again whenever the subscription is lost.
+async fn run_consumer<S>(
+    state: Arc<S>,
+    ingest_addr: String,
+    subscriber: Arc<dyn ClusterSubscriber>,
+    stored_slots: Arc<Mutex<StoredSlots>>,
+    metrics: Arc<ClusterMetrics>,
+) where
+    S: Wormhole,
+{
+    let mut exit = crate::EXIT.subscribe();
+    loop {
+        let current_time = Instant::now();
+        tokio::select! {
+            _ = exit.changed() => break,
+            Err(err) = consume(&*state, &*subscriber, &stored_slots, &metrics) => {
+                tracing::error!(error = ?err, ingest_addr, "Error in cluster subscription.");
+                if current_time.elapsed() < Duration::from_secs(30) {
+                    tracing::error!(ingest_addr, "Cluster subscription restarting too quickly. Sleep 1s.");
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+            }
+        }
+    }
+    tracing::info!(ingest_addr, "Shutting down cluster subscription...");
+}
+
+#[tracing::instrument(skip(opts, state))]
+pub async fn spawn<S>(opts: Options, state: Arc<S>) -> Result<()>
+where
+    S: Wormhole,
+    S: Metrics,
+    S: Send + Sync + 'static,
+{
+    if opts.publish_listen_addr.is_none() && opts.ingest_addrs.is_empty() {
+        return Ok(());
+    }
+    // An ingest node already stores the slots it ingests, it cannot consume them as well.
+    ensure!(
+        opts.publish_listen_addr.is_none() || opts.ingest_addrs.is_empty(),
+        "A cluster node cannot both publish to and consume from the cluster"
+    );
+    let secret = opts
+        .secret
+        .as_deref()
+        .filter(|secret| !secret.is_empty())
+        .map(tcp::Secret::new)
+        .context("Cluster mode requires a cluster secret")?;
+    let metrics = Arc::new(ClusterMetrics::default());
+
+    if let Some(listen_addr) = opts.publish_listen_addr {
+        metrics.register(&*state).await;
+        let publisher = tcp::TcpPublisher::bind(listen_addr, secret).await?;
+        tracing::info!(endpoint = %publisher.local_addr(), "Publishing completed slots to the cluster.");
+        run_ingest(state, Arc::new(publisher), metrics).await;
+    } else if !opts.ingest_addrs.is_empty() {
+        metrics.register(&*state).await;
+        tracing::info!(ingest_addrs = ?opts.ingest_addrs, "Consuming completed slots from the cluster.");
+        let stored_slots = Arc::new(Mutex::new(StoredSlots::default()));
+        join_all(opts.ingest_addrs.into_iter().map(|ingest_addr| {
+            let subscriber = Arc::new(tcp::TcpSubscriber::new(ingest_addr.clone(), secret.clone()));
+            run_consumer(
+                state.clone(),
+                ingest_addr,
+                subscriber,
+                stored_slots.clone(),
+                metrics.clone(),
+            )
+        }))
+        .await;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use {
+        super::{loopback::LoopbackPubSub, *},
+        crate::state::{
+            aggregate::{
+                test::{
+                    create_dummy_price_feed_message, generate_update,
+                    store_multiple_concurrent_valid_updates,
+                },
+                AggregationEvent, RequestTime,
+            },
+            test::setup_state,
+        },
+    };
+
+    #[tokio::test]
+    async fn test_api_node_serves_completed_slots_of_ingest_nodes() {
+        let (ingest, _) = setup_state(10).await;
+        let (api, mut api_events) = setup_state(10).await;
+        let pubsub = LoopbackPubSub::new();
+        let mut messages = pubsub.subscribe(None).await.unwrap();
+
+        let mut completed_slots = Aggregates::subscribe_completed_slots(&*ingest);
+        store_multiple_concurrent_valid_updates(
+            ingest.clone(),
+            generate_update(
+                vec![Message::PriceFeedMessage(create_dummy_price_feed_message(
+                    100, 10, 9,
+                ))],
+                10,
+                20,
+            ),
+        )
+        .await;
+        let completed_slot = completed_slots.recv().await.unwrap();
+        assert_eq!(completed_slot.slot, 10);
+
+        // The slot is published by two ingest nodes, along with the metadata.
+        for _ in 0..2 {
+            pubsub
+                .publish(Some(10), encode_completed_slot(&completed_slot).unwrap())
+                .await
+                .unwrap();
+        }
+        let price_feeds_metadata = vec![PriceFeedMetadata {
+            id: RpcPriceIdentifier::new([100; 32]),
+            attributes: [("symbol".to_string(), "Crypto.BTC/USD".to_string())].into(),
+        }];
+        pubsub
+            .publish(
+                None,
+                encode_price_feeds_metadata(&price_feeds_metadata).unwrap(),
+            )
+            .await
+            .unwrap();
+
+        let stored_slots = Mutex::new(StoredSlots::default());
+        let mut stored = vec![];
+        for _ in 0..3 {
+            let message = messages.next().await.unwrap().unwrap();
+            stored.push(store_message(&*api, &stored_slots, &message).await.unwrap());
+        }
+        assert_eq!(stored, vec![true, false, true]);
+
+        // The slot is only stored once.
+        assert_eq!(
+            api_events.recv().await,
+            Ok(AggregationEvent::New { slot: 10 })
+        );
+        assert!(api_events.try_recv().is_err());
+
+        // The API node serves the same updates as the ingest node, and is ready.
+        let price_ids = [PriceIdentifier::new([100; 32])];
+        assert_eq!(
+            Aggregates::get_price_feeds_with_update_data(&*api, &price_ids, RequestTime::Latest)
+                .await
+                .unwrap(),
+            Aggregates::get_price_feeds_with_update_data(&*ingest, &price_ids, RequestTime::Latest)
+                .await
+                .unwrap(),
+        );
+        assert_eq!(
+            PriceFeedMeta::retrieve_price_feeds_metadata(&*api)
+                .await
+                .unwrap()[0]
+                .attributes,
+            price_feeds_metadata[0].attributes
+        );
+        let (is_ready, readiness) = Aggregates::is_ready(&*api).await;
+        assert!(is_ready);
+        assert_eq!(readiness.latest_completed_slot, Some(10));
+        assert_eq!(readiness.latest_observed_slot, Some(10));
+    }
+
+    #[tokio::test]
+    async fn test_api_node_rejects_unproven_slots() {
+        let (ingest, _) = setup_state(10).await;
+        let (api, _) = setup_state(10).await;
+
+        let mut completed_slots = Aggregates::subscribe_completed_slots(&*ingest);
+        store_multiple_concurrent_valid_updates(
+            ingest.clone(),
+            generate_update(
+                vec![Message::PriceFeedMessage(create_dummy_price_feed_message(
+                    100, 10, 9,
+                ))],
+                10,
+                20,
+            ),
+        )
+        .await;
+        let completed_slot = completed_slots.recv().await.unwrap();
+
+        // A message that is not the one proven by the VAA is rejected, along with its slot.
+        let mut forged_slot = (*completed_slot).clone();
+        let forged_message =
+            Message::PriceFeedMessage(create_dummy_price_feed_message(100, 11, 10));
+        forged_slot.message_states[0].raw_message =
+            pythnet_sdk::wire::to_vec::<_, BigEndian>(&forged_message).unwrap();
+        let stored_slots = Mutex::new(StoredSlots::default());
+        assert!(store_message(
+            &*api,
+            &stored_slots,
+            &encode_completed_slot(&forged_slot).unwrap()
+        )
+        .await
+        .is_err());
+
+        // A slot proven by a VAA of another slot is rejected too.
+        let mut forged_slot = (*completed_slot).clone();
+        forged_slot.slot = 11;
+        forged_slot.message_states[0].slot = 11;
+        assert!(store_message(
+            &*api,
+            &stored_slots,
+            &encode_completed_slot(&forged_slot).unwrap()
+        )
+        .await
+        .is_err());
+
+        // The genuine slot is still stored.
+        assert!(store_message(
+            &*api,
+            &stored_slots,
+            &encode_completed_slot(&completed_slot).unwrap()
+        )
+        .await
+        .unwrap());
+        assert_eq!(
+            Aggregates::get_price_feed_ids(&*api).await,
+            [PriceIdentifier::new([100; 32])].into()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_api_node_stores_slots_delivered_while_a_forged_copy_is_verified() {
+        let (ingest, _) = setup_state(10).await;
+        let (api, _) = setup_state(10).await;
+
+        let mut completed_slots = Aggregates::subscribe_completed_slots(&*ingest);
+        store_multiple_concurrent_valid_updates(
+            ingest.clone(),
+            generate_update(
+                vec![Message::PriceFeedMessage(create_dummy_price_feed_message(
+                    100, 10, 9,
+                ))],
+                10,
+                20,
+            ),
+        )
+        .await;
+        let completed_slot = completed_slots.recv().await.unwrap();
+        let mut forged_slot = (*completed_slot).clone();
+        let forged_message =
+            Message::PriceFeedMessage(create_dummy_price_feed_message(100, 11, 10));
+        forged_slot.message_states[0].raw_message =
+            pythnet_sdk::wire::to_vec::<_, BigEndian>(&forged_message).unwrap();
+
+        // The genuine copy is not dropped as a duplicate of the forged one being verified.
+        let stored_slots = Mutex::new(StoredSlots::default());
+        let forged = encode_completed_slot(&forged_slot).unwrap();
+        let genuine = encode_completed_slot(&completed_slot).unwrap();
+        let (forged, genuine) = tokio::join!(
+            store_message(&*api, &stored_slots, &forged),
+            store_message(&*api, &stored_slots, &genuine)
+        );
+        assert!(forged.is_err());
+        assert!(genuine.unwrap());
+        assert_eq!(stored_slots.lock().await.latest(), Some(10));
+    }
+
+    #[tokio::test]
+    async fn test_cluster_node_cannot_both_publish_and_consume() {
+        let (state, _) = setup_state(10).await;
+        let opts = Options {
+            publish_listen_addr: Some("127.0.0.1:0".parse().unwrap()),
+            ingest_addrs: vec!["127.0.0.1:1".to_string()],
+            secret: Some("secret".to_string()),
+        };
+        assert!(spawn(opts, state).await.is_err());
+    }
+}
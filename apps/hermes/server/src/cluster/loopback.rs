@@ -0,0 +1,40 @@
+use {
+    super::{ClusterPublisher, ClusterSubscriber},
+    crate::state::aggregate::Slot,
+    anyhow::{anyhow, Result},
+    futures::{stream::BoxStream, StreamExt},
+    tokio::sync::broadcast,
+    tokio_stream::wrappers::BroadcastStream,
+};
+
+/// A pub/sub delivering the messages within the process, used to test the cluster mode without
+/// a network. It doesn't catch up the subscribers.
+pub struct LoopbackPubSub {
+    sender: broadcast::Sender<Vec<u8>>,
+}
+
+impl LoopbackPubSub {
+    pub fn new() -> Self {
+        Self {
+            sender: broadcast::channel(100).0,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl ClusterPublisher for LoopbackPubSub {
+    async fn publish(&self, _slot: Option<Slot>, message: Vec<u8>) -> Result<()> {
+        // Like a real pub/sub, messages published without subscribers are dropped.
+        let _ = self.sender.send(message);
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl ClusterSubscriber for LoopbackPubSub {
+    async fn subscribe(&self, _after: Option<Slot>) -> Result<BoxStream<'static, Result<Vec<u8>>>> {
+        Ok(BroadcastStream::new(self.sender.subscribe())
+            .map(|message| message.map_err(|e| anyhow!(e)))
+            .boxed())
+    }
+}
@@ -0,0 +1,400 @@
+//! A cluster pub/sub over plain TCP connections. The ingest node accepts the connections of the
+//! API nodes and writes every message to each of them.
+//!
+//! Every frame is a message prefixed with its length as a `u32` (BE) and followed by its tag, the
+//! Keccak256 hash of the cluster secret and the message, so that only the nodes knowing the secret
+//! can publish. On connection, the API node sends the latest slot it stored and the ingest node
+//! sends the retained completed slots after it before the new messages.
+
+use {
+    super::{ClusterPublisher, ClusterSubscriber},
+    crate::state::aggregate::Slot,
+    anyhow::{ensure, Context, Result},
+    borsh::{BorshDeserialize, BorshSerialize},
+    futures::{
+        stream::{self, BoxStream},
+        StreamExt,
+    },
+    sha3::{Digest, Keccak256},
+    std::{
+        collections::{BTreeSet, VecDeque},
+        io::ErrorKind,
+        net::SocketAddr,
+        sync::{Arc, Mutex},
+    },
+    tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+        sync::broadcast::{self, error::RecvError},
+    },
+};
+
+/// Capacity of the channel of each connection. The connections falling further behind catch up
+/// from the retained slots.
+const CONNECTION_CHANNEL_SIZE: usize = 100;
+
+/// Number of the most recent completed slots retained to catch up the API nodes that reconnect or
+/// fall behind, about a minute of slots. They share their memory with the channel.
+const RETAINED_SLOTS: usize = 150;
+
+/// Maximum size of a message, to reject corrupted length prefixes.
+const MAX_MESSAGE_SIZE: usize = 64 * 1024 * 1024;
+
+/// Maximum size of the subscription of an API node, a borsh encoded `Option<Slot>`. It is read
+/// before the frame is authenticated, so it must not allocate more than that.
+const MAX_SUBSCRIPTION_SIZE: usize = 1 + std::mem::size_of::<Slot>();
+
+/// The secret shared by the nodes of a cluster, authenticating the frames.
+#[derive(Clone)]
+pub struct Secret(Arc<[u8]>);
+
+impl Secret {
+    pub fn new(secret: &str) -> Self {
+        Self(secret.as_bytes().into())
+    }
+
+    fn tag(&self, message: &[u8]) -> [u8; 32] {
+        Keccak256::new()
+            .chain_update(self.0.as_ref())
+            .chain_update(message)
+            .finalize()
+            .into()
+    }
+
+    /// Check the tag of a message in constant time.
+    fn check(&self, message: &[u8], tag: &[u8; 32]) -> bool {
+        self.tag(message)
+            .iter()
+            .zip(tag)
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+    }
+}
+
+#[derive(Clone)]
+struct Frame {
+    /// The slot of a completed slot message.
+    slot: Option<Slot>,
+    message: Arc<Vec<u8>>,
+}
+
+type RetainedFrames = Arc<Mutex<VecDeque<Frame>>>;
+
+pub struct TcpPublisher {
+    sender: broadcast::Sender<Frame>,
+    retained: RetainedFrames,
+    local_addr: SocketAddr,
+}
+
+impl TcpPublisher {
+    /// Listen for the connections of the API nodes.
+    pub async fn bind(listen_addr: SocketAddr, secret: Secret) -> Result<Self> {
+        let listener = TcpListener::bind(listen_addr)
+            .await
+            .with_context(|| format!("Failed to listen on {}", listen_addr))?;
+        let local_addr = listener.local_addr()?;
+        let sender = broadcast::channel(CONNECTION_CHANNEL_SIZE).0;
+        let retained = RetainedFrames::default();
+        tokio::spawn(accept(listener, sender.clone(), retained.clone(), secret));
+        Ok(Self {
+            sender,
+            retained,
+            local_addr,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+async fn accept(
+    listener: TcpListener,
+    sender: broadcast::Sender<Frame>,
+    retained: RetainedFrames,
+    secret: Secret,
+) {
+    let mut exit = crate::EXIT.subscribe();
+    loop {
+        tokio::select! {
+            _ = exit.changed() => break,
+            connection = listener.accept() => match connection {
+                Ok((stream, peer_addr)) => {
+                    tracing::info!(%peer_addr, "API node connected to the cluster publisher.");
+                    // The connection receives the messages published from now on, the older ones
+                    // are caught up from the retained slots.
+                    let receiver = sender.subscribe();
+                    let retained = retained.clone();
+                    let secret = secret.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = serve(stream, peer_addr, receiver, retained, secret).await {
+                            tracing::info!(
+                                %peer_addr,
+                                error = ?e,
+                                "API node disconnected from the cluster publisher."
+                            );
+                        }
+                    });
+                }
+                Err(e) => tracing::warn!(error = ?e, "Failed to accept cluster connection."),
+            }
+        }
+    }
+}
+
+async fn serve(
+    mut stream: TcpStream,
+    peer_addr: SocketAddr,
+    mut receiver: broadcast::Receiver<Frame>,
+    retained: RetainedFrames,
+    secret: Secret,
+) -> Result<()> {
+    let _ = stream.set_nodelay(true);
+
+    // The API node starts with the latest slot it stored.
+    let after = read_frame(&mut stream, &secret, MAX_SUBSCRIPTION_SIZE)
+        .await?
+        .context("Connection closed before subscribing")?;
+    let after = Option::<Slot>::try_from_slice(&after)?;
+
+    // The slots sent to the API node, to send each of them once.
+    let mut sent_slots = BTreeSet::new();
+    catch_up(&mut stream, &retained, &secret, after, &mut sent_slots).await?;
+
+    loop {
+        match receiver.recv().await {
+            Ok(frame) => {
+                if let Some(slot) = frame.slot {
+                    if !insert_sent_slot(&mut sent_slots, slot) {
+                        continue;
+                    }
+                }
+                write_frame(&mut stream, &secret, &frame.message).await?;
+            }
+            Err(RecvError::Lagged(skipped)) => {
+                tracing::warn!(
+                    %peer_addr,
+                    skipped,
+                    "API node fell behind, catching up from the retained slots."
+                );
+                catch_up(&mut stream, &retained, &secret, None, &mut sent_slots).await?;
+            }
+            Err(RecvError::Closed) => return Ok(()),
+        }
+    }
+}
+
+/// Remember a slot sent to an API node, returns false if it was already sent.
+fn insert_sent_slot(sent_slots: &mut BTreeSet<Slot>, slot: Slot) -> bool {
+    let inserted = sent_slots.insert(slot);
+    // The slots older than the retained ones are not sent again.
+    if sent_slots.len() > 2 * RETAINED_SLOTS {
+        sent_slots.pop_first();
+    }
+    inserted
+}
+
+/// Send the retained slots after the given slot that were not sent yet.
+async fn catch_up(
+    stream: &mut TcpStream,
+    retained: &RetainedFrames,
+    secret: &Secret,
+    after: Option<Slot>,
+    sent_slots: &mut BTreeSet<Slot>,
+) -> Result<()> {
+    let frames = retained
+        .lock()
+        .map_err(|_| anyhow::anyhow!("Retained slots lock is poisoned"))?
+        .clone();
+    for frame in frames {
+        let Some(slot) = frame.slot else {
+            continue;
+        };
+        if after.is_some_and(|after| slot <= after) || !insert_sent_slot(sent_slots, slot) {
+            continue;
+        }
+        write_frame(stream, secret, &frame.message).await?;
+    }
+    Ok(())
+}
+
+async fn write_frame(stream: &mut TcpStream, secret: &Secret, message: &[u8]) -> Result<()> {
+    let len = u32::try_from(message.len())?;
+    let mut frame = Vec::with_capacity(4 + message.len() + 32);
+    frame.extend_from_slice(&len.to_be_bytes());
+    frame.extend_from_slice(message);
+    frame.extend_from_slice(&secret.tag(message));
+    stream.write_all(&frame).await?;
+    Ok(())
+}
+
+/// Read the next message of at most `max_len` bytes, or `None` if the connection was closed.
+async fn read_frame(
+    stream: &mut TcpStream,
+    secret: &Secret,
+    max_len: usize,
+) -> Result<Option<Vec<u8>>> {
+    let len = match stream.read_u32().await {
+        Ok(len) => len as usize,
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    ensure!(
+        len <= max_len,
+        "Cluster message of {} bytes is too large",
+        len
+    );
+    let mut message = vec![0; len];
+    stream.read_exact(&mut message).await?;
+    let mut tag = [0; 32];
+    stream.read_exact(&mut tag).await?;
+    ensure!(
+        secret.check(&message, &tag),
+        "Cluster message is not authenticated by the cluster secret"
+    );
+    Ok(Some(message))
+}
+
+#[async_trait::async_trait]
+impl ClusterPublisher for TcpPublisher {
+    async fn publish(&self, slot: Option<Slot>, message: Vec<u8>) -> Result<()> {
+        let frame = Frame {
+            slot,
+            message: Arc::new(message),
+        };
+        if slot.is_some() {
+            let mut retained = self
+                .retained
+                .lock()
+                .map_err(|_| anyhow::anyhow!("Retained slots lock is poisoned"))?;
+            retained.push_back(frame.clone());
+            if retained.len() > RETAINED_SLOTS {
+                retained.pop_front();
+            }
+        }
+        // There may be no API node connected.
+        let _ = self.sender.send(frame);
+        Ok(())
+    }
+}
+
+pub struct TcpSubscriber {
+    ingest_addr: String,
+    secret: Secret,
+}
+
+impl TcpSubscriber {
+    pub fn new(ingest_addr: String, secret: Secret) -> Self {
+        Self {
+            ingest_addr,
+            secret,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl ClusterSubscriber for TcpSubscriber {
+    async fn subscribe(&self, after: Option<Slot>) -> Result<BoxStream<'static, Result<Vec<u8>>>> {
+        let mut stream = TcpStream::connect(&self.ingest_addr)
+            .await
+            .with_context(|| format!("Failed to connect to ingest node {}", self.ingest_addr))?;
+        write_frame(&mut stream, &self.secret, &after.try_to_vec()?).await?;
+        let secret = self.secret.clone();
+        Ok(stream::try_unfold(stream, move |mut stream| {
+            let secret = secret.clone();
+            async move {
+                Ok(read_frame(&mut stream, &secret, MAX_MESSAGE_SIZE)
+                    .await?
+                    .map(|message| (message, stream)))
+            }
+        })
+        .boxed())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use {super::*, std::time::Duration};
+
+    async fn wait_for_connections(publisher: &TcpPublisher, count: usize) {
+        // The connections are accepted in the background, so wait until they are served.
+        while publisher.sender.receiver_count() < count {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tcp_messages_are_delivered_in_order() {
+        let secret = Secret::new("secret");
+        let publisher = TcpPublisher::bind("127.0.0.1:0".parse().unwrap(), secret.clone())
+            .await
+            .unwrap();
+        let subscriber = TcpSubscriber::new(publisher.local_addr().to_string(), secret);
+        let mut messages = subscriber.subscribe(None).await.unwrap();
+        wait_for_connections(&publisher, 1).await;
+
+        let large_message = vec![7; 100_000];
+        for message in [vec![1, 2, 3], vec![], large_message.clone()] {
+            publisher.publish(None, message).await.unwrap();
+        }
+        assert_eq!(messages.next().await.unwrap().unwrap(), vec![1, 2, 3]);
+        assert_eq!(messages.next().await.unwrap().unwrap(), Vec::<u8>::new());
+        assert_eq!(messages.next().await.unwrap().unwrap(), large_message);
+    }
+
+    #[tokio::test]
+    async fn test_tcp_subscriber_catches_up_the_retained_slots() {
+        let secret = Secret::new("secret");
+        let publisher = TcpPublisher::bind("127.0.0.1:0".parse().unwrap(), secret.clone())
+            .await
+            .unwrap();
+        for slot in 1..=3 {
+            publisher
+                .publish(Some(slot), vec![slot as u8])
+                .await
+                .unwrap();
+        }
+        // Metadata is not retained.
+        publisher.publish(None, vec![0]).await.unwrap();
+
+        // A subscriber that stored the slot 1 receives the slots after it, then the new ones.
+        let subscriber = TcpSubscriber::new(publisher.local_addr().to_string(), secret);
+        let mut messages = subscriber.subscribe(Some(1)).await.unwrap();
+        assert_eq!(messages.next().await.unwrap().unwrap(), vec![2]);
+        assert_eq!(messages.next().await.unwrap().unwrap(), vec![3]);
+        publisher.publish(Some(4), vec![4]).await.unwrap();
+        assert_eq!(messages.next().await.unwrap().unwrap(), vec![4]);
+    }
+
+    #[tokio::test]
+    async fn test_tcp_messages_require_the_cluster_secret() {
+        let publisher = TcpPublisher::bind("127.0.0.1:0".parse().unwrap(), Secret::new("secret"))
+            .await
+            .unwrap();
+        let subscriber =
+            TcpSubscriber::new(publisher.local_addr().to_string(), Secret::new("other"));
+        let mut messages = subscriber.subscribe(None).await.unwrap();
+
+        // The publisher closes the connection, as the subscription is not authenticated.
+        match messages.next().await {
+            None | Some(Err(_)) => {}
+            Some(Ok(message)) => panic!("Unexpected message {:?}", message),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tcp_publisher_rejects_oversized_subscriptions() {
+        let publisher = TcpPublisher::bind("127.0.0.1:0".parse().unwrap(), Secret::new("secret"))
+            .await
+            .unwrap();
+        let mut stream = TcpStream::connect(publisher.local_addr()).await.unwrap();
+        stream
+            .write_u32(MAX_SUBSCRIPTION_SIZE as u32 + 1)
+            .await
+            .unwrap();
+
+        // The publisher closes the connection without waiting for the rest of the frame.
+        let mut buf = [0; 1];
+        assert!(matches!(stream.read(&mut buf).await, Ok(0) | Err(_)));
+    }
+}
@@ -1,14 +1,23 @@
-use clap::{crate_authors, crate_description, crate_name, crate_version, Args, Parser};
+use {
+    clap::{crate_authors, crate_description, crate_name, crate_version, Args, Parser},
+    std::path::PathBuf,
+};
 
-mod aggregate;
-mod benchmarks;
-mod metrics;
-mod pythnet;
-mod rpc;
-mod wormhole;
+pub mod aggregate;
+pub mod benchmarks;
+pub mod cache;
+pub mod cluster;
+pub mod file;
+pub mod metrics;
+pub mod pythnet;
+pub mod replay;
+pub mod rpc;
+pub mod sinks;
+pub mod telemetry;
+pub mod wormhole;
 
 // `Options` is a structup definition to provide clean command-line args for Hermes.
-#[derive(Parser, Debug)]
+#[derive(Parser, Clone, Debug, PartialEq)]
 #[command(name = crate_name!())]
 #[command(author = crate_authors!())]
 #[command(about = crate_description!())]
@@ -18,11 +27,15 @@ pub enum Options {
     /// Run the Hermes Price Service.
     Run(RunOptions),
 
+    /// Run the Hermes Price Service on updates replayed from a recording instead of Wormhole and
+    /// Pythnet.
+    Replay(ReplayOptions),
+
     /// Show Overridden Environment Variables.
     ShowEnv(ShowEnvOptions),
 }
 
-#[derive(Args, Clone, Debug)]
+#[derive(Args, Clone, Debug, PartialEq)]
 pub struct RunOptions {
     /// Aggregate Options
     #[command(flatten)]
@@ -32,6 +45,14 @@ pub struct RunOptions {
     #[command(flatten)]
     pub benchmarks: benchmarks::Options,
 
+    /// Cache Options
+    #[command(flatten)]
+    pub cache: cache::Options,
+
+    /// Cluster Options
+    #[command(flatten)]
+    pub cluster: cluster::Options,
+
     /// Metrics Options
     #[command(flatten)]
     pub metrics: metrics::Options,
@@ -44,12 +65,140 @@ pub struct RunOptions {
     #[command(flatten)]
     pub rpc: rpc::Options,
 
+    /// Sinks Options
+    #[command(flatten)]
+    pub sinks: sinks::Options,
+
+    /// Telemetry Options
+    #[command(flatten)]
+    pub telemetry: telemetry::Options,
+
     /// Wormhole Options.
     #[command(flatten)]
     pub wormhole: wormhole::Options,
+
+    /// Record the guardian sets, VAAs and accumulator messages received to this file so they can
+    /// be replayed later with the `replay` command. Updates are appended if the file exists.
+    #[arg(long = "record")]
+    #[arg(env = "RECORD_FILE")]
+    pub record: Option<PathBuf>,
+
+    /// Path to a TOML or YAML file setting any of the options, see `config::file` for the
+    /// layout. The command line and environment variables take precedence over the file.
+    ///
+    /// The file is watched, and changes to the websocket rate limit and whitelist, the price
+    /// feeds metadata refresh interval and the benchmarks endpoint apply without a restart.
+    #[arg(long = "config-file")]
+    #[arg(env = "CONFIG_FILE")]
+    pub config_file: Option<PathBuf>,
+}
+
+#[derive(Args, Clone, Debug, PartialEq)]
+pub struct ReplayOptions {
+    /// Aggregate Options
+    #[command(flatten)]
+    pub aggregate: aggregate::Options,
+
+    /// Benchmarks Options
+    #[command(flatten)]
+    pub benchmarks: benchmarks::Options,
+
+    /// Cache Options
+    #[command(flatten)]
+    pub cache: cache::Options,
+
+    /// Metrics Options
+    #[command(flatten)]
+    pub metrics: metrics::Options,
+
+    /// Replay Options
+    #[command(flatten)]
+    pub replay: replay::Options,
+
+    /// RPC Options
+    #[command(flatten)]
+    pub rpc: rpc::Options,
+
+    /// Sinks Options
+    #[command(flatten)]
+    pub sinks: sinks::Options,
+
+    /// Telemetry Options
+    #[command(flatten)]
+    pub telemetry: telemetry::Options,
+
+    /// Path to a TOML or YAML file setting any of the options, see `config::file` for the
+    /// layout. The command line and environment variables take precedence over the file.
+    ///
+    /// The file is watched, and changes to the websocket rate limit and whitelist, the price
+    /// feeds metadata refresh interval and the benchmarks endpoint apply without a restart.
+    #[arg(long = "config-file")]
+    #[arg(env = "CONFIG_FILE")]
+    pub config_file: Option<PathBuf>,
+}
+
+impl Options {
+    /// The telemetry options of the subcommands serving the APIs.
+    pub fn telemetry(&self) -> Option<&telemetry::Options> {
+        match self {
+            Options::Run(opts) => Some(&opts.telemetry),
+            Options::Replay(opts) => Some(&opts.telemetry),
+            Options::ShowEnv(_) => None,
+        }
+    }
+
+    pub fn rpc(&self) -> Option<&rpc::Options> {
+        match self {
+            Options::Run(opts) => Some(&opts.rpc),
+            Options::Replay(opts) => Some(&opts.rpc),
+            Options::ShowEnv(_) => None,
+        }
+    }
+
+    pub fn benchmarks(&self) -> Option<&benchmarks::Options> {
+        match self {
+            Options::Run(opts) => Some(&opts.benchmarks),
+            Options::Replay(opts) => Some(&opts.benchmarks),
+            Options::ShowEnv(_) => None,
+        }
+    }
+
+    pub fn pythnet(&self) -> Option<&pythnet::Options> {
+        match self {
+            Options::Run(opts) => Some(&opts.pythnet),
+            Options::Replay(_) | Options::ShowEnv(_) => None,
+        }
+    }
+
+    pub fn config_file(&self) -> Option<&PathBuf> {
+        match self {
+            Options::Run(opts) => opts.config_file.as_ref(),
+            Options::Replay(opts) => opts.config_file.as_ref(),
+            Options::ShowEnv(_) => None,
+        }
+    }
+
+    /// These options, with the values of `reloaded` for the options that are safe to change
+    /// while running: they do not affect the ingestion nor the state already built.
+    pub fn with_reloaded(&self, reloaded: &Options) -> Options {
+        let mut options = self.clone();
+        match (&mut options, reloaded) {
+            (Options::Run(opts), Options::Run(reloaded)) => {
+                opts.rpc.reload(&reloaded.rpc);
+                opts.benchmarks = reloaded.benchmarks.clone();
+                opts.pythnet.metadata_refresh_interval = reloaded.pythnet.metadata_refresh_interval;
+            }
+            (Options::Replay(opts), Options::Replay(reloaded)) => {
+                opts.rpc.reload(&reloaded.rpc);
+                opts.benchmarks = reloaded.benchmarks.clone();
+            }
+            _ => {}
+        }
+        options
+    }
 }
 
-#[derive(Args, Clone, Debug)]
+#[derive(Args, Clone, Debug, PartialEq)]
 pub struct ShowEnvOptions {
     /// Show Hermes environment variables.
     ///
@@ -1,6 +1,6 @@
-use {clap::Args, humantime::Duration};
+use {clap::Args, humantime::Duration, std::path::PathBuf};
 
-#[derive(Args, Clone, Debug)]
+#[derive(Args, Clone, Debug, PartialEq)]
 #[command(next_help_heading = "Aggregate Options")]
 #[group(id = "Aggregate")]
 pub struct Options {
@@ -15,4 +15,21 @@ pub struct Options {
     #[arg(env = "AGGREGATE_READINESS_MAX_ALLOWED_SLOT_LAG")]
     #[arg(default_value = "10")]
     pub readiness_max_allowed_slot_lag: u64,
+
+    /// Path to a JSON file listing the price feeds derived from other price feeds.
+    ///
+    /// The file contains a list of objects with the `id` of the derived feed, a `formula` that
+    /// multiplies and divides price feed ids (e.g. `<ETH/USD id> / <BTC/USD id>`) and an optional
+    /// `expo` for the derived prices.
+    #[arg(long = "aggregate-derived-feeds-file")]
+    #[arg(env = "AGGREGATE_DERIVED_FEEDS_FILE")]
+    pub derived_feeds_file: Option<PathBuf>,
+
+    /// Capacity of the channel notifying the API subscribers of the aggregated slots. Subscribers
+    /// falling further behind skip the oldest slots.
+    #[arg(long = "aggregate-update-channel-capacity")]
+    #[arg(env = "AGGREGATE_UPDATE_CHANNEL_CAPACITY")]
+    #[arg(default_value = "1000")]
+    #[arg(value_parser = clap::value_parser!(u64).range(1..))]
+    pub update_channel_capacity: u64,
 }
@@ -1,6 +1,6 @@
 use {clap::Args, reqwest::Url};
 
-#[derive(Args, Clone, Debug)]
+#[derive(Args, Clone, Debug, PartialEq)]
 #[command(next_help_heading = "Benchmark Options")]
 #[group(id = "Benchmarks")]
 pub struct Options {
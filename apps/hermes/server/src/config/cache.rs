@@ -0,0 +1,27 @@
+use {clap::Args, humantime::Duration, std::path::PathBuf};
+
+#[derive(Args, Clone, Debug, PartialEq)]
+#[command(next_help_heading = "Cache Options")]
+#[group(id = "Cache")]
+pub struct Options {
+    /// Number of updates kept in memory for each price feed.
+    #[arg(long = "cache-size")]
+    #[arg(env = "CACHE_SIZE")]
+    #[arg(default_value = "1000")]
+    #[arg(value_parser = clap::value_parser!(u64).range(1..))]
+    pub size: u64,
+
+    /// Path to a directory used to persist the cache history on disk.
+    ///
+    /// When set, all cached updates are also written to this directory and are used to serve
+    /// historical queries beyond the in-memory cache, as well as to warm up the cache on restart.
+    #[arg(long = "cache-storage-path")]
+    #[arg(env = "CACHE_STORAGE_PATH")]
+    pub storage_path: Option<PathBuf>,
+
+    /// How long updates are kept in the persistent cache storage.
+    #[arg(long = "cache-storage-retention")]
+    #[arg(env = "CACHE_STORAGE_RETENTION")]
+    #[arg(default_value = "7days")]
+    pub storage_retention: Duration,
+}
@@ -0,0 +1,34 @@
+use {clap::Args, std::net::SocketAddr};
+
+#[derive(Args, Clone, Debug, PartialEq)]
+#[command(next_help_heading = "Cluster Options")]
+#[group(id = "Cluster")]
+pub struct Options {
+    /// Address and port to publish the completed slots on, which makes this instance an ingest
+    /// node of a cluster.
+    ///
+    /// The ingest nodes listen to Wormhole and Pythnet as usual, and stream the message states of
+    /// every completed slot and the price feeds metadata to the API nodes connected to them.
+    #[arg(long = "cluster-publish-listen-addr")]
+    #[arg(env = "CLUSTER_PUBLISH_LISTEN_ADDR")]
+    pub publish_listen_addr: Option<SocketAddr>,
+
+    /// Addresses of the ingest nodes to consume the completed slots from (separated by comma),
+    /// which makes this instance an API node of a cluster.
+    ///
+    /// API nodes do not listen to Wormhole and Pythnet, they serve the completed slots of the
+    /// ingest nodes, storing each slot once whichever ingest node delivers it first. They still
+    /// poll the guardian sets from Pythnet to verify the slots, and the price feeds publishers.
+    #[arg(long = "cluster-ingest-addr")]
+    #[arg(env = "CLUSTER_INGEST_ADDR")]
+    #[arg(value_delimiter = ',')]
+    #[arg(conflicts_with = "publish_listen_addr")]
+    pub ingest_addrs: Vec<String>,
+
+    /// Secret shared by the nodes of a cluster, required in cluster mode. Every message exchanged
+    /// between the nodes is authenticated with it.
+    #[arg(long = "cluster-secret")]
+    #[arg(env = "CLUSTER_SECRET")]
+    #[arg(hide_env_values = true)]
+    pub secret: Option<String>,
+}
@@ -0,0 +1,361 @@
+//! Options set in a TOML or YAML file, given with `--config-file`.
+//!
+//! The file has a section per option group, named after the group, e.g. `[rpc]`. The keys of a
+//! section are the long names of the options of the group, with or without the group prefix and
+//! with either `-` or `_`, so `listen-addr`, `listen_addr` and `rpc-listen-addr` all set
+//! `--rpc-listen-addr`. Options outside of any group, e.g. `record`, are set at the top level.
+//! Options taking several values accept a list, and flags a boolean:
+//!
+//! ```toml
+//! [rpc]
+//! listen-addr = "0.0.0.0:33999"
+//! ws-whitelist = ["10.0.0.0/8"]
+//! api-key-required = true
+//!
+//! [cache]
+//! size = 2000
+//! ```
+//!
+//! The file is turned into command line arguments for the options that are not already given on
+//! the command line or in the environment, so it goes through the same validation as them.
+
+use {
+    super::Options,
+    crate::EXIT,
+    anyhow::{anyhow, bail, Context, Result},
+    clap::{Arg, Command, CommandFactory, Parser},
+    serde_json::Value,
+    std::{
+        ffi::OsString,
+        path::{Path, PathBuf},
+        time::{Duration, SystemTime},
+    },
+    tokio::sync::watch,
+};
+
+/// How often the file is checked for changes.
+const RELOAD_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+const CONFIG_FILE_ARG: &str = "config-file";
+
+/// Parse the options from the command line arguments, the environment and the config file if
+/// one is given. Exits on `--help` and on invalid arguments, like `Options::parse`.
+pub fn parse(args: &[OsString]) -> Result<Options> {
+    Ok(Options::parse_from(with_config_file(args)?))
+}
+
+/// Same as `parse`, but invalid arguments are returned as an error.
+fn try_parse(args: &[OsString]) -> Result<Options> {
+    Ok(Options::try_parse_from(with_config_file(args)?)?)
+}
+
+/// Append the arguments of the options set in the config file to the command line arguments.
+fn with_config_file(args: &[OsString]) -> Result<Vec<OsString>> {
+    let command = Options::command();
+    let Some(subcommand) = args
+        .iter()
+        .skip(1)
+        .find_map(|arg| command.find_subcommand(arg))
+    else {
+        return Ok(args.to_vec());
+    };
+    let Some(path) = config_file(args, subcommand) else {
+        return Ok(args.to_vec());
+    };
+
+    let file_args = read(&path)
+        .and_then(|options| file_args(subcommand, args, &options))
+        .with_context(|| format!("Invalid config file {}", path.display()))?;
+    Ok(args.iter().cloned().chain(file_args).collect())
+}
+
+fn config_file(args: &[OsString], subcommand: &Command) -> Option<PathBuf> {
+    let flag = format!("--{CONFIG_FILE_ARG}");
+    let mut args = args.iter().filter_map(|arg| arg.to_str());
+    while let Some(arg) = args.next() {
+        if arg == flag {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.strip_prefix(&format!("{flag}=")) {
+            return Some(PathBuf::from(path));
+        }
+    }
+    subcommand
+        .get_arguments()
+        .find(|arg| arg.get_long() == Some(CONFIG_FILE_ARG))
+        .and_then(Arg::get_env)
+        .and_then(std::env::var_os)
+        .map(PathBuf::from)
+}
+
+/// Read the options of the file, whose format is told by its extension.
+fn read(path: &Path) -> Result<serde_json::Map<String, Value>> {
+    let content = std::fs::read_to_string(path)?;
+    let value: Value = match path.extension().and_then(|extension| extension.to_str()) {
+        Some("toml") => toml::from_str(&content)?,
+        Some("yaml" | "yml") => serde_yaml::from_str(&content)?,
+        _ => bail!("The config file must have a .toml, .yaml or .yml extension"),
+    };
+    match value {
+        Value::Object(options) => Ok(options),
+        Value::Null => Ok(Default::default()),
+        _ => bail!("The config file must contain a table of options"),
+    }
+}
+
+/// The arguments setting the options of the file that are not set on the command line nor in
+/// the environment.
+fn file_args(
+    command: &Command,
+    args: &[OsString],
+    options: &serde_json::Map<String, Value>,
+) -> Result<Vec<OsString>> {
+    let mut file_args = vec![];
+    for (key, value) in options {
+        match value {
+            Value::Object(section) => {
+                let group = command
+                    .get_groups()
+                    .find(|group| group.get_id().as_str().eq_ignore_ascii_case(key))
+                    .ok_or_else(|| anyhow!("Unknown option group `{key}`"))?;
+                for (name, value) in section {
+                    let name = name.replace('_', "-");
+                    let prefixed_name = format!("{}-{name}", key.to_lowercase());
+                    let arg = group
+                        .get_args()
+                        .filter_map(|id| command.get_arguments().find(|arg| arg.get_id() == id))
+                        .find(|arg| {
+                            arg.get_long() == Some(name.as_str())
+                                || arg.get_long() == Some(prefixed_name.as_str())
+                        })
+                        .ok_or_else(|| anyhow!("Unknown option `{name}` in group `{key}`"))?;
+                    push_arg(&mut file_args, arg, args, value)?;
+                }
+            }
+            _ => {
+                let name = key.replace('_', "-");
+                let arg = command
+                    .get_arguments()
+                    .find(|arg| arg.get_long() == Some(name.as_str()))
+                    .filter(|arg| arg.get_long() != Some(CONFIG_FILE_ARG))
+                    .ok_or_else(|| anyhow!("Unknown option `{key}`"))?;
+                push_arg(&mut file_args, arg, args, value)?;
+            }
+        }
+    }
+    Ok(file_args)
+}
+
+fn push_arg(
+    file_args: &mut Vec<OsString>,
+    arg: &Arg,
+    args: &[OsString],
+    value: &Value,
+) -> Result<()> {
+    let long = format!("--{}", arg.get_long().unwrap_or_default());
+
+    // The command line and the environment take precedence over the file.
+    let on_command_line = args.iter().filter_map(|arg| arg.to_str()).any(|arg| {
+        arg == long
+            || arg
+                .strip_prefix(long.as_str())
+                .is_some_and(|value| value.starts_with('='))
+    });
+    let in_environment = arg.get_env().and_then(std::env::var_os).is_some();
+    if on_command_line || in_environment {
+        return Ok(());
+    }
+
+    if !arg.get_action().takes_values() {
+        match value {
+            Value::Bool(true) => file_args.push(long.into()),
+            Value::Bool(false) => {}
+            _ => bail!("`{long}` expects a boolean"),
+        }
+        return Ok(());
+    }
+
+    let values = match value {
+        Value::Array(values) => values.as_slice(),
+        value => std::slice::from_ref(value),
+    };
+    for value in values {
+        let value = match value {
+            Value::String(value) => value.clone(),
+            Value::Number(value) => value.to_string(),
+            Value::Bool(value) => value.to_string(),
+            _ => bail!("`{long}` expects a value or a list of values"),
+        };
+        file_args.push(format!("{long}={value}").into());
+    }
+    Ok(())
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// Poll the config file for changes and publish the options with the reloadable ones updated,
+/// see `Options::with_reloaded`. Invalid changes are logged and ignored.
+pub async fn run_reloader(
+    path: PathBuf,
+    args: Vec<OsString>,
+    options: watch::Sender<Options>,
+) -> Result<()> {
+    tracing::info!(path = %path.display(), "Watching config file...");
+    let mut exit = EXIT.subscribe();
+    let mut last_modified = modified(&path);
+    loop {
+        tokio::select! {
+            _ = exit.changed() => break,
+            _ = tokio::time::sleep(RELOAD_POLL_INTERVAL) => {}
+        }
+
+        let current_modified = modified(&path);
+        if current_modified == last_modified {
+            continue;
+        }
+        last_modified = current_modified;
+
+        let reloaded = match try_parse(&args) {
+            Ok(reloaded) => reloaded,
+            Err(e) => {
+                tracing::error!(error = ?e, "Failed to reload the config file, keeping the current options.");
+                continue;
+            }
+        };
+
+        options.send_if_modified(|options| {
+            let updated = options.with_reloaded(&reloaded);
+            if updated != reloaded {
+                tracing::warn!("Some changed options require a restart to apply and are ignored.");
+            }
+            if updated == *options {
+                return false;
+            }
+            tracing::info!("Reloaded options from the config file.");
+            *options = updated;
+            true
+        });
+    }
+
+    tracing::info!("Shutting down config file reloader...");
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse_with_file(extension: &str, content: &str, args: &[&str]) -> Result<Options> {
+        let path = std::env::temp_dir().join(format!(
+            "hermes-config-{}.{extension}",
+            rand::random::<u64>()
+        ));
+        std::fs::write(&path, content).unwrap();
+        let args: Vec<OsString> = ["hermes", "run", "--config-file"]
+            .into_iter()
+            .map(OsString::from)
+            .chain([path.clone().into_os_string()])
+            .chain(args.iter().map(OsString::from))
+            .collect();
+        let result = try_parse(&args);
+        std::fs::remove_file(&path).unwrap();
+        result
+    }
+
+    const TOML_CONFIG: &str = r#"
+        record = "/tmp/hermes.record"
+
+        [pythnet]
+        ws-addr = ["ws://a", "ws://b"]
+        http_addr = "http://a"
+        metadata-refresh-interval = "1min"
+
+        [wormhole]
+        spy-rpc-addr = "http://spy"
+
+        [rpc]
+        listen-addr = "0.0.0.0:1234"
+        rpc-ws-whitelist = ["10.0.0.0/8"]
+        api-key-required = true
+
+        [cache]
+        size = 10
+    "#;
+
+    #[test]
+    fn test_options_are_read_from_the_config_file() {
+        let Options::Run(opts) = parse_with_file("toml", TOML_CONFIG, &[]).unwrap() else {
+            panic!("Expected run options");
+        };
+        assert_eq!(opts.record, Some(PathBuf::from("/tmp/hermes.record")));
+        assert_eq!(opts.pythnet.ws_addrs, vec!["ws://a", "ws://b"]);
+        assert_eq!(opts.pythnet.http_addrs, vec!["http://a"]);
+        assert_eq!(
+            Duration::from(opts.pythnet.metadata_refresh_interval),
+            Duration::from_secs(60)
+        );
+        assert_eq!(opts.rpc.listen_addr, "0.0.0.0:1234".parse().unwrap());
+        assert_eq!(opts.rpc.ws_whitelist, vec!["10.0.0.0/8".parse().unwrap()]);
+        assert!(opts.rpc.api_key_required);
+        assert_eq!(opts.cache.size, 10);
+        assert_eq!(opts.aggregate.update_channel_capacity, 1000);
+
+        // The command line takes precedence over the file.
+        let Options::Run(opts) = parse_with_file(
+            "toml",
+            TOML_CONFIG,
+            &["--rpc-listen-addr", "127.0.0.1:1", "--cache-size=20"],
+        )
+        .unwrap() else {
+            panic!("Expected run options");
+        };
+        assert_eq!(opts.rpc.listen_addr, "127.0.0.1:1".parse().unwrap());
+        assert_eq!(opts.cache.size, 20);
+
+        let yaml_config = "
+            pythnet:
+              ws_addr: [ws://a]
+              http_addr: [http://a]
+            wormhole:
+              spy_rpc_addr: [http://spy]
+            benchmarks:
+              endpoint: https://benchmarks.pyth.network
+        ";
+        let Options::Run(opts) = parse_with_file("yaml", yaml_config, &[]).unwrap() else {
+            panic!("Expected run options");
+        };
+        assert_eq!(
+            opts.benchmarks.endpoint,
+            Some("https://benchmarks.pyth.network".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_invalid_config_files_are_rejected() {
+        let required_args = [
+            "--pythnet-ws-addr=ws://a",
+            "--pythnet-http-addr=http://a",
+            "--wormhole-spy-rpc-addr=http://spy",
+        ];
+        assert!(parse_with_file("toml", "", &required_args).is_ok());
+
+        for config in [
+            "[unknown]\nkey = 1",
+            "[rpc]\nunknown = 1",
+            "[rpc]\napi-key-required = \"yes\"",
+            "[cache]\nsize = 0",
+            "[rpc]\nlisten-addr = \"not an address\"",
+            "config-file = \"other.toml\"",
+        ] {
+            assert!(
+                parse_with_file("toml", config, &required_args).is_err(),
+                "{config}"
+            );
+        }
+        assert!(parse_with_file("json", "{}", &required_args).is_err());
+    }
+}
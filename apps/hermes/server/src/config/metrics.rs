@@ -2,7 +2,7 @@ use {clap::Args, std::net::SocketAddr};
 
 const DEFAULT_METRICS_SERVER_LISTEN_ADDR: &str = "127.0.0.1:33888";
 
-#[derive(Args, Clone, Debug)]
+#[derive(Args, Clone, Debug, PartialEq)]
 #[command(next_help_heading = "Metrics Options")]
 #[group(id = "Metrics")]
 pub struct Options {
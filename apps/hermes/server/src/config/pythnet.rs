@@ -1,24 +1,48 @@
-use {clap::Args, solana_sdk::pubkey::Pubkey};
+use {clap::Args, humantime::Duration, solana_sdk::pubkey::Pubkey};
 
 const DEFAULT_PYTHNET_MAPPING_ADDR: &str = "AHtgzX45WTKfkPG53L6WYhGEXwQkN1BVknET3sVsLL8J";
+const DEFAULT_PYTHNET_METADATA_REFRESH_INTERVAL: &str = "10min";
 
-#[derive(Args, Clone, Debug)]
+#[derive(Args, Clone, Debug, PartialEq)]
 #[command(next_help_heading = "Pythnet Options")]
 #[group(id = "Pythnet")]
 pub struct Options {
-    /// Address of a PythNet compatible websocket RPC endpoint.
+    /// Addresses of PythNet compatible websocket RPC endpoints (separated by comma).
+    ///
+    /// Hermes subscribes to all of them at once and uses the first accumulator update received
+    /// for each slot.
     #[arg(long = "pythnet-ws-addr")]
     #[arg(env = "PYTHNET_WS_ADDR")]
-    pub ws_addr: String,
+    #[arg(value_delimiter = ',')]
+    #[arg(required_unless_present = "ingest_addrs")]
+    pub ws_addrs: Vec<String>,
 
-    /// Address of a PythNet compatible HTP RPC endpoint.
+    /// Addresses of PythNet compatible HTTP RPC endpoints (separated by comma).
+    ///
+    /// Requests are sent to the first endpoint and fail over to the following ones on error.
     #[arg(long = "pythnet-http-addr")]
     #[arg(env = "PYTHNET_HTTP_ADDR")]
-    pub http_addr: String,
+    #[arg(value_delimiter = ',')]
+    #[arg(required_unless_present = "ingest_addrs")]
+    pub http_addrs: Vec<String>,
 
     /// Pyth mapping account address on Pythnet.
     #[arg(long = "pythnet-mapping-addr")]
     #[arg(default_value = DEFAULT_PYTHNET_MAPPING_ADDR)]
     #[arg(env = "PYTHNET_MAPPING_ADDR")]
     pub mapping_addr: Pubkey,
+
+    /// Interval between two polls of the price accounts for the prices of each publisher.
+    ///
+    /// Every price account is fetched on each poll, so the poller is disabled if not set and the
+    /// publisher prices are then not available.
+    #[arg(long = "pythnet-publishers-poll-interval")]
+    #[arg(env = "PYTHNET_PUBLISHERS_POLL_INTERVAL")]
+    pub publishers_poll_interval: Option<Duration>,
+
+    /// Interval between two fetches of the price feeds metadata.
+    #[arg(long = "pythnet-metadata-refresh-interval")]
+    #[arg(default_value = DEFAULT_PYTHNET_METADATA_REFRESH_INTERVAL)]
+    #[arg(env = "PYTHNET_METADATA_REFRESH_INTERVAL")]
+    pub metadata_refresh_interval: Duration,
 }
@@ -0,0 +1,18 @@
+use {clap::Args, std::path::PathBuf};
+
+#[derive(Args, Clone, Debug, PartialEq)]
+#[command(next_help_heading = "Replay Options")]
+#[group(id = "Replay")]
+pub struct Options {
+    /// Path to a file recorded with `run --record` to replay.
+    #[arg(long = "replay-file")]
+    #[arg(env = "REPLAY_FILE")]
+    pub file: PathBuf,
+
+    /// Factor applied to the original pace of the recording, 2 replays it twice as fast. A speed
+    /// of 0 replays the updates as fast as possible.
+    #[arg(long = "replay-speed")]
+    #[arg(env = "REPLAY_SPEED")]
+    #[arg(default_value = "1")]
+    pub speed: f64,
+}
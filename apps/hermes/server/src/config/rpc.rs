@@ -1,9 +1,14 @@
-use {clap::Args, ipnet::IpNet, std::net::SocketAddr};
+use {
+    clap::Args,
+    ipnet::IpNet,
+    std::{net::SocketAddr, num::NonZeroU32, path::PathBuf},
+};
 
 const DEFAULT_RPC_LISTEN_ADDR: &str = "127.0.0.1:33999";
 const DEFAULT_RPC_REQUESTER_IP_HEADER_NAME: &str = "X-Forwarded-For";
+const DEFAULT_RPC_WS_BYTES_LIMIT_PER_IP_PER_SECOND: &str = "262144"; // 256 KiB
 
-#[derive(Args, Clone, Debug)]
+#[derive(Args, Clone, Debug, PartialEq)]
 #[command(next_help_heading = "RPC Options")]
 #[group(id = "RPC")]
 pub struct Options {
@@ -13,15 +18,65 @@ pub struct Options {
     #[arg(env = "RPC_LISTEN_ADDR")]
     pub listen_addr: SocketAddr,
 
+    /// Address and port the gRPC server will bind to. The gRPC server is disabled if not set.
+    #[arg(long = "rpc-grpc-listen-addr")]
+    #[arg(env = "RPC_GRPC_LISTEN_ADDR")]
+    pub grpc_listen_addr: Option<SocketAddr>,
+
     /// Whitelisted websocket ip network addresses (separated by comma).
     #[arg(long = "rpc-ws-whitelist")]
     #[arg(value_delimiter = ',')]
     #[arg(env = "RPC_WS_WHITELIST")]
     pub ws_whitelist: Vec<IpNet>,
 
+    /// Maximum number of bytes sent per second on the websocket connections of an IP address
+    /// without an API key. Connections exceeding it are closed, unless whitelisted.
+    #[arg(long = "rpc-ws-bytes-limit-per-ip-per-second")]
+    #[arg(default_value = DEFAULT_RPC_WS_BYTES_LIMIT_PER_IP_PER_SECOND)]
+    #[arg(env = "RPC_WS_BYTES_LIMIT_PER_IP_PER_SECOND")]
+    pub ws_bytes_limit_per_ip_per_second: NonZeroU32,
+
     /// Header name (case insensitive) to fetch requester IP from.
     #[arg(long = "rpc-requester-ip-header-name")]
     #[arg(default_value = DEFAULT_RPC_REQUESTER_IP_HEADER_NAME)]
     #[arg(env = "RPC_REQUESTER_IP_HEADER_NAME")]
     pub requester_ip_header_name: String,
+
+    /// Path to a JSON file listing the API keys and their quotas.
+    ///
+    /// The file contains a list of objects with a `key`, a `name` used in metrics and optional
+    /// `requests_per_second` and `bytes_per_second` quotas.
+    #[arg(long = "rpc-api-keys-file")]
+    #[arg(env = "RPC_API_KEYS_FILE")]
+    pub api_keys_file: Option<PathBuf>,
+
+    /// Reject requests that do not carry a valid API key, on both the REST and gRPC servers.
+    #[arg(long = "rpc-api-key-required")]
+    #[arg(env = "RPC_API_KEY_REQUIRED")]
+    #[arg(default_value = "false")]
+    pub api_key_required: bool,
+
+    /// Address and port the admin server will bind to. The admin server is disabled if not set.
+    ///
+    /// The admin server exposes runtime operations and internal statistics, it should only be
+    /// reachable by operators.
+    #[arg(long = "rpc-admin-listen-addr")]
+    #[arg(env = "RPC_ADMIN_LISTEN_ADDR")]
+    #[arg(requires = "admin_token")]
+    pub admin_listen_addr: Option<SocketAddr>,
+
+    /// Bearer token authenticating the requests to the admin server. It must not be empty.
+    #[arg(long = "rpc-admin-token")]
+    #[arg(env = "RPC_ADMIN_TOKEN")]
+    #[arg(hide_env_values = true)]
+    pub admin_token: Option<String>,
+}
+
+impl Options {
+    /// Take the websocket byte limit and whitelist of `reloaded`, which apply to new messages
+    /// without reconnecting.
+    pub fn reload(&mut self, reloaded: &Options) {
+        self.ws_whitelist = reloaded.ws_whitelist.clone();
+        self.ws_bytes_limit_per_ip_per_second = reloaded.ws_bytes_limit_per_ip_per_second;
+    }
 }
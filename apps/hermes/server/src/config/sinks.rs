@@ -0,0 +1,15 @@
+use {clap::Args, std::path::PathBuf};
+
+#[derive(Args, Clone, Debug, PartialEq)]
+#[command(next_help_heading = "Sinks Options")]
+#[group(id = "Sinks")]
+pub struct Options {
+    /// Path to a JSON file listing the outbound sinks price updates are pushed to.
+    ///
+    /// Each sink has a `name`, a `type` with its own settings (e.g. a `webhook` with its `url`)
+    /// and the `feeds` it receives, each with optional `deviation_bps`, `heartbeat_seconds` and
+    /// `conf_ratio_bps` filters. No updates are pushed if not set.
+    #[arg(long = "sinks-file")]
+    #[arg(env = "SINKS_FILE")]
+    pub file: Option<PathBuf>,
+}
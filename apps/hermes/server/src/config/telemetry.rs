@@ -0,0 +1,20 @@
+use clap::Args;
+
+const DEFAULT_OTLP_SERVICE_NAME: &str = "hermes";
+
+#[derive(Args, Clone, Debug, PartialEq)]
+#[command(next_help_heading = "Telemetry Options")]
+#[group(id = "Telemetry")]
+pub struct Options {
+    /// OTLP gRPC endpoint to export the tracing spans to, e.g. `http://localhost:4317`. Spans are
+    /// only logged if not set.
+    #[arg(long = "otlp-endpoint")]
+    #[arg(env = "OTLP_ENDPOINT")]
+    pub otlp_endpoint: Option<String>,
+
+    /// Service name the exported spans are attributed to.
+    #[arg(long = "otlp-service-name")]
+    #[arg(env = "OTLP_SERVICE_NAME")]
+    #[arg(default_value = DEFAULT_OTLP_SERVICE_NAME)]
+    pub otlp_service_name: String,
+}
@@ -1,8 +1,9 @@
-use {clap::Args, solana_sdk::pubkey::Pubkey};
+use {clap::Args, humantime::Duration, solana_sdk::pubkey::Pubkey};
 
 const DEFAULT_CONTRACT_ADDR: &str = "H3fxXJ86ADW2PNuDDmZJg6mzTtPxkYCpNuQUTgmJ7AjU";
+const DEFAULT_VAA_POLL_INTERVAL: &str = "1s";
 
-#[derive(Args, Clone, Debug)]
+#[derive(Args, Clone, Debug, PartialEq)]
 #[command(next_help_heading = "Wormhole Options")]
 #[group(id = "Wormhole")]
 pub struct Options {
@@ -12,11 +13,31 @@ pub struct Options {
     #[arg(env = "WORMHOLE_CONTRACT_ADDR")]
     pub contract_addr: Pubkey,
 
-    /// gRPC endpoint for a Wormhole spy.
+    /// gRPC endpoints for Wormhole spies (separated by comma).
     ///
-    /// This can either be a standard Wormhole spy gRPC endpoint or a beacon endpoint if
-    /// load-balancing is desired.
+    /// These can either be standard Wormhole spy gRPC endpoints or beacon endpoints if
+    /// load-balancing is desired. Hermes subscribes to every spy at once and deduplicates the
+    /// VAAs by sequence, so a single stalled spy does not stop slots from completing.
     #[arg(long = "wormhole-spy-rpc-addr")]
     #[arg(env = "WORMHOLE_SPY_RPC_ADDR")]
-    pub spy_rpc_addr: String,
+    #[arg(value_delimiter = ',')]
+    #[arg(required_unless_present = "ingest_addrs")]
+    pub spy_rpc_addrs: Vec<String>,
+
+    /// gRPC endpoint of a Wormhole guardian public RPC to poll for signed VAAs.
+    ///
+    /// This is an optional extra source, usually the guardian running next to a local Pythnet
+    /// node. Pythnet only stores the messages posted by the accumulator, not the VAAs signed for
+    /// them, so the signed VAAs are polled from the guardian while the Pythnet RPC gives the
+    /// sequence to start from. It then fetches the VAAs following the latest observed sequence,
+    /// so it keeps working when every spy is down.
+    #[arg(long = "wormhole-vaa-poll-addr")]
+    #[arg(env = "WORMHOLE_VAA_POLL_ADDR")]
+    pub vaa_poll_addr: Option<String>,
+
+    /// Interval between two polls of the guardian public RPC.
+    #[arg(long = "wormhole-vaa-poll-interval")]
+    #[arg(default_value = DEFAULT_VAA_POLL_INTERVAL)]
+    #[arg(env = "WORMHOLE_VAA_POLL_INTERVAL")]
+    pub vaa_poll_interval: Duration,
 }
@@ -1,17 +1,22 @@
 use {
     anyhow::Result,
-    clap::{CommandFactory, Parser},
+    clap::CommandFactory,
     futures::future::join_all,
     lazy_static::lazy_static,
-    std::io::IsTerminal,
+    opentelemetry::KeyValue,
+    opentelemetry_otlp::WithExportConfig,
+    std::{ffi::OsString, io::IsTerminal, sync::Arc},
     tokio::{spawn, sync::watch},
+    tracing_subscriber::{layer::SubscriberExt, registry::LookupSpan},
 };
 
 mod api;
+mod cluster;
 mod config;
 mod metrics_server;
 mod network;
 mod serde;
+mod sinks;
 mod state;
 
 lazy_static! {
@@ -27,46 +32,182 @@ lazy_static! {
     pub static ref EXIT: watch::Sender<bool> = watch::channel(false).0;
 }
 
+/// Initialize the shared state of the application.
+fn new_state(
+    aggregate: &config::aggregate::Options,
+    benchmarks: &config::benchmarks::Options,
+    cache: &config::cache::Options,
+    record_file: Option<std::fs::File>,
+) -> Result<Arc<impl state::Metrics + state::Wormhole + state::Publishers + state::DerivedFeeds>> {
+    // The update broadcast channel is used to send store update notifications to the public API.
+    let (update_tx, _) =
+        tokio::sync::broadcast::channel(aggregate.update_channel_capacity as usize);
+
+    // Open the persistent cache storage if one is configured.
+    let storage = match &cache.storage_path {
+        Some(path) => {
+            tracing::info!(path = %path.display(), "Opening cache storage...");
+            let storage: Arc<dyn state::cache::storage::Storage> =
+                Arc::new(state::cache::storage::SledStorage::open(path)?);
+            Some(storage)
+        }
+        None => None,
+    };
+
+    let derived_feeds = match &aggregate.derived_feeds_file {
+        Some(path) => state::derived_feeds::load_derived_feeds(path)?,
+        None => vec![],
+    };
+    tracing::info!(count = derived_feeds.len(), "Loaded derived feeds.");
+
+    // Initialize a cache store with a circular buffer per price feed.
+    Ok(state::new(
+        update_tx,
+        cache.size,
+        storage,
+        cache.storage_retention.into(),
+        benchmarks.endpoint.clone(),
+        aggregate.readiness_staleness_threshold.into(),
+        aggregate.readiness_max_allowed_slot_lag,
+        record_file,
+        derived_feeds,
+    ))
+}
+
+/// Listen for Ctrl+C so we can set the exit flag and wait for a graceful shutdown.
+fn spawn_shutdown_handler() {
+    spawn(async move {
+        tracing::info!("Registered shutdown signal handler...");
+        tokio::signal::ctrl_c().await.unwrap();
+        tracing::info!("Shut down signal received, waiting for tasks...");
+        let _ = EXIT.send(true);
+    });
+}
+
+/// Build the layer exporting the tracing spans to an OTLP collector, if one is configured.
+fn otlp_layer<S>(
+    opts: Option<&config::telemetry::Options>,
+) -> Result<Option<tracing_opentelemetry::OpenTelemetryLayer<S, opentelemetry_sdk::trace::Tracer>>>
+where
+    S: tracing::Subscriber + for<'span> LookupSpan<'span>,
+{
+    let Some(opts) = opts else {
+        return Ok(None);
+    };
+    let Some(endpoint) = &opts.otlp_endpoint else {
+        return Ok(None);
+    };
+
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(opentelemetry_sdk::trace::config().with_resource(
+            opentelemetry_sdk::Resource::new([KeyValue::new(
+                "service.name",
+                opts.otlp_service_name.clone(),
+            )]),
+        ))
+        .install_batch(opentelemetry_sdk::runtime::Tokio)?;
+
+    Ok(Some(tracing_opentelemetry::layer().with_tracer(tracer)))
+}
+
 /// Initialize the Application. This can be invoked either by real main, or by the Geyser plugin.
-#[tracing::instrument]
-async fn init() -> Result<()> {
+#[tracing::instrument(skip(opts, args))]
+async fn init(opts: config::Options, args: Vec<OsString>) -> Result<()> {
     tracing::info!("Initializing Hermes...");
 
-    // Parse the command line arguments with StructOpt, will exit automatically on `--help` or
-    // with invalid arguments.
-    match config::Options::parse() {
+    // The options are published again when the config file changes, for the tasks that apply
+    // the reloadable options without restarting.
+    let (reloaded_options_tx, reloaded_options) = watch::channel(opts.clone());
+    let config_file_reloader = opts
+        .config_file()
+        .cloned()
+        .map(|path| spawn(config::file::run_reloader(path, args, reloaded_options_tx)));
+
+    match opts {
         config::Options::Run(opts) => {
             tracing::info!("Starting hermes service...");
 
-            // The update broadcast channel is used to send store update notifications to the public API.
-            let (update_tx, _) = tokio::sync::broadcast::channel(1000);
-
-            // Initialize a cache store with a 1000 element circular buffer.
-            let state = state::new(
-                update_tx.clone(),
-                1000,
-                opts.benchmarks.endpoint.clone(),
-                opts.aggregate.readiness_staleness_threshold.into(),
-                opts.aggregate.readiness_max_allowed_slot_lag,
-            );
-
-            // Listen for Ctrl+C so we can set the exit flag and wait for a graceful shutdown.
-            spawn(async move {
-                tracing::info!("Registered shutdown signal handler...");
-                tokio::signal::ctrl_c().await.unwrap();
-                tracing::info!("Shut down signal received, waiting for tasks...");
-                let _ = EXIT.send(true);
-            });
+            // Open the recording file if the input of Hermes is to be recorded.
+            let record_file = match &opts.record {
+                Some(path) => {
+                    tracing::info!(path = %path.display(), "Recording updates...");
+                    Some(
+                        std::fs::OpenOptions::new()
+                            .create(true)
+                            .append(true)
+                            .open(path)?,
+                    )
+                }
+                None => None,
+            };
+
+            let state = new_state(&opts.aggregate, &opts.benchmarks, &opts.cache, record_file)?;
+            spawn_shutdown_handler();
 
             // Spawn all worker tasks, and wait for all to complete (which will happen if a shutdown
             // signal has been observed).
-            let tasks = join_all(vec![
-                spawn(network::wormhole::spawn(opts.clone(), state.clone())),
-                spawn(network::pythnet::spawn(opts.clone(), state.clone())),
-                spawn(metrics_server::run(opts.clone(), state.clone())),
-                spawn(api::spawn(opts.clone(), state.clone())),
-            ])
-            .await;
+            let mut tasks = vec![
+                spawn(metrics_server::run(opts.metrics.clone(), state.clone())),
+                spawn(api::spawn(
+                    opts.rpc.clone(),
+                    state.clone(),
+                    reloaded_options.clone(),
+                )),
+                spawn(sinks::spawn(opts.sinks.clone(), state.clone())),
+                spawn(state::cache::run_storage_pruner(state.clone())),
+                spawn(cluster::spawn(opts.cluster.clone(), state.clone())),
+            ];
+            tasks.extend(config_file_reloader);
+
+            // The API nodes of a cluster consume the completed slots of the ingest nodes instead
+            // of listening to Wormhole and Pythnet.
+            if opts.cluster.ingest_addrs.is_empty() {
+                tasks.push(spawn(network::wormhole::spawn(opts.clone(), state.clone())));
+                tasks.push(spawn(network::pythnet::spawn(
+                    opts.clone(),
+                    state.clone(),
+                    reloaded_options.clone(),
+                )));
+            } else {
+                tasks.push(spawn(network::pythnet::spawn_cluster_api_node(
+                    opts.clone(),
+                    state.clone(),
+                )));
+            }
+            let tasks = join_all(tasks).await;
+
+            for task in tasks {
+                task??;
+            }
+        }
+
+        config::Options::Replay(opts) => {
+            tracing::info!("Starting hermes service in replay mode...");
+
+            let state = new_state(&opts.aggregate, &opts.benchmarks, &opts.cache, None)?;
+            spawn_shutdown_handler();
+
+            // The recording replaces the Wormhole and Pythnet listeners, the APIs keep serving the
+            // replayed state until shutdown.
+            let mut tasks = vec![
+                spawn(network::replay::spawn(opts.replay.clone(), state.clone())),
+                spawn(metrics_server::run(opts.metrics.clone(), state.clone())),
+                spawn(api::spawn(
+                    opts.rpc.clone(),
+                    state.clone(),
+                    reloaded_options.clone(),
+                )),
+                spawn(sinks::spawn(opts.sinks.clone(), state.clone())),
+                spawn(state::cache::run_storage_pruner(state.clone())),
+            ];
+            tasks.extend(config_file_reloader);
+            let tasks = join_all(tasks).await;
 
             for task in tasks {
                 task??;
@@ -75,10 +216,16 @@ async fn init() -> Result<()> {
 
         config::Options::ShowEnv(opts) => {
             // For each subcommand, scan for arguments that allow overriding with an ENV variable
-            // and print that variable.
+            // and print that variable. Subcommands share option groups so each variable is only
+            // printed once.
+            let mut printed = std::collections::HashSet::new();
             for subcommand in config::Options::command().get_subcommands() {
                 for arg in subcommand.get_arguments() {
-                    if let Some(env) = arg.get_env().and_then(|env| env.to_str()) {
+                    if let Some(env) = arg
+                        .get_env()
+                        .and_then(|env| env.to_str())
+                        .filter(|env| printed.insert(env.to_string()))
+                    {
                         // Find the defaults for this argument, if present.
                         let defaults = arg
                             .get_default_values()
@@ -107,6 +254,11 @@ async fn init() -> Result<()> {
 #[tokio::main]
 #[tracing::instrument]
 async fn main() -> Result<()> {
+    // Parse the command line arguments and the config file, will exit automatically on `--help`
+    // or with invalid arguments. They are parsed first as they configure the span exporter.
+    let args: Vec<OsString> = std::env::args_os().collect();
+    let opts = config::file::parse(&args)?;
+
     // Initialize a Tracing Subscriber
     let fmt_builder = tracing_subscriber::fmt()
         .with_file(false)
@@ -116,15 +268,25 @@ async fn main() -> Result<()> {
         .with_ansi(std::io::stderr().is_terminal());
 
     // Use the compact formatter if we're in a terminal, otherwise use the JSON formatter.
+    // The spans are also exported if an OTLP endpoint is configured.
     if std::io::stderr().is_terminal() {
-        tracing::subscriber::set_global_default(fmt_builder.compact().finish())?;
+        let subscriber = fmt_builder.compact().finish();
+        let otlp_layer = otlp_layer(opts.telemetry())?;
+        tracing::subscriber::set_global_default(subscriber.with(otlp_layer))?;
     } else {
-        tracing::subscriber::set_global_default(fmt_builder.json().finish())?;
+        let subscriber = fmt_builder.json().finish();
+        let otlp_layer = otlp_layer(opts.telemetry())?;
+        tracing::subscriber::set_global_default(subscriber.with(otlp_layer))?;
     }
 
     // Launch the application. If it fails, print the full backtrace and exit. RUST_BACKTRACE
     // should be set to 1 for this otherwise it will only print the top-level error.
-    if let Err(result) = init().await {
+    let result = init(opts, args).await;
+
+    // Export the spans still batched before exiting.
+    opentelemetry::global::shutdown_tracer_provider();
+
+    if let Err(result) = result {
         eprintln!("{}", result.backtrace());
         result.chain().for_each(|cause| eprintln!("{cause}"));
         std::process::exit(1);
@@ -1,2 +1,3 @@
 pub mod pythnet;
+pub mod replay;
 pub mod wormhole;
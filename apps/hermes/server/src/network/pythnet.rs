@@ -5,17 +5,24 @@
 use {
     crate::{
         api::types::{PriceFeedMetadata, RpcPriceIdentifier},
-        config::RunOptions,
+        config::{self, RunOptions},
         network::wormhole::{BridgeData, GuardianSet, GuardianSetData},
         state::{
-            aggregate::{AccumulatorMessages, Aggregates, Update},
-            price_feeds_metadata::{PriceFeedMeta, DEFAULT_PRICE_FEEDS_CACHE_UPDATE_INTERVAL},
+            aggregate::{AccumulatorMessages, Aggregates, Slot, Update},
+            metrics::Metrics,
+            price_feeds_metadata::PriceFeedMeta,
+            publishers::{PriceFeedPublishers, Publishers},
+            recorder::{RecordedUpdate, Recorder},
             wormhole::Wormhole,
         },
     },
     anyhow::{anyhow, Result},
     borsh::BorshDeserialize,
-    futures::stream::StreamExt,
+    futures::{future::join_all, stream::StreamExt, Future},
+    prometheus_client::{
+        encoding::{EncodeLabelSet, EncodeLabelValue},
+        metrics::{counter::Counter, family::Family, gauge::Gauge},
+    },
     pyth_sdk::PriceIdentifier,
     pyth_sdk_solana::state::{load_mapping_account, load_product_account},
     solana_account_decoder::UiAccountEncoding,
@@ -27,10 +34,25 @@ use {
     solana_sdk::{
         account::Account, bs58, commitment_config::CommitmentConfig, pubkey::Pubkey, system_program,
     },
-    std::{collections::BTreeMap, sync::Arc, time::Duration},
-    tokio::time::Instant,
+    std::{
+        collections::{BTreeMap, BTreeSet},
+        sync::{Arc, Mutex},
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    },
+    tokio::{sync::watch, time::Instant},
 };
 
+/// The number of slots remembered to deduplicate the accumulator updates received from the
+/// different endpoints, which are expected to be at most a few slots apart.
+const OBSERVED_SLOTS_SIZE: usize = 1000;
+
+/// The minimum interval between two health reports of an endpoint to the state, so that
+/// endpoints delivering an update every slot do not contend on the aggregate state lock.
+const ENDPOINT_HEALTH_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The maximum number of accounts the RPC `getMultipleAccounts` method accepts in a request.
+const MAX_MULTIPLE_ACCOUNTS: usize = 100;
+
 /// Using a Solana RPC endpoint, fetches the target GuardianSet based on an index.
 async fn fetch_guardian_set(
     client: &RpcClient,
@@ -103,7 +125,103 @@ async fn fetch_bridge_data(
     }
 }
 
-pub async fn run<S>(store: Arc<S>, pythnet_ws_endpoint: String) -> Result<()>
+/// Slots whose accumulator messages were already received from one of the endpoints.
+#[derive(Default)]
+struct ObservedSlots(Mutex<BTreeSet<Slot>>);
+
+impl ObservedSlots {
+    /// Returns true if the slot had not been observed before.
+    fn observe(&self, slot: Slot) -> bool {
+        let mut slots = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        if !slots.insert(slot) {
+            return false;
+        }
+        while slots.len() > OBSERVED_SLOTS_SIZE {
+            slots.pop_first();
+        }
+        true
+    }
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelValue)]
+pub enum UpdateStatus {
+    /// The endpoint was the first to deliver the accumulator messages of the slot.
+    First,
+    /// The accumulator messages of the slot were already delivered by another endpoint.
+    Duplicate,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct EndpointLabels {
+    endpoint: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct UpdateLabels {
+    endpoint: String,
+    status: UpdateStatus,
+}
+
+/// Per-endpoint metrics of both the websocket and the HTTP Pythnet endpoints.
+#[derive(Clone)]
+struct EndpointMetrics {
+    updates: Family<UpdateLabels, Counter>,
+    errors: Family<EndpointLabels, Counter>,
+    last_update_time: Family<EndpointLabels, Gauge>,
+}
+
+impl EndpointMetrics {
+    async fn new<S>(state: &S) -> Self
+    where
+        S: Metrics,
+    {
+        let new = Self {
+            updates: Family::default(),
+            errors: Family::default(),
+            last_update_time: Family::default(),
+        };
+
+        Metrics::register(
+            state,
+            (
+                "pythnet_endpoint_updates",
+                "Total number of accumulator updates received from each Pythnet endpoint",
+                new.updates.clone(),
+            ),
+        )
+        .await;
+        Metrics::register(
+            state,
+            (
+                "pythnet_endpoint_errors",
+                "Total number of failures of each Pythnet endpoint",
+                new.errors.clone(),
+            ),
+        )
+        .await;
+        Metrics::register(
+            state,
+            (
+                "pythnet_endpoint_last_update_timestamp",
+                "Unix timestamp of the last accumulator update received from each Pythnet endpoint",
+                new.last_update_time.clone(),
+            ),
+        )
+        .await;
+
+        new
+    }
+}
+
+/// Listens to the accumulator updates of a single Pythnet websocket endpoint.
+///
+/// Every endpoint delivers the same updates, only the first one received for a slot is stored.
+async fn run<S>(
+    store: Arc<S>,
+    pythnet_ws_endpoint: String,
+    observed_slots: Arc<ObservedSlots>,
+    metrics: EndpointMetrics,
+) -> Result<()>
 where
     S: Aggregates,
     S: Wormhole,
@@ -129,7 +247,14 @@ where
         .program_subscribe(&system_program::id(), Some(config))
         .await?;
 
+    let labels = EndpointLabels {
+        endpoint: pythnet_ws_endpoint.clone(),
+    };
+    let mut last_health_report: Option<Instant> = None;
+
     while let Some(update) = notif.next().await {
+        // The stages of the processing of a slot are timed from its notification.
+        let observed_at = Instant::now();
         let account: Account = match update.value.account.decode() {
             Some(account) => account,
             None => {
@@ -150,17 +275,60 @@ where
                 );
 
                 if candidate.to_string() == update.value.pubkey {
-                    let store = store.clone();
-                    tokio::spawn(async move {
-                        if let Err(err) = Aggregates::store_update(
+                    metrics.last_update_time.get_or_create(&labels).set(
+                        SystemTime::now()
+                            .duration_since(UNIX_EPOCH)
+                            .unwrap_or_default()
+                            .as_secs() as i64,
+                    );
+                    if last_health_report
+                        .is_none_or(|report| report.elapsed() >= ENDPOINT_HEALTH_REPORT_INTERVAL)
+                    {
+                        Aggregates::update_pythnet_endpoint_health(
                             &*store,
-                            Update::AccumulatorMessages(accumulator_messages),
+                            &pythnet_ws_endpoint,
+                            true,
                         )
-                        .await
-                        {
-                            tracing::error!(error = ?err, "Failed to store accumulator messages.");
-                        }
-                    });
+                        .await;
+                        last_health_report = Some(Instant::now());
+                    }
+
+                    let status = if observed_slots.observe(accumulator_messages.slot) {
+                        Recorder::record(
+                            &*store,
+                            RecordedUpdate::AccumulatorMessages {
+                                data: account.data.clone(),
+                            },
+                        );
+
+                        let store = store.clone();
+                        tokio::spawn(async move {
+                            Aggregates::observe_pythnet_slot(
+                                &*store,
+                                accumulator_messages.slot,
+                                observed_at,
+                            )
+                            .await;
+                            if let Err(err) = Aggregates::store_update(
+                                &*store,
+                                Update::AccumulatorMessages(accumulator_messages),
+                            )
+                            .await
+                            {
+                                tracing::error!(error = ?err, "Failed to store accumulator messages.");
+                            }
+                        });
+                        UpdateStatus::First
+                    } else {
+                        UpdateStatus::Duplicate
+                    };
+                    metrics
+                        .updates
+                        .get_or_create(&UpdateLabels {
+                            endpoint: pythnet_ws_endpoint.clone(),
+                            status,
+                        })
+                        .inc();
                 } else {
                     tracing::error!(
                         ?candidate,
@@ -179,6 +347,36 @@ where
     Err(anyhow!("Pythnet network listener connection terminated"))
 }
 
+/// Sends a request to the Pythnet HTTP endpoints in order, failing over to the next endpoint when
+/// it fails. The error of the last endpoint is returned if they all fail.
+async fn with_http_failover<T, F, Fut>(
+    pythnet_http_endpoints: &[String],
+    metrics: &EndpointMetrics,
+    request: F,
+) -> Result<T>
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut last_err = anyhow!("No Pythnet HTTP endpoint configured");
+    for endpoint in pythnet_http_endpoints {
+        match request(endpoint.clone()).await {
+            Ok(result) => return Ok(result),
+            Err(err) => {
+                tracing::warn!(error = ?err, endpoint = endpoint, "Pythnet HTTP request failed.");
+                metrics
+                    .errors
+                    .get_or_create(&EndpointLabels {
+                        endpoint: endpoint.clone(),
+                    })
+                    .inc();
+                last_err = err;
+            }
+        }
+    }
+    Err(last_err)
+}
+
 /// Fetch existing GuardianSet accounts from Wormhole.
 ///
 /// This method performs the necessary work to pull down the bridge state and associated guardian
@@ -230,102 +428,187 @@ where
     Ok(())
 }
 
-#[tracing::instrument(skip(opts, state))]
-pub async fn spawn<S>(opts: RunOptions, state: Arc<S>) -> Result<()>
+/// Fetch the price accounts of every known price feed and store their publisher components.
+async fn fetch_and_store_price_feeds_publishers<S>(
+    state: Arc<S>,
+    pythnet_http_endpoint: String,
+) -> Result<()>
+where
+    S: Aggregates,
+    S: Publishers,
+{
+    let client = RpcClient::new(pythnet_http_endpoint);
+
+    // The price feed ids are the addresses of the price accounts.
+    let price_accounts = Aggregates::get_price_feed_ids(&*state)
+        .await
+        .into_iter()
+        .map(|id| Pubkey::new_from_array(id.to_bytes()))
+        .collect::<Vec<_>>();
+
+    let mut price_feeds_publishers = Vec::with_capacity(price_accounts.len());
+    for price_accounts_chunk in price_accounts.chunks(MAX_MULTIPLE_ACCOUNTS) {
+        let accounts = client
+            .get_multiple_accounts_with_commitment(
+                price_accounts_chunk,
+                CommitmentConfig::confirmed(),
+            )
+            .await?
+            .value;
+
+        for (price_account, account) in price_accounts_chunk.iter().zip(accounts) {
+            let Some(account) = account else {
+                continue;
+            };
+            match PriceFeedPublishers::try_from_price_account(
+                PriceIdentifier::new(price_account.to_bytes()),
+                &account.data,
+            ) {
+                Ok(publishers) => price_feeds_publishers.push(publishers),
+                Err(err) => {
+                    tracing::warn!(error = ?err, %price_account, "Failed to parse price account.")
+                }
+            }
+        }
+    }
+
+    Publishers::store_price_feeds_publishers(&*state, price_feeds_publishers).await;
+    Ok(())
+}
+
+#[tracing::instrument(skip(opts, state, reloaded_options))]
+pub async fn spawn<S>(
+    opts: RunOptions,
+    state: Arc<S>,
+    mut reloaded_options: watch::Receiver<config::Options>,
+) -> Result<()>
 where
     S: Wormhole,
+    S: Publishers,
+    S: Metrics,
     S: Send + Sync + 'static,
 {
-    tracing::info!(endpoint = opts.pythnet.ws_addr, "Started Pythnet Listener.");
+    tracing::info!(endpoints = ?opts.pythnet.ws_addrs, "Started Pythnet Listener.");
 
-    // Create RpcClient instance here
-    let rpc_client = RpcClient::new(opts.pythnet.http_addr.clone());
+    let metrics = EndpointMetrics::new(&*state).await;
+    let http_addrs = opts.pythnet.http_addrs.clone();
 
-    fetch_existing_guardian_sets(
-        state.clone(),
-        opts.pythnet.http_addr.clone(),
-        opts.wormhole.contract_addr,
-    )
+    with_http_failover(&http_addrs, &metrics, |endpoint| {
+        fetch_existing_guardian_sets(state.clone(), endpoint, opts.wormhole.contract_addr)
+    })
     .await?;
 
-    let task_listener = {
+    // One listener is started per websocket endpoint. They share the observed slots so that
+    // whichever endpoint is fastest feeds the state, and a failing endpoint does not stop the
+    // updates as long as another one is connected.
+    let observed_slots = Arc::new(ObservedSlots::default());
+    let task_listeners = opts.pythnet.ws_addrs.iter().map(|pythnet_ws_endpoint| {
         let store = state.clone();
-        let pythnet_ws_endpoint = opts.pythnet.ws_addr.clone();
+        let pythnet_ws_endpoint = pythnet_ws_endpoint.clone();
+        let observed_slots = observed_slots.clone();
+        let metrics = metrics.clone();
         let mut exit = crate::EXIT.subscribe();
         tokio::spawn(async move {
+            // Register the endpoint so that readiness accounts for it before its first update.
+            Aggregates::update_pythnet_endpoint_health(&*store, &pythnet_ws_endpoint, false).await;
             loop {
                 let current_time = Instant::now();
                 tokio::select! {
                     _ = exit.changed() => break,
-                    Err(err) = run(store.clone(), pythnet_ws_endpoint.clone()) => {
-                        tracing::error!(error = ?err, "Error in Pythnet network listener.");
+                    Err(err) = run(store.clone(), pythnet_ws_endpoint.clone(), observed_slots.clone(), metrics.clone()) => {
+                        tracing::error!(error = ?err, endpoint = pythnet_ws_endpoint, "Error in Pythnet network listener.");
+                        metrics
+                            .errors
+                            .get_or_create(&EndpointLabels {
+                                endpoint: pythnet_ws_endpoint.clone(),
+                            })
+                            .inc();
+                        Aggregates::update_pythnet_endpoint_health(&*store, &pythnet_ws_endpoint, false).await;
                         if current_time.elapsed() < Duration::from_secs(30) {
-                            tracing::error!("Pythnet listener restarting too quickly. Sleep 1s.");
+                            tracing::error!(endpoint = pythnet_ws_endpoint, "Pythnet listener restarting too quickly. Sleep 1s.");
                             tokio::time::sleep(Duration::from_secs(1)).await;
                         }
                     }
                 }
             }
-            tracing::info!("Shutting down Pythnet listener...");
+            tracing::info!(endpoint = pythnet_ws_endpoint, "Shutting down Pythnet listener...");
         })
-    };
+    });
+    let task_listener = join_all(task_listeners);
 
-    let task_guardian_watcher = {
-        let store = state.clone();
-        let pythnet_http_endpoint = opts.pythnet.http_addr.clone();
+    let task_guardian_watcher = spawn_guardian_set_poller(
+        state.clone(),
+        http_addrs.clone(),
+        metrics.clone(),
+        opts.wormhole.contract_addr,
+    );
+
+    let task_publishers_poller = spawn_publishers_poller(
+        state.clone(),
+        http_addrs.clone(),
+        metrics.clone(),
+        opts.pythnet.publishers_poll_interval.map(Into::into),
+    );
+
+    let task_price_feeds_metadata_updater = {
+        let price_feeds_state = state.clone();
+        let reload_state = state.clone();
         let mut exit = crate::EXIT.subscribe();
-        tokio::spawn(async move {
-            loop {
-                tokio::select! {
-                    _ = exit.changed() => break,
-                    _ = tokio::time::sleep(Duration::from_secs(60)) => {
-                        if let Err(err) = fetch_existing_guardian_sets(
-                            store.clone(),
-                            pythnet_http_endpoint.clone(),
-                            opts.wormhole.contract_addr,
+        let mapping_addr = opts.pythnet.mapping_addr;
+        let mut refresh_interval: Duration = opts.pythnet.metadata_refresh_interval.into();
+        let fetch_and_store = move || {
+            let price_feeds_state = price_feeds_state.clone();
+            let http_addrs = http_addrs.clone();
+            let metrics = metrics.clone();
+            async move {
+                with_http_failover(&http_addrs, &metrics, |endpoint| {
+                    let price_feeds_state = price_feeds_state.clone();
+                    async move {
+                        let rpc_client = RpcClient::new(endpoint);
+                        fetch_and_store_price_feeds_metadata(
+                            price_feeds_state.as_ref(),
+                            &mapping_addr,
+                            &rpc_client,
                         )
                         .await
-                        {
-                            tracing::error!(error = ?err, "Failed to poll for new guardian sets.")
-                        }
                     }
-                }
+                })
+                .await
             }
-            tracing::info!("Shutting down Pythnet guardian set poller...");
-        })
-    };
-
-    let task_price_feeds_metadata_updater = {
-        let price_feeds_state = state.clone();
-        let mut exit = crate::EXIT.subscribe();
+        };
         tokio::spawn(async move {
             // Run fetch and store once before the loop
-            if let Err(e) = fetch_and_store_price_feeds_metadata(
-                price_feeds_state.as_ref(),
-                &opts.pythnet.mapping_addr,
-                &rpc_client,
-            )
-            .await
-            {
+            if let Err(e) = fetch_and_store().await {
                 tracing::error!(
                     "Error in initial fetching and storing price feeds metadata: {}",
                     e
                 );
             }
+            // The next refresh is due an interval after the last fetch, so reloading the options
+            // does not postpone it.
+            let mut last_fetch = Instant::now();
             loop {
                 tokio::select! {
                     _ = exit.changed() => break,
-                    _ = tokio::time::sleep(Duration::from_secs(DEFAULT_PRICE_FEEDS_CACHE_UPDATE_INTERVAL)) => {
-                        if let Err(e) = fetch_and_store_price_feeds_metadata(
-                            price_feeds_state.as_ref(),
-                            &opts.pythnet.mapping_addr,
-                            &rpc_client,
-                        )
-                        .await
-                        {
+                    // A reloaded interval applies from the last fetch.
+                    Ok(()) = reloaded_options.changed() => {
+                        if let Some(pythnet) = reloaded_options.borrow_and_update().pythnet() {
+                            refresh_interval = pythnet.metadata_refresh_interval.into();
+                        }
+                    }
+                    _ = tokio::time::sleep_until(last_fetch + refresh_interval) => {
+                        last_fetch = Instant::now();
+                        if let Err(e) = fetch_and_store().await {
                             tracing::error!("Error in fetching and storing price feeds metadata: {}", e);
                         }
                     }
+                    _ = PriceFeedMeta::price_feeds_metadata_reload_requested(&*reload_state) => {
+                        tracing::info!("Reloading price feeds metadata on request.");
+                        last_fetch = Instant::now();
+                        if let Err(e) = fetch_and_store().await {
+                            tracing::error!("Error in reloading price feeds metadata: {}", e);
+                        }
+                    }
                 }
             }
         })
@@ -334,18 +617,122 @@ where
     let _ = tokio::join!(
         task_listener,
         task_guardian_watcher,
+        task_publishers_poller,
         task_price_feeds_metadata_updater
     );
     Ok(())
 }
 
+/// Poll the guardian sets, so that a new guardian set is used as soon as it is rotated.
+fn spawn_guardian_set_poller<S>(
+    state: Arc<S>,
+    http_addrs: Vec<String>,
+    metrics: EndpointMetrics,
+    wormhole_contract_addr: Pubkey,
+) -> tokio::task::JoinHandle<()>
+where
+    S: Wormhole,
+    S: Send + Sync + 'static,
+{
+    let mut exit = crate::EXIT.subscribe();
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = exit.changed() => break,
+                _ = tokio::time::sleep(Duration::from_secs(60)) => {
+                    if let Err(err) = with_http_failover(&http_addrs, &metrics, |endpoint| {
+                        fetch_existing_guardian_sets(state.clone(), endpoint, wormhole_contract_addr)
+                    })
+                    .await
+                    {
+                        tracing::error!(error = ?err, "Failed to poll for new guardian sets.")
+                    }
+                }
+            }
+        }
+        tracing::info!("Shutting down Pythnet guardian set poller...");
+    })
+}
+
+/// Poll the publishers of the price feeds, if a poll interval is configured.
+fn spawn_publishers_poller<S>(
+    state: Arc<S>,
+    http_addrs: Vec<String>,
+    metrics: EndpointMetrics,
+    poll_interval: Option<Duration>,
+) -> tokio::task::JoinHandle<()>
+where
+    S: Aggregates,
+    S: Publishers,
+    S: Send + Sync + 'static,
+{
+    let mut exit = crate::EXIT.subscribe();
+    tokio::spawn(async move {
+        let Some(poll_interval) = poll_interval else {
+            tracing::info!("Pythnet publishers poller is disabled.");
+            return;
+        };
+        loop {
+            tokio::select! {
+                _ = exit.changed() => break,
+                _ = tokio::time::sleep(poll_interval) => {
+                    if let Err(err) = with_http_failover(&http_addrs, &metrics, |endpoint| {
+                        fetch_and_store_price_feeds_publishers(state.clone(), endpoint)
+                    })
+                    .await
+                    {
+                        tracing::error!(error = ?err, "Failed to poll the price feeds publishers.");
+                    }
+                }
+            }
+        }
+        tracing::info!("Shutting down Pythnet publishers poller...");
+    })
+}
+
+/// Poll what the API nodes of a cluster need from Pythnet. They don't listen to the accumulator
+/// updates, but they need the guardian sets to verify the slots of the ingest nodes, and serve the
+/// publishers of the price feeds.
+#[tracing::instrument(skip(opts, state))]
+pub async fn spawn_cluster_api_node<S>(opts: RunOptions, state: Arc<S>) -> Result<()>
+where
+    S: Wormhole,
+    S: Publishers,
+    S: Metrics,
+    S: Send + Sync + 'static,
+{
+    let metrics = EndpointMetrics::new(&*state).await;
+    let http_addrs = opts.pythnet.http_addrs.clone();
+
+    with_http_failover(&http_addrs, &metrics, |endpoint| {
+        fetch_existing_guardian_sets(state.clone(), endpoint, opts.wormhole.contract_addr)
+    })
+    .await?;
+
+    let _ = tokio::join!(
+        spawn_guardian_set_poller(
+            state.clone(),
+            http_addrs.clone(),
+            metrics.clone(),
+            opts.wormhole.contract_addr,
+        ),
+        spawn_publishers_poller(
+            state.clone(),
+            http_addrs,
+            metrics,
+            opts.pythnet.publishers_poll_interval.map(Into::into),
+        ),
+    );
+    Ok(())
+}
+
 pub async fn fetch_and_store_price_feeds_metadata<S>(
     state: &S,
     mapping_address: &Pubkey,
     rpc_client: &RpcClient,
 ) -> Result<Vec<PriceFeedMetadata>>
 where
-    S: PriceFeedMeta + Aggregates,
+    S: PriceFeedMeta + Aggregates + Recorder,
 {
     let price_feeds_metadata = fetch_price_feeds_metadata(mapping_address, rpc_client).await?;
     let all_ids = Aggregates::get_price_feed_ids(state).await;
@@ -356,6 +743,15 @@ where
         .filter(|metadata| all_ids.contains(&PriceIdentifier::from(metadata.id)))
         .collect();
 
+    // The metadata is refreshed periodically, only changes are recorded.
+    if state.retrieve_price_feeds_metadata().await? != filtered_metadata {
+        Recorder::record(
+            state,
+            RecordedUpdate::PriceFeedsMetadata {
+                metadata: filtered_metadata.clone(),
+            },
+        );
+    }
     state.store_price_feeds_metadata(&filtered_metadata).await?;
     Ok(filtered_metadata)
 }
@@ -0,0 +1,203 @@
+//! This module feeds the updates of a recording made with `run --record` back into Hermes, in
+//! place of the Wormhole and Pythnet listeners.
+
+use {
+    crate::{
+        config::replay::Options,
+        network::wormhole::GuardianSet,
+        state::{
+            aggregate::{AccumulatorMessages, Aggregates, Update},
+            price_feeds_metadata::PriceFeedMeta,
+            recorder::{Record, RecordedUpdate},
+            wormhole::Wormhole,
+        },
+    },
+    anyhow::{ensure, Context, Result},
+    borsh::BorshDeserialize,
+    std::{sync::Arc, time::Duration},
+    tokio::{
+        fs::File,
+        io::{AsyncBufReadExt, BufReader},
+        time::Instant,
+    },
+};
+
+/// Apply a recorded update to the state.
+async fn replay_update<S>(state: &S, update: RecordedUpdate) -> Result<()>
+where
+    S: Wormhole,
+{
+    match update {
+        RecordedUpdate::GuardianSet { id, keys } => {
+            Wormhole::update_guardian_set(state, id, GuardianSet { keys }).await;
+        }
+        RecordedUpdate::Vaa { data } => {
+            Aggregates::store_update(state, Update::Vaa(data)).await?;
+        }
+        RecordedUpdate::AccumulatorMessages { data } => {
+            let accumulator_messages = AccumulatorMessages::try_from_slice(&data)?;
+            Aggregates::store_update(state, Update::AccumulatorMessages(accumulator_messages))
+                .await?;
+        }
+        RecordedUpdate::PriceFeedsMetadata { metadata } => {
+            PriceFeedMeta::store_price_feeds_metadata(state, &metadata).await?;
+        }
+    }
+    Ok(())
+}
+
+#[tracing::instrument(skip(opts, state))]
+pub async fn spawn<S>(opts: Options, state: Arc<S>) -> Result<()>
+where
+    S: Wormhole,
+    S: Send + Sync + 'static,
+{
+    ensure!(
+        opts.speed >= 0.0 && opts.speed.is_finite(),
+        "Replay speed must be a non-negative number"
+    );
+
+    let file = File::open(&opts.file)
+        .await
+        .with_context(|| format!("Failed to open recording {}", opts.file.display()))?;
+    let mut lines = BufReader::new(file).lines();
+
+    tracing::info!(file = %opts.file.display(), speed = opts.speed, "Replaying recording.");
+
+    let mut exit = crate::EXIT.subscribe();
+    let start = Instant::now();
+    let mut first_timestamp_ms = None;
+    let mut replayed = 0usize;
+
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record = Record::parse(&line)
+            .with_context(|| format!("Failed to parse record {}", replayed + 1))?;
+
+        // Wait until the update is due, relative to the first update of the recording.
+        if opts.speed > 0.0 {
+            let first_timestamp_ms = *first_timestamp_ms.get_or_insert(record.timestamp_ms);
+            let offset =
+                Duration::from_millis(record.timestamp_ms.saturating_sub(first_timestamp_ms))
+                    .div_f64(opts.speed);
+            tokio::select! {
+                _ = tokio::time::sleep_until(start + offset) => {}
+                _ = exit.changed() => {
+                    tracing::info!("Shutting down replay...");
+                    return Ok(());
+                }
+            }
+        }
+
+        if let Err(e) = replay_update(&*state, record.update).await {
+            tracing::warn!(error = ?e, record = replayed + 1, "Failed to replay update.");
+        }
+        replayed += 1;
+    }
+
+    tracing::info!(replayed, "Finished replaying recording.");
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use {
+        super::*,
+        crate::{
+            api::types::{PriceFeedMetadata, RpcPriceIdentifier},
+            state::{
+                aggregate::{
+                    test::{create_dummy_price_feed_message, generate_update},
+                    RequestTime,
+                },
+                test::setup_state,
+            },
+        },
+        borsh::BorshSerialize,
+        pyth_sdk::PriceIdentifier,
+        pythnet_sdk::messages::Message,
+        std::io::Write,
+    };
+
+    /// The raw account data the accumulator messages are recorded as.
+    fn accumulator_account_data(accumulator_messages: &AccumulatorMessages) -> Vec<u8> {
+        (
+            accumulator_messages.magic,
+            accumulator_messages.slot,
+            accumulator_messages.ring_size,
+            accumulator_messages.raw_messages.clone(),
+        )
+            .try_to_vec()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_replay_restores_a_ready_state() {
+        let updates = generate_update(
+            vec![Message::PriceFeedMessage(create_dummy_price_feed_message(
+                100, 10, 9,
+            ))],
+            10,
+            20,
+        );
+        let records = [RecordedUpdate::GuardianSet {
+            id: 0,
+            keys: vec![[0; 20]],
+        }]
+        .into_iter()
+        .chain(updates.iter().map(|update| match update {
+            Update::Vaa(data) => RecordedUpdate::Vaa { data: data.clone() },
+            Update::AccumulatorMessages(accumulator_messages) => {
+                RecordedUpdate::AccumulatorMessages {
+                    data: accumulator_account_data(accumulator_messages),
+                }
+            }
+        }))
+        .chain([RecordedUpdate::PriceFeedsMetadata {
+            metadata: vec![PriceFeedMetadata {
+                id: RpcPriceIdentifier::new([100; 32]),
+                attributes: Default::default(),
+            }],
+        }])
+        .enumerate()
+        .map(|(i, update)| Record {
+            timestamp_ms: i as u64,
+            update,
+        });
+
+        let path = std::env::temp_dir().join(format!("hermes-replay-{}", std::process::id()));
+        let mut file = std::fs::File::create(&path).unwrap();
+        for record in records {
+            writeln!(file, "{}", serde_json::to_string(&record).unwrap()).unwrap();
+        }
+        drop(file);
+
+        let (state, _) = setup_state(10).await;
+        spawn(
+            Options {
+                file: path.clone(),
+                speed: 0.0,
+            },
+            state.clone(),
+        )
+        .await
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let price_feeds = Aggregates::get_price_feeds_with_update_data(
+            &*state,
+            &[PriceIdentifier::new([100; 32])],
+            RequestTime::Latest,
+        )
+        .await
+        .unwrap();
+        assert_eq!(price_feeds.price_feeds.len(), 1);
+        assert_eq!(price_feeds.price_feeds[0].slot, Some(10));
+
+        let (is_ready, readiness) = Aggregates::is_ready(&*state).await;
+        assert!(readiness.is_metadata_loaded);
+        assert!(is_ready);
+    }
+}
@@ -1,24 +1,49 @@
 //! Wormhole gRPC Service
 //!
-//! This module defines a service that connects to a Wormhole gRPC server and subscribes to VAA
-//! updates. These updates are then stored in Hermes and made available to the rest of the
-//! application.
+//! This module defines a service that connects to a set of Wormhole gRPC servers and subscribes to
+//! VAA updates. These updates are then stored in Hermes and made available to the rest of the
+//! application. Every source delivers the same VAAs, the state deduplicates them by sequence.
 
 use {
-    crate::{config::RunOptions, state::wormhole::Wormhole},
+    crate::{
+        config::RunOptions,
+        state::{metrics::Metrics, wormhole::Wormhole},
+    },
     anyhow::{anyhow, Result},
-    futures::StreamExt,
-    proto::spy::v1::{
-        filter_entry::Filter, spy_rpc_service_client::SpyRpcServiceClient, EmitterFilter,
-        FilterEntry, SubscribeSignedVaaRequest,
+    borsh::BorshDeserialize,
+    futures::{future::join_all, StreamExt},
+    prometheus_client::{
+        encoding::{EncodeLabelSet, EncodeLabelValue},
+        metrics::{counter::Counter, family::Family, gauge::Gauge, histogram::Histogram},
+    },
+    proto::{
+        publicrpc::v1::{
+            public_rpc_service_client::PublicRpcServiceClient, ChainId, GetSignedVaaRequest,
+            MessageId,
+        },
+        spy::v1::{
+            filter_entry::Filter, spy_rpc_service_client::SpyRpcServiceClient, EmitterFilter,
+            FilterEntry, SubscribeSignedVaaRequest,
+        },
     },
     pythnet_sdk::ACCUMULATOR_EMITTER_ADDRESS,
-    std::{sync::Arc, time::Duration},
+    serde_wormhole::RawMessage,
+    solana_client::nonblocking::rpc_client::RpcClient,
+    solana_sdk::{commitment_config::CommitmentConfig, pubkey::Pubkey},
+    std::{
+        fmt,
+        sync::Arc,
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    },
     tokio::time::Instant,
-    tonic::Request,
-    wormhole_sdk::Chain,
+    tonic::{Code, Request},
+    wormhole_sdk::{Chain, Vaa},
 };
 
+/// The maximum number of VAAs fetched from the guardian public RPC in a single poll, so that a
+/// poller far behind catches up progressively instead of flooding the state.
+const MAX_POLLED_VAAS: usize = 32;
+
 pub type VaaBytes = Vec<u8>;
 
 #[derive(Eq, PartialEq, Clone, Hash, Debug)]
@@ -67,6 +92,13 @@ pub struct GuardianSetData {
     pub _expiration_time: u32,
 }
 
+/// SequenceTracker extracted from the wormhole emitter sequence account, due to no API. It holds
+/// the sequence of the next message posted by the emitter.
+#[derive(borsh::BorshDeserialize)]
+pub struct SequenceTracker {
+    pub sequence: u64,
+}
+
 /// Wormhole `prost` compiled definitions.
 ///
 /// We use `prost` to build the protobuf definitions from the upstream Wormhole repository. Which
@@ -102,39 +134,218 @@ mod proto {
     }
 }
 
-// Launches the Wormhole gRPC service.
+/// An upstream Hermes receives VAAs from.
+#[derive(Clone, Debug)]
+enum Source {
+    /// A Wormhole spy, streaming every signed VAA of the accumulator emitter.
+    Spy { addr: String },
+    /// A Wormhole guardian public RPC, polled for the VAAs following the latest observed one.
+    /// Until a VAA is observed, it starts from the latest message the accumulator posted on
+    /// Pythnet.
+    Poll {
+        addr: String,
+        interval: Duration,
+        pythnet_http_addrs: Vec<String>,
+        wormhole_contract_addr: Pubkey,
+    },
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Spy { addr } => write!(f, "spy:{}", addr),
+            Source::Poll { addr, .. } => write!(f, "poll:{}", addr),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelValue)]
+pub enum VaaStatus {
+    /// The source was the first to deliver the VAA.
+    First,
+    /// The VAA was already delivered by another source.
+    Duplicate,
+    Invalid,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct SourceLabels {
+    source: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct VaaLabels {
+    source: String,
+    status: VaaStatus,
+}
+
+/// Per-source metrics, used to tell which upstream is lagging or unhealthy.
+#[derive(Clone)]
+struct SourceMetrics {
+    vaas: Family<VaaLabels, Counter>,
+    lag: Family<SourceLabels, Histogram>,
+    errors: Family<SourceLabels, Counter>,
+    last_vaa_time: Family<SourceLabels, Gauge>,
+}
+
+impl SourceMetrics {
+    async fn new<S>(state: &S) -> Self
+    where
+        S: Metrics,
+    {
+        let new = Self {
+            vaas: Family::default(),
+            lag: Family::new_with_constructor(|| {
+                Histogram::new([0.5, 1.0, 2.0, 3.0, 5.0, 10.0, 20.0, 30.0, 60.0, 120.0].into_iter())
+            }),
+            errors: Family::default(),
+            last_vaa_time: Family::default(),
+        };
+
+        Metrics::register(
+            state,
+            (
+                "wormhole_source_vaas",
+                "Total number of VAAs received from each Wormhole source",
+                new.vaas.clone(),
+            ),
+        )
+        .await;
+        Metrics::register(
+            state,
+            (
+                "wormhole_source_vaa_lag_seconds",
+                "Delay between the VAA timestamp and its receipt from each Wormhole source",
+                new.lag.clone(),
+            ),
+        )
+        .await;
+        Metrics::register(
+            state,
+            (
+                "wormhole_source_errors",
+                "Total number of connection failures of each Wormhole source",
+                new.errors.clone(),
+            ),
+        )
+        .await;
+        Metrics::register(
+            state,
+            (
+                "wormhole_source_last_vaa_timestamp",
+                "Unix timestamp of the last VAA received from each Wormhole source",
+                new.last_vaa_time.clone(),
+            ),
+        )
+        .await;
+
+        new
+    }
+}
+
+// Launches a Wormhole listener for every configured source.
 #[tracing::instrument(skip(opts, state))]
 pub async fn spawn<S>(opts: RunOptions, state: Arc<S>) -> Result<()>
 where
     S: Wormhole,
+    S: Metrics,
     S: Send + Sync + 'static,
 {
+    let metrics = SourceMetrics::new(&*state).await;
+
+    let mut sources: Vec<Source> = opts
+        .wormhole
+        .spy_rpc_addrs
+        .into_iter()
+        .map(|addr| Source::Spy { addr })
+        .collect();
+    if let Some(addr) = opts.wormhole.vaa_poll_addr {
+        sources.push(Source::Poll {
+            addr,
+            interval: opts.wormhole.vaa_poll_interval.into(),
+            pythnet_http_addrs: opts.pythnet.http_addrs.clone(),
+            wormhole_contract_addr: opts.wormhole.contract_addr,
+        });
+    }
+
+    join_all(
+        sources
+            .into_iter()
+            .map(|source| tokio::spawn(run_source(source, state.clone(), metrics.clone()))),
+    )
+    .await;
+
+    tracing::info!("Shutting down Wormhole gRPC service...");
+    Ok(())
+}
+
+/// Runs a single source until shutdown, restarting it whenever it fails.
+#[tracing::instrument(skip(state, metrics))]
+async fn run_source<S>(source: Source, state: Arc<S>, metrics: SourceMetrics)
+where
+    S: Wormhole,
+    S: Send + Sync + 'static,
+{
+    let labels = SourceLabels {
+        source: source.to_string(),
+    };
     let mut exit = crate::EXIT.subscribe();
     loop {
         let current_time = Instant::now();
         tokio::select! {
             _ = exit.changed() => break,
-            Err(err) = run(opts.clone(), state.clone()) => {
-                tracing::error!(error = ?err, "Wormhole gRPC service failed.");
+            Err(err) = run(&source, state.clone(), metrics.clone()) => {
+                tracing::error!(error = ?err, source = %source, "Wormhole gRPC service failed.");
+                metrics.errors.get_or_create(&labels).inc();
 
                 if current_time.elapsed() < Duration::from_secs(30) {
-                    tracing::error!("Wormhole listener restarting too quickly. Sleep 1s.");
+                    tracing::error!(source = %source, "Wormhole listener restarting too quickly. Sleep 1s.");
                     tokio::time::sleep(Duration::from_secs(1)).await;
                 }
             }
         }
     }
-    tracing::info!("Shutting down Wormhole gRPC service...");
-    Ok(())
 }
 
-#[tracing::instrument(skip(opts, state))]
-async fn run<S>(opts: RunOptions, state: Arc<S>) -> Result<()>
+async fn run<S>(source: &Source, state: Arc<S>, metrics: SourceMetrics) -> Result<()>
 where
     S: Wormhole,
     S: Send + Sync + 'static,
 {
-    let mut client = SpyRpcServiceClient::connect(opts.wormhole.spy_rpc_addr).await?;
+    match source {
+        Source::Spy { addr } => run_spy(source, addr.clone(), state, metrics).await,
+        Source::Poll {
+            addr,
+            interval,
+            pythnet_http_addrs,
+            wormhole_contract_addr,
+        } => {
+            run_poller(
+                source,
+                addr.clone(),
+                *interval,
+                pythnet_http_addrs,
+                wormhole_contract_addr,
+                state,
+                metrics,
+            )
+            .await
+        }
+    }
+}
+
+#[tracing::instrument(skip(state, metrics))]
+async fn run_spy<S>(
+    source: &Source,
+    addr: String,
+    state: Arc<S>,
+    metrics: SourceMetrics,
+) -> Result<()>
+where
+    S: Wormhole,
+    S: Send + Sync + 'static,
+{
+    let mut client = SpyRpcServiceClient::connect(addr).await?;
     let mut stream = client
         .subscribe_signed_vaa(Request::new(SubscribeSignedVaaRequest {
             filters: vec![FilterEntry {
@@ -149,12 +360,151 @@ where
 
     while let Some(Ok(message)) = stream.next().await {
         let state = state.clone();
+        let metrics = metrics.clone();
+        let source = source.to_string();
         tokio::spawn(async move {
-            if let Err(e) = state.process_message(message.vaa_bytes).await {
-                tracing::debug!(error = ?e, "Skipped VAA.");
-            }
+            process_vaa(&*state, &metrics, source, message.vaa_bytes).await;
         });
     }
 
     Err(anyhow!("Wormhole gRPC stream terminated."))
 }
+
+/// Fetch the sequence of the latest message posted by the accumulator on Pythnet, from the first
+/// Pythnet endpoint that answers.
+async fn fetch_latest_posted_sequence(
+    pythnet_http_addrs: &[String],
+    wormhole_contract_addr: &Pubkey,
+) -> Result<u64> {
+    let sequence_addr = Pubkey::find_program_address(
+        &[b"Sequence", &ACCUMULATOR_EMITTER_ADDRESS],
+        wormhole_contract_addr,
+    )
+    .0;
+
+    let mut last_err = anyhow!("No Pythnet HTTP endpoint configured");
+    for addr in pythnet_http_addrs {
+        let client = RpcClient::new(addr.clone());
+        let account = client
+            .get_account_with_commitment(&sequence_addr, CommitmentConfig::confirmed())
+            .await;
+        match account {
+            Ok(response) => match response.value {
+                Some(account) => {
+                    let tracker = SequenceTracker::deserialize(&mut account.data.as_ref())
+                        .map_err(|err| {
+                            anyhow!("Failed to deserialize Sequence account: {}", err)
+                        })?;
+                    return Ok(tracker.sequence.saturating_sub(1));
+                }
+                None => return Err(anyhow!("Sequence account not found")),
+            },
+            Err(err) => last_err = anyhow!("Failed to fetch Sequence account: {}", err),
+        }
+    }
+    Err(last_err)
+}
+
+#[tracing::instrument(skip(state, metrics))]
+async fn run_poller<S>(
+    source: &Source,
+    addr: String,
+    interval: Duration,
+    pythnet_http_addrs: &[String],
+    wormhole_contract_addr: &Pubkey,
+    state: Arc<S>,
+    metrics: SourceMetrics,
+) -> Result<()>
+where
+    S: Wormhole,
+    S: Send + Sync + 'static,
+{
+    let mut client = PublicRpcServiceClient::connect(addr).await?;
+    let mut next_sequence: Option<u64> = None;
+
+    loop {
+        tokio::time::sleep(interval).await;
+
+        // Resume after the newest VAA observed from any source, or after the last one this poller
+        // fetched if the other sources are behind. Before any VAA is observed, for instance when
+        // every spy is down from startup, start from the latest message posted on Pythnet.
+        let latest_sequence = Wormhole::latest_observed_vaa_seq(&*state)
+            .await
+            .map(|sequence| sequence + 1);
+        let mut sequence = match next_sequence.max(latest_sequence) {
+            Some(sequence) => sequence,
+            None => {
+                match fetch_latest_posted_sequence(pythnet_http_addrs, wormhole_contract_addr).await
+                {
+                    Ok(sequence) => sequence,
+                    Err(err) => {
+                        tracing::warn!(
+                            error = ?err,
+                            "Failed to fetch the starting sequence of the VAA poller."
+                        );
+                        continue;
+                    }
+                }
+            }
+        };
+
+        for _ in 0..MAX_POLLED_VAAS {
+            let response = client
+                .get_signed_vaa(Request::new(GetSignedVaaRequest {
+                    message_id: Some(MessageId {
+                        emitter_chain: ChainId::Pythnet.into(),
+                        emitter_address: hex::encode(ACCUMULATOR_EMITTER_ADDRESS),
+                        sequence,
+                    }),
+                }))
+                .await;
+
+            match response {
+                Ok(response) => {
+                    let vaa_bytes = response.into_inner().vaa_bytes;
+                    process_vaa(&*state, &metrics, source.to_string(), vaa_bytes).await;
+                    sequence += 1;
+                }
+                // The VAA has not been signed yet, try again on the next poll.
+                Err(status) if status.code() == Code::NotFound => break,
+                Err(status) => return Err(status.into()),
+            }
+        }
+
+        next_sequence = Some(sequence);
+    }
+}
+
+/// Hand a VAA received from a source to the state and record the source metrics.
+async fn process_vaa<S>(state: &S, metrics: &SourceMetrics, source: String, vaa_bytes: VaaBytes)
+where
+    S: Wormhole,
+{
+    let labels = SourceLabels {
+        source: source.clone(),
+    };
+    if let Ok(vaa) = serde_wormhole::from_slice::<Vaa<&RawMessage>>(&vaa_bytes) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        metrics
+            .lag
+            .get_or_create(&labels)
+            .observe((now - vaa.timestamp as f64).max(0.0));
+        metrics.last_vaa_time.get_or_create(&labels).set(now as i64);
+    }
+
+    let status = match state.process_message(vaa_bytes).await {
+        Ok(true) => VaaStatus::First,
+        Ok(false) => VaaStatus::Duplicate,
+        Err(e) => {
+            tracing::debug!(error = ?e, source = source, "Skipped VAA.");
+            VaaStatus::Invalid
+        }
+    };
+    metrics
+        .vaas
+        .get_or_create(&VaaLabels { source, status })
+        .inc();
+}
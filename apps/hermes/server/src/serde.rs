@@ -76,3 +76,25 @@ pub mod hex {
         }
     }
 }
+
+pub mod base64 {
+    use {
+        base64::{engine::general_purpose::STANDARD as base64_standard_engine, Engine as _},
+        serde::{de::Error, Deserialize, Deserializer, Serializer},
+    };
+
+    pub fn serialize<S>(b: &[u8], s: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        s.serialize_str(base64_standard_engine.encode(b).as_str())
+    }
+
+    pub fn deserialize<'de, D>(d: D) -> Result<Vec<u8>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s: String = Deserialize::deserialize(d)?;
+        base64_standard_engine.decode(s).map_err(D::Error::custom)
+    }
+}
@@ -0,0 +1,632 @@
+//! Outbound sinks push price updates to consumers that can't hold a websocket open, such as HTTP
+//! webhooks or message queues.
+//!
+//! Every sink listens to the aggregation events and pushes the updates of its feeds that match
+//! their `UpdateFilter`: the price deviating from the last pushed price by a number of basis
+//! points, a heartbeat once the last pushed price is old enough, or a wide confidence interval.
+//! Feeds without filter are pushed on every new price.
+
+use {
+    crate::{
+        api::types::{BinaryUpdate, EncodingType, ParsedPriceUpdate, UpdateFilter},
+        config::sinks::Options,
+        state::{
+            aggregate::{Aggregates, AggregationEvent, PriceFeedUpdate, RequestTime, Slot},
+            metrics::Metrics,
+        },
+    },
+    anyhow::{ensure, Context, Result},
+    futures::future::join_all,
+    prometheus_client::{
+        encoding::{EncodeLabelSet, EncodeLabelValue},
+        metrics::{counter::Counter, family::Family},
+    },
+    pyth_sdk::{Price, PriceIdentifier},
+    serde::{Deserialize, Serialize},
+    std::{
+        collections::{HashMap, HashSet},
+        path::Path,
+        sync::Arc,
+    },
+    tokio::sync::broadcast::error::RecvError,
+};
+
+#[cfg(test)]
+mod channel;
+mod webhook;
+
+/// A message pushed to a sink with the updates of the triggered feeds at a slot.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SinkMessage {
+    /// The name of the sink, so consumers can tell apart sinks sharing a destination.
+    pub sink: String,
+    pub slot: Slot,
+    pub parsed: Vec<ParsedPriceUpdate>,
+    /// The update data of the triggered feeds, only present if requested by the sink.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub binary: Option<BinaryUpdate>,
+}
+
+/// A destination messages are published to. Webhooks are built in, message queues such as Kafka
+/// or NATS can be supported by implementing this trait.
+#[async_trait::async_trait]
+pub trait SinkPublisher: Send + Sync {
+    /// Deliver a message, retrying transient failures if the destination allows it.
+    async fn publish(&self, message: &SinkMessage) -> Result<()>;
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SinkKind {
+    Webhook(webhook::WebhookConfig),
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct FeedConfig {
+    #[serde(with = "crate::serde::hex")]
+    pub id: [u8; 32],
+    #[serde(flatten)]
+    pub filter: UpdateFilter,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct SinkConfig {
+    pub name: String,
+    #[serde(flatten)]
+    pub kind: SinkKind,
+    /// If true, the update data of the pushed feeds is included so it can be verified on-chain.
+    #[serde(default)]
+    pub binary: bool,
+    pub feeds: Vec<FeedConfig>,
+}
+
+/// Load the sinks from a JSON file containing a list of `SinkConfig`.
+pub fn load_sinks(path: &Path) -> Result<Vec<SinkConfig>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read sinks file {}", path.display()))?;
+    let sinks: Vec<SinkConfig> = serde_json::from_str(&contents)
+        .with_context(|| format!("Failed to parse sinks file {}", path.display()))?;
+
+    let mut names = HashSet::new();
+    for sink in &sinks {
+        ensure!(
+            names.insert(sink.name.as_str()),
+            "Duplicate sink name {}",
+            sink.name
+        );
+        ensure!(!sink.feeds.is_empty(), "Sink {} has no feeds", sink.name);
+    }
+
+    Ok(sinks)
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelValue)]
+pub enum MessageStatus {
+    Success,
+    Error,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, EncodeLabelSet)]
+pub struct MessageLabels {
+    pub sink: String,
+    pub status: MessageStatus,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, EncodeLabelSet)]
+pub struct SinkLabels {
+    pub sink: String,
+}
+
+#[derive(Default)]
+pub struct SinkMetrics {
+    pub messages: Family<MessageLabels, Counter>,
+    pub retries: Family<SinkLabels, Counter>,
+    pub lagged_events: Family<SinkLabels, Counter>,
+}
+
+impl SinkMetrics {
+    async fn register<S>(&self, state: &S)
+    where
+        S: Metrics,
+    {
+        Metrics::register(
+            state,
+            (
+                "sink_messages",
+                "Total number of messages published to the sinks by status",
+                self.messages.clone(),
+            ),
+        )
+        .await;
+        Metrics::register(
+            state,
+            (
+                "sink_retries",
+                "Total number of retried deliveries to the sinks",
+                self.retries.clone(),
+            ),
+        )
+        .await;
+        Metrics::register(
+            state,
+            (
+                "sink_lagged_events",
+                "Total number of aggregation events skipped by the sinks for falling behind",
+                self.lagged_events.clone(),
+            ),
+        )
+        .await;
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+struct FeedTrigger {
+    filter: UpdateFilter,
+    last_pushed: Option<Price>,
+}
+
+impl FeedTrigger {
+    fn is_triggered(&self, price: &Price) -> bool {
+        // Unlike subscribers, sinks never push the same price twice.
+        self.last_pushed
+            .is_none_or(|last_pushed| price.publish_time > last_pushed.publish_time)
+            && self.filter.matches(price, self.last_pushed.as_ref())
+    }
+}
+
+pub struct Sink {
+    name: String,
+    binary: bool,
+    feeds: HashMap<PriceIdentifier, FeedTrigger>,
+    publisher: Arc<dyn SinkPublisher>,
+    success: Counter,
+    error: Counter,
+    lagged_events: Counter,
+}
+
+impl Sink {
+    fn new(config: SinkConfig, metrics: &SinkMetrics) -> Result<Self> {
+        let labels = SinkLabels {
+            sink: config.name.clone(),
+        };
+        let publisher: Arc<dyn SinkPublisher> = match config.kind.clone() {
+            SinkKind::Webhook(webhook) => Arc::new(
+                webhook::WebhookPublisher::new(
+                    webhook,
+                    metrics.retries.get_or_create(&labels).clone(),
+                )
+                .with_context(|| format!("Invalid webhook of sink {}", config.name))?,
+            ),
+        };
+        Ok(Self::with_publisher(config, publisher, metrics))
+    }
+
+    fn with_publisher(
+        config: SinkConfig,
+        publisher: Arc<dyn SinkPublisher>,
+        metrics: &SinkMetrics,
+    ) -> Self {
+        // Each counter is cloned in its own statement as the family is locked while a counter
+        // reference is alive.
+        let labels = |status| MessageLabels {
+            sink: config.name.clone(),
+            status,
+        };
+        let success = metrics
+            .messages
+            .get_or_create(&labels(MessageStatus::Success))
+            .clone();
+        let error = metrics
+            .messages
+            .get_or_create(&labels(MessageStatus::Error))
+            .clone();
+        let lagged_events = metrics
+            .lagged_events
+            .get_or_create(&SinkLabels {
+                sink: config.name.clone(),
+            })
+            .clone();
+        Self {
+            success,
+            error,
+            lagged_events,
+            name: config.name,
+            binary: config.binary,
+            feeds: config
+                .feeds
+                .into_iter()
+                .map(|feed| {
+                    (
+                        PriceIdentifier::new(feed.id),
+                        FeedTrigger {
+                            filter: feed.filter,
+                            last_pushed: None,
+                        },
+                    )
+                })
+                .collect(),
+            publisher,
+        }
+    }
+
+    /// Keep the price feeds whose trigger fired.
+    fn select(&self, price_feeds: Vec<PriceFeedUpdate>) -> Vec<PriceFeedUpdate> {
+        price_feeds
+            .into_iter()
+            .filter(|update| {
+                self.feeds
+                    .get(&update.price_feed.id)
+                    .is_some_and(|trigger| {
+                        trigger.is_triggered(&update.price_feed.get_price_unchecked())
+                    })
+            })
+            .collect()
+    }
+
+    /// Publish the price feeds, the triggers are only reset once the message is delivered so
+    /// that failed updates are pushed again on the next aggregation.
+    async fn push(
+        &mut self,
+        slot: Slot,
+        price_feeds: Vec<PriceFeedUpdate>,
+        binary: Option<BinaryUpdate>,
+    ) -> Result<()> {
+        let pushed_prices = price_feeds
+            .iter()
+            .map(|update| {
+                (
+                    update.price_feed.id,
+                    update.price_feed.get_price_unchecked(),
+                )
+            })
+            .collect::<Vec<_>>();
+        let message = SinkMessage {
+            sink: self.name.clone(),
+            slot,
+            parsed: price_feeds.into_iter().map(Into::into).collect(),
+            binary,
+        };
+
+        if let Err(e) = self.publisher.publish(&message).await {
+            self.error.inc();
+            return Err(e);
+        }
+        self.success.inc();
+
+        for (id, price) in pushed_prices {
+            if let Some(trigger) = self.feeds.get_mut(&id) {
+                trigger.last_pushed = Some(price);
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle_event<S>(&mut self, state: &S, event: AggregationEvent) -> Result<()>
+    where
+        S: Aggregates,
+    {
+        // Pushed prices only move forward, so out-of-order slots are skipped.
+        let slot = match event {
+            AggregationEvent::New { slot } => slot,
+            AggregationEvent::OutOfOrder { .. } => return Ok(()),
+        };
+
+        // Only the feeds updated in the slot can have a new price to push, the triggers of the
+        // others are evaluated once they are updated.
+        let price_ids = self.feeds.keys().copied().collect::<Vec<_>>();
+        let price_ids = Aggregates::get_price_feed_ids_at_slot(state, &price_ids, slot).await;
+        if price_ids.is_empty() {
+            return Ok(());
+        }
+
+        let price_feeds = Aggregates::get_price_feeds_with_update_data(
+            state,
+            &price_ids,
+            RequestTime::AtSlot(slot),
+        )
+        .await?
+        .price_feeds;
+        let price_feeds = self.select(price_feeds);
+        if price_feeds.is_empty() {
+            return Ok(());
+        }
+
+        let binary = if self.binary {
+            let price_ids = price_feeds
+                .iter()
+                .map(|update| update.price_feed.id)
+                .collect::<Vec<_>>();
+            let update_data = Aggregates::get_price_feeds_with_update_data(
+                state,
+                &price_ids,
+                RequestTime::AtSlot(slot),
+            )
+            .await?
+            .update_data;
+            Some(BinaryUpdate {
+                encoding: EncodingType::Base64,
+                data: update_data
+                    .iter()
+                    .map(|data| EncodingType::Base64.encode_str(data))
+                    .collect(),
+            })
+        } else {
+            None
+        };
+
+        self.push(slot, price_feeds, binary).await
+    }
+
+    async fn run<S>(mut self, state: Arc<S>)
+    where
+        S: Aggregates,
+    {
+        let mut receiver = Aggregates::subscribe(&*state);
+        let mut exit = crate::EXIT.subscribe();
+
+        loop {
+            let event = tokio::select! {
+                event = receiver.recv() => event,
+                _ = exit.changed() => break,
+            };
+
+            match event {
+                Ok(event) => {
+                    // Deliveries can be retried for a while, so they are cut short on shutdown.
+                    tokio::select! {
+                        result = self.handle_event(&*state, event) => {
+                            if let Err(e) = result {
+                                tracing::warn!(sink = self.name, error = ?e, "Failed to push updates.");
+                            }
+                        }
+                        _ = exit.changed() => break,
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    tracing::warn!(
+                        sink = self.name,
+                        skipped,
+                        "Sink fell behind, skipping events."
+                    );
+                    self.lagged_events.inc_by(skipped);
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+
+        tracing::info!(sink = self.name, "Shutting down sink...");
+    }
+}
+
+#[tracing::instrument(skip(opts, state))]
+pub async fn spawn<S>(opts: Options, state: Arc<S>) -> Result<()>
+where
+    S: Aggregates,
+    S: Metrics,
+    S: Send + Sync + 'static,
+{
+    let Some(path) = opts.file else {
+        return Ok(());
+    };
+
+    let metrics = SinkMetrics::default();
+    let sinks = load_sinks(&path)?
+        .into_iter()
+        .map(|config| Sink::new(config, &metrics))
+        .collect::<Result<Vec<_>>>()?;
+    metrics.register(&*state).await;
+
+    tracing::info!(count = sinks.len(), "Starting sinks.");
+    join_all(sinks.into_iter().map(|sink| sink.run(state.clone()))).await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use {
+        super::{channel::ChannelPublisher, *},
+        crate::state::{
+            aggregate::test::{
+                create_dummy_price_feed_message, generate_update,
+                store_multiple_concurrent_valid_updates,
+            },
+            test::setup_state,
+        },
+        pyth_sdk::{PriceFeed, UnixTimestamp},
+        pythnet_sdk::messages::Message,
+    };
+
+    fn price(price: i64, publish_time: UnixTimestamp) -> Price {
+        Price {
+            price,
+            conf: 1,
+            expo: -2,
+            publish_time,
+        }
+    }
+
+    fn price_feed_update(id: PriceIdentifier, price: Price) -> PriceFeedUpdate {
+        PriceFeedUpdate {
+            price_feed: PriceFeed::new(id, price, price),
+            slot: Some(10),
+            received_at: Some(price.publish_time),
+            update_data: None,
+            prev_publish_time: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sink_pushes_triggered_feeds() {
+        let deviation_feed = PriceIdentifier::new([2; 32]);
+        let heartbeat_feed = PriceIdentifier::new([3; 32]);
+        let config: SinkConfig = serde_json::from_value(serde_json::json!({
+            "name": "test",
+            "type": "webhook",
+            "url": "http://localhost",
+            "feeds": [
+                {"id": deviation_feed.to_hex(), "deviation_bps": 100},
+                {"id": heartbeat_feed.to_hex(), "heartbeat_seconds": 10},
+            ],
+        }))
+        .unwrap();
+        let (publisher, mut receiver) = ChannelPublisher::new();
+        let metrics = SinkMetrics::default();
+        let mut sink = Sink::with_publisher(config, Arc::new(publisher), &metrics);
+
+        // Both feeds are pushed the first time, then only once their trigger fires.
+        for (slot, prices) in [
+            (
+                1,
+                [
+                    (deviation_feed, price(1000, 1)),
+                    (heartbeat_feed, price(1000, 1)),
+                ],
+            ),
+            (
+                2,
+                [
+                    (deviation_feed, price(1005, 2)),
+                    (heartbeat_feed, price(2000, 2)),
+                ],
+            ),
+            (
+                3,
+                [
+                    (deviation_feed, price(1010, 3)),
+                    (heartbeat_feed, price(2000, 11)),
+                ],
+            ),
+            (
+                4,
+                [
+                    (deviation_feed, price(1019, 4)),
+                    (heartbeat_feed, price(2000, 12)),
+                ],
+            ),
+            // Prices that were already pushed are not pushed again.
+            (
+                5,
+                [
+                    (deviation_feed, price(1100, 3)),
+                    (heartbeat_feed, price(2000, 11)),
+                ],
+            ),
+        ] {
+            let price_feeds = sink.select(
+                prices
+                    .into_iter()
+                    .map(|(id, price)| price_feed_update(id, price))
+                    .collect(),
+            );
+            if !price_feeds.is_empty() {
+                sink.push(slot, price_feeds, None).await.unwrap();
+            }
+        }
+
+        let pushed = std::iter::from_fn(|| receiver.try_recv().ok())
+            .map(|message| {
+                assert_eq!(message.sink, "test");
+                (
+                    message.slot,
+                    message
+                        .parsed
+                        .into_iter()
+                        .map(|update| (PriceIdentifier::from(update.id), update.price.price))
+                        .collect::<Vec<_>>(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(pushed.len(), 2);
+        assert_eq!(pushed[0].0, 1);
+        assert_eq!(pushed[0].1.len(), 2);
+        assert_eq!(pushed[1].0, 3);
+        assert_eq!(
+            pushed[1].1.iter().collect::<HashSet<_>>(),
+            HashSet::from([&(deviation_feed, 1010), &(heartbeat_feed, 2000)])
+        );
+        assert_eq!(
+            metrics
+                .messages
+                .get_or_create(&MessageLabels {
+                    sink: "test".to_string(),
+                    status: MessageStatus::Success,
+                })
+                .get(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sink_pushes_the_feeds_updated_in_the_slot() {
+        let (state, _) = setup_state(10).await;
+        let config: SinkConfig = serde_json::from_value(serde_json::json!({
+            "name": "test",
+            "type": "webhook",
+            "url": "http://localhost",
+            "feeds": [
+                {"id": PriceIdentifier::new([100; 32]).to_hex()},
+                {"id": PriceIdentifier::new([200; 32]).to_hex()},
+            ],
+        }))
+        .unwrap();
+        let (publisher, mut receiver) = ChannelPublisher::new();
+        let mut sink = Sink::with_publisher(config, Arc::new(publisher), &SinkMetrics::default());
+
+        // Both feeds are updated at slot 10, only the first one at slot 11.
+        store_multiple_concurrent_valid_updates(
+            state.clone(),
+            generate_update(
+                vec![
+                    Message::PriceFeedMessage(create_dummy_price_feed_message(100, 10, 9)),
+                    Message::PriceFeedMessage(create_dummy_price_feed_message(200, 10, 9)),
+                ],
+                10,
+                20,
+            ),
+        )
+        .await;
+        store_multiple_concurrent_valid_updates(
+            state.clone(),
+            generate_update(
+                vec![Message::PriceFeedMessage(create_dummy_price_feed_message(
+                    100, 11, 10,
+                ))],
+                11,
+                21,
+            ),
+        )
+        .await;
+
+        for slot in [10, 11] {
+            sink.handle_event(&*state, AggregationEvent::New { slot })
+                .await
+                .unwrap();
+        }
+
+        let pushed = std::iter::from_fn(|| receiver.try_recv().ok())
+            .map(|message| {
+                (
+                    message.slot,
+                    message
+                        .parsed
+                        .into_iter()
+                        .map(|update| PriceIdentifier::from(update.id))
+                        .collect::<HashSet<_>>(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            pushed,
+            vec![
+                (
+                    10,
+                    HashSet::from([
+                        PriceIdentifier::new([100; 32]),
+                        PriceIdentifier::new([200; 32])
+                    ])
+                ),
+                (11, HashSet::from([PriceIdentifier::new([100; 32])])),
+            ]
+        );
+    }
+}
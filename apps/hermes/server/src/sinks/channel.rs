@@ -0,0 +1,27 @@
+use {
+    super::{SinkMessage, SinkPublisher},
+    anyhow::{anyhow, Result},
+    tokio::sync::mpsc,
+};
+
+/// A publisher delivering the messages to an in-process channel, used to test sinks without an
+/// external service.
+pub struct ChannelPublisher {
+    sender: mpsc::UnboundedSender<SinkMessage>,
+}
+
+impl ChannelPublisher {
+    pub fn new() -> (Self, mpsc::UnboundedReceiver<SinkMessage>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        (Self { sender }, receiver)
+    }
+}
+
+#[async_trait::async_trait]
+impl SinkPublisher for ChannelPublisher {
+    async fn publish(&self, message: &SinkMessage) -> Result<()> {
+        self.sender
+            .send(message.clone())
+            .map_err(|_| anyhow!("Channel receiver is closed"))
+    }
+}
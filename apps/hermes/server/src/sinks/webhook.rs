@@ -0,0 +1,218 @@
+use {
+    super::{SinkMessage, SinkPublisher},
+    anyhow::{anyhow, bail, Context, Result},
+    prometheus_client::metrics::counter::Counter,
+    reqwest::{
+        header::{HeaderMap, HeaderName, HeaderValue},
+        StatusCode, Url,
+    },
+    serde::Deserialize,
+    std::{collections::HashMap, time::Duration},
+};
+
+const DEFAULT_MAX_RETRIES: u32 = 5;
+const DEFAULT_INITIAL_BACKOFF_MS: u64 = 500;
+const DEFAULT_MAX_BACKOFF_MS: u64 = 30_000;
+const DEFAULT_TIMEOUT_MS: u64 = 5_000;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct WebhookConfig {
+    /// The URL the messages are POSTed to as JSON.
+    pub url: String,
+    /// Headers added to every request, e.g. to authenticate with the endpoint.
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Number of times a failed delivery is retried before the message is dropped.
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled after every failed retry.
+    #[serde(default = "default_initial_backoff_ms")]
+    pub initial_backoff_ms: u64,
+    #[serde(default = "default_max_backoff_ms")]
+    pub max_backoff_ms: u64,
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+fn default_max_retries() -> u32 {
+    DEFAULT_MAX_RETRIES
+}
+
+fn default_initial_backoff_ms() -> u64 {
+    DEFAULT_INITIAL_BACKOFF_MS
+}
+
+fn default_max_backoff_ms() -> u64 {
+    DEFAULT_MAX_BACKOFF_MS
+}
+
+fn default_timeout_ms() -> u64 {
+    DEFAULT_TIMEOUT_MS
+}
+
+pub struct WebhookPublisher {
+    client: reqwest::Client,
+    url: Url,
+    headers: HeaderMap,
+    max_retries: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    retries: Counter,
+}
+
+impl WebhookPublisher {
+    pub fn new(config: WebhookConfig, retries: Counter) -> Result<Self> {
+        let headers = config
+            .headers
+            .iter()
+            .map(|(name, value)| {
+                Ok((
+                    HeaderName::from_bytes(name.as_bytes())
+                        .with_context(|| format!("Invalid header name {}", name))?,
+                    HeaderValue::from_str(value)
+                        .with_context(|| format!("Invalid value of header {}", name))?,
+                ))
+            })
+            .collect::<Result<HeaderMap>>()?;
+
+        Ok(Self {
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_millis(config.timeout_ms))
+                .build()?,
+            url: Url::parse(&config.url)
+                .with_context(|| format!("Invalid webhook url {}", config.url))?,
+            headers,
+            max_retries: config.max_retries,
+            initial_backoff: Duration::from_millis(config.initial_backoff_ms),
+            max_backoff: Duration::from_millis(config.max_backoff_ms),
+            retries,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl SinkPublisher for WebhookPublisher {
+    async fn publish(&self, message: &SinkMessage) -> Result<()> {
+        let mut backoff = self.initial_backoff;
+        let mut attempt = 0;
+        loop {
+            let result = self
+                .client
+                .post(self.url.clone())
+                .headers(self.headers.clone())
+                .json(message)
+                .send()
+                .await;
+
+            let error = match result {
+                Ok(response) if response.status().is_success() => return Ok(()),
+                // Client errors are not going to be fixed by retrying, except for rate limits.
+                Ok(response)
+                    if response.status().is_client_error()
+                        && response.status() != StatusCode::TOO_MANY_REQUESTS =>
+                {
+                    bail!(
+                        "Webhook rejected the message with status {}",
+                        response.status()
+                    )
+                }
+                Ok(response) => anyhow!("Webhook responded with status {}", response.status()),
+                Err(e) => anyhow!(e),
+            };
+
+            if attempt >= self.max_retries {
+                return Err(error.context(format!("Delivery failed after {} retries", attempt)));
+            }
+            tracing::debug!(url = %self.url, error = ?error, ?backoff, "Retrying webhook delivery.");
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(self.max_backoff);
+            attempt += 1;
+            self.retries.inc();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use {
+        super::*,
+        axum::{http::StatusCode as AxumStatusCode, routing::post, Json, Router},
+        std::sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    /// Serve a webhook endpoint responding with the given statuses in order, then with 200.
+    fn serve(statuses: Vec<u16>) -> (Url, Arc<AtomicUsize>) {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let app = Router::new().route(
+            "/",
+            post({
+                let requests = requests.clone();
+                move |Json(message): Json<SinkMessage>| async move {
+                    assert_eq!(message.sink, "test");
+                    let request = requests.fetch_add(1, Ordering::SeqCst);
+                    let status = statuses.get(request).copied().unwrap_or(200);
+                    AxumStatusCode::from_u16(status).unwrap()
+                }
+            }),
+        );
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+        (url, requests)
+    }
+
+    fn publisher(url: Url, max_retries: u32) -> WebhookPublisher {
+        WebhookPublisher::new(
+            WebhookConfig {
+                url: url.to_string(),
+                headers: HashMap::new(),
+                max_retries,
+                initial_backoff_ms: 1,
+                max_backoff_ms: 10,
+                timeout_ms: 1_000,
+            },
+            Counter::default(),
+        )
+        .unwrap()
+    }
+
+    fn message() -> SinkMessage {
+        SinkMessage {
+            sink: "test".to_string(),
+            slot: 10,
+            parsed: vec![],
+            binary: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_webhook_retries_until_delivered() {
+        let (url, requests) = serve(vec![503, 429]);
+        let publisher = publisher(url, 3);
+
+        publisher.publish(&message()).await.unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+        assert_eq!(publisher.retries.get(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_webhook_gives_up() {
+        // Server errors are retried up to the limit.
+        let (url, requests) = serve(vec![500; 5]);
+        assert!(publisher(url, 2).publish(&message()).await.is_err());
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+
+        // Client errors are not retried.
+        let (url, requests) = serve(vec![400]);
+        assert!(publisher(url, 2).publish(&message()).await.is_err());
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+}
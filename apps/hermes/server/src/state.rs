@@ -4,29 +4,36 @@ use {
     self::{
         aggregate::{AggregateState, AggregationEvent},
         benchmarks::BenchmarksState,
-        cache::CacheState,
+        cache::{storage::Storage, CacheState},
+        derived_feeds::{DerivedFeed, DerivedFeedsState},
         metrics::MetricsState,
         price_feeds_metadata::PriceFeedMetaState,
+        publishers::PublishersState,
+        recorder::RecorderState,
         wormhole::WormholeState,
     },
     aggregate::Slot,
     prometheus_client::registry::Registry,
     reqwest::Url,
-    std::{sync::Arc, time::Duration},
+    std::{fs::File, sync::Arc, time::Duration},
     tokio::sync::broadcast::Sender,
 };
 
 pub mod aggregate;
 pub mod benchmarks;
 pub mod cache;
+pub mod derived_feeds;
 pub mod metrics;
 pub mod price_feeds_metadata;
+pub mod publishers;
+pub mod recorder;
 pub mod wormhole;
 
 // Expose State interfaces and types for other modules.
 pub use {
-    aggregate::Aggregates, benchmarks::Benchmarks, cache::Cache, metrics::Metrics,
-    price_feeds_metadata::PriceFeedMeta, wormhole::Wormhole,
+    aggregate::Aggregates, benchmarks::Benchmarks, cache::Cache, derived_feeds::DerivedFeeds,
+    metrics::Metrics, price_feeds_metadata::PriceFeedMeta, publishers::Publishers,
+    wormhole::Wormhole,
 };
 
 /// State contains all relevant shared application state.
@@ -47,23 +54,37 @@ struct State {
     /// State for accessing/storing Pyth price aggregates.
     pub aggregates: AggregateState,
 
+    /// State for the `DerivedFeeds` service for computing prices from other price feeds.
+    pub derived_feeds: DerivedFeedsState,
+
     /// State for tracking wormhole state when reading VAAs.
     pub wormhole: WormholeState,
 
+    /// State for the `Publishers` service for looking up the per-publisher price components.
+    pub publishers: PublishersState,
+
+    /// State for the `Recorder` service for capturing the input of Hermes to replay it later.
+    pub recorder: RecorderState,
+
     /// Metrics registry for tracking process metrics and timings.
     pub metrics: MetricsState,
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn new(
     update_tx: Sender<AggregationEvent>,
     cache_size: u64,
+    storage: Option<Arc<dyn Storage>>,
+    storage_retention: Duration,
     benchmarks_endpoint: Option<Url>,
     readiness_staleness_threshold: Duration,
     readiness_max_allowed_slot_lag: Slot,
-) -> Arc<impl Metrics + Wormhole> {
+    record_file: Option<File>,
+    derived_feeds: Vec<DerivedFeed>,
+) -> Arc<impl Metrics + Wormhole + Publishers + DerivedFeeds> {
     let mut metrics_registry = Registry::default();
     Arc::new(State {
-        cache: CacheState::new(cache_size),
+        cache: CacheState::new(cache_size, storage, storage_retention),
         benchmarks: BenchmarksState::new(benchmarks_endpoint),
         price_feed_meta: PriceFeedMetaState::new(),
         aggregates: AggregateState::new(
@@ -72,7 +93,10 @@ pub fn new(
             readiness_max_allowed_slot_lag,
             &mut metrics_registry,
         ),
-        wormhole: WormholeState::new(),
+        derived_feeds: DerivedFeedsState::new(derived_feeds),
+        wormhole: WormholeState::new(&mut metrics_registry),
+        publishers: PublishersState::new(),
+        recorder: RecorderState::new(record_file),
         metrics: MetricsState::new(metrics_registry),
     })
 }
@@ -80,7 +104,7 @@ pub fn new(
 #[cfg(test)]
 pub mod test {
     use {
-        super::{aggregate::AggregationEvent, Aggregates, Wormhole},
+        super::{aggregate::AggregationEvent, DerivedFeeds, Metrics, Publishers, Wormhole},
         crate::network::wormhole::GuardianSet,
         std::{sync::Arc, time::Duration},
         tokio::sync::broadcast::Receiver,
@@ -88,9 +112,22 @@ pub mod test {
 
     pub async fn setup_state(
         cache_size: u64,
-    ) -> (Arc<impl Aggregates>, Receiver<AggregationEvent>) {
+    ) -> (
+        Arc<impl Wormhole + Metrics + Publishers + DerivedFeeds>,
+        Receiver<AggregationEvent>,
+    ) {
         let (update_tx, update_rx) = tokio::sync::broadcast::channel(1000);
-        let state = super::new(update_tx, cache_size, None, Duration::from_secs(30), 10);
+        let state = super::new(
+            update_tx,
+            cache_size,
+            None,
+            Duration::default(),
+            None,
+            Duration::from_secs(30),
+            10,
+            None,
+            vec![],
+        );
 
         // Add an initial guardian set with public key 0
         Wormhole::update_guardian_set(
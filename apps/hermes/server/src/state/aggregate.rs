@@ -6,16 +6,20 @@ use pythnet_sdk::messages::TwapMessage;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use {
-    self::wormhole_merkle::{
-        construct_message_states_proofs, construct_update_data,
-        store_wormhole_merkle_verified_message, WormholeMerkleMessageProof, WormholeMerkleState,
+    self::{
+        feed_stats::PublishTimeStats,
+        wormhole_merkle::{
+            construct_message_states_proofs, construct_update_data,
+            store_wormhole_merkle_verified_message, RawMessageWithMerkleProof,
+            WormholeMerkleMessageProof, WormholeMerkleState,
+        },
     },
     crate::{
         api::types::{ParsedPublisherStakeCap, ParsedPublisherStakeCapsUpdate},
         network::wormhole::VaaBytes,
         state::{
             benchmarks::Benchmarks,
-            cache::{Cache, MessageState, MessageStateFilter},
+            cache::{Cache, MessageState, MessageStateFilter, MessageStateKey},
             price_feeds_metadata::PriceFeedMeta,
             State,
         },
@@ -24,9 +28,9 @@ use {
     borsh::BorshDeserialize,
     byteorder::BigEndian,
     prometheus_client::registry::Registry,
-    pyth_sdk::{Price, PriceFeed, PriceIdentifier},
+    pyth_sdk::{DurationInSeconds, Price, PriceFeed, PriceIdentifier},
     pythnet_sdk::{
-        messages::{Message, MessageType, PUBLISHER_STAKE_CAPS_MESSAGE_FEED_ID},
+        messages::{FeedId, Message, MessageType, PUBLISHER_STAKE_CAPS_MESSAGE_FEED_ID},
         wire::{
             from_slice,
             v1::{WormholeMessage, WormholePayload},
@@ -35,13 +39,24 @@ use {
     rust_decimal::Decimal,
     serde::Serialize,
     solana_sdk::pubkey::Pubkey,
-    std::{collections::HashSet, time::Duration},
-    tokio::sync::{
-        broadcast::{Receiver, Sender},
-        RwLock,
+    std::{
+        collections::{BTreeMap, HashMap, HashSet},
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc,
+        },
+        time::Duration,
+    },
+    tokio::{
+        sync::{
+            broadcast::{Receiver, Sender},
+            RwLock,
+        },
+        time::Instant,
     },
     wormhole_sdk::Vaa,
 };
+pub mod feed_stats;
 pub mod metrics;
 pub mod wormhole_merkle;
 
@@ -64,6 +79,28 @@ pub enum RequestTime {
     AtSlot(Slot),
 }
 
+/// An inclusive range of updates to look up, either by publish time or by slot.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RequestRange {
+    Time {
+        start: UnixTimestamp,
+        end: UnixTimestamp,
+    },
+    Slot {
+        start: Slot,
+        end: Slot,
+    },
+}
+
+/// The position of an update within a range, updates are ordered by publish time, slot and feed
+/// id. A page of a range starts after the cursor of the last update of the previous page.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct RangeCursor {
+    pub publish_time: UnixTimestamp,
+    pub slot: Slot,
+    pub feed_id: FeedId,
+}
+
 pub type RawMessage = Vec<u8>;
 
 /// An event that is emitted when an aggregation is completed.
@@ -82,6 +119,16 @@ impl AggregationEvent {
     }
 }
 
+/// Capacity of the channel notifying subscribers of the completed slots.
+const COMPLETED_SLOTS_CHANNEL_SIZE: usize = 100;
+
+/// The message states built when the aggregation of a slot completes.
+#[derive(Clone, PartialEq, Debug)]
+pub struct CompletedSlot {
+    pub slot: Slot,
+    pub message_states: Vec<MessageState>,
+}
+
 pub struct AggregateStateData {
     /// The latest completed slot. This is used to check whether a completed state is new or out of
     /// order.
@@ -103,8 +150,16 @@ pub struct AggregateStateData {
     /// The maximum allowed slot lag between the latest observed slot and the latest completed slot.
     pub readiness_max_allowed_slot_lag: Slot,
 
+    /// Time of the latest update received from each Pythnet endpoint, or `None` if the endpoint
+    /// has not delivered an update since it last (re)connected. An endpoint is healthy if it has
+    /// delivered an update within the readiness staleness threshold.
+    pub pythnet_endpoints: BTreeMap<String, Option<SystemTime>>,
+
     /// Aggregate Specific Metrics
     pub metrics: metrics::Metrics,
+
+    /// Publish time statistics of each feed.
+    pub feed_stats: feed_stats::FeedStats,
 }
 
 impl AggregateStateData {
@@ -117,7 +172,9 @@ impl AggregateStateData {
             latest_completed_slot: None,
             latest_completed_update_time: None,
             latest_observed_slot: None,
+            pythnet_endpoints: BTreeMap::new(),
             metrics: metrics::Metrics::new(metrics_registry),
+            feed_stats: feed_stats::FeedStats::new(metrics_registry),
             readiness_staleness_threshold,
             readiness_max_allowed_slot_lag,
         }
@@ -127,6 +184,10 @@ impl AggregateStateData {
 pub struct AggregateState {
     pub data: RwLock<AggregateStateData>,
     pub api_update_tx: Sender<AggregationEvent>,
+    pub completed_slot_tx: Sender<Arc<CompletedSlot>>,
+    /// The newest slot an update was sent to a subscriber for, so that only the first update
+    /// sent of a slot takes the lock of the data to be timed.
+    pub newest_sent_slot: AtomicU64,
 }
 
 impl AggregateState {
@@ -143,6 +204,8 @@ impl AggregateState {
                 metrics_registry,
             )),
             api_update_tx: update_tx,
+            completed_slot_tx: tokio::sync::broadcast::channel(COMPLETED_SLOTS_CHANNEL_SIZE).0,
+            newest_sent_slot: AtomicU64::new(0),
         }
     }
 }
@@ -173,7 +236,7 @@ pub enum Update {
     AccumulatorMessages(AccumulatorMessages),
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct PriceFeedTwap {
     pub id: PriceIdentifier,
     pub start_timestamp: UnixTimestamp,
@@ -218,6 +281,9 @@ pub struct ReadinessMetadata {
     pub latest_observed_slot: Option<Slot>,
     pub latest_completed_unix_timestamp: Option<UnixTimestamp>,
     pub price_feeds_metadata_len: usize,
+    pub has_healthy_pythnet_endpoint: bool,
+    pub healthy_pythnet_endpoints: usize,
+    pub pythnet_endpoints: usize,
 }
 
 #[async_trait::async_trait]
@@ -228,14 +294,42 @@ where
     Self: PriceFeedMeta,
 {
     fn subscribe(&self) -> Receiver<AggregationEvent>;
+    fn subscriber_count(&self) -> usize;
+    fn subscribe_completed_slots(&self) -> Receiver<Arc<CompletedSlot>>;
     async fn is_ready(&self) -> (bool, ReadinessMetadata);
+    async fn update_pythnet_endpoint_health(&self, endpoint: &str, received_update: bool);
+    /// Record when a slot was observed on Pythnet, which the stages of the slot are timed from.
+    async fn observe_pythnet_slot(&self, slot: Slot, observed_at: Instant);
+    /// Record that an update of a slot was sent to a subscriber. Only the first one is timed.
+    async fn observe_update_sent(&self, slot: Slot);
     async fn store_update(&self, update: Update) -> Result<()>;
+    async fn store_completed_slot(&self, completed_slot: CompletedSlot) -> Result<()>;
     async fn get_price_feed_ids(&self) -> HashSet<PriceIdentifier>;
+    /// Keep the price feeds that have an update at the slot. Requesting the updates of a slot
+    /// fails if any of the price feeds was not updated in it.
+    async fn get_price_feed_ids_at_slot(
+        &self,
+        price_ids: &[PriceIdentifier],
+        slot: Slot,
+    ) -> Vec<PriceIdentifier>;
     async fn get_price_feeds_with_update_data(
         &self,
         price_ids: &[PriceIdentifier],
         request_time: RequestTime,
     ) -> Result<PriceFeedsWithUpdateData>;
+    async fn get_price_feeds_with_merged_update_data(
+        &self,
+        price_ids: &[PriceIdentifier],
+        max_staleness: DurationInSeconds,
+    ) -> Result<PriceFeedsWithUpdateData>;
+    async fn get_price_feeds_range_with_update_data(
+        &self,
+        price_ids: &[PriceIdentifier],
+        range: RequestRange,
+        after: Option<RangeCursor>,
+        limit: usize,
+    ) -> Result<PriceFeedsWithUpdateData>;
+    async fn get_feed_stats(&self, key: MessageStateKey) -> Option<PublishTimeStats>;
     async fn get_latest_publisher_stake_caps_with_update_data(
         &self,
     ) -> Result<PublisherStakeCapsWithUpdateData>;
@@ -245,6 +339,14 @@ where
         start_time: RequestTime,
         end_time: RequestTime,
     ) -> Result<TwapsWithUpdateData>;
+    /// Get the TWAPs over the window ending at the updates of `end_slot`. The window starts
+    /// `window_seconds` before the publish time of the update of each feed.
+    async fn get_rolling_twaps_with_update_data(
+        &self,
+        price_ids: &[PriceIdentifier],
+        window_seconds: DurationInSeconds,
+        end_slot: Slot,
+    ) -> Result<TwapsWithUpdateData>;
 }
 
 /// Allow downcasting State into CacheState for functions that depend on the `Cache` service.
@@ -268,6 +370,18 @@ where
         self.into().api_update_tx.subscribe()
     }
 
+    /// Number of live receivers of the aggregation events, i.e. the streaming API clients and the
+    /// sinks.
+    fn subscriber_count(&self) -> usize {
+        self.into().api_update_tx.receiver_count()
+    }
+
+    /// Subscribe to the message states of every completed slot, e.g. to share them with other
+    /// instances.
+    fn subscribe_completed_slots(&self) -> Receiver<Arc<CompletedSlot>> {
+        self.into().completed_slot_tx.subscribe()
+    }
+
     /// Stores the update data in the store
     #[tracing::instrument(skip(self, update))]
     async fn store_update(&self, update: Update) -> Result<()> {
@@ -275,6 +389,7 @@ where
         // in all the updates.
         let slot = match update {
             Update::Vaa(update_vaa) => {
+                let received_at = Instant::now();
                 let vaa = serde_wormhole::from_slice::<Vaa<&serde_wormhole::RawMessage>>(
                     update_vaa.as_ref(),
                 )?;
@@ -289,12 +404,17 @@ where
                         )
                         .await?;
 
-                        self.into()
-                            .data
-                            .write()
-                            .await
-                            .metrics
-                            .observe(proof.slot, metrics::Event::Vaa);
+                        {
+                            let mut aggregate_state = self.into().data.write().await;
+                            aggregate_state
+                                .metrics
+                                .observe(proof.slot, metrics::Event::Vaa);
+                            aggregate_state.metrics.observe_stage(
+                                proof.slot,
+                                metrics::Stage::VaaReceived,
+                                received_at,
+                            );
+                        }
 
                         proof.slot
                     }
@@ -307,12 +427,17 @@ where
                 self.store_accumulator_messages(accumulator_messages)
                     .await?;
 
-                self.into()
-                    .data
-                    .write()
-                    .await
-                    .metrics
-                    .observe(slot, metrics::Event::AccumulatorMessages);
+                {
+                    let mut aggregate_state = self.into().data.write().await;
+                    aggregate_state
+                        .metrics
+                        .observe(slot, metrics::Event::AccumulatorMessages);
+                    aggregate_state.metrics.observe_stage(
+                        slot,
+                        metrics::Stage::AccumulatorStored,
+                        Instant::now(),
+                    );
+                }
                 slot
             }
         };
@@ -339,12 +464,37 @@ where
         // Once the accumulator reaches a complete state for a specific slot
         // we can build the message states
         let message_states = build_message_states(accumulator_messages, wormhole_merkle_state)?;
+        self.store_completed_slot(CompletedSlot {
+            slot,
+            message_states,
+        })
+        .await
+    }
+
+    /// Stores the message states of a completed slot, built from the updates or received from
+    /// another instance, and notifies the subscribers.
+    #[tracing::instrument(skip(self, completed_slot), fields(slot = completed_slot.slot))]
+    async fn store_completed_slot(&self, completed_slot: CompletedSlot) -> Result<()> {
+        let slot = completed_slot.slot;
+        // The completed slot is only copied if it is going to be shared.
+        let shared_completed_slot = (self.into().completed_slot_tx.receiver_count() > 0)
+            .then(|| Arc::new(completed_slot.clone()));
+        let message_states = completed_slot.message_states;
 
         let message_state_keys = message_states
             .iter()
             .map(|message_state| message_state.key())
             .collect::<HashSet<_>>();
 
+        let current_time: UnixTimestamp =
+            SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as _;
+        self.into()
+            .data
+            .write()
+            .await
+            .feed_stats
+            .observe(&message_states, current_time);
+
         tracing::info!(len = message_states.len(), "Storing Message States.");
         self.store_message_states(message_states).await?;
 
@@ -361,6 +511,7 @@ where
                     .send(AggregationEvent::New { slot })
             }
             Some(latest) if slot > latest => {
+                aggregate_state.feed_stats.retain(&message_state_keys);
                 self.prune_removed_keys(message_state_keys).await;
                 aggregate_state.latest_completed_slot.replace(slot);
                 self.into()
@@ -382,9 +533,20 @@ where
             .latest_completed_update_time
             .replace(SystemTime::now());
 
+        // The updates already set the observed slot, the completed slots received from other
+        // instances are observed here.
+        aggregate_state.latest_observed_slot = aggregate_state.latest_observed_slot.max(Some(slot));
+
         aggregate_state
             .metrics
             .observe(slot, metrics::Event::CompletedUpdate);
+        aggregate_state
+            .metrics
+            .observe_stage(slot, metrics::Stage::SlotCompleted, Instant::now());
+
+        if let Some(completed_slot) = shared_completed_slot {
+            let _ = self.into().completed_slot_tx.send(completed_slot);
+        }
 
         Ok(())
     }
@@ -405,13 +567,32 @@ where
         {
             Ok(twaps_with_update_data) => Ok(twaps_with_update_data),
             Err(e) => {
-                // TODO: Hit benchmarks if data not found in the cache
-                tracing::debug!("Update data not found in cache, falling back to Benchmarks");
+                if let (RequestTime::FirstAfter(start_time), RequestTime::FirstAfter(end_time)) =
+                    (start_time, end_time)
+                {
+                    tracing::debug!("Update data not found in cache, falling back to Benchmarks");
+                    return Benchmarks::get_verified_twaps_with_update_data(
+                        self,
+                        price_ids,
+                        (end_time - start_time) as DurationInSeconds,
+                        end_time,
+                    )
+                    .await;
+                }
                 Err(e)
             }
         }
     }
 
+    async fn get_rolling_twaps_with_update_data(
+        &self,
+        price_ids: &[PriceIdentifier],
+        window_seconds: DurationInSeconds,
+        end_slot: Slot,
+    ) -> Result<TwapsWithUpdateData> {
+        get_verified_rolling_twaps_with_update_data(self, price_ids, window_seconds, end_slot).await
+    }
+
     async fn get_price_feeds_with_update_data(
         &self,
         price_ids: &[PriceIdentifier],
@@ -429,6 +610,114 @@ where
         }
     }
 
+    /// Returns the latest price updates of the given feeds in as few update data as possible.
+    ///
+    /// Feeds updated in different slots need one update data, and so one VAA, per slot. To avoid
+    /// that, a feed can be returned at an older slot shared with other feeds as long as its price
+    /// there is at most `max_staleness` seconds older than its latest one.
+    async fn get_price_feeds_with_merged_update_data(
+        &self,
+        price_ids: &[PriceIdentifier],
+        max_staleness: DurationInSeconds,
+    ) -> Result<PriceFeedsWithUpdateData> {
+        let feed_ids: Vec<FeedId> = price_ids
+            .iter()
+            .map(|price_id| price_id.to_bytes())
+            .collect();
+        let latest_messages = self
+            .fetch_message_states(
+                feed_ids.clone(),
+                RequestTime::Latest,
+                MessageStateFilter::Only(MessageType::PriceFeedMessage),
+            )
+            .await?;
+
+        let max_staleness = UnixTimestamp::try_from(max_staleness)?;
+        let publish_times = latest_messages.iter().map(|m| m.time().publish_time);
+        let candidate_messages = match (publish_times.clone().min(), publish_times.max()) {
+            (Some(start), Some(end)) => {
+                self.fetch_message_states_range(
+                    feed_ids,
+                    RequestRange::Time {
+                        start: start.saturating_sub(max_staleness),
+                        end,
+                    },
+                    MessageStateFilter::Only(MessageType::PriceFeedMessage),
+                    None,
+                    usize::MAX,
+                )
+                .await
+            }
+            _ => vec![],
+        };
+
+        let messages = select_merged_messages(latest_messages, candidate_messages, max_staleness);
+
+        let price_feeds = messages
+            .iter()
+            .map(price_feed_update_from_message_state)
+            .collect::<Result<Vec<_>>>()?;
+
+        let update_data = construct_update_data(messages.into_iter().map(|m| m.into()).collect())?;
+
+        Ok(PriceFeedsWithUpdateData {
+            price_feeds,
+            update_data,
+        })
+    }
+
+    /// Returns the price updates of the given feeds within the range that come after the cursor,
+    /// ordered by publish time.
+    ///
+    /// The update data is grouped by slot so that updates sharing a slot share a single proof.
+    async fn get_price_feeds_range_with_update_data(
+        &self,
+        price_ids: &[PriceIdentifier],
+        range: RequestRange,
+        after: Option<RangeCursor>,
+        limit: usize,
+    ) -> Result<PriceFeedsWithUpdateData> {
+        let messages = self
+            .fetch_message_states_range(
+                price_ids
+                    .iter()
+                    .map(|price_id| price_id.to_bytes())
+                    .collect(),
+                range,
+                MessageStateFilter::Only(MessageType::PriceFeedMessage),
+                after,
+                limit,
+            )
+            .await;
+
+        let price_feeds = messages
+            .iter()
+            .map(price_feed_update_from_message_state)
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut messages_by_slot: BTreeMap<Slot, Vec<RawMessageWithMerkleProof>> = BTreeMap::new();
+        for message in messages {
+            messages_by_slot
+                .entry(message.slot)
+                .or_default()
+                .push(message.into());
+        }
+
+        let mut update_data = Vec::new();
+        for messages in messages_by_slot.into_values() {
+            update_data.extend(construct_update_data(messages)?);
+        }
+
+        Ok(PriceFeedsWithUpdateData {
+            price_feeds,
+            update_data,
+        })
+    }
+
+    async fn get_feed_stats(&self, key: MessageStateKey) -> Option<PublishTimeStats> {
+        self.into().data.read().await.feed_stats.get(&key)
+    }
+
     async fn get_latest_publisher_stake_caps_with_update_data(
         &self,
     ) -> Result<PublisherStakeCapsWithUpdateData> {
@@ -473,6 +762,58 @@ where
             .collect()
     }
 
+    async fn get_price_feed_ids_at_slot(
+        &self,
+        price_ids: &[PriceIdentifier],
+        slot: Slot,
+    ) -> Vec<PriceIdentifier> {
+        Cache::fetch_message_state_ids_at_slot(
+            self,
+            price_ids.iter().map(|id| id.to_bytes()).collect(),
+            slot,
+            MessageStateFilter::Only(MessageType::PriceFeedMessage),
+        )
+        .await
+        .into_iter()
+        .map(PriceIdentifier::new)
+        .collect()
+    }
+
+    /// Record that a Pythnet endpoint delivered an update, or reset it when its connection fails.
+    async fn update_pythnet_endpoint_health(&self, endpoint: &str, received_update: bool) {
+        let mut state_data = self.into().data.write().await;
+        let last_update_time = received_update.then(SystemTime::now);
+        state_data
+            .pythnet_endpoints
+            .insert(endpoint.to_string(), last_update_time);
+    }
+
+    async fn observe_pythnet_slot(&self, slot: Slot, observed_at: Instant) {
+        self.into()
+            .data
+            .write()
+            .await
+            .metrics
+            .observe_pythnet_slot(slot, observed_at);
+    }
+
+    async fn observe_update_sent(&self, slot: Slot) {
+        let sent_at = Instant::now();
+        if self
+            .into()
+            .newest_sent_slot
+            .fetch_max(slot, Ordering::Relaxed)
+            >= slot
+        {
+            return;
+        }
+        self.into().data.write().await.metrics.observe_stage(
+            slot,
+            metrics::Stage::FirstByteSent,
+            sent_at,
+        );
+    }
+
     async fn is_ready(&self) -> (bool, ReadinessMetadata) {
         let state_data = self.into().data.read().await;
         let price_feeds_metadata = PriceFeedMeta::retrieve_price_feeds_metadata(self)
@@ -503,8 +844,28 @@ where
         };
 
         let is_metadata_loaded = !price_feeds_metadata.is_empty();
+
+        // Only one healthy endpoint is needed as every endpoint delivers the same updates. The
+        // check is skipped if no endpoint has been registered.
+        let healthy_pythnet_endpoints = state_data
+            .pythnet_endpoints
+            .values()
+            .flatten()
+            .filter(|last_update_time| {
+                current_time
+                    .duration_since(**last_update_time)
+                    .unwrap_or(Duration::from_secs(0))
+                    < state_data.readiness_staleness_threshold
+            })
+            .count();
+        let has_healthy_pythnet_endpoint =
+            state_data.pythnet_endpoints.is_empty() || healthy_pythnet_endpoints > 0;
+
         (
-            has_completed_recently && is_not_behind && is_metadata_loaded,
+            has_completed_recently
+                && is_not_behind
+                && is_metadata_loaded
+                && has_healthy_pythnet_endpoint,
             ReadinessMetadata {
                 has_completed_recently,
                 is_not_behind,
@@ -519,6 +880,9 @@ where
                     },
                 ),
                 price_feeds_metadata_len: price_feeds_metadata.len(),
+                has_healthy_pythnet_endpoint,
+                healthy_pythnet_endpoints,
+                pythnet_endpoints: state_data.pythnet_endpoints.len(),
             },
         )
     }
@@ -556,6 +920,38 @@ fn build_message_states(
         .collect::<Result<Vec<_>>>()
 }
 
+fn price_feed_update_from_message_state(message_state: &MessageState) -> Result<PriceFeedUpdate> {
+    match message_state.message {
+        Message::PriceFeedMessage(price_feed) => Ok(PriceFeedUpdate {
+            price_feed: PriceFeed::new(
+                PriceIdentifier::new(price_feed.feed_id),
+                Price {
+                    price: price_feed.price,
+                    conf: price_feed.conf,
+                    expo: price_feed.exponent,
+                    publish_time: price_feed.publish_time,
+                },
+                Price {
+                    price: price_feed.ema_price,
+                    conf: price_feed.ema_conf,
+                    expo: price_feed.exponent,
+                    publish_time: price_feed.publish_time,
+                },
+            ),
+            received_at: Some(message_state.received_at),
+            slot: Some(message_state.slot),
+            update_data: Some(
+                construct_update_data(vec![message_state.clone().into()])?
+                    .into_iter()
+                    .next()
+                    .ok_or(anyhow!("Missing update data for message"))?,
+            ),
+            prev_publish_time: Some(price_feed.prev_publish_time),
+        }),
+        _ => Err(anyhow!("Invalid message state type")),
+    }
+}
+
 async fn get_verified_price_feeds<S>(
     state: &S,
     price_ids: &[PriceIdentifier],
@@ -577,35 +973,7 @@ where
 
     let price_feeds = messages
         .iter()
-        .map(|message_state| match message_state.message {
-            Message::PriceFeedMessage(price_feed) => Ok(PriceFeedUpdate {
-                price_feed: PriceFeed::new(
-                    PriceIdentifier::new(price_feed.feed_id),
-                    Price {
-                        price: price_feed.price,
-                        conf: price_feed.conf,
-                        expo: price_feed.exponent,
-                        publish_time: price_feed.publish_time,
-                    },
-                    Price {
-                        price: price_feed.ema_price,
-                        conf: price_feed.ema_conf,
-                        expo: price_feed.exponent,
-                        publish_time: price_feed.publish_time,
-                    },
-                ),
-                received_at: Some(message_state.received_at),
-                slot: Some(message_state.slot),
-                update_data: Some(
-                    construct_update_data(vec![message_state.clone().into()])?
-                        .into_iter()
-                        .next()
-                        .ok_or(anyhow!("Missing update data for message"))?,
-                ),
-                prev_publish_time: Some(price_feed.prev_publish_time),
-            }),
-            _ => Err(anyhow!("Invalid message state type")),
-        })
+        .map(price_feed_update_from_message_state)
         .collect::<Result<Vec<_>>>()?;
 
     let update_data = construct_update_data(messages.into_iter().map(|m| m.into()).collect())?;
@@ -616,6 +984,71 @@ where
     })
 }
 
+/// Replaces the latest message of each feed with one of the candidate messages so that the
+/// messages span as few slots as possible. A candidate is only eligible if it is at most
+/// `max_staleness` seconds older than the latest message of its feed.
+///
+/// Finding the fewest slots is a set cover problem, so slots are picked greedily: the slot with
+/// the most feeds still to cover goes first, and the most recent slot wins ties. The latest
+/// messages are candidates too, so every feed ends up covered.
+fn select_merged_messages(
+    latest_messages: Vec<MessageState>,
+    candidate_messages: Vec<MessageState>,
+    max_staleness: UnixTimestamp,
+) -> Vec<MessageState> {
+    let min_publish_times: HashMap<FeedId, UnixTimestamp> = latest_messages
+        .iter()
+        .map(|m| {
+            (
+                m.message.feed_id(),
+                m.time().publish_time.saturating_sub(max_staleness),
+            )
+        })
+        .collect();
+
+    let mut messages_by_slot: BTreeMap<Slot, HashMap<FeedId, MessageState>> = BTreeMap::new();
+    for message in candidate_messages
+        .into_iter()
+        .chain(latest_messages.iter().cloned())
+    {
+        let feed_id = message.message.feed_id();
+        if min_publish_times
+            .get(&feed_id)
+            .is_some_and(|min_publish_time| message.time().publish_time >= *min_publish_time)
+        {
+            messages_by_slot
+                .entry(message.slot)
+                .or_default()
+                .insert(feed_id, message);
+        }
+    }
+
+    let mut selected: HashMap<FeedId, MessageState> = HashMap::new();
+    while selected.len() < min_publish_times.len() {
+        // `max_by_key` returns the last maximum, which is the most recent slot.
+        let Some(slot) = messages_by_slot
+            .iter()
+            .max_by_key(|(_, messages)| {
+                messages
+                    .keys()
+                    .filter(|feed_id| !selected.contains_key(*feed_id))
+                    .count()
+            })
+            .map(|(slot, _)| *slot)
+        else {
+            break;
+        };
+        for (feed_id, message) in messages_by_slot.remove(&slot).unwrap_or_default() {
+            selected.entry(feed_id).or_insert(message);
+        }
+    }
+
+    latest_messages
+        .into_iter()
+        .map(|m| selected.remove(&m.message.feed_id()).unwrap_or(m))
+        .collect()
+}
+
 async fn get_verified_twaps_with_update_data<S>(
     state: &S,
     price_ids: &[PriceIdentifier],
@@ -643,6 +1076,53 @@ where
         )
         .await?;
 
+    build_twaps_with_update_data(start_messages, end_messages)
+}
+
+async fn get_verified_rolling_twaps_with_update_data<S>(
+    state: &S,
+    price_ids: &[PriceIdentifier],
+    window_seconds: DurationInSeconds,
+    end_slot: Slot,
+) -> Result<TwapsWithUpdateData>
+where
+    S: Cache,
+{
+    let end_messages = state
+        .fetch_message_states(
+            price_ids.iter().map(|id| id.to_bytes()).collect(),
+            RequestTime::AtSlot(end_slot),
+            MessageStateFilter::Only(MessageType::TwapMessage),
+        )
+        .await?;
+
+    // The window of each feed ends at its own update, so their start times differ.
+    let mut start_messages = Vec::with_capacity(end_messages.len());
+    for end_message in &end_messages {
+        let start_time = end_message
+            .message
+            .publish_time()
+            .saturating_sub(window_seconds as i64);
+        start_messages.extend(
+            state
+                .fetch_message_states(
+                    vec![end_message.message.feed_id()],
+                    RequestTime::FirstAfter(start_time),
+                    MessageStateFilter::Only(MessageType::TwapMessage),
+                )
+                .await?,
+        );
+    }
+
+    build_twaps_with_update_data(start_messages, end_messages)
+}
+
+/// Calculate the TWAPs between the start and end messages of each feed, which are expected in the
+/// same order, along with their update data.
+fn build_twaps_with_update_data(
+    start_messages: Vec<MessageState>,
+    end_messages: Vec<MessageState>,
+) -> Result<TwapsWithUpdateData> {
     // Verify we have matching start and end messages.
     // The cache should throw an error earlier, but checking just in case.
     if start_messages.len() != end_messages.len() {
@@ -752,7 +1232,7 @@ fn calculate_twap(start_message: &TwapMessage, end_message: &TwapMessage) -> Res
 }
 
 #[cfg(test)]
-mod test {
+pub mod test {
     use {
         super::*,
         crate::{
@@ -1113,6 +1593,17 @@ mod test {
         // Check the state is ready
         assert!(state.is_ready().await.0);
 
+        // Check a Pythnet endpoint without any update since it connected makes the state not
+        // ready until it delivers an update
+        state
+            .update_pythnet_endpoint_health("ws://pythnet", false)
+            .await;
+        assert!(!state.is_ready().await.0);
+        state
+            .update_pythnet_endpoint_health("ws://pythnet", true)
+            .await;
+        assert!(state.is_ready().await.0);
+
         // Advance the clock to make the prices stale
         let staleness_threshold = Duration::from_secs(30);
         MockClock::advance_system_time(staleness_threshold);
@@ -1191,6 +1682,139 @@ mod test {
         }
     }
 
+    #[tokio::test]
+    pub async fn test_merged_update_data_covers_feeds_with_fewest_slots() {
+        let (state, _update_rx) = setup_state(10).await;
+
+        // Feed 200 is last updated at slot 12, and the other feeds at slot 11. The older slots
+        // arrive late so that the feeds missing from slot 12 are not pruned.
+        for (slot, messages) in [
+            (12, vec![(200, 12)]),
+            (10, vec![(100, 10), (200, 10), (150, 10)]),
+            (11, vec![(100, 11), (150, 11)]),
+        ] {
+            let messages = messages
+                .into_iter()
+                .map(|(seed, publish_time)| {
+                    Message::PriceFeedMessage(create_dummy_price_feed_message(
+                        seed,
+                        publish_time,
+                        publish_time - 1,
+                    ))
+                })
+                .collect();
+            store_multiple_concurrent_valid_updates(
+                state.clone(),
+                generate_update(messages, slot, slot),
+            )
+            .await;
+        }
+
+        let price_ids = [
+            PriceIdentifier::new([100; 32]),
+            PriceIdentifier::new([200; 32]),
+            PriceIdentifier::new([150; 32]),
+        ];
+        let slots = |price_feeds_with_update_data: &PriceFeedsWithUpdateData| {
+            price_feeds_with_update_data
+                .price_feeds
+                .iter()
+                .map(|price_feed| price_feed.slot.unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        let latest = (*state)
+            .get_price_feeds_with_update_data(&price_ids, RequestTime::Latest)
+            .await
+            .unwrap();
+        assert_eq!(slots(&latest), vec![11, 12, 11]);
+        assert_eq!(latest.update_data.len(), 2);
+
+        // Without any staleness allowed, the latest prices are returned.
+        let merged = (*state)
+            .get_price_feeds_with_merged_update_data(&price_ids, 0)
+            .await
+            .unwrap();
+        assert_eq!(slots(&merged), vec![11, 12, 11]);
+        assert_eq!(merged.update_data, latest.update_data);
+
+        // Slot 10 covers every feed, but feed 200 is too stale there.
+        let merged = (*state)
+            .get_price_feeds_with_merged_update_data(&price_ids, 1)
+            .await
+            .unwrap();
+        assert_eq!(slots(&merged), vec![11, 12, 11]);
+
+        let merged = (*state)
+            .get_price_feeds_with_merged_update_data(&price_ids, 2)
+            .await
+            .unwrap();
+        assert_eq!(slots(&merged), vec![10, 10, 10]);
+        assert_eq!(merged.update_data.len(), 1);
+        assert_eq!(
+            merged
+                .price_feeds
+                .iter()
+                .map(|price_feed| price_feed.price_feed.id)
+                .collect::<Vec<_>>(),
+            price_ids
+        );
+    }
+
+    #[tokio::test]
+    pub async fn test_feed_stats_track_publish_time_gaps() {
+        let (state, _update_rx) = setup_state(10).await;
+
+        // The message of slot 11 is a repetition of the one of slot 10 and the aggregation fails
+        // at slot 12.
+        for (slot, publish_time, prev_publish_time) in
+            [(10, 10, 5), (11, 10, 5), (12, 12, 12), (13, 15, 12)]
+        {
+            store_multiple_concurrent_valid_updates(
+                state.clone(),
+                generate_update(
+                    vec![Message::PriceFeedMessage(create_dummy_price_feed_message(
+                        100,
+                        publish_time,
+                        prev_publish_time,
+                    ))],
+                    slot,
+                    slot,
+                ),
+            )
+            .await;
+        }
+
+        let key = MessageStateKey {
+            feed_id: [100; 32],
+            type_: MessageType::PriceFeedMessage,
+        };
+        let stats = (*state).get_feed_stats(key).await.unwrap();
+        assert_eq!(
+            stats,
+            PublishTimeStats {
+                updates: 2,
+                failed_aggregations: 1,
+                last_publish_time: 15,
+                gaps: vec![5, 3],
+            }
+        );
+
+        let stats = crate::api::types::RpcFeedStats::new(stats, 20);
+        assert_eq!(stats.last_update_age, 5);
+        let gap_stats = stats.publish_time_gap.unwrap();
+        assert_eq!((gap_stats.min, gap_stats.max, gap_stats.mean), (3, 5, 4.0));
+        assert_eq!((gap_stats.p50, gap_stats.p99), (3, 5));
+
+        assert!((*state)
+            .get_feed_stats(MessageStateKey {
+                feed_id: [100; 32],
+                type_: MessageType::TwapMessage,
+            })
+            .await
+            .is_none());
+    }
+
     /// Helper function to create a TWAP message with basic defaults
     pub(crate) fn create_basic_twap_message(
         feed_id: [u8; 32],
@@ -1311,6 +1935,61 @@ mod test {
         // update_data should have 2 elements, one for the start block and one for the end block.
         assert_eq!(result.update_data.len(), 2);
     }
+    #[tokio::test]
+    async fn test_get_rolling_twaps_window_ends_at_the_slot_update() {
+        let (state, _update_rx) = setup_state(10).await;
+        let feed_id = [1u8; 32];
+
+        for (sequence, (cumulative_price, publish_time, slot)) in
+            [(100, 100, 1000), (200, 150, 1050), (400, 200, 1100)]
+                .into_iter()
+                .enumerate()
+        {
+            store_multiple_concurrent_valid_updates(
+                state.clone(),
+                generate_update(
+                    vec![create_basic_twap_message(
+                        feed_id,
+                        cumulative_price,
+                        0,
+                        publish_time,
+                        publish_time - 10,
+                        slot,
+                    )],
+                    slot,
+                    20 + sequence as u64,
+                ),
+            )
+            .await;
+        }
+
+        // The window starts 50 seconds before the publish time of the update at slot 1100,
+        // regardless of the current time.
+        let result = state
+            .get_rolling_twaps_with_update_data(&[PriceIdentifier::new(feed_id)], 50, 1100)
+            .await
+            .unwrap();
+        assert_eq!(result.twaps.len(), 1);
+        assert_eq!(result.twaps[0].start_timestamp, 150);
+        assert_eq!(result.twaps[0].end_timestamp, 200);
+        assert_eq!(result.twaps[0].twap.price, 4); // (400-200)/(1100-1050) = 4
+        assert_eq!(result.update_data.len(), 2);
+
+        // A window ending at an earlier slot ends at the update of that slot.
+        let result = state
+            .get_rolling_twaps_with_update_data(&[PriceIdentifier::new(feed_id)], 50, 1050)
+            .await
+            .unwrap();
+        assert_eq!(result.twaps[0].start_timestamp, 100);
+        assert_eq!(result.twaps[0].end_timestamp, 150);
+
+        // A window starting before the oldest cached update is not available.
+        assert!(state
+            .get_rolling_twaps_with_update_data(&[PriceIdentifier::new(feed_id)], 150, 1100)
+            .await
+            .is_err());
+    }
+
     #[tokio::test]
 
     async fn test_get_verified_twaps_with_missing_messages_throws_error() {
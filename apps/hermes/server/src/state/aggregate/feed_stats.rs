@@ -0,0 +1,211 @@
+//! Publish time analytics of each feed, to spot feeds that update irregularly or often fail to
+//! aggregate.
+//!
+//! The statistics are kept per message state key and served by the stats endpoint. The Prometheus
+//! metrics are aggregated over all the feeds, by message type only, so their cardinality stays
+//! bounded however many feeds are listed.
+
+use {
+    super::UnixTimestamp,
+    crate::state::cache::{MessageState, MessageStateKey},
+    prometheus_client::{
+        encoding::{EncodeLabelSet, EncodeLabelValue},
+        metrics::{counter::Counter, family::Family, gauge::Gauge, histogram::Histogram},
+        registry::Registry,
+    },
+    pyth_sdk::DurationInSeconds,
+    pythnet_sdk::messages::{Message, MessageType},
+    std::collections::{HashMap, HashSet, VecDeque},
+};
+
+/// Number of the most recent publish time gaps kept for each feed.
+const MAX_GAP_OBSERVATIONS: usize = 100;
+
+/// Ages, in seconds, from which a feed is counted in the stale feeds metric.
+const STALE_FEED_AGES: [u64; 4] = [10, 60, 300, 3600];
+
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, EncodeLabelValue)]
+pub enum FeedMessageType {
+    PriceFeed,
+    Twap,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct MessageTypeLabels {
+    pub message_type: FeedMessageType,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct StaleFeedsLabels {
+    pub message_type: FeedMessageType,
+    pub min_age_seconds: u64,
+}
+
+#[derive(Clone, Debug, Default)]
+struct KeyStats {
+    last_publish_time: UnixTimestamp,
+    updates: u64,
+    failed_aggregations: u64,
+    gaps: VecDeque<DurationInSeconds>,
+}
+
+/// Summary of the publish times of a feed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PublishTimeStats {
+    /// Number of observed publish times with a successful aggregation.
+    pub updates: u64,
+    /// Number of observed publish times where `prev_publish_time == publish_time`, i.e. the
+    /// aggregation failed and the price was not updated.
+    pub failed_aggregations: u64,
+    pub last_publish_time: UnixTimestamp,
+    /// The most recent gaps between the publish time and the previous publish time, oldest first.
+    pub gaps: Vec<DurationInSeconds>,
+}
+
+#[derive(Clone, Debug)]
+pub struct FeedStats {
+    keys: HashMap<MessageStateKey, KeyStats>,
+    publish_time_gap: Family<MessageTypeLabels, Histogram>,
+    failed_aggregations: Family<MessageTypeLabels, Counter>,
+    stale_feeds: Family<StaleFeedsLabels, Gauge>,
+}
+
+impl FeedStats {
+    pub fn new(metrics_registry: &mut Registry) -> Self {
+        let new = Self {
+            keys: HashMap::new(),
+            publish_time_gap: Family::new_with_constructor(|| {
+                Histogram::new(
+                    [
+                        1.0, 2.0, 3.0, 5.0, 10.0, 20.0, 30.0, 60.0, 120.0, 300.0, 600.0, 3600.0,
+                    ]
+                    .into_iter(),
+                )
+            }),
+            failed_aggregations: Family::default(),
+            stale_feeds: Family::default(),
+        };
+
+        {
+            let publish_time_gap = new.publish_time_gap.clone();
+            let failed_aggregations = new.failed_aggregations.clone();
+            let stale_feeds = new.stale_feeds.clone();
+
+            metrics_registry.register(
+                "aggregate_feed_publish_time_gap_seconds",
+                "Gap between the publish time of the feed updates and their previous publish time",
+                publish_time_gap,
+            );
+
+            metrics_registry.register(
+                "aggregate_feed_failed_aggregations",
+                "Total number of feed updates with a failed aggregation",
+                failed_aggregations,
+            );
+
+            metrics_registry.register(
+                "aggregate_stale_feeds",
+                "Number of feeds whose latest publish time is at least min_age_seconds old",
+                stale_feeds,
+            );
+        }
+
+        new
+    }
+
+    /// Observe the message states of a completed slot. Only the messages with a publish time newer
+    /// than the last observed one of their feed are counted, because unchanged messages are
+    /// repeated in every slot and older slots may complete late.
+    pub fn observe(&mut self, message_states: &[MessageState], now: UnixTimestamp) {
+        for message_state in message_states {
+            let (message_type, publish_time, prev_publish_time) = match &message_state.message {
+                Message::PriceFeedMessage(message) => (
+                    FeedMessageType::PriceFeed,
+                    message.publish_time,
+                    message.prev_publish_time,
+                ),
+                Message::TwapMessage(message) => (
+                    FeedMessageType::Twap,
+                    message.publish_time,
+                    message.prev_publish_time,
+                ),
+                _ => continue,
+            };
+
+            let stats = self.keys.entry(message_state.key()).or_default();
+            if publish_time <= stats.last_publish_time {
+                continue;
+            }
+            stats.last_publish_time = publish_time;
+
+            let labels = MessageTypeLabels { message_type };
+            if publish_time == prev_publish_time {
+                stats.failed_aggregations += 1;
+                self.failed_aggregations.get_or_create(&labels).inc();
+                continue;
+            }
+
+            let gap = publish_time.saturating_sub(prev_publish_time).max(0) as DurationInSeconds;
+            stats.updates += 1;
+            stats.gaps.push_back(gap);
+            if stats.gaps.len() > MAX_GAP_OBSERVATIONS {
+                stats.gaps.pop_front();
+            }
+            self.publish_time_gap
+                .get_or_create(&labels)
+                .observe(gap as f64);
+        }
+
+        self.update_stale_feeds(now);
+    }
+
+    fn update_stale_feeds(&self, now: UnixTimestamp) {
+        let mut stale_feeds: HashMap<StaleFeedsLabels, i64> = HashMap::new();
+        for message_type in [FeedMessageType::PriceFeed, FeedMessageType::Twap] {
+            for min_age_seconds in STALE_FEED_AGES {
+                stale_feeds.insert(
+                    StaleFeedsLabels {
+                        message_type,
+                        min_age_seconds,
+                    },
+                    0,
+                );
+            }
+        }
+
+        for (key, stats) in &self.keys {
+            let message_type = match key.type_ {
+                MessageType::PriceFeedMessage => FeedMessageType::PriceFeed,
+                MessageType::TwapMessage => FeedMessageType::Twap,
+                _ => continue,
+            };
+            let age = now.saturating_sub(stats.last_publish_time).max(0) as u64;
+            for min_age_seconds in STALE_FEED_AGES.into_iter().filter(|&min| age >= min) {
+                *stale_feeds
+                    .entry(StaleFeedsLabels {
+                        message_type,
+                        min_age_seconds,
+                    })
+                    .or_default() += 1;
+            }
+        }
+
+        for (labels, count) in stale_feeds {
+            self.stale_feeds.get_or_create(&labels).set(count);
+        }
+    }
+
+    pub fn get(&self, key: &MessageStateKey) -> Option<PublishTimeStats> {
+        self.keys.get(key).map(|stats| PublishTimeStats {
+            updates: stats.updates,
+            failed_aggregations: stats.failed_aggregations,
+            last_publish_time: stats.last_publish_time,
+            gaps: stats.gaps.iter().copied().collect(),
+        })
+    }
+
+    /// Forget the feeds that are no longer in the accumulator.
+    pub fn retain(&mut self, keys: &HashSet<MessageStateKey>) {
+        self.keys.retain(|key, _| keys.contains(key));
+    }
+}
@@ -30,33 +30,55 @@ struct ObservedSlotLabels {
     pub event: Event,
 }
 
+/// Stages of the processing of a slot, timed from the slot being observed on Pythnet.
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelValue)]
+pub enum Stage {
+    AccumulatorStored,
+    VaaReceived,
+    SlotCompleted,
+    /// The first update of the slot sent to a subscriber, on any of the APIs.
+    FirstByteSent,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct StageLabels {
+    pub stage: Stage,
+}
+
+fn latency_histogram() -> Histogram {
+    Histogram::new(
+        [
+            0.1, 0.2, 0.3, 0.4, 0.5, 0.7, 1.0, 1.3, 1.7, 2.0, 3.0, 5.0, 10.0, 20.0,
+        ]
+        .into_iter(),
+    )
+}
+
 #[derive(Clone, Debug)]
 pub struct Metrics {
     observed_slot: Family<ObservedSlotLabels, Counter>,
     observed_slot_latency: Family<ObservedSlotLabels, Histogram>,
     first_observed_time_of_slot: BTreeMap<Slot, Instant>,
     newest_observed_slot: HashMap<Event, Slot>,
+    stage_latency: Family<StageLabels, Histogram>,
+    pythnet_observed_time_of_slot: BTreeMap<Slot, Instant>,
 }
 
 impl Metrics {
     pub fn new(metrics_registry: &mut Registry) -> Self {
         let new = Self {
             observed_slot: Family::default(),
-            observed_slot_latency: Family::new_with_constructor(|| {
-                Histogram::new(
-                    [
-                        0.1, 0.2, 0.3, 0.4, 0.5, 0.7, 1.0, 1.3, 1.7, 2.0, 3.0, 5.0, 10.0, 20.0,
-                    ]
-                    .into_iter(),
-                )
-            }),
+            observed_slot_latency: Family::new_with_constructor(latency_histogram),
             first_observed_time_of_slot: BTreeMap::new(),
             newest_observed_slot: HashMap::new(),
+            stage_latency: Family::new_with_constructor(latency_histogram),
+            pythnet_observed_time_of_slot: BTreeMap::new(),
         };
 
         {
             let observed_slot = new.observed_slot.clone();
             let observed_slot_latency = new.observed_slot_latency.clone();
+            let stage_latency = new.stage_latency.clone();
 
             metrics_registry.register(
                 "aggregate_observed_slot",
@@ -69,6 +91,13 @@ impl Metrics {
                 "Latency of observed slots in seconds",
                 observed_slot_latency,
             );
+
+            metrics_registry.register(
+                "aggregate_stage_latency_seconds",
+                "Latency of each processing stage of a slot since it was observed on Pythnet, \
+                 in seconds",
+                stage_latency,
+            );
         }
 
         new
@@ -110,4 +139,26 @@ impl Metrics {
             self.first_observed_time_of_slot.remove(&oldest_slot);
         }
     }
+
+    /// Record the time a slot was observed on Pythnet, which the stages of the slot are timed
+    /// from. Only the first observation of a slot counts.
+    pub fn observe_pythnet_slot(&mut self, slot: Slot, observed_at: Instant) {
+        self.pythnet_observed_time_of_slot
+            .entry(slot)
+            .or_insert(observed_at);
+
+        while self.pythnet_observed_time_of_slot.len() > MAX_SLOT_OBSERVATIONS {
+            self.pythnet_observed_time_of_slot.pop_first();
+        }
+    }
+
+    /// Observe a stage of a slot. Stages reached before the slot was observed on Pythnet, e.g.
+    /// a VAA arriving first, or of slots that were not observed at all are not timed.
+    pub fn observe_stage(&mut self, slot: Slot, stage: Stage, reached_at: Instant) {
+        if let Some(observed_at) = self.pythnet_observed_time_of_slot.get(&slot) {
+            self.stage_latency
+                .get_or_create(&StageLabels { stage })
+                .observe(reached_at.duration_since(*observed_at).as_secs_f64());
+        }
+    }
 }
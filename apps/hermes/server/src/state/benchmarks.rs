@@ -2,15 +2,16 @@
 
 use {
     super::{
-        aggregate::{PriceFeedsWithUpdateData, UnixTimestamp},
+        aggregate::{PriceFeedsWithUpdateData, TwapsWithUpdateData, UnixTimestamp},
         State,
     },
-    crate::api::types::PriceUpdate,
+    crate::api::types::{PriceUpdate, TwapsResponse},
     anyhow::Result,
     base64::{engine::general_purpose::STANDARD as base64_standard_engine, Engine as _},
-    pyth_sdk::PriceIdentifier,
+    pyth_sdk::{DurationInSeconds, PriceIdentifier},
     reqwest::Url,
     serde::Deserialize,
+    std::sync::{PoisonError, RwLock},
 };
 
 const BENCHMARKS_REQUEST_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
@@ -47,12 +48,14 @@ impl TryFrom<BinaryBlob> for Vec<Vec<u8>> {
 }
 
 pub struct BenchmarksState {
-    endpoint: Option<Url>,
+    endpoint: RwLock<Option<Url>>,
 }
 
 impl BenchmarksState {
     pub fn new(url: Option<Url>) -> Self {
-        Self { endpoint: url }
+        Self {
+            endpoint: RwLock::new(url),
+        }
     }
 }
 
@@ -70,6 +73,16 @@ pub trait Benchmarks {
         price_ids: &[PriceIdentifier],
         publish_time: UnixTimestamp,
     ) -> Result<PriceFeedsWithUpdateData>;
+
+    async fn get_verified_twaps_with_update_data(
+        &self,
+        price_ids: &[PriceIdentifier],
+        window_seconds: DurationInSeconds,
+        publish_time: UnixTimestamp,
+    ) -> Result<TwapsWithUpdateData>;
+
+    /// Replace the benchmarks endpoint, e.g. when the options are reloaded.
+    fn set_benchmarks_endpoint(&self, endpoint: Option<Url>);
 }
 
 #[async_trait::async_trait]
@@ -86,6 +99,8 @@ where
         let endpoint = self
             .into()
             .endpoint
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
             .as_ref()
             .ok_or_else(|| anyhow::anyhow!("Benchmarks endpoint is not set"))?
             .join(&format!("/v1/updates/price/{}", publish_time))
@@ -113,4 +128,54 @@ where
         let price_update: PriceUpdate = response.json().await?;
         price_update.try_into()
     }
+
+    async fn get_verified_twaps_with_update_data(
+        &self,
+        price_ids: &[PriceIdentifier],
+        window_seconds: DurationInSeconds,
+        publish_time: UnixTimestamp,
+    ) -> Result<TwapsWithUpdateData> {
+        let endpoint = self
+            .into()
+            .endpoint
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Benchmarks endpoint is not set"))?
+            .join(&format!(
+                "/v1/updates/twap/{}/{}",
+                window_seconds, publish_time
+            ))
+            .unwrap();
+
+        let mut request = reqwest::Client::new()
+            .get(endpoint)
+            .timeout(BENCHMARKS_REQUEST_TIMEOUT)
+            .query(&[("encoding", "hex")])
+            .query(&[("parsed", "true")]);
+
+        for price_id in price_ids {
+            request = request.query(&[("ids", price_id)])
+        }
+
+        let response = request.send().await?;
+
+        if response.status() != reqwest::StatusCode::OK {
+            return Err(anyhow::anyhow!(format!(
+                "TWAP for price ids {:?} with window {}s ending at {} not found in benchmarks. Status code: {}, message: {}",
+                price_ids, window_seconds, publish_time, response.status(), response.text().await?
+            )));
+        }
+
+        let twaps: TwapsResponse = response.json().await?;
+        twaps.try_into()
+    }
+
+    fn set_benchmarks_endpoint(&self, endpoint: Option<Url>) {
+        *self
+            .into()
+            .endpoint
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = endpoint;
+    }
 }
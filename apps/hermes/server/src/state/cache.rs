@@ -1,20 +1,31 @@
+#[cfg(test)]
+use mock_instant::{SystemTime, UNIX_EPOCH};
+#[cfg(not(test))]
+use std::time::{SystemTime, UNIX_EPOCH};
 use {
+    self::storage::Storage,
     super::State,
     crate::state::aggregate::{
-        wormhole_merkle::WormholeMerkleState, AccumulatorMessages, ProofSet, RawMessage,
-        RequestTime, Slot, UnixTimestamp,
+        wormhole_merkle::WormholeMerkleState, AccumulatorMessages, ProofSet, RangeCursor,
+        RawMessage, RequestRange, RequestTime, Slot, UnixTimestamp,
     },
     anyhow::{anyhow, Result},
     futures::future::join_all,
     pythnet_sdk::messages::{FeedId, Message, MessageType},
     std::{
-        collections::{BTreeMap, HashMap, HashSet},
+        collections::{BTreeMap, BTreeSet, HashMap, HashSet},
         sync::Arc,
+        time::Duration,
     },
     strum::IntoEnumIterator,
     tokio::sync::RwLock,
 };
 
+pub mod storage;
+
+/// How often expired entries are pruned from the persistent storage.
+const STORAGE_PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
 #[derive(Clone, PartialEq, Eq, Debug, Hash)]
 pub struct MessageStateKey {
     pub feed_id: FeedId,
@@ -73,6 +84,34 @@ impl MessageState {
     }
 }
 
+/// Occupancy of the cache of a message state key.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MessageStateKeyStats {
+    pub key: MessageStateKey,
+    pub len: usize,
+    pub oldest: Option<MessageStateTime>,
+    pub newest: Option<MessageStateTime>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CacheStats {
+    /// Maximum number of entries kept by each cache.
+    pub cache_size: u64,
+    pub accumulator_messages: usize,
+    pub wormhole_merkle_states: usize,
+    pub message_states: Vec<MessageStateKeyStats>,
+}
+
+/// The slots cached in memory for a set of feeds, to replay their updates.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MessageStateSlots {
+    /// The feeds with a message state at each slot, in ascending slot order.
+    pub feeds: BTreeMap<Slot, BTreeSet<FeedId>>,
+    /// The most recent of the oldest slots cached for the feeds that reached the cache size.
+    /// Older updates of these feeds might have been evicted.
+    pub oldest_slot: Option<Slot>,
+}
+
 #[derive(Clone, Copy)]
 #[allow(dead_code)]
 pub enum MessageStateFilter {
@@ -95,15 +134,54 @@ pub struct CacheState {
     wormhole_merkle_state_cache: WormholeMerkleStateCache,
     message_cache: MessageCache,
     cache_size: u64,
+
+    /// Optional persistent storage that every cache write goes through to. It is used to answer
+    /// historical lookups that fall outside of the in-memory cache.
+    storage: Option<Arc<dyn Storage>>,
+    storage_retention: Duration,
 }
 
 impl CacheState {
-    pub fn new(size: u64) -> Self {
+    pub fn new(size: u64, storage: Option<Arc<dyn Storage>>, storage_retention: Duration) -> Self {
+        let mut accumulator_messages_cache = BTreeMap::new();
+        let mut wormhole_merkle_state_cache = BTreeMap::new();
+        let mut message_cache: HashMap<_, BTreeMap<_, _>> = HashMap::new();
+
+        // Warm up the in-memory caches with the most recent entries from the storage so that
+        // a restart does not leave a gap in the cache window.
+        if let Some(storage) = &storage {
+            let limit = size as usize;
+            match storage.latest_accumulator_messages(limit) {
+                Ok(entries) => accumulator_messages_cache
+                    .extend(entries.into_iter().map(|entry| (entry.slot, entry))),
+                Err(e) => tracing::warn!(error = ?e, "Failed to load accumulator messages."),
+            }
+            match storage.latest_wormhole_merkle_states(limit) {
+                Ok(entries) => wormhole_merkle_state_cache
+                    .extend(entries.into_iter().map(|entry| (entry.root.slot, entry))),
+                Err(e) => tracing::warn!(error = ?e, "Failed to load wormhole merkle states."),
+            }
+            match storage.latest_message_states(limit) {
+                Ok(entries) => {
+                    tracing::info!(len = entries.len(), "Loaded message states from storage.");
+                    for entry in entries {
+                        message_cache
+                            .entry(entry.key())
+                            .or_default()
+                            .insert(entry.time(), entry);
+                    }
+                }
+                Err(e) => tracing::warn!(error = ?e, "Failed to load message states."),
+            }
+        }
+
         Self {
-            accumulator_messages_cache: Arc::new(RwLock::new(BTreeMap::new())),
-            wormhole_merkle_state_cache: Arc::new(RwLock::new(BTreeMap::new())),
-            message_cache: Arc::new(RwLock::new(HashMap::new())),
+            accumulator_messages_cache: Arc::new(RwLock::new(accumulator_messages_cache)),
+            wormhole_merkle_state_cache: Arc::new(RwLock::new(wormhole_merkle_state_cache)),
+            message_cache: Arc::new(RwLock::new(message_cache)),
             cache_size: size,
+            storage,
+            storage_retention,
         }
     }
 }
@@ -129,6 +207,8 @@ pub trait Cache {
         wormhole_merkle_state: WormholeMerkleState,
     ) -> Result<()>;
     async fn fetch_wormhole_merkle_state(&self, slot: Slot) -> Result<Option<WormholeMerkleState>>;
+    async fn prune_storage(&self) -> Result<()>;
+    async fn cache_stats(&self) -> CacheStats;
     async fn message_state_keys(&self) -> Vec<MessageStateKey>;
     async fn fetch_message_states(
         &self,
@@ -136,6 +216,27 @@ pub trait Cache {
         request_time: RequestTime,
         filter: MessageStateFilter,
     ) -> Result<Vec<MessageState>>;
+    async fn fetch_message_state_slots(
+        &self,
+        ids: Vec<FeedId>,
+        after: Slot,
+        filter: MessageStateFilter,
+    ) -> MessageStateSlots;
+    async fn fetch_message_state_ids_at_slot(
+        &self,
+        ids: Vec<FeedId>,
+        slot: Slot,
+        filter: MessageStateFilter,
+    ) -> Vec<FeedId>;
+    async fn fetch_message_states_at_slot(&self, slot: Slot) -> Vec<MessageState>;
+    async fn fetch_message_states_range(
+        &self,
+        ids: Vec<FeedId>,
+        range: RequestRange,
+        filter: MessageStateFilter,
+        after: Option<RangeCursor>,
+        limit: usize,
+    ) -> Vec<MessageState>;
 }
 
 #[async_trait::async_trait]
@@ -155,6 +256,17 @@ where
     }
 
     async fn store_message_states(&self, message_states: Vec<MessageState>) -> Result<()> {
+        if let Some(storage) = &self.into().storage {
+            let persisted = message_states.clone();
+            if let Err(e) = run_blocking(storage, move |storage| {
+                storage.store_message_states(&persisted)
+            })
+            .await
+            {
+                tracing::error!(error = ?e, "Failed to persist message states.");
+            }
+        }
+
         let mut message_cache = self.into().message_cache.write().await;
 
         for message_state in message_states {
@@ -223,10 +335,164 @@ where
         .ok_or(anyhow!("Message not found"))
     }
 
+    /// Returns the slots after the given slot for which a message state of any of the given
+    /// feeds is cached in memory, with the feeds cached at each of them.
+    async fn fetch_message_state_slots(
+        &self,
+        ids: Vec<FeedId>,
+        after: Slot,
+        filter: MessageStateFilter,
+    ) -> MessageStateSlots {
+        let ids = ids.into_iter().collect::<HashSet<_>>();
+        let cache_size = self.into().cache_size;
+        let message_cache = self.into().message_cache.read().await;
+
+        let mut slots = MessageStateSlots::default();
+        for (key, key_cache) in message_cache
+            .iter()
+            .filter(|(key, _)| ids.contains(&key.feed_id))
+            .filter(|(key, _)| match filter {
+                MessageStateFilter::All => true,
+                MessageStateFilter::Only(type_) => key.type_ == type_,
+            })
+        {
+            // Only a full cache evicts updates.
+            if key_cache.len() as u64 >= cache_size {
+                let oldest_slot = key_cache.keys().map(|time| time.slot).min();
+                slots.oldest_slot = slots.oldest_slot.max(oldest_slot);
+            }
+            for time in key_cache.keys().filter(|time| time.slot > after) {
+                slots
+                    .feeds
+                    .entry(time.slot)
+                    .or_default()
+                    .insert(key.feed_id);
+            }
+        }
+        slots
+    }
+
+    /// Returns the given feeds, in the same order, that have a message state cached in memory at
+    /// the slot.
+    async fn fetch_message_state_ids_at_slot(
+        &self,
+        ids: Vec<FeedId>,
+        slot: Slot,
+        filter: MessageStateFilter,
+    ) -> Vec<FeedId> {
+        let message_cache = self.into().message_cache.read().await;
+        let message_types: Vec<MessageType> = match filter {
+            MessageStateFilter::All => MessageType::iter().collect(),
+            MessageStateFilter::Only(t) => vec![t],
+        };
+
+        ids.into_iter()
+            .filter(|id| {
+                message_types.iter().any(|message_type| {
+                    message_cache
+                        .get(&MessageStateKey {
+                            feed_id: *id,
+                            type_: *message_type,
+                        })
+                        .is_some_and(|key_cache| {
+                            // Usually the slot lies at the end of the map.
+                            key_cache.keys().rev().any(|time| time.slot == slot)
+                        })
+                })
+            })
+            .collect()
+    }
+
+    /// Returns every message state cached in memory at the slot.
+    async fn fetch_message_states_at_slot(&self, slot: Slot) -> Vec<MessageState> {
+        let message_cache = self.into().message_cache.read().await;
+        message_cache
+            .values()
+            .flat_map(|key_cache| {
+                key_cache
+                    .iter()
+                    .rev()
+                    .find(|(time, _)| time.slot == slot)
+                    .map(|(_, message_state)| message_state.clone())
+            })
+            .collect()
+    }
+
+    /// Returns the cached message states of the given feeds within the range, ordered by
+    /// publish time, slot and feed id. At most `limit` states are returned, starting right after
+    /// the `after` cursor when one is given.
+    async fn fetch_message_states_range(
+        &self,
+        ids: Vec<FeedId>,
+        range: RequestRange,
+        filter: MessageStateFilter,
+        after: Option<RangeCursor>,
+        limit: usize,
+    ) -> Vec<MessageState> {
+        let ids = ids.into_iter().collect::<HashSet<_>>();
+        let message_cache = self.into().message_cache.read().await;
+
+        let mut message_states = message_cache
+            .iter()
+            .filter(|(key, _)| ids.contains(&key.feed_id))
+            .filter(|(key, _)| match filter {
+                MessageStateFilter::All => true,
+                MessageStateFilter::Only(type_) => key.type_ == type_,
+            })
+            .flat_map(|(key, key_cache)| {
+                let in_range: Box<dyn Iterator<Item = (&MessageStateTime, &MessageState)>> =
+                    match range {
+                        RequestRange::Time { start, end } => Box::new(key_cache.range(
+                            MessageStateTime {
+                                publish_time: start,
+                                slot: 0,
+                            }..=MessageStateTime {
+                                publish_time: end,
+                                slot: Slot::MAX,
+                            },
+                        )),
+                        RequestRange::Slot { start, end } => Box::new(
+                            key_cache
+                                .iter()
+                                .filter(move |(time, _)| (start..=end).contains(&time.slot)),
+                        ),
+                    };
+                in_range.map(move |(time, message_state)| {
+                    let cursor = RangeCursor {
+                        publish_time: time.publish_time,
+                        slot: time.slot,
+                        feed_id: key.feed_id,
+                    };
+                    (cursor, message_state)
+                })
+            })
+            .filter(|(cursor, _)| after.map_or(true, |after| *cursor > after))
+            .collect::<Vec<_>>();
+
+        message_states.sort_by_key(|(cursor, _)| *cursor);
+
+        message_states
+            .into_iter()
+            .take(limit)
+            .map(|(_, message_state)| message_state.clone())
+            .collect()
+    }
+
     async fn store_accumulator_messages(
         &self,
         accumulator_messages: AccumulatorMessages,
     ) -> Result<()> {
+        if let Some(storage) = &self.into().storage {
+            let persisted = accumulator_messages.clone();
+            if let Err(e) = run_blocking(storage, move |storage| {
+                storage.store_accumulator_messages(&persisted)
+            })
+            .await
+            {
+                tracing::error!(error = ?e, "Failed to persist accumulator messages.");
+            }
+        }
+
         let mut cache = self.into().accumulator_messages_cache.write().await;
         cache.insert(accumulator_messages.slot, accumulator_messages);
         while cache.len() > self.into().cache_size as usize {
@@ -236,14 +502,42 @@ where
     }
 
     async fn fetch_accumulator_messages(&self, slot: Slot) -> Result<Option<AccumulatorMessages>> {
-        let cache = self.into().accumulator_messages_cache.read().await;
-        Ok(cache.get(&slot).cloned())
+        if let Some(accumulator_messages) = self
+            .into()
+            .accumulator_messages_cache
+            .read()
+            .await
+            .get(&slot)
+        {
+            return Ok(Some(accumulator_messages.clone()));
+        }
+
+        match &self.into().storage {
+            Some(storage) => {
+                run_blocking(storage, move |storage| {
+                    storage.fetch_accumulator_messages(slot)
+                })
+                .await
+            }
+            None => Ok(None),
+        }
     }
 
     async fn store_wormhole_merkle_state(
         &self,
         wormhole_merkle_state: WormholeMerkleState,
     ) -> Result<()> {
+        if let Some(storage) = &self.into().storage {
+            let persisted = wormhole_merkle_state.clone();
+            if let Err(e) = run_blocking(storage, move |storage| {
+                storage.store_wormhole_merkle_state(&persisted)
+            })
+            .await
+            {
+                tracing::error!(error = ?e, "Failed to persist wormhole merkle state.");
+            }
+        }
+
         let mut cache = self.into().wormhole_merkle_state_cache.write().await;
         cache.insert(wormhole_merkle_state.root.slot, wormhole_merkle_state);
         while cache.len() > self.into().cache_size as usize {
@@ -253,9 +547,99 @@ where
     }
 
     async fn fetch_wormhole_merkle_state(&self, slot: Slot) -> Result<Option<WormholeMerkleState>> {
-        let cache = self.into().wormhole_merkle_state_cache.read().await;
-        Ok(cache.get(&slot).cloned())
+        if let Some(wormhole_merkle_state) = self
+            .into()
+            .wormhole_merkle_state_cache
+            .read()
+            .await
+            .get(&slot)
+        {
+            return Ok(Some(wormhole_merkle_state.clone()));
+        }
+
+        match &self.into().storage {
+            Some(storage) => {
+                run_blocking(storage, move |storage| {
+                    storage.fetch_wormhole_merkle_state(slot)
+                })
+                .await
+            }
+            None => Ok(None),
+        }
     }
+
+    async fn prune_storage(&self) -> Result<()> {
+        let cache = self.into();
+        if let Some(storage) = &cache.storage {
+            let cutoff = now() - cache.storage_retention.as_secs() as UnixTimestamp;
+            let pruned = run_blocking(storage, move |storage| storage.prune(cutoff)).await?;
+            tracing::debug!(pruned = pruned, "Pruned expired entries from storage.");
+        }
+        Ok(())
+    }
+
+    async fn cache_stats(&self) -> CacheStats {
+        let cache = self.into();
+        let message_states = cache
+            .message_cache
+            .read()
+            .await
+            .iter()
+            .map(|(key, key_cache)| MessageStateKeyStats {
+                key: key.clone(),
+                len: key_cache.len(),
+                oldest: key_cache.keys().next().cloned(),
+                newest: key_cache.keys().next_back().cloned(),
+            })
+            .collect();
+
+        CacheStats {
+            cache_size: cache.cache_size,
+            accumulator_messages: cache.accumulator_messages_cache.read().await.len(),
+            wormhole_merkle_states: cache.wormhole_merkle_state_cache.read().await.len(),
+            message_states,
+        }
+    }
+}
+
+fn now() -> UnixTimestamp {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as UnixTimestamp)
+        .unwrap_or_default()
+}
+
+/// Run a storage operation on the blocking thread pool, as disk reads, writes and flushes
+/// would otherwise stall the async runtime.
+async fn run_blocking<T, F>(storage: &Arc<dyn Storage>, f: F) -> Result<T>
+where
+    F: FnOnce(&dyn Storage) -> Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    let storage = storage.clone();
+    tokio::task::spawn_blocking(move || f(storage.as_ref())).await?
+}
+
+/// Periodically removes entries older than the configured retention from the storage.
+pub async fn run_storage_pruner<S>(state: Arc<S>) -> Result<()>
+where
+    S: Cache,
+    S: Send + Sync + 'static,
+{
+    let mut exit = crate::EXIT.subscribe();
+    let mut interval = tokio::time::interval(STORAGE_PRUNE_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = exit.changed() => break,
+            _ = interval.tick() => {
+                if let Err(e) = state.prune_storage().await {
+                    tracing::error!(error = ?e, "Failed to prune storage.");
+                }
+            }
+        }
+    }
+    tracing::info!("Shutting down storage pruner...");
+    Ok(())
 }
 
 async fn retrieve_message_state(
@@ -263,7 +647,35 @@ async fn retrieve_message_state(
     key: MessageStateKey,
     request_time: RequestTime,
 ) -> Option<MessageState> {
-    match cache.message_cache.read().await.get(&key) {
+    if let Some(message_state) = retrieve_cached_message_state(cache, &key, &request_time).await {
+        return Some(message_state);
+    }
+
+    // The latest state is always served from memory, as feeds removed from the accumulator
+    // should not keep resolving to their last stored state.
+    if let RequestTime::Latest = request_time {
+        return None;
+    }
+
+    match run_blocking(cache.storage.as_ref()?, move |storage| {
+        storage.fetch_message_state(&key, &request_time)
+    })
+    .await
+    {
+        Ok(message_state) => message_state,
+        Err(e) => {
+            tracing::warn!(error = ?e, "Failed to fetch message state from storage.");
+            None
+        }
+    }
+}
+
+async fn retrieve_cached_message_state(
+    cache: &CacheState,
+    key: &MessageStateKey,
+    request_time: &RequestTime,
+) -> Option<MessageState> {
+    match cache.message_cache.read().await.get(key) {
         Some(key_cache) => {
             match request_time {
                 RequestTime::Latest => key_cache.last_key_value().map(|(_, v)| v).cloned(),
@@ -271,13 +683,13 @@ async fn retrieve_message_state(
                     // If the requested time is before the first element in the vector, we are
                     // not sure that the first element is the closest one.
                     if let Some((_, oldest_record_value)) = key_cache.first_key_value() {
-                        if time < oldest_record_value.time().publish_time {
+                        if *time < oldest_record_value.time().publish_time {
                             return None;
                         }
                     }
 
                     let lookup_time = MessageStateTime {
-                        publish_time: time,
+                        publish_time: *time,
                         slot: 0,
                     };
 
@@ -293,7 +705,7 @@ async fn retrieve_message_state(
                     key_cache
                         .iter()
                         .rev() // Usually the slot lies at the end of the map
-                        .find(|(k, _)| k.slot == slot)
+                        .find(|(k, _)| k.slot == *slot)
                         .map(|(_, v)| v)
                         .cloned()
                 }
@@ -304,7 +716,7 @@ async fn retrieve_message_state(
 }
 
 #[cfg(test)]
-mod test {
+pub mod test {
     use {
         super::*,
         crate::state::{aggregate::wormhole_merkle::WormholeMerkleMessageProof, test::setup_state},
@@ -361,6 +773,136 @@ mod test {
         message_state
     }
 
+    #[tokio::test]
+    pub async fn test_fetch_message_state_slots_works() {
+        // Initialize state with a cache size of 2 per key.
+        let (state, _) = setup_state(2).await;
+
+        // Store message states for two feeds at overlapping and distinct slots.
+        create_and_store_dummy_price_feed_message_state(&*state, [1; 32], 10, 5).await;
+        create_and_store_dummy_price_feed_message_state(&*state, [1; 32], 11, 6).await;
+        create_and_store_dummy_price_feed_message_state(&*state, [2; 32], 11, 6).await;
+        create_and_store_dummy_price_feed_message_state(&*state, [2; 32], 12, 8).await;
+        create_and_store_dummy_price_feed_message_state(&*state, [3; 32], 12, 9).await;
+
+        // Slots are deduplicated, sorted and only include the requested feeds. Both feeds filled
+        // their cache, the oldest slot is the one from which every requested feed is cached.
+        assert_eq!(
+            state
+                .fetch_message_state_slots(
+                    vec![[1; 32], [2; 32]],
+                    5,
+                    MessageStateFilter::Only(MessageType::PriceFeedMessage),
+                )
+                .await,
+            MessageStateSlots {
+                feeds: BTreeMap::from([
+                    (6, BTreeSet::from([[1; 32], [2; 32]])),
+                    (8, BTreeSet::from([[2; 32]])),
+                ]),
+                oldest_slot: Some(6),
+            }
+        );
+
+        // Only the feeds with a message state at the slot are returned, in the given order.
+        assert_eq!(
+            state
+                .fetch_message_state_ids_at_slot(
+                    vec![[3; 32], [2; 32], [1; 32]],
+                    6,
+                    MessageStateFilter::Only(MessageType::PriceFeedMessage),
+                )
+                .await,
+            vec![[2; 32], [1; 32]]
+        );
+
+        // No slots are returned for other message types.
+        assert_eq!(
+            state
+                .fetch_message_state_slots(
+                    vec![[1; 32], [2; 32]],
+                    0,
+                    MessageStateFilter::Only(MessageType::TwapMessage),
+                )
+                .await,
+            MessageStateSlots::default()
+        );
+
+        // A feed that did not fill its cache has no evicted updates.
+        assert_eq!(
+            state
+                .fetch_message_state_slots(
+                    vec![[3; 32]],
+                    5,
+                    MessageStateFilter::Only(MessageType::PriceFeedMessage),
+                )
+                .await,
+            MessageStateSlots {
+                feeds: BTreeMap::from([(9, BTreeSet::from([[3; 32]]))]),
+                oldest_slot: None,
+            }
+        );
+    }
+
+    #[tokio::test]
+    pub async fn test_fetch_message_states_range_works() {
+        // Initialize state with a cache size of 3 per key.
+        let (state, _) = setup_state(3).await;
+
+        let first = create_and_store_dummy_price_feed_message_state(&*state, [1; 32], 10, 5).await;
+        let second = create_and_store_dummy_price_feed_message_state(&*state, [2; 32], 10, 5).await;
+        let third = create_and_store_dummy_price_feed_message_state(&*state, [1; 32], 20, 9).await;
+        create_and_store_dummy_price_feed_message_state(&*state, [1; 32], 30, 12).await;
+
+        let filter = MessageStateFilter::Only(MessageType::PriceFeedMessage);
+
+        // A time range returns every state within the bounds, ordered by time and feed id.
+        assert_eq!(
+            state
+                .fetch_message_states_range(
+                    vec![[1; 32], [2; 32]],
+                    RequestRange::Time { start: 10, end: 20 },
+                    filter,
+                    None,
+                    10,
+                )
+                .await,
+            vec![first.clone(), second.clone(), third.clone()]
+        );
+
+        // A slot range does the same by slot.
+        assert_eq!(
+            state
+                .fetch_message_states_range(
+                    vec![[1; 32]],
+                    RequestRange::Slot { start: 6, end: 11 },
+                    filter,
+                    None,
+                    10,
+                )
+                .await,
+            vec![third.clone()]
+        );
+
+        // The cursor of the last update of a page and the limit select the following page.
+        assert_eq!(
+            state
+                .fetch_message_states_range(
+                    vec![[1; 32], [2; 32]],
+                    RequestRange::Time { start: 0, end: 100 },
+                    filter,
+                    Some(RangeCursor {
+                        publish_time: 10,
+                        slot: 5,
+                        feed_id: [1; 32],
+                    }),
+                    2,
+                )
+                .await,
+            vec![second, third]
+        );
+    }
+
     #[tokio::test]
     pub async fn test_store_and_retrieve_latest_message_state_works() {
         // Initialize state with a cache size of 2 per key.
@@ -0,0 +1,640 @@
+//! Persistent storage backing the in-memory `Cache`.
+//!
+//! The in-memory cache only holds the last few updates per feed which is not enough to serve
+//! historical queries after a restart or beyond the cache window. A `Storage` keeps every state
+//! written to the cache on disk for a configurable retention period so that the cache can fall
+//! back to it on a miss and warm itself up on startup.
+
+use {
+    super::{now, MessageState, MessageStateKey, MessageStateTime},
+    crate::{
+        network::wormhole::VaaBytes,
+        state::aggregate::{
+            wormhole_merkle::{WormholeMerkleMessageProof, WormholeMerkleState},
+            AccumulatorMessages, ProofSet, RawMessage, RequestTime, Slot, UnixTimestamp,
+        },
+    },
+    anyhow::{anyhow, Result},
+    borsh::{BorshDeserialize, BorshSerialize},
+    byteorder::BigEndian,
+    pythnet_sdk::{
+        accumulators::merkle::MerklePath,
+        hashers::keccak256_160::Keccak160,
+        messages::{Message, MessageType},
+        wire::{from_slice, to_vec, v1::WormholeMerkleRoot},
+    },
+    std::path::Path,
+    strum::IntoEnumIterator,
+};
+
+/// A durable store of cache entries.
+///
+/// Writes and pruning block on disk IO, the cache runs them on the blocking thread pool. Writes
+/// are applied as they come in and pruning is driven externally by calling `prune`.
+pub trait Storage: Send + Sync {
+    fn store_message_states(&self, message_states: &[MessageState]) -> Result<()>;
+    fn store_accumulator_messages(&self, accumulator_messages: &AccumulatorMessages) -> Result<()>;
+    fn store_wormhole_merkle_state(
+        &self,
+        wormhole_merkle_state: &WormholeMerkleState,
+    ) -> Result<()>;
+    fn fetch_message_state(
+        &self,
+        key: &MessageStateKey,
+        request_time: &RequestTime,
+    ) -> Result<Option<MessageState>>;
+    fn fetch_accumulator_messages(&self, slot: Slot) -> Result<Option<AccumulatorMessages>>;
+    fn fetch_wormhole_merkle_state(&self, slot: Slot) -> Result<Option<WormholeMerkleState>>;
+
+    /// Return up to `limit` of the most recent message states for every stored key.
+    fn latest_message_states(&self, limit: usize) -> Result<Vec<MessageState>>;
+    /// Return up to `limit` of the most recent accumulator messages.
+    fn latest_accumulator_messages(&self, limit: usize) -> Result<Vec<AccumulatorMessages>>;
+    /// Return up to `limit` of the most recent wormhole merkle states.
+    fn latest_wormhole_merkle_states(&self, limit: usize) -> Result<Vec<WormholeMerkleState>>;
+
+    /// Remove every entry that was stored before `cutoff`. Returns the number of removed entries.
+    fn prune(&self, cutoff: UnixTimestamp) -> Result<usize>;
+}
+
+#[derive(BorshSerialize, BorshDeserialize)]
+struct MessageStateRecord {
+    slot: Slot,
+    message: Vec<u8>,
+    raw_message: RawMessage,
+    proof: MerklePath<Keccak160>,
+    vaa: VaaBytes,
+    received_at: UnixTimestamp,
+}
+
+impl MessageStateRecord {
+    fn encode(message_state: &MessageState) -> Result<Vec<u8>> {
+        let record = Self {
+            slot: message_state.slot,
+            message: to_vec::<_, BigEndian>(&message_state.message)
+                .map_err(|e| anyhow!("Failed to serialize message: {:?}", e))?,
+            raw_message: message_state.raw_message.clone(),
+            proof: message_state.proof_set.wormhole_merkle_proof.proof.clone(),
+            vaa: message_state.proof_set.wormhole_merkle_proof.vaa.clone(),
+            received_at: message_state.received_at,
+        };
+        Ok(record.try_to_vec()?)
+    }
+
+    fn decode(bytes: &[u8]) -> Result<MessageState> {
+        let record = Self::try_from_slice(bytes)?;
+        Ok(MessageState {
+            slot: record.slot,
+            message: from_slice::<BigEndian, Message>(&record.message)
+                .map_err(|e| anyhow!("Failed to deserialize message: {:?}", e))?,
+            raw_message: record.raw_message,
+            proof_set: ProofSet {
+                wormhole_merkle_proof: WormholeMerkleMessageProof {
+                    proof: record.proof,
+                    vaa: record.vaa,
+                },
+            },
+            received_at: record.received_at,
+        })
+    }
+}
+
+#[derive(BorshSerialize, BorshDeserialize)]
+struct AccumulatorMessagesRecord {
+    magic: [u8; 4],
+    slot: u64,
+    ring_size: u32,
+    raw_messages: Vec<RawMessage>,
+}
+
+impl AccumulatorMessagesRecord {
+    fn encode(accumulator_messages: &AccumulatorMessages) -> Result<Vec<u8>> {
+        let record = Self {
+            magic: accumulator_messages.magic,
+            slot: accumulator_messages.slot,
+            ring_size: accumulator_messages.ring_size,
+            raw_messages: accumulator_messages.raw_messages.clone(),
+        };
+        Ok(record.try_to_vec()?)
+    }
+
+    fn decode(bytes: &[u8]) -> Result<AccumulatorMessages> {
+        let record = Self::try_from_slice(bytes)?;
+        Ok(AccumulatorMessages {
+            magic: record.magic,
+            slot: record.slot,
+            ring_size: record.ring_size,
+            raw_messages: record.raw_messages,
+        })
+    }
+}
+
+#[derive(BorshSerialize, BorshDeserialize)]
+struct WormholeMerkleStateRecord {
+    slot: u64,
+    ring_size: u32,
+    root: [u8; 20],
+    vaa: VaaBytes,
+}
+
+impl WormholeMerkleStateRecord {
+    fn encode(wormhole_merkle_state: &WormholeMerkleState) -> Result<Vec<u8>> {
+        let record = Self {
+            slot: wormhole_merkle_state.root.slot,
+            ring_size: wormhole_merkle_state.root.ring_size,
+            root: wormhole_merkle_state.root.root,
+            vaa: wormhole_merkle_state.vaa.clone(),
+        };
+        Ok(record.try_to_vec()?)
+    }
+
+    fn decode(bytes: &[u8]) -> Result<WormholeMerkleState> {
+        let record = Self::try_from_slice(bytes)?;
+        Ok(WormholeMerkleState {
+            root: WormholeMerkleRoot {
+                slot: record.slot,
+                ring_size: record.ring_size,
+                root: record.root,
+            },
+            vaa: record.vaa,
+        })
+    }
+}
+
+/// Length of the per-key prefix of message state keys: feed id followed by the message type.
+const MESSAGE_KEY_PREFIX_LEN: usize = 33;
+
+// Tree identifiers used in the expiry index to know where the expired entry lives.
+const EXPIRY_MESSAGE_STATE: u8 = 0;
+const EXPIRY_ACCUMULATOR_MESSAGES: u8 = 1;
+const EXPIRY_WORMHOLE_MERKLE_STATE: u8 = 2;
+
+/// A `Storage` implementation on top of the sled embedded database.
+///
+/// The layout is one tree per entry type, keyed so that lexicographic key order matches the
+/// order we query in:
+/// - `message_states`: `feed_id | type | publish_time | slot`
+/// - `message_slots`: `feed_id | type | slot` pointing to the `message_states` key
+/// - `accumulator_messages` and `wormhole_merkle_states`: `slot`
+/// - `expiry`: `stored_at | tree | key`, used to enforce retention.
+/// - `expiry_keys`: `tree | key` pointing to the `stored_at` of the last write of the entry, so
+///   that writing an entry again replaces its pending expiry instead of adding another one.
+pub struct SledStorage {
+    db: sled::Db,
+    message_states: sled::Tree,
+    message_slots: sled::Tree,
+    accumulator_messages: sled::Tree,
+    wormhole_merkle_states: sled::Tree,
+    expiry: sled::Tree,
+    expiry_keys: sled::Tree,
+}
+
+impl SledStorage {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::from_db(sled::open(path)?)
+    }
+
+    pub fn from_db(db: sled::Db) -> Result<Self> {
+        Ok(Self {
+            message_states: db.open_tree("message_states")?,
+            message_slots: db.open_tree("message_slots")?,
+            accumulator_messages: db.open_tree("accumulator_messages")?,
+            wormhole_merkle_states: db.open_tree("wormhole_merkle_states")?,
+            expiry: db.open_tree("expiry")?,
+            expiry_keys: db.open_tree("expiry_keys")?,
+            db,
+        })
+    }
+
+    /// Schedule the expiry of an entry written at `stored_at`, replacing the expiry of any
+    /// previous write of the same entry.
+    fn expire_at(&self, stored_at: UnixTimestamp, tree: u8, key: &[u8]) -> Result<()> {
+        let stored_at = encode_timestamp(stored_at);
+        let mut entry_key = vec![tree];
+        entry_key.extend_from_slice(key);
+
+        if let Some(previous) = self.expiry_keys.insert(&entry_key, &stored_at[..])? {
+            if previous[..] != stored_at[..] {
+                let mut previous_expiry_key = previous.to_vec();
+                previous_expiry_key.extend_from_slice(&entry_key);
+                self.expiry.remove(previous_expiry_key)?;
+            }
+        }
+
+        let mut expiry_key = stored_at.to_vec();
+        expiry_key.extend_from_slice(&entry_key);
+        self.expiry.insert(expiry_key, &[])?;
+        Ok(())
+    }
+
+    fn store_message_states_at(
+        &self,
+        stored_at: UnixTimestamp,
+        message_states: &[MessageState],
+    ) -> Result<()> {
+        for message_state in message_states {
+            let key = message_state.key();
+            let state_key = message_state_key(&key, &message_state.time());
+            self.message_states
+                .insert(&state_key, MessageStateRecord::encode(message_state)?)?;
+            self.message_slots.insert(
+                message_slot_key(&key, message_state.slot),
+                state_key.as_slice(),
+            )?;
+            self.expire_at(stored_at, EXPIRY_MESSAGE_STATE, &state_key)?;
+        }
+        Ok(())
+    }
+}
+
+/// Encode a signed timestamp such that its big-endian bytes sort in numeric order.
+fn encode_timestamp(time: UnixTimestamp) -> [u8; 8] {
+    ((time as u64) ^ (1 << 63)).to_be_bytes()
+}
+
+fn message_key_prefix(key: &MessageStateKey) -> Vec<u8> {
+    let mut prefix = Vec::with_capacity(MESSAGE_KEY_PREFIX_LEN + 16);
+    prefix.extend_from_slice(&key.feed_id);
+    prefix.push(message_type_to_byte(&key.type_));
+    prefix
+}
+
+fn message_state_key(key: &MessageStateKey, time: &MessageStateTime) -> Vec<u8> {
+    let mut encoded = message_key_prefix(key);
+    encoded.extend_from_slice(&encode_timestamp(time.publish_time));
+    encoded.extend_from_slice(&time.slot.to_be_bytes());
+    encoded
+}
+
+fn message_slot_key(key: &MessageStateKey, slot: Slot) -> Vec<u8> {
+    let mut encoded = message_key_prefix(key);
+    encoded.extend_from_slice(&slot.to_be_bytes());
+    encoded
+}
+
+fn message_type_to_byte(message_type: &MessageType) -> u8 {
+    MessageType::iter()
+        .position(|t| &t == message_type)
+        .unwrap_or_default() as u8
+}
+
+impl Storage for SledStorage {
+    fn store_message_states(&self, message_states: &[MessageState]) -> Result<()> {
+        self.store_message_states_at(now(), message_states)
+    }
+
+    fn store_accumulator_messages(&self, accumulator_messages: &AccumulatorMessages) -> Result<()> {
+        let key = accumulator_messages.slot.to_be_bytes();
+        self.accumulator_messages.insert(
+            key,
+            AccumulatorMessagesRecord::encode(accumulator_messages)?,
+        )?;
+        self.expire_at(now(), EXPIRY_ACCUMULATOR_MESSAGES, &key)
+    }
+
+    fn store_wormhole_merkle_state(
+        &self,
+        wormhole_merkle_state: &WormholeMerkleState,
+    ) -> Result<()> {
+        let key = wormhole_merkle_state.root.slot.to_be_bytes();
+        self.wormhole_merkle_states.insert(
+            key,
+            WormholeMerkleStateRecord::encode(wormhole_merkle_state)?,
+        )?;
+        self.expire_at(now(), EXPIRY_WORMHOLE_MERKLE_STATE, &key)
+    }
+
+    fn fetch_message_state(
+        &self,
+        key: &MessageStateKey,
+        request_time: &RequestTime,
+    ) -> Result<Option<MessageState>> {
+        let prefix = message_key_prefix(key);
+        let entry = match request_time {
+            RequestTime::Latest => self.message_states.scan_prefix(&prefix).next_back(),
+            RequestTime::FirstAfter(time) => {
+                // Same as the in-memory cache, a time before the oldest stored record cannot be
+                // answered reliably because the closest record might have been pruned.
+                match self.message_states.scan_prefix(&prefix).next() {
+                    Some(Ok((oldest, _)))
+                        if oldest[MESSAGE_KEY_PREFIX_LEN..MESSAGE_KEY_PREFIX_LEN + 8]
+                            > encode_timestamp(*time)[..] =>
+                    {
+                        return Ok(None)
+                    }
+                    _ => {}
+                }
+                let lookup_key = message_state_key(
+                    key,
+                    &MessageStateTime {
+                        publish_time: *time,
+                        slot: 0,
+                    },
+                );
+                self.message_states
+                    .range(lookup_key..)
+                    .next()
+                    .filter(|entry| {
+                        entry
+                            .as_ref()
+                            .map(|(k, _)| k.starts_with(&prefix))
+                            .unwrap_or(true)
+                    })
+            }
+            RequestTime::AtSlot(slot) => {
+                match self.message_slots.get(message_slot_key(key, *slot))? {
+                    Some(state_key) => self
+                        .message_states
+                        .get(&state_key)?
+                        .map(|value| Ok((state_key, value))),
+                    None => None,
+                }
+            }
+        };
+
+        entry
+            .transpose()?
+            .map(|(_, value)| MessageStateRecord::decode(&value))
+            .transpose()
+    }
+
+    fn fetch_accumulator_messages(&self, slot: Slot) -> Result<Option<AccumulatorMessages>> {
+        self.accumulator_messages
+            .get(slot.to_be_bytes())?
+            .map(|value| AccumulatorMessagesRecord::decode(&value))
+            .transpose()
+    }
+
+    fn fetch_wormhole_merkle_state(&self, slot: Slot) -> Result<Option<WormholeMerkleState>> {
+        self.wormhole_merkle_states
+            .get(slot.to_be_bytes())?
+            .map(|value| WormholeMerkleStateRecord::decode(&value))
+            .transpose()
+    }
+
+    fn latest_message_states(&self, limit: usize) -> Result<Vec<MessageState>> {
+        let mut message_states = Vec::new();
+        let mut next = self.message_states.first()?;
+
+        // Walk over the distinct key prefixes, taking the last `limit` entries of each.
+        while let Some((key, _)) = next {
+            let prefix = key[..MESSAGE_KEY_PREFIX_LEN].to_vec();
+            let mut latest = self
+                .message_states
+                .scan_prefix(&prefix)
+                .rev()
+                .take(limit)
+                .map(|entry| MessageStateRecord::decode(&entry?.1))
+                .collect::<Result<Vec<_>>>()?;
+            latest.reverse();
+            message_states.extend(latest);
+
+            next = match self.message_states.scan_prefix(&prefix).next_back() {
+                Some(last) => self.message_states.get_gt(last?.0)?,
+                None => None,
+            };
+        }
+
+        Ok(message_states)
+    }
+
+    fn latest_accumulator_messages(&self, limit: usize) -> Result<Vec<AccumulatorMessages>> {
+        let mut latest = self
+            .accumulator_messages
+            .iter()
+            .rev()
+            .take(limit)
+            .map(|entry| AccumulatorMessagesRecord::decode(&entry?.1))
+            .collect::<Result<Vec<_>>>()?;
+        latest.reverse();
+        Ok(latest)
+    }
+
+    fn latest_wormhole_merkle_states(&self, limit: usize) -> Result<Vec<WormholeMerkleState>> {
+        let mut latest = self
+            .wormhole_merkle_states
+            .iter()
+            .rev()
+            .take(limit)
+            .map(|entry| WormholeMerkleStateRecord::decode(&entry?.1))
+            .collect::<Result<Vec<_>>>()?;
+        latest.reverse();
+        Ok(latest)
+    }
+
+    fn prune(&self, cutoff: UnixTimestamp) -> Result<usize> {
+        let mut pruned = 0;
+        for entry in self.expiry.range(..encode_timestamp(cutoff)) {
+            let (expiry_key, _) = entry?;
+            let (stored_at, entry_key) = expiry_key.split_at(8);
+            let key = &entry_key[1..];
+            match entry_key[0] {
+                EXPIRY_MESSAGE_STATE => {
+                    // The slot index key is the key prefix followed by the trailing slot.
+                    let mut slot_key = key[..MESSAGE_KEY_PREFIX_LEN].to_vec();
+                    slot_key.extend_from_slice(&key[key.len() - 8..]);
+                    self.message_slots.remove(slot_key)?;
+                    self.message_states.remove(key)?;
+                }
+                EXPIRY_ACCUMULATOR_MESSAGES => {
+                    self.accumulator_messages.remove(key)?;
+                }
+                EXPIRY_WORMHOLE_MERKLE_STATE => {
+                    self.wormhole_merkle_states.remove(key)?;
+                }
+                _ => {}
+            }
+            self.expiry.remove(&expiry_key)?;
+            // Forget the last write of the entry unless it was written again in the meantime.
+            let _ = self
+                .expiry_keys
+                .compare_and_swap(entry_key, Some(stored_at), None::<&[u8]>)?;
+            pruned += 1;
+        }
+        self.db.flush()?;
+        Ok(pruned)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use {
+        super::*,
+        crate::state::cache::{
+            test::create_dummy_price_feed_message_state, Cache, CacheState, MessageStateFilter,
+        },
+        std::{sync::Arc, time::Duration},
+    };
+
+    fn temporary_storage() -> SledStorage {
+        SledStorage::from_db(sled::Config::new().temporary(true).open().unwrap()).unwrap()
+    }
+
+    fn dummy_key(feed_id: [u8; 32]) -> MessageStateKey {
+        MessageStateKey {
+            feed_id,
+            type_: MessageType::PriceFeedMessage,
+        }
+    }
+
+    #[test]
+    fn test_store_and_fetch_message_states_works() {
+        let storage = temporary_storage();
+        let old = create_dummy_price_feed_message_state([1; 32], 10, 5);
+        let new = create_dummy_price_feed_message_state([1; 32], 20, 10);
+        let other = create_dummy_price_feed_message_state([2; 32], 15, 7);
+        storage
+            .store_message_states(&[old.clone(), new.clone(), other.clone()])
+            .unwrap();
+
+        let key = dummy_key([1; 32]);
+        assert_eq!(
+            storage
+                .fetch_message_state(&key, &RequestTime::Latest)
+                .unwrap(),
+            Some(new.clone())
+        );
+        assert_eq!(
+            storage
+                .fetch_message_state(&key, &RequestTime::FirstAfter(10))
+                .unwrap(),
+            Some(old.clone())
+        );
+        assert_eq!(
+            storage
+                .fetch_message_state(&key, &RequestTime::FirstAfter(11))
+                .unwrap(),
+            Some(new.clone())
+        );
+        assert_eq!(
+            storage
+                .fetch_message_state(&key, &RequestTime::AtSlot(5))
+                .unwrap(),
+            Some(old)
+        );
+
+        // Times before the oldest record and after the latest record are not found, and the
+        // lookup does not leak into the next feed.
+        assert_eq!(
+            storage
+                .fetch_message_state(&key, &RequestTime::FirstAfter(9))
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            storage
+                .fetch_message_state(&key, &RequestTime::FirstAfter(21))
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            storage
+                .fetch_message_state(&key, &RequestTime::AtSlot(7))
+                .unwrap(),
+            None
+        );
+
+        assert_eq!(storage.latest_message_states(1).unwrap(), vec![new, other]);
+    }
+
+    #[test]
+    fn test_prune_removes_expired_entries() {
+        let storage = temporary_storage();
+        let stored_at = now();
+        storage
+            .store_message_states(&[create_dummy_price_feed_message_state([1; 32], 10, 5)])
+            .unwrap();
+        storage
+            .store_wormhole_merkle_state(&WormholeMerkleState {
+                root: WormholeMerkleRoot {
+                    slot: 5,
+                    ring_size: 100,
+                    root: [0; 20],
+                },
+                vaa: vec![1, 2, 3],
+            })
+            .unwrap();
+
+        // Nothing has been stored before the cutoff yet.
+        assert_eq!(storage.prune(stored_at).unwrap(), 0);
+        assert!(storage.fetch_wormhole_merkle_state(5).unwrap().is_some());
+
+        assert_eq!(storage.prune(now() + 1).unwrap(), 2);
+        assert_eq!(storage.fetch_wormhole_merkle_state(5).unwrap(), None);
+        assert_eq!(
+            storage
+                .fetch_message_state(&dummy_key([1; 32]), &RequestTime::AtSlot(5))
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_storing_again_replaces_expiry() {
+        let storage = temporary_storage();
+        let message_state = create_dummy_price_feed_message_state([1; 32], 10, 5);
+        storage
+            .store_message_states_at(100, &[message_state.clone()])
+            .unwrap();
+        storage
+            .store_message_states_at(200, &[message_state.clone()])
+            .unwrap();
+
+        // The first write does not expire the entry written again later.
+        assert_eq!(storage.prune(150).unwrap(), 0);
+        assert_eq!(
+            storage
+                .fetch_message_state(&dummy_key([1; 32]), &RequestTime::AtSlot(5))
+                .unwrap(),
+            Some(message_state)
+        );
+
+        assert_eq!(storage.prune(250).unwrap(), 1);
+        assert_eq!(
+            storage
+                .fetch_message_state(&dummy_key([1; 32]), &RequestTime::AtSlot(5))
+                .unwrap(),
+            None
+        );
+        assert!(storage.expiry_keys.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_cache_falls_back_to_storage_and_warms_up() {
+        let storage: Arc<dyn Storage> = Arc::new(temporary_storage());
+
+        // A cache of size 1 only holds the latest state, the older one is served from storage.
+        let cache = CacheState::new(1, Some(storage.clone()), Duration::from_secs(60));
+        let old = create_dummy_price_feed_message_state([1; 32], 10, 5);
+        let new = create_dummy_price_feed_message_state([1; 32], 20, 10);
+        cache
+            .store_message_states(vec![old.clone(), new.clone()])
+            .await
+            .unwrap();
+        assert_eq!(
+            cache
+                .fetch_message_states(
+                    vec![[1; 32]],
+                    RequestTime::FirstAfter(10),
+                    MessageStateFilter::Only(MessageType::PriceFeedMessage),
+                )
+                .await
+                .unwrap(),
+            vec![old]
+        );
+
+        // A new cache on top of the same storage starts with the latest state.
+        let restarted = CacheState::new(1, Some(storage), Duration::from_secs(60));
+        assert_eq!(
+            restarted
+                .fetch_message_states(
+                    vec![[1; 32]],
+                    RequestTime::Latest,
+                    MessageStateFilter::Only(MessageType::PriceFeedMessage),
+                )
+                .await
+                .unwrap(),
+            vec![new]
+        );
+    }
+}
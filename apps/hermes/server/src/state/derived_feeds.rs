@@ -0,0 +1,552 @@
+//! This module computes price feeds derived from the prices of other feeds, such as cross rates
+//! (ETH/BTC from ETH/USD and BTC/USD) or inverse pairs (USD/JPY from JPY/USD).
+//!
+//! Derived feeds are configured with a formula multiplying and dividing the prices of existing
+//! feeds. They are computed by Hermes and are not signed by the Wormhole guardians, so they are
+//! only ever served parsed and can't be verified on-chain.
+
+use {
+    super::{
+        aggregate::{RequestTime, Slot},
+        cache::{Cache, MessageStateFilter},
+        State,
+    },
+    anyhow::{anyhow, bail, ensure, Context, Result},
+    pyth_sdk::{Price, PriceIdentifier},
+    pythnet_sdk::messages::{Message, MessageType, PriceFeedMessage},
+    serde::Deserialize,
+    std::{
+        collections::{HashMap, HashSet},
+        path::Path,
+        str::FromStr,
+    },
+};
+
+/// Exponent of the derived prices if not configured. Intermediate results are normalized by
+/// `pyth_sdk` so prices are only precise to about 9 significant digits regardless of this value.
+const DEFAULT_DERIVED_FEED_EXPONENT: i32 = -8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Operation {
+    Multiply,
+    Divide,
+}
+
+/// A product of price feed prices, each of them either multiplying or dividing the result.
+///
+/// Formulas are written as ids separated by `*` and `/`, e.g. `<ETH/USD id> / <BTC/USD id>`. A
+/// formula may start with `1` for inverse pairs, e.g. `1 / <JPY/USD id>`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Formula {
+    terms: Vec<(Operation, PriceIdentifier)>,
+}
+
+impl Formula {
+    /// The ids of the price feeds the formula depends on.
+    pub fn price_ids(&self) -> HashSet<PriceIdentifier> {
+        self.terms.iter().map(|(_, id)| *id).collect()
+    }
+
+    /// Evaluate the formula over the given prices, scaling the result to `expo`.
+    ///
+    /// The confidence interval is propagated like in `pyth_sdk`: the relative confidences of the
+    /// terms are summed, which bounds the exact (quadrature) propagation from above. The publish
+    /// time of the result is the oldest one of the terms.
+    fn evaluate(&self, prices: &HashMap<PriceIdentifier, Price>, expo: i32) -> Option<Price> {
+        // Dividing a small integer loses precision as `pyth_sdk` keeps the digits of the
+        // numerator, so the unit is represented with as many digits as a normalized price.
+        let mut result = Price {
+            price: 100_000_000,
+            conf: 0,
+            expo: -8,
+            publish_time: i64::MAX,
+        };
+        for (operation, id) in &self.terms {
+            let price = prices.get(id)?;
+            result = match operation {
+                Operation::Multiply => result.mul(price)?,
+                Operation::Divide => result.div(price)?,
+            };
+        }
+        result.scale_to_exponent(expo)
+    }
+}
+
+impl FromStr for Formula {
+    type Err = anyhow::Error;
+
+    fn from_str(formula: &str) -> Result<Self> {
+        let formula = formula.replace('*', " * ").replace('/', " / ");
+        let mut tokens = formula.split_whitespace().peekable();
+
+        // A leading `1` is the multiplicative identity and is only useful before a division.
+        let mut operation = Some(Operation::Multiply);
+        if tokens.peek() == Some(&"1") {
+            tokens.next();
+            ensure!(
+                tokens.next() == Some("/"),
+                "Formula must divide the leading 1"
+            );
+            operation = Some(Operation::Divide);
+        }
+
+        let mut terms = vec![];
+        for token in tokens {
+            match (token, operation) {
+                ("*", None) => operation = Some(Operation::Multiply),
+                ("/", None) => operation = Some(Operation::Divide),
+                (id, Some(current)) if id != "*" && id != "/" => {
+                    let id = PriceIdentifier::from_hex(id.strip_prefix("0x").unwrap_or(id))
+                        .map_err(|_| anyhow!("Invalid price feed id {}", id))?;
+                    terms.push((current, id));
+                    operation = None;
+                }
+                _ => bail!("Unexpected token {} in formula", token),
+            }
+        }
+
+        ensure!(!terms.is_empty(), "Formula has no price feed");
+        ensure!(operation.is_none(), "Formula ends with an operator");
+        Ok(Self { terms })
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct DerivedFeedConfig {
+    #[serde(with = "crate::serde::hex")]
+    id: [u8; 32],
+    formula: String,
+    #[serde(default = "default_derived_feed_exponent")]
+    expo: i32,
+}
+
+fn default_derived_feed_exponent() -> i32 {
+    DEFAULT_DERIVED_FEED_EXPONENT
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DerivedFeed {
+    pub id: PriceIdentifier,
+    pub formula: Formula,
+    /// The formula as configured, to show where the price comes from.
+    pub formula_str: String,
+    pub expo: i32,
+}
+
+/// Load the derived feeds from a JSON file containing a list of `{"id", "formula", "expo"}`
+/// objects, the exponent being optional.
+pub fn load_derived_feeds(path: &Path) -> Result<Vec<DerivedFeed>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read derived feeds file {}", path.display()))?;
+    let configs: Vec<DerivedFeedConfig> = serde_json::from_str(&contents)
+        .with_context(|| format!("Failed to parse derived feeds file {}", path.display()))?;
+
+    let mut ids = HashSet::new();
+    let feeds = configs
+        .into_iter()
+        .map(|config| {
+            let id = PriceIdentifier::new(config.id);
+            ensure!(ids.insert(id), "Duplicate derived feed id {}", id);
+            let formula: Formula = config
+                .formula
+                .parse()
+                .with_context(|| format!("Invalid formula of derived feed {}", id))?;
+            Ok(DerivedFeed {
+                id,
+                formula,
+                formula_str: config.formula,
+                expo: config.expo,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    // The terms of the formulas are real price feeds, a derived feed with the same id would
+    // shadow them.
+    let term_ids = feeds
+        .iter()
+        .flat_map(|feed| feed.formula.price_ids())
+        .collect::<HashSet<_>>();
+    if let Some(feed) = feeds.iter().find(|feed| term_ids.contains(&feed.id)) {
+        bail!("Derived feed id {} collides with a price feed id", feed.id);
+    }
+    Ok(feeds)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DerivedPriceFeed {
+    pub id: PriceIdentifier,
+    pub price: Price,
+    pub ema_price: Price,
+    /// The oldest slot of the prices the feed is derived from.
+    pub slot: Slot,
+    pub formula: String,
+}
+
+pub struct DerivedFeedsState {
+    feeds: HashMap<PriceIdentifier, DerivedFeed>,
+}
+
+impl Default for DerivedFeedsState {
+    fn default() -> Self {
+        Self::new(vec![])
+    }
+}
+
+impl DerivedFeedsState {
+    pub fn new(feeds: Vec<DerivedFeed>) -> Self {
+        Self {
+            feeds: feeds.into_iter().map(|feed| (feed.id, feed)).collect(),
+        }
+    }
+}
+
+/// Allow downcasting State into DerivedFeedsState for functions that depend on the `DerivedFeeds` service.
+impl<'a> From<&'a State> for &'a DerivedFeedsState {
+    fn from(state: &'a State) -> &'a DerivedFeedsState {
+        &state.derived_feeds
+    }
+}
+
+#[async_trait::async_trait]
+pub trait DerivedFeeds: Cache {
+    fn is_derived_feed(&self, id: &PriceIdentifier) -> bool;
+    /// Compute the derived feeds from the prices of their terms at the given time. Fails if any of
+    /// the prices is not available.
+    async fn get_derived_price_feeds(
+        &self,
+        ids: &[PriceIdentifier],
+        request_time: RequestTime,
+    ) -> Result<Vec<DerivedPriceFeed>>;
+}
+
+#[async_trait::async_trait]
+impl<T> DerivedFeeds for T
+where
+    for<'a> &'a T: Into<&'a DerivedFeedsState>,
+    T: Cache,
+    T: Sync,
+{
+    fn is_derived_feed(&self, id: &PriceIdentifier) -> bool {
+        self.into().feeds.contains_key(id)
+    }
+
+    async fn get_derived_price_feeds(
+        &self,
+        ids: &[PriceIdentifier],
+        request_time: RequestTime,
+    ) -> Result<Vec<DerivedPriceFeed>> {
+        let feeds = ids
+            .iter()
+            .map(|id| {
+                self.into()
+                    .feeds
+                    .get(id)
+                    .ok_or_else(|| anyhow!("Unknown derived feed {}", id))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if feeds.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let term_ids = feeds
+            .iter()
+            .flat_map(|feed| feed.formula.price_ids())
+            .collect::<HashSet<_>>();
+        let messages: HashMap<PriceIdentifier, (Slot, PriceFeedMessage)> =
+            Cache::fetch_message_states(
+                self,
+                term_ids.into_iter().map(|id| id.to_bytes()).collect(),
+                request_time,
+                MessageStateFilter::Only(MessageType::PriceFeedMessage),
+            )
+            .await?
+            .into_iter()
+            .filter_map(|message_state| match message_state.message {
+                Message::PriceFeedMessage(message) => Some((
+                    PriceIdentifier::new(message.feed_id),
+                    (message_state.slot, message),
+                )),
+                _ => None,
+            })
+            .collect();
+
+        let prices = messages
+            .iter()
+            .map(|(id, (_, message))| {
+                (
+                    *id,
+                    Price {
+                        price: message.price,
+                        conf: message.conf,
+                        expo: message.exponent,
+                        publish_time: message.publish_time,
+                    },
+                )
+            })
+            .collect::<HashMap<_, _>>();
+        let ema_prices = messages
+            .iter()
+            .map(|(id, (_, message))| {
+                (
+                    *id,
+                    Price {
+                        price: message.ema_price,
+                        conf: message.ema_conf,
+                        expo: message.exponent,
+                        publish_time: message.publish_time,
+                    },
+                )
+            })
+            .collect::<HashMap<_, _>>();
+
+        feeds
+            .into_iter()
+            .map(|feed| {
+                let slot = feed
+                    .formula
+                    .price_ids()
+                    .iter()
+                    .map(|id| messages.get(id).map(|(slot, _)| *slot))
+                    .min()
+                    .flatten()
+                    .ok_or_else(|| anyhow!("Missing prices of derived feed {}", feed.id))?;
+                let price = feed.formula.evaluate(&prices, feed.expo).ok_or_else(|| {
+                    anyhow!("Failed to compute price of derived feed {}", feed.id)
+                })?;
+                let ema_price = feed
+                    .formula
+                    .evaluate(&ema_prices, feed.expo)
+                    .ok_or_else(|| {
+                        anyhow!("Failed to compute EMA price of derived feed {}", feed.id)
+                    })?;
+                Ok(DerivedPriceFeed {
+                    id: feed.id,
+                    price,
+                    ema_price,
+                    slot,
+                    formula: feed.formula_str.clone(),
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use {
+        super::*,
+        crate::state::cache::{
+            test::create_dummy_price_feed_message_state, CacheState, MessageState,
+        },
+        std::time::Duration,
+    };
+
+    fn price(price: i64, conf: u64, expo: i32, publish_time: i64) -> Price {
+        Price {
+            price,
+            conf,
+            expo,
+            publish_time,
+        }
+    }
+
+    #[test]
+    fn test_parse_formula_works() {
+        let a = PriceIdentifier::new([2; 32]);
+        let b = PriceIdentifier::new([3; 32]);
+
+        assert_eq!(
+            format!("{} / {}", a, b.to_hex())
+                .parse::<Formula>()
+                .unwrap(),
+            Formula {
+                terms: vec![(Operation::Multiply, a), (Operation::Divide, b)],
+            }
+        );
+        assert_eq!(
+            format!("{}*{}", a, b).parse::<Formula>().unwrap(),
+            Formula {
+                terms: vec![(Operation::Multiply, a), (Operation::Multiply, b)],
+            }
+        );
+        assert_eq!(
+            format!("1 / {}", a).parse::<Formula>().unwrap(),
+            Formula {
+                terms: vec![(Operation::Divide, a)],
+            }
+        );
+
+        assert!("".parse::<Formula>().is_err());
+        assert!("1".parse::<Formula>().is_err());
+        assert!(format!("1 * {}", a).parse::<Formula>().is_err());
+        assert!(format!("{} /", a).parse::<Formula>().is_err());
+        assert!(format!("{} {}", a, b).parse::<Formula>().is_err());
+        assert!(format!("{} * / {}", a, b).parse::<Formula>().is_err());
+        assert!(format!("/ {}", a).parse::<Formula>().is_err());
+        assert!("abc / def".parse::<Formula>().is_err());
+    }
+
+    #[test]
+    fn test_evaluate_formula_works() {
+        let eth = PriceIdentifier::new([2; 32]);
+        let btc = PriceIdentifier::new([3; 32]);
+        let prices = HashMap::from([
+            // 3000 +- 3 (0.1%)
+            (eth, price(300_000_000_000, 300_000_000, -8, 100)),
+            // 60000 +- 120 (0.2%)
+            (btc, price(6_000_000, 12_000, -2, 90)),
+        ]);
+
+        // ETH/BTC = 0.05 with a relative confidence of 0.3%.
+        let formula: Formula = format!("{} / {}", eth, btc).parse().unwrap();
+        assert_eq!(
+            formula.evaluate(&prices, -8),
+            Some(price(5_000_000, 15_000, -8, 90))
+        );
+
+        // BTC/ETH = 20 with a relative confidence of 0.3%.
+        let formula: Formula = format!("{} / {}", btc, eth).parse().unwrap();
+        assert_eq!(
+            formula.evaluate(&prices, -5),
+            Some(price(2_000_000, 6_000, -5, 90))
+        );
+
+        // USD/BTC = 1 / 60000 with a relative confidence of 0.2%.
+        let formula: Formula = format!("1 / {}", btc).parse().unwrap();
+        assert_eq!(
+            formula.evaluate(&prices, -12),
+            Some(price(16_666_666, 33_333, -12, 90))
+        );
+
+        // ETH * BTC, exercising the multiplication.
+        let formula: Formula = format!("{} * {}", eth, btc).parse().unwrap();
+        assert_eq!(
+            formula.evaluate(&prices, 0),
+            Some(price(180_000_000, 540_000, 0, 90))
+        );
+
+        // Missing prices and divisions by zero can't be evaluated.
+        let formula: Formula = format!("{} / {}", eth, PriceIdentifier::new([4; 32]))
+            .parse()
+            .unwrap();
+        assert_eq!(formula.evaluate(&prices, -8), None);
+        let prices = HashMap::from([(eth, price(0, 0, -8, 100))]);
+        let formula: Formula = format!("1 / {}", eth).parse().unwrap();
+        assert_eq!(formula.evaluate(&prices, -8), None);
+    }
+
+    #[test]
+    fn test_load_derived_feeds_rejects_colliding_ids() {
+        let eth = PriceIdentifier::new([2; 32]);
+        let btc = PriceIdentifier::new([3; 32]);
+        let eth_btc = PriceIdentifier::new([4; 32]);
+        let path = std::env::temp_dir().join(format!("hermes-derived-{}", std::process::id()));
+        let load = |feeds: serde_json::Value| {
+            std::fs::write(&path, feeds.to_string()).unwrap();
+            let result = load_derived_feeds(&path);
+            std::fs::remove_file(&path).unwrap();
+            result
+        };
+
+        let feeds = load(serde_json::json!([
+            { "id": eth_btc.to_hex(), "formula": format!("{} / {}", eth, btc) },
+        ]))
+        .unwrap();
+        assert_eq!(feeds.len(), 1);
+        assert_eq!(feeds[0].id, eth_btc);
+        assert_eq!(feeds[0].expo, DEFAULT_DERIVED_FEED_EXPONENT);
+
+        // A derived feed can't use the id of a price feed, including its own terms.
+        assert!(load(serde_json::json!([
+            { "id": eth.to_hex(), "formula": format!("{} / {}", eth, btc) },
+        ]))
+        .is_err());
+        assert!(load(serde_json::json!([
+            { "id": eth_btc.to_hex(), "formula": format!("{} / {}", eth, btc) },
+            { "id": btc.to_hex(), "formula": format!("1 / {}", eth) },
+        ]))
+        .is_err());
+    }
+
+    struct TestState {
+        cache: CacheState,
+        derived_feeds: DerivedFeedsState,
+    }
+
+    impl<'a> From<&'a TestState> for &'a CacheState {
+        fn from(state: &'a TestState) -> &'a CacheState {
+            &state.cache
+        }
+    }
+
+    impl<'a> From<&'a TestState> for &'a DerivedFeedsState {
+        fn from(state: &'a TestState) -> &'a DerivedFeedsState {
+            &state.derived_feeds
+        }
+    }
+
+    fn message_state(feed_id: [u8; 32], price: i64, ema_price: i64, slot: Slot) -> MessageState {
+        let mut message_state = create_dummy_price_feed_message_state(feed_id, 100, slot);
+        if let Message::PriceFeedMessage(message) = &mut message_state.message {
+            message.price = price;
+            message.conf = 0;
+            message.exponent = -8;
+            message.ema_price = ema_price;
+            message.ema_conf = 0;
+        }
+        message_state
+    }
+
+    #[tokio::test]
+    async fn test_get_derived_price_feeds_works() {
+        let eth = PriceIdentifier::new([2; 32]);
+        let btc = PriceIdentifier::new([3; 32]);
+        let eth_btc = PriceIdentifier::new([4; 32]);
+        let state = TestState {
+            cache: CacheState::new(10, None, Duration::default()),
+            derived_feeds: DerivedFeedsState::new(vec![DerivedFeed {
+                id: eth_btc,
+                formula: format!("{} / {}", eth, btc).parse().unwrap(),
+                formula_str: format!("{} / {}", eth, btc),
+                expo: -8,
+            }]),
+        };
+
+        assert!(state.is_derived_feed(&eth_btc));
+        assert!(!state.is_derived_feed(&eth));
+
+        // The prices of the terms are not available yet.
+        assert!(state
+            .get_derived_price_feeds(&[eth_btc], RequestTime::Latest)
+            .await
+            .is_err());
+
+        state
+            .store_message_states(vec![
+                message_state(eth.to_bytes(), 300_000_000_000, 310_000_000_000, 10),
+                message_state(btc.to_bytes(), 6_000_000_000_000, 6_200_000_000_000, 11),
+            ])
+            .await
+            .unwrap();
+
+        assert_eq!(
+            state
+                .get_derived_price_feeds(&[eth_btc], RequestTime::Latest)
+                .await
+                .unwrap(),
+            vec![DerivedPriceFeed {
+                id: eth_btc,
+                price: price(5_000_000, 0, -8, 100),
+                ema_price: price(5_000_000, 0, -8, 100),
+                slot: 10,
+                formula: format!("{} / {}", eth, btc),
+            }]
+        );
+
+        // Only derived feeds can be computed.
+        assert!(state
+            .get_derived_price_feeds(&[eth], RequestTime::Latest)
+            .await
+            .is_err());
+    }
+}
@@ -1,23 +1,24 @@
 use {
-    crate::{
-        api::types::{AssetType, PriceFeedMetadata},
-        state::State,
-    },
+    crate::{api::types::PriceFeedMetadata, state::State},
     anyhow::Result,
-    tokio::sync::RwLock,
+    search::{PriceFeedsMetadataFilter, PriceFeedsMetadataIndex},
+    tokio::sync::{Notify, RwLock},
 };
 
-pub const DEFAULT_PRICE_FEEDS_CACHE_UPDATE_INTERVAL: u64 = 600;
+pub mod search;
 
 #[derive(Default)]
 pub struct PriceFeedMetaState {
-    pub data: RwLock<Vec<PriceFeedMetadata>>,
+    pub data: RwLock<PriceFeedsMetadataIndex>,
+    /// Notified to fetch the metadata before the next scheduled update.
+    reload: Notify,
 }
 
 impl PriceFeedMetaState {
     pub fn new() -> Self {
         Self {
-            data: RwLock::new(Vec::new()),
+            data: RwLock::new(PriceFeedsMetadataIndex::default()),
+            reload: Notify::new(),
         }
     }
 }
@@ -38,9 +39,10 @@ pub trait PriceFeedMeta {
     ) -> Result<()>;
     async fn get_price_feeds_metadata(
         &self,
-        query: Option<String>,
-        asset_type: Option<AssetType>,
+        filter: &PriceFeedsMetadataFilter,
     ) -> Result<Vec<PriceFeedMetadata>>;
+    fn request_price_feeds_metadata_reload(&self);
+    async fn price_feeds_metadata_reload_requested(&self);
 }
 
 #[async_trait::async_trait]
@@ -51,7 +53,7 @@ where
 {
     async fn retrieve_price_feeds_metadata(&self) -> Result<Vec<PriceFeedMetadata>> {
         let price_feeds_metadata = self.into().data.read().await;
-        Ok(price_feeds_metadata.clone())
+        Ok(price_feeds_metadata.price_feeds_metadata())
     }
 
     async fn store_price_feeds_metadata(
@@ -59,36 +61,25 @@ where
         price_feeds_metadata: &[PriceFeedMetadata],
     ) -> Result<()> {
         let mut price_feeds_metadata_write_guard = self.into().data.write().await;
-        *price_feeds_metadata_write_guard = price_feeds_metadata.to_vec();
+        *price_feeds_metadata_write_guard = PriceFeedsMetadataIndex::new(price_feeds_metadata);
         Ok(())
     }
 
     async fn get_price_feeds_metadata(
         &self,
-        query: Option<String>,
-        asset_type: Option<AssetType>,
+        filter: &PriceFeedsMetadataFilter,
     ) -> Result<Vec<PriceFeedMetadata>> {
-        let mut price_feeds_metadata = self.retrieve_price_feeds_metadata().await?;
-
-        // Filter by query if provided
-        if let Some(query_str) = &query {
-            price_feeds_metadata.retain(|feed| {
-                feed.attributes.get("symbol").map_or(false, |symbol| {
-                    symbol.to_lowercase().contains(&query_str.to_lowercase())
-                })
-            });
-        }
+        Ok(self.into().data.read().await.search(filter))
+    }
 
-        // Filter by asset_type if provided
-        if let Some(asset_type) = &asset_type {
-            price_feeds_metadata.retain(|feed| {
-                feed.attributes.get("asset_type").map_or(false, |type_str| {
-                    type_str.to_lowercase().trim().replace(" ", "_")
-                        == asset_type.to_string().to_lowercase()
-                })
-            });
-        }
+    /// Ask the metadata updater to fetch the metadata now. Requests made while a fetch is pending
+    /// are merged into one.
+    fn request_price_feeds_metadata_reload(&self) {
+        self.into().reload.notify_one();
+    }
 
-        Ok(price_feeds_metadata)
+    /// Wait until a reload of the metadata is requested.
+    async fn price_feeds_metadata_reload_requested(&self) {
+        self.into().reload.notified().await;
     }
 }
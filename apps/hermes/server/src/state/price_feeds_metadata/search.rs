@@ -0,0 +1,364 @@
+//! Search index over the price feeds metadata.
+//!
+//! The index is rebuilt every time the metadata is refreshed, so the attributes are normalized and
+//! split into words once instead of on every search.
+
+use {
+    crate::api::types::{AssetType, PriceFeedMetadata},
+    std::collections::BTreeMap,
+};
+
+/// Attributes matched by a fuzzy query, besides the symbol.
+const FUZZY_SEARCH_ATTRIBUTES: [&str; 5] = [
+    "base",
+    "description",
+    "generic_symbol",
+    "display_symbol",
+    "cms_symbol",
+];
+
+/// Filters of a price feeds metadata search. Every filter that is set must match.
+#[derive(Clone, Debug, Default)]
+pub struct PriceFeedsMetadataFilter {
+    /// Matched against the symbol as a case insensitive substring or, if `fuzzy` is set, against
+    /// the symbol and the descriptive attributes with ranking.
+    pub query: Option<String>,
+    pub fuzzy: bool,
+    pub asset_type: Option<AssetType>,
+    pub base: Option<String>,
+    pub quote_currency: Option<String>,
+    pub country: Option<String>,
+    pub tenor: Option<String>,
+    pub cms_symbol: Option<String>,
+}
+
+impl PriceFeedsMetadataFilter {
+    /// The normalized values expected for each filtered attribute.
+    fn attributes(&self) -> Vec<(&'static str, String)> {
+        [
+            (
+                "asset_type",
+                self.asset_type.as_ref().map(ToString::to_string),
+            ),
+            ("base", self.base.clone()),
+            ("quote_currency", self.quote_currency.clone()),
+            ("country", self.country.clone()),
+            ("tenor", self.tenor.clone()),
+            ("cms_symbol", self.cms_symbol.clone()),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| Some((name, normalize(&value?))))
+        .collect()
+    }
+}
+
+/// Normalize an attribute value for exact comparisons, e.g. `"Crypto Redemption Rate"` becomes
+/// `"crypto_redemption_rate"`.
+fn normalize(value: &str) -> String {
+    value.trim().to_lowercase().replace(' ', "_")
+}
+
+/// Split a value into its lowercased alphanumeric words, e.g. `"Crypto.BTC/USD"` becomes
+/// `["crypto", "btc", "usd"]`.
+fn words(value: &str) -> impl Iterator<Item = String> + '_ {
+    value
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+}
+
+/// Levenshtein distance between two strings, in characters.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut distances: Vec<usize> = (0..=b.len()).collect();
+    for (i, a) in a.chars().enumerate() {
+        let mut previous_diagonal = distances[0];
+        distances[0] = i + 1;
+        for (j, b) in b.iter().enumerate() {
+            let substitution = previous_diagonal + usize::from(a != *b);
+            previous_diagonal = distances[j + 1];
+            distances[j + 1] = substitution.min(distances[j] + 1).min(distances[j + 1] + 1);
+        }
+    }
+    distances[b.len()]
+}
+
+/// How well a query word matches a word of a feed. Lower is better.
+fn word_rank(query_word: &str, word: &str) -> Option<u32> {
+    // Allow one typo for every four characters, and none in words too short to tell apart.
+    let max_distance = match query_word.chars().count() {
+        0..=2 => 0,
+        len => (len / 4).max(1),
+    };
+    if word == query_word {
+        Some(0)
+    } else if word.starts_with(query_word) {
+        Some(1)
+    } else if edit_distance(query_word, word) <= max_distance {
+        Some(2)
+    } else {
+        None
+    }
+}
+
+#[derive(Clone, Debug)]
+struct IndexEntry {
+    metadata: PriceFeedMetadata,
+    /// The normalized attributes, compared with the structured filters.
+    attributes: BTreeMap<String, String>,
+    /// The lowercased symbol, e.g. `"crypto.btc/usd"`.
+    symbol: String,
+    /// The lowercased words of the symbol and of the fuzzy searched attributes.
+    words: Vec<String>,
+}
+
+impl IndexEntry {
+    fn new(metadata: &PriceFeedMetadata) -> Self {
+        let symbol = metadata
+            .attributes
+            .get("symbol")
+            .map(|symbol| symbol.to_lowercase())
+            .unwrap_or_default();
+        let mut words: Vec<String> = words(&symbol)
+            .chain(
+                FUZZY_SEARCH_ATTRIBUTES
+                    .iter()
+                    .filter_map(|name| metadata.attributes.get(*name))
+                    .flat_map(|value| words(value)),
+            )
+            .collect();
+        words.sort();
+        words.dedup();
+
+        Self {
+            metadata: metadata.clone(),
+            attributes: metadata
+                .attributes
+                .iter()
+                .map(|(name, value)| (name.clone(), normalize(value)))
+                .collect(),
+            symbol,
+            words,
+        }
+    }
+
+    fn matches_attributes(&self, attributes: &[(&'static str, String)]) -> bool {
+        attributes
+            .iter()
+            .all(|(name, value)| self.attributes.get(*name) == Some(value))
+    }
+
+    /// Rank of the entry for a lowercased fuzzy query. Lower is better, and `None` means that
+    /// the entry does not match.
+    ///
+    /// Matches on the whole symbol come first: exact (with or without the asset type prefix,
+    /// e.g. `btc/usd` for `Crypto.BTC/USD`), then prefix, then substring. Otherwise every word of
+    /// the query must match a word of the entry, exactly, as a prefix or with a few typos, and the
+    /// worst of these matches ranks the entry.
+    fn fuzzy_rank(&self, query: &str) -> Option<u32> {
+        let unprefixed_symbol = self
+            .symbol
+            .split_once('.')
+            .map_or(self.symbol.as_str(), |(_, symbol)| symbol);
+        if self.symbol == query || unprefixed_symbol == query {
+            return Some(0);
+        }
+        if self.symbol.starts_with(query) || unprefixed_symbol.starts_with(query) {
+            return Some(1);
+        }
+        if self.symbol.contains(query) {
+            return Some(2);
+        }
+
+        let mut rank = None;
+        for query_word in words(query) {
+            let word_rank = self
+                .words
+                .iter()
+                .filter_map(|word| word_rank(&query_word, word))
+                .min()?;
+            rank = rank.max(Some(3 + word_rank));
+        }
+        rank
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct PriceFeedsMetadataIndex {
+    entries: Vec<IndexEntry>,
+}
+
+impl PriceFeedsMetadataIndex {
+    pub fn new(price_feeds_metadata: &[PriceFeedMetadata]) -> Self {
+        Self {
+            entries: price_feeds_metadata.iter().map(IndexEntry::new).collect(),
+        }
+    }
+
+    pub fn price_feeds_metadata(&self) -> Vec<PriceFeedMetadata> {
+        self.entries
+            .iter()
+            .map(|entry| entry.metadata.clone())
+            .collect()
+    }
+
+    /// The metadata of the price feeds matching the filter. They are in the order they were
+    /// fetched in, unless the query is fuzzy, in which case the best matches come first.
+    pub fn search(&self, filter: &PriceFeedsMetadataFilter) -> Vec<PriceFeedMetadata> {
+        let attributes = filter.attributes();
+        let query = filter.query.as_ref().map(|query| query.to_lowercase());
+        let entries = self
+            .entries
+            .iter()
+            .filter(|entry| entry.matches_attributes(&attributes));
+
+        let entries: Vec<&IndexEntry> = match query {
+            None => entries.collect(),
+            Some(query) if !filter.fuzzy => entries
+                .filter(|entry| entry.symbol.contains(&query))
+                .collect(),
+            Some(query) => {
+                let mut ranked: Vec<(u32, &IndexEntry)> = entries
+                    .filter_map(|entry| Some((entry.fuzzy_rank(&query)?, entry)))
+                    .collect();
+                ranked.sort_by(|(a_rank, a), (b_rank, b)| {
+                    (a_rank, a.symbol.len(), &a.symbol).cmp(&(b_rank, b.symbol.len(), &b.symbol))
+                });
+                ranked.into_iter().map(|(_, entry)| entry).collect()
+            }
+        };
+
+        entries
+            .into_iter()
+            .map(|entry| entry.metadata.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use {super::*, crate::api::types::RpcPriceIdentifier, pyth_sdk::PriceIdentifier};
+
+    fn metadata(id: u8, attributes: &[(&str, &str)]) -> PriceFeedMetadata {
+        PriceFeedMetadata {
+            id: RpcPriceIdentifier::new([id; 32]),
+            attributes: attributes
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        }
+    }
+
+    fn search(index: &PriceFeedsMetadataIndex, filter: PriceFeedsMetadataFilter) -> Vec<u8> {
+        index
+            .search(&filter)
+            .into_iter()
+            .map(|metadata| PriceIdentifier::from(metadata.id).to_bytes()[0])
+            .collect()
+    }
+
+    #[test]
+    fn test_price_feeds_metadata_search() {
+        let index = PriceFeedsMetadataIndex::new(&[
+            metadata(
+                1,
+                &[
+                    ("symbol", "Crypto.BTC/USD"),
+                    ("asset_type", "Crypto"),
+                    ("base", "BTC"),
+                    ("quote_currency", "USD"),
+                    ("description", "BITCOIN / US DOLLAR"),
+                ],
+            ),
+            metadata(
+                2,
+                &[
+                    ("symbol", "Crypto.WBTC/USD"),
+                    ("asset_type", "Crypto"),
+                    ("base", "WBTC"),
+                    ("quote_currency", "USD"),
+                    ("description", "WRAPPED BITCOIN / US DOLLAR"),
+                ],
+            ),
+            metadata(
+                3,
+                &[
+                    ("symbol", "Crypto.STBTC/BTC.RR"),
+                    ("asset_type", "Crypto Redemption Rate"),
+                    ("base", "STBTC"),
+                    ("quote_currency", "BTC"),
+                ],
+            ),
+            metadata(
+                4,
+                &[
+                    ("symbol", "Equity.US.AAPL/USD"),
+                    ("asset_type", "Equity"),
+                    ("base", "AAPL"),
+                    ("quote_currency", "USD"),
+                    ("country", "US"),
+                    ("cms_symbol", "AAPL"),
+                    ("description", "APPLE INC"),
+                ],
+            ),
+        ]);
+
+        // The substring query keeps the fetch order.
+        let filter = |query: &str, fuzzy: bool| PriceFeedsMetadataFilter {
+            query: Some(query.to_string()),
+            fuzzy,
+            ..Default::default()
+        };
+        assert_eq!(search(&index, filter("btc", false)), vec![1, 2, 3]);
+        assert_eq!(search(&index, filter("bitcoin", false)), Vec::<u8>::new());
+
+        // The fuzzy query ranks the matches on the symbol first, and tolerates typos.
+        assert_eq!(search(&index, filter("BTC/USD", true)), vec![1, 2]);
+        assert_eq!(search(&index, filter("bitcoin", true)), vec![1, 2]);
+        assert_eq!(search(&index, filter("wraped bitcoin", true)), vec![2]);
+        assert_eq!(search(&index, filter("apple", true)), vec![4]);
+        assert_eq!(search(&index, filter("aple", true)), vec![4]);
+        assert_eq!(search(&index, filter("ethereum", true)), Vec::<u8>::new());
+
+        // The structured filters are exact and case insensitive.
+        assert_eq!(
+            search(
+                &index,
+                PriceFeedsMetadataFilter {
+                    quote_currency: Some("usd".to_string()),
+                    country: Some("us".to_string()),
+                    ..Default::default()
+                }
+            ),
+            vec![4]
+        );
+        assert_eq!(
+            search(
+                &index,
+                PriceFeedsMetadataFilter {
+                    asset_type: Some(AssetType::CryptoRedemptionRate),
+                    ..Default::default()
+                }
+            ),
+            vec![3]
+        );
+        assert_eq!(
+            search(
+                &index,
+                PriceFeedsMetadataFilter {
+                    base: Some("btc".to_string()),
+                    ..filter("bitcoin", true)
+                }
+            ),
+            vec![1]
+        );
+    }
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("bitcoin", "bitcoin"), 0);
+        assert_eq!(edit_distance("bitcon", "bitcoin"), 1);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+    }
+}
@@ -0,0 +1,282 @@
+//! This module keeps the latest per-publisher components of the Pyth price accounts, which are not
+//! part of the accumulator messages and are read from Pythnet separately.
+
+use {
+    super::{aggregate::Slot, State},
+    anyhow::{anyhow, ensure, Result},
+    pyth_sdk::PriceIdentifier,
+    solana_sdk::pubkey::Pubkey,
+    std::collections::HashMap,
+    tokio::sync::{
+        broadcast::{Receiver, Sender},
+        RwLock,
+    },
+};
+
+/// Capacity of the channel notifying subscribers of new publisher components.
+const PUBLISHERS_UPDATE_CHANNEL_SIZE: usize = 100;
+
+/// Layout of the Pyth oracle price account, see `pc.h` in pyth-client.
+///
+/// The components are decoded directly rather than through `pyth_sdk_solana` as the number of
+/// components of the Pythnet price accounts differs from the Solana ones the SDK targets.
+const PRICE_ACCOUNT_MAGIC: u32 = 0xa1b2c3d4;
+const PRICE_ACCOUNT_TYPE: u32 = 3;
+const PRICE_ACCOUNT_EXPO_OFFSET: usize = 20;
+const PRICE_ACCOUNT_NUM_COMPONENTS_OFFSET: usize = 24;
+const PRICE_ACCOUNT_LAST_SLOT_OFFSET: usize = 32;
+const PRICE_ACCOUNT_COMPONENTS_OFFSET: usize = 240;
+const PRICE_COMPONENT_SIZE: usize = 96;
+const PRICE_COMPONENT_LATEST_OFFSET: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PublisherStatus {
+    Unknown,
+    Trading,
+    Halted,
+    Auction,
+    Ignored,
+}
+
+impl From<u32> for PublisherStatus {
+    fn from(status: u32) -> Self {
+        match status {
+            1 => PublisherStatus::Trading,
+            2 => PublisherStatus::Halted,
+            3 => PublisherStatus::Auction,
+            4 => PublisherStatus::Ignored,
+            _ => PublisherStatus::Unknown,
+        }
+    }
+}
+
+/// The latest price submitted by a publisher to a price account.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PublisherPrice {
+    pub publisher: Pubkey,
+    pub price: i64,
+    pub conf: u64,
+    pub status: PublisherStatus,
+    pub slot: Slot,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PriceFeedPublishers {
+    pub id: PriceIdentifier,
+    pub expo: i32,
+    /// The slot of the latest aggregation of the price account.
+    pub slot: Slot,
+    pub publishers: Vec<PublisherPrice>,
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32> {
+    Ok(u32::from_le_bytes(
+        data.get(offset..offset + 4)
+            .ok_or(anyhow!("Price account is too short"))?
+            .try_into()?,
+    ))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Result<u64> {
+    Ok(u64::from_le_bytes(
+        data.get(offset..offset + 8)
+            .ok_or(anyhow!("Price account is too short"))?
+            .try_into()?,
+    ))
+}
+
+impl PriceFeedPublishers {
+    /// Decode the publisher components of a price account, the price feed id being the address of
+    /// the account.
+    pub fn try_from_price_account(id: PriceIdentifier, data: &[u8]) -> Result<Self> {
+        ensure!(
+            read_u32(data, 0)? == PRICE_ACCOUNT_MAGIC,
+            "Invalid price account magic"
+        );
+        ensure!(
+            read_u32(data, 8)? == PRICE_ACCOUNT_TYPE,
+            "Account is not a price account"
+        );
+
+        let num_components = read_u32(data, PRICE_ACCOUNT_NUM_COMPONENTS_OFFSET)? as usize;
+        let publishers = (0..num_components)
+            .map(|i| {
+                let offset = PRICE_ACCOUNT_COMPONENTS_OFFSET + i * PRICE_COMPONENT_SIZE;
+                let latest = offset + PRICE_COMPONENT_LATEST_OFFSET;
+                let publisher: [u8; 32] = data
+                    .get(offset..offset + 32)
+                    .ok_or(anyhow!("Price account is too short"))?
+                    .try_into()?;
+                Ok(PublisherPrice {
+                    publisher: Pubkey::new_from_array(publisher),
+                    price: read_u64(data, latest)? as i64,
+                    conf: read_u64(data, latest + 8)?,
+                    status: read_u32(data, latest + 16)?.into(),
+                    slot: read_u64(data, latest + 24)?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            id,
+            expo: read_u32(data, PRICE_ACCOUNT_EXPO_OFFSET)? as i32,
+            slot: read_u64(data, PRICE_ACCOUNT_LAST_SLOT_OFFSET)?,
+            publishers,
+        })
+    }
+}
+
+pub struct PublishersState {
+    data: RwLock<HashMap<PriceIdentifier, PriceFeedPublishers>>,
+    update_tx: Sender<()>,
+}
+
+impl Default for PublishersState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PublishersState {
+    pub fn new() -> Self {
+        Self {
+            data: RwLock::new(HashMap::new()),
+            update_tx: tokio::sync::broadcast::channel(PUBLISHERS_UPDATE_CHANNEL_SIZE).0,
+        }
+    }
+}
+
+/// Allow downcasting State into PublishersState for functions that depend on the `Publishers` service.
+impl<'a> From<&'a State> for &'a PublishersState {
+    fn from(state: &'a State) -> &'a PublishersState {
+        &state.publishers
+    }
+}
+
+#[async_trait::async_trait]
+pub trait Publishers {
+    /// Subscribe to a notification sent every time new publisher components are stored.
+    fn subscribe_publishers(&self) -> Receiver<()>;
+    async fn store_price_feeds_publishers(&self, price_feeds_publishers: Vec<PriceFeedPublishers>);
+    async fn get_price_feed_publishers(&self, id: PriceIdentifier) -> Option<PriceFeedPublishers>;
+}
+
+#[async_trait::async_trait]
+impl<T> Publishers for T
+where
+    for<'a> &'a T: Into<&'a PublishersState>,
+    T: Sync,
+{
+    fn subscribe_publishers(&self) -> Receiver<()> {
+        self.into().update_tx.subscribe()
+    }
+
+    async fn store_price_feeds_publishers(&self, price_feeds_publishers: Vec<PriceFeedPublishers>) {
+        {
+            let mut data = self.into().data.write().await;
+            for price_feed_publishers in price_feeds_publishers {
+                data.insert(price_feed_publishers.id, price_feed_publishers);
+            }
+        }
+
+        // Ignore the result as there might be no subscribers.
+        let _ = self.into().update_tx.send(());
+    }
+
+    async fn get_price_feed_publishers(&self, id: PriceIdentifier) -> Option<PriceFeedPublishers> {
+        self.into().data.read().await.get(&id).cloned()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn create_price_account(expo: i32, last_slot: Slot, components: &[PublisherPrice]) -> Vec<u8> {
+        let mut data = vec![0u8; PRICE_ACCOUNT_COMPONENTS_OFFSET + 32 * PRICE_COMPONENT_SIZE];
+        data[0..4].copy_from_slice(&PRICE_ACCOUNT_MAGIC.to_le_bytes());
+        data[8..12].copy_from_slice(&PRICE_ACCOUNT_TYPE.to_le_bytes());
+        data[20..24].copy_from_slice(&expo.to_le_bytes());
+        data[24..28].copy_from_slice(&(components.len() as u32).to_le_bytes());
+        data[32..40].copy_from_slice(&last_slot.to_le_bytes());
+        for (i, component) in components.iter().enumerate() {
+            let offset = PRICE_ACCOUNT_COMPONENTS_OFFSET + i * PRICE_COMPONENT_SIZE;
+            let latest = offset + PRICE_COMPONENT_LATEST_OFFSET;
+            data[offset..offset + 32].copy_from_slice(&component.publisher.to_bytes());
+            data[latest..latest + 8].copy_from_slice(&component.price.to_le_bytes());
+            data[latest + 8..latest + 16].copy_from_slice(&component.conf.to_le_bytes());
+            let status: u32 = match component.status {
+                PublisherStatus::Unknown => 0,
+                PublisherStatus::Trading => 1,
+                PublisherStatus::Halted => 2,
+                PublisherStatus::Auction => 3,
+                PublisherStatus::Ignored => 4,
+            };
+            data[latest + 16..latest + 20].copy_from_slice(&status.to_le_bytes());
+            data[latest + 24..latest + 32].copy_from_slice(&component.slot.to_le_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn test_try_from_price_account_works() {
+        let components = vec![
+            PublisherPrice {
+                publisher: Pubkey::new_from_array([1; 32]),
+                price: -100,
+                conf: 5,
+                status: PublisherStatus::Trading,
+                slot: 10,
+            },
+            PublisherPrice {
+                publisher: Pubkey::new_from_array([2; 32]),
+                price: 200,
+                conf: 7,
+                status: PublisherStatus::Halted,
+                slot: 9,
+            },
+        ];
+        let data = create_price_account(-8, 11, &components);
+
+        let id = PriceIdentifier::new([3; 32]);
+        assert_eq!(
+            PriceFeedPublishers::try_from_price_account(id, &data).unwrap(),
+            PriceFeedPublishers {
+                id,
+                expo: -8,
+                slot: 11,
+                publishers: components,
+            }
+        );
+
+        // Accounts that are not price accounts or are truncated are rejected.
+        assert!(PriceFeedPublishers::try_from_price_account(id, &data[..100]).is_err());
+        let mut data = data;
+        data[8..12].copy_from_slice(&2u32.to_le_bytes());
+        assert!(PriceFeedPublishers::try_from_price_account(id, &data).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_store_and_get_price_feed_publishers_works() {
+        let state = PublishersState::new();
+        let mut rx = Publishers::subscribe_publishers(&state);
+        let price_feed_publishers = PriceFeedPublishers {
+            id: PriceIdentifier::new([1; 32]),
+            expo: -8,
+            slot: 10,
+            publishers: vec![],
+        };
+
+        Publishers::store_price_feeds_publishers(&state, vec![price_feed_publishers.clone()]).await;
+
+        assert!(rx.try_recv().is_ok());
+        assert_eq!(
+            Publishers::get_price_feed_publishers(&state, PriceIdentifier::new([1; 32])).await,
+            Some(price_feed_publishers)
+        );
+        assert_eq!(
+            Publishers::get_price_feed_publishers(&state, PriceIdentifier::new([2; 32])).await,
+            None
+        );
+    }
+}
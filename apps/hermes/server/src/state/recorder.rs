@@ -0,0 +1,198 @@
+//! This module records the input of Hermes to a file so it can later be replayed offline, for
+//! example to reproduce an incident or to run end-to-end tests without a Wormhole spy or Pythnet.
+//!
+//! The recording is a JSON document per line, each holding an update and the time it was received.
+
+use {
+    super::State,
+    crate::api::types::PriceFeedMetadata,
+    anyhow::Result,
+    serde::{Deserialize, Serialize},
+    std::{
+        fs::File,
+        io::{LineWriter, Write},
+        sync::mpsc::{self, Receiver, Sender},
+        thread::{self, JoinHandle},
+        time::{SystemTime, UNIX_EPOCH},
+    },
+};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RecordedUpdate {
+    GuardianSet {
+        id: u32,
+        keys: Vec<[u8; 20]>,
+    },
+    /// A VAA that has been verified against the guardian sets.
+    Vaa {
+        #[serde(with = "crate::serde::base64")]
+        data: Vec<u8>,
+    },
+    /// The raw data of a Pythnet accumulator account.
+    AccumulatorMessages {
+        #[serde(with = "crate::serde::base64")]
+        data: Vec<u8>,
+    },
+    /// The metadata of the price feeds, which the state needs to be ready.
+    PriceFeedsMetadata {
+        metadata: Vec<PriceFeedMetadata>,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    /// The time the update was received in milliseconds since the Unix epoch.
+    pub timestamp_ms: u64,
+    #[serde(flatten)]
+    pub update: RecordedUpdate,
+}
+
+impl Record {
+    pub fn parse(line: &str) -> Result<Self> {
+        Ok(serde_json::from_str(line)?)
+    }
+}
+
+pub struct RecorderState {
+    /// The records are sent to a dedicated thread that appends them to the file, so that the
+    /// listeners never wait on the disk. Nothing is recorded if not set.
+    records_tx: Option<Sender<Record>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl Default for RecorderState {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl RecorderState {
+    pub fn new(file: Option<File>) -> Self {
+        let Some(file) = file else {
+            return Self {
+                records_tx: None,
+                writer: None,
+            };
+        };
+
+        let (records_tx, records_rx) = mpsc::channel();
+        Self {
+            records_tx: Some(records_tx),
+            writer: Some(thread::spawn(move || write_records(file, records_rx))),
+        }
+    }
+}
+
+/// Flush the pending records when the state is dropped.
+impl Drop for RecorderState {
+    fn drop(&mut self) {
+        self.records_tx.take();
+        if let Some(writer) = self.writer.take() {
+            if writer.join().is_err() {
+                tracing::error!("Recording writer panicked.");
+            }
+        }
+    }
+}
+
+/// Append the records to the file, a line at a time, until the state is dropped.
+fn write_records(file: File, records_rx: Receiver<Record>) {
+    let mut file = LineWriter::new(file);
+    for record in records_rx {
+        let result = serde_json::to_string(&record)
+            .map_err(anyhow::Error::from)
+            .and_then(|line| Ok(writeln!(file, "{}", line)?));
+        if let Err(e) = result {
+            tracing::error!(error = ?e, "Failed to record update.");
+        }
+    }
+}
+
+/// Allow downcasting State into RecorderState for functions that depend on the `Recorder` service.
+impl<'a> From<&'a State> for &'a RecorderState {
+    fn from(state: &'a State) -> &'a RecorderState {
+        &state.recorder
+    }
+}
+
+pub trait Recorder {
+    /// Append an update to the recording, if recording is enabled.
+    fn record(&self, update: RecordedUpdate);
+}
+
+impl<T> Recorder for T
+where
+    for<'a> &'a T: Into<&'a RecorderState>,
+    T: Sync,
+{
+    fn record(&self, update: RecordedUpdate) {
+        let Some(records_tx) = &self.into().records_tx else {
+            return;
+        };
+
+        let record = Record {
+            timestamp_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|now| now.as_millis() as u64)
+                .unwrap_or_default(),
+            update,
+        };
+        if records_tx.send(record).is_err() {
+            tracing::error!("Failed to record update, the recording writer stopped.");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use {
+        super::*,
+        crate::api::types::RpcPriceIdentifier,
+        std::io::{BufRead, BufReader},
+    };
+
+    #[test]
+    fn test_record_and_parse_works() {
+        let path = std::env::temp_dir().join(format!("hermes-record-{}", std::process::id()));
+        let state = RecorderState::new(Some(File::create(&path).unwrap()));
+
+        let updates = vec![
+            RecordedUpdate::GuardianSet {
+                id: 3,
+                keys: vec![[1; 20], [2; 20]],
+            },
+            RecordedUpdate::Vaa {
+                data: vec![1, 2, 3],
+            },
+            RecordedUpdate::AccumulatorMessages { data: vec![4, 5] },
+            RecordedUpdate::PriceFeedsMetadata {
+                metadata: vec![PriceFeedMetadata {
+                    id: RpcPriceIdentifier::new([3; 32]),
+                    attributes: [("symbol".to_string(), "BTC/USD".to_string())].into(),
+                }],
+            },
+        ];
+        for update in updates.clone() {
+            Recorder::record(&state, update);
+        }
+        drop(state);
+
+        let records = BufReader::new(File::open(&path).unwrap())
+            .lines()
+            .map(|line| Record::parse(&line.unwrap()).unwrap())
+            .collect::<Vec<_>>();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            records
+                .iter()
+                .map(|record| record.update.clone())
+                .collect::<Vec<_>>(),
+            updates
+        );
+        assert!(records
+            .windows(2)
+            .all(|records| records[0].timestamp_ms <= records[1].timestamp_ms));
+    }
+}
@@ -1,22 +1,29 @@
 use {
     super::{
         aggregate::{Aggregates, Update},
+        recorder::{RecordedUpdate, Recorder},
         State,
     },
     crate::network::wormhole::GuardianSet,
     anyhow::{anyhow, ensure, Result},
-    chrono::DateTime,
+    chrono::{DateTime, Utc},
+    prometheus_client::{
+        encoding::{EncodeLabelSet, EncodeLabelValue},
+        metrics::{counter::Counter, family::Family},
+        registry::Registry,
+    },
     pythnet_sdk::{
-        wire::v1::{WormholeMessage, WormholePayload},
+        wire::v1::{WormholeMerkleRoot, WormholeMessage, WormholePayload},
         ACCUMULATOR_EMITTER_ADDRESS,
     },
     secp256k1::{
         ecdsa::{RecoverableSignature, RecoveryId},
         Message, Secp256k1,
     },
+    serde::Serialize,
     serde_wormhole::RawMessage,
     sha3::{Digest, Keccak256},
-    std::collections::{BTreeMap, BTreeSet},
+    std::collections::{BTreeMap, BTreeSet, VecDeque},
     tokio::sync::RwLock,
     wormhole_sdk::{
         vaa::{Body, Header},
@@ -26,6 +33,57 @@ use {
 
 const OBSERVED_CACHE_SIZE: usize = 1000;
 
+/// Number of the most recently rejected VAAs kept for auditing.
+const REJECTED_VAAS_SIZE: usize = 1000;
+
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, EncodeLabelValue, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RejectionReason {
+    /// The VAA or its payload could not be parsed.
+    Malformed,
+    /// The VAA was not emitted by the Pythnet accumulator.
+    WrongEmitter,
+    /// The VAA is signed by a guardian set that is not known to Hermes.
+    UnknownGuardianSet,
+    /// A signature is invalid, out of the guardian set or out of order.
+    BadSignature,
+    /// Too few signatures match the guardian set.
+    QuorumNotMet,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct RejectedVaaLabels {
+    pub reason: RejectionReason,
+}
+
+/// The reason a VAA failed verification, along with the underlying error.
+#[derive(Debug)]
+pub struct VaaRejection {
+    pub reason: RejectionReason,
+    pub error: anyhow::Error,
+}
+
+impl VaaRejection {
+    fn new(reason: RejectionReason, error: impl Into<anyhow::Error>) -> Self {
+        Self {
+            reason,
+            error: error.into(),
+        }
+    }
+}
+
+/// A VAA that failed verification, as reported by the audit endpoint.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct RejectedVaa {
+    pub reason: RejectionReason,
+    pub error: String,
+    /// Missing if the VAA could not be parsed.
+    pub sequence: Option<u64>,
+    pub guardian_set_index: Option<u32>,
+    pub emitter_chain: Option<u16>,
+    pub rejected_at: DateTime<Utc>,
+}
+
 pub struct WormholeState {
     /// Sequence numbers of lately observed Vaas.
     ///
@@ -34,19 +92,27 @@ pub struct WormholeState {
 
     /// Wormhole guardian sets. It is used to verify Vaas before using them.
     guardian_set: RwLock<BTreeMap<u32, GuardianSet>>,
-}
 
-impl Default for WormholeState {
-    fn default() -> Self {
-        Self::new()
-    }
+    /// The most recently rejected Vaas, oldest first.
+    rejected_vaas: RwLock<VecDeque<RejectedVaa>>,
+
+    rejected_vaas_total: Family<RejectedVaaLabels, Counter>,
 }
 
 impl WormholeState {
-    pub fn new() -> Self {
+    pub fn new(metrics_registry: &mut Registry) -> Self {
+        let rejected_vaas_total = Family::default();
+        metrics_registry.register(
+            "wormhole_rejected_vaas",
+            "Total number of VAAs that failed verification",
+            rejected_vaas_total.clone(),
+        );
+
         Self {
             observed_vaa_seqs: RwLock::new(BTreeSet::new()),
             guardian_set: RwLock::new(BTreeMap::new()),
+            rejected_vaas: RwLock::new(VecDeque::new()),
+            rejected_vaas_total,
         }
     }
 }
@@ -59,10 +125,14 @@ impl<'a> From<&'a State> for &'a WormholeState {
 }
 
 #[async_trait::async_trait]
-pub trait Wormhole: Aggregates {
-    async fn store_vaa(&self, sequence: u64, vaa_bytes: Vec<u8>);
-    async fn process_message(&self, vaa_bytes: Vec<u8>) -> Result<()>;
+pub trait Wormhole: Aggregates + Recorder {
+    async fn store_vaa(&self, sequence: u64, vaa_bytes: Vec<u8>) -> bool;
+    async fn process_message(&self, vaa_bytes: Vec<u8>) -> Result<bool>;
+    async fn verify_message(&self, vaa_bytes: &[u8]) -> Result<WormholeMerkleRoot>;
+    async fn latest_observed_vaa_seq(&self) -> Option<u64>;
     async fn update_guardian_set(&self, id: u32, guardian_set: GuardianSet);
+    async fn add_guardian_set(&self, id: u32, guardian_set: GuardianSet) -> Result<()>;
+    async fn get_rejected_vaas(&self) -> Vec<RejectedVaa>;
 }
 
 #[async_trait::async_trait]
@@ -71,80 +141,227 @@ where
     for<'a> &'a T: Into<&'a WormholeState>,
     T: Sync,
     T: Aggregates,
+    T: Recorder,
 {
     /// Update the guardian set with the given ID in the state.
     #[tracing::instrument(skip(self, guardian_set))]
     async fn update_guardian_set(&self, id: u32, guardian_set: GuardianSet) {
         let mut guardian_sets = self.into().guardian_set.write().await;
+        // The guardian sets are polled periodically, only changes are recorded.
+        if guardian_sets.get(&id) == Some(&guardian_set) {
+            return;
+        }
+        self.record(RecordedUpdate::GuardianSet {
+            id,
+            keys: guardian_set.keys.clone(),
+        });
+        guardian_sets.insert(id, guardian_set);
+    }
+
+    /// Add a guardian set newer than the known ones. Guardian sets only ever rotate forward, so a
+    /// known guardian set can't be replaced.
+    #[tracing::instrument(skip(self, guardian_set))]
+    async fn add_guardian_set(&self, id: u32, guardian_set: GuardianSet) -> Result<()> {
+        let mut guardian_sets = self.into().guardian_set.write().await;
+        if let Some(latest_id) = guardian_sets.keys().next_back() {
+            ensure!(
+                id > *latest_id,
+                "Guardian set {} is not newer than the latest guardian set {}",
+                id,
+                latest_id
+            );
+        }
+        self.record(RecordedUpdate::GuardianSet {
+            id,
+            keys: guardian_set.keys.clone(),
+        });
         guardian_sets.insert(id, guardian_set);
+        Ok(())
     }
 
+    /// Store a verified VAA, returning false if a VAA with the same sequence was already stored.
     #[tracing::instrument(skip(self, vaa_bytes))]
-    async fn store_vaa(&self, sequence: u64, vaa_bytes: Vec<u8>) {
+    async fn store_vaa(&self, sequence: u64, vaa_bytes: Vec<u8>) -> bool {
         // Check VAA hasn't already been seen, this may have been checked previously
         // but due to async nature it's possible other threads have mutated the state
         // since this VAA started processing.
-        let mut observed_vaa_seqs = self.into().observed_vaa_seqs.write().await;
-        if observed_vaa_seqs.contains(&sequence) {
-            return;
-        }
+        {
+            let mut observed_vaa_seqs = self.into().observed_vaa_seqs.write().await;
+            if !observed_vaa_seqs.insert(sequence) {
+                return false;
+            }
 
-        // Clear old cached VAA sequences.
-        while observed_vaa_seqs.len() > OBSERVED_CACHE_SIZE {
-            observed_vaa_seqs.pop_first();
+            // Clear old cached VAA sequences.
+            while observed_vaa_seqs.len() > OBSERVED_CACHE_SIZE {
+                observed_vaa_seqs.pop_first();
+            }
         }
 
+        self.record(RecordedUpdate::Vaa {
+            data: vaa_bytes.clone(),
+        });
+
         // Hand the VAA to the aggregate store.
         if let Err(e) = Aggregates::store_update(self, Update::Vaa(vaa_bytes)).await {
             tracing::error!(error = ?e, "Failed to store VAA in aggregate store.");
         }
+        true
     }
 
-    async fn process_message(&self, vaa_bytes: Vec<u8>) -> Result<()> {
-        let vaa = serde_wormhole::from_slice::<Vaa<&RawMessage>>(&vaa_bytes)?;
+    async fn latest_observed_vaa_seq(&self) -> Option<u64> {
+        self.into().observed_vaa_seqs.read().await.last().copied()
+    }
 
-        // Log VAA Processing.
-        let vaa_timestamp = DateTime::from_timestamp(vaa.timestamp as i64, 0)
-            .ok_or(anyhow!("Failed to parse VAA Tiestamp"))?
-            .format("%Y-%m-%dT%H:%M:%S.%fZ")
-            .to_string();
+    async fn get_rejected_vaas(&self) -> Vec<RejectedVaa> {
+        self.into()
+            .rejected_vaas
+            .read()
+            .await
+            .iter()
+            .cloned()
+            .collect()
+    }
 
-        let slot = match WormholeMessage::try_from_bytes(vaa.payload)?.payload {
-            WormholePayload::Merkle(proof) => proof.slot,
+    /// Verify and store a VAA received from any source, returning false if it was already
+    /// observed. The same VAA is expected to arrive from several sources.
+    ///
+    /// VAAs failing verification are kept for auditing and counted by reason.
+    async fn process_message(&self, vaa_bytes: Vec<u8>) -> Result<bool> {
+        let vaa = match serde_wormhole::from_slice::<Vaa<&RawMessage>>(&vaa_bytes) {
+            Ok(vaa) => vaa,
+            Err(e) => {
+                let rejection = VaaRejection::new(RejectionReason::Malformed, e);
+                return Err(reject_vaa(self.into(), None, rejection).await);
+            }
         };
-        tracing::info!(slot = slot, vaa_timestamp = vaa_timestamp, "Observed VAA");
-
-        // Check VAA hasn't already been seen.
-        ensure!(
-            !self
-                .into()
-                .observed_vaa_seqs
-                .read()
-                .await
-                .contains(&vaa.sequence),
-            "Previously observed VAA: {}",
-            vaa.sequence
-        );
 
-        // Check VAA source is valid, we don't want to process other protocols VAAs.
-        validate_vaa_source(&vaa)?;
+        // Check VAA hasn't already been seen, before logging as every source delivers it.
+        if self
+            .into()
+            .observed_vaa_seqs
+            .read()
+            .await
+            .contains(&vaa.sequence)
+        {
+            return Ok(false);
+        }
 
-        // Verify the VAA has been signed by a known guardian set.
-        let vaa = verify_vaa(
-            self.into()
-                .guardian_set
-                .read()
-                .await
-                .get(&vaa.guardian_set_index)
-                .ok_or_else(|| anyhow!("Unknown guardian set: {}", vaa.guardian_set_index))?,
-            vaa,
-        )?;
+        if let Err(rejection) = verify_vaa_message(self.into(), &vaa).await {
+            return Err(reject_vaa(self.into(), Some(&vaa), rejection).await);
+        }
 
         // Finally, store the resulting VAA in Hermes.
-        self.store_vaa(vaa.sequence, vaa_bytes).await;
-        Ok(())
+        Ok(self.store_vaa(vaa.sequence, vaa_bytes).await)
     }
+
+    /// Verify a VAA without storing it, returning the merkle root it signs. VAAs failing
+    /// verification are kept for auditing like in `process_message`.
+    async fn verify_message(&self, vaa_bytes: &[u8]) -> Result<WormholeMerkleRoot> {
+        let vaa = match serde_wormhole::from_slice::<Vaa<&RawMessage>>(vaa_bytes) {
+            Ok(vaa) => vaa,
+            Err(e) => {
+                let rejection = VaaRejection::new(RejectionReason::Malformed, e);
+                return Err(reject_vaa(self.into(), None, rejection).await);
+            }
+        };
+        if let Err(rejection) = verify_vaa_message(self.into(), &vaa).await {
+            return Err(reject_vaa(self.into(), Some(&vaa), rejection).await);
+        }
+        match WormholeMessage::try_from_bytes(vaa.payload)?.payload {
+            WormholePayload::Merkle(root) => Ok(root),
+        }
+    }
+}
+
+/// Check the source, payload and signatures of a VAA.
+async fn verify_vaa_message(
+    state: &WormholeState,
+    vaa: &Vaa<&RawMessage>,
+) -> Result<(), VaaRejection> {
+    // Check VAA source is valid, we don't want to process other protocols VAAs.
+    validate_vaa_source(vaa).map_err(|e| VaaRejection::new(RejectionReason::WrongEmitter, e))?;
+
+    // Log VAA Processing.
+    let vaa_timestamp = DateTime::from_timestamp(vaa.timestamp as i64, 0)
+        .ok_or(anyhow!("Failed to parse VAA Tiestamp"))
+        .map_err(|e| VaaRejection::new(RejectionReason::Malformed, e))?
+        .format("%Y-%m-%dT%H:%M:%S.%fZ")
+        .to_string();
+
+    let slot = match WormholeMessage::try_from_bytes(vaa.payload)
+        .map_err(|e| VaaRejection::new(RejectionReason::Malformed, e))?
+        .payload
+    {
+        WormholePayload::Merkle(proof) => proof.slot,
+    };
+    tracing::info!(slot = slot, vaa_timestamp = vaa_timestamp, "Observed VAA");
+
+    // Verify the VAA has been signed by a known guardian set.
+    verify_vaa(
+        state
+            .guardian_set
+            .read()
+            .await
+            .get(&vaa.guardian_set_index)
+            .ok_or_else(|| {
+                VaaRejection::new(
+                    RejectionReason::UnknownGuardianSet,
+                    anyhow!("Unknown guardian set: {}", vaa.guardian_set_index),
+                )
+            })?,
+        vaa.clone(),
+    )?;
+    Ok(())
+}
+
+/// Record a rejected VAA and return the error to report to the caller.
+///
+/// Every source delivers the same VAAs, so a sequence that was already rejected is not recorded
+/// again.
+async fn reject_vaa(
+    state: &WormholeState,
+    vaa: Option<&Vaa<&RawMessage>>,
+    rejection: VaaRejection,
+) -> anyhow::Error {
+    let sequence = vaa.map(|vaa| vaa.sequence);
+    let mut rejected_vaas = state.rejected_vaas.write().await;
+    if sequence.is_some()
+        && rejected_vaas
+            .iter()
+            .any(|rejected_vaa| rejected_vaa.sequence == sequence)
+    {
+        return rejection.error;
+    }
+
+    tracing::warn!(
+        reason = ?rejection.reason,
+        sequence,
+        error = ?rejection.error,
+        "Rejected VAA."
+    );
+
+    state
+        .rejected_vaas_total
+        .get_or_create(&RejectedVaaLabels {
+            reason: rejection.reason,
+        })
+        .inc();
+
+    rejected_vaas.push_back(RejectedVaa {
+        reason: rejection.reason,
+        error: rejection.error.to_string(),
+        sequence,
+        guardian_set_index: vaa.map(|vaa| vaa.guardian_set_index),
+        emitter_chain: vaa.map(|vaa| vaa.emitter_chain.into()),
+        rejected_at: Utc::now(),
+    });
+    while rejected_vaas.len() > REJECTED_VAAS_SIZE {
+        rejected_vaas.pop_front();
+    }
+
+    rejection.error
 }
+
 // Rejects VAAs from invalid sources.
 #[tracing::instrument(skip(vaa))]
 fn validate_vaa_source(vaa: &Vaa<&RawMessage>) -> Result<()> {
@@ -165,9 +382,12 @@ fn validate_vaa_source(vaa: &Vaa<&RawMessage>) -> Result<()> {
 fn verify_vaa<'a>(
     guardian_set: &GuardianSet,
     vaa: Vaa<&'a RawMessage>,
-) -> Result<Vaa<&'a RawMessage>> {
+) -> Result<Vaa<&'a RawMessage>, VaaRejection> {
+    let bad_signature = |e| VaaRejection::new(RejectionReason::BadSignature, e);
     let (header, body): (Header, Body<&RawMessage>) = vaa.into();
-    let digest = body.digest()?;
+    let digest = body
+        .digest()
+        .map_err(|e| VaaRejection::new(RejectionReason::Malformed, e))?;
 
     // Ideally we need to test the signatures but currently Wormhole doesn't give us any easy way
     // to do it, so we just bypass the check in tests.
@@ -189,37 +409,43 @@ fn verify_vaa<'a>(
 
         let signer_id: usize = signature.index.into();
         if signer_id >= guardian_set.keys.len() {
-            return Err(anyhow!(
+            return Err(bad_signature(anyhow!(
                 "Signer ID is out of range. Signer ID: {}, guardian set size: {}",
                 signer_id,
                 guardian_set.keys.len()
-            ));
+            )));
         }
 
         // On-chain verification expects signatures to be sorted by signer ID. We can exit early if
         // this constraint is violated.
         if let Some(true) = last_signer_id.map(|v| v >= signer_id) {
-            return Err(anyhow!(
+            return Err(bad_signature(anyhow!(
                 "Signatures are not sorted by signer ID. Last signer ID: {:?}, current signer ID: {}",
                 last_signer_id,
                 signer_id
-            ));
+            )));
         }
 
         // Recover the public key from an [u8; 65] serialized ECDSA signature in (v, r, s) format
-        let recid = RecoveryId::from_i32(signature.signature[64].into())?;
+        let recid = RecoveryId::from_i32(signature.signature[64].into())
+            .map_err(|e| bad_signature(e.into()))?;
 
         // An address is the last 20 bytes of the Keccak256 hash of the uncompressed public key.
-        let pubkey: &[u8; 65] = &secp
-            .recover_ecdsa(
-                &Message::from_slice(&digest.secp256k_hash)?,
-                &RecoverableSignature::from_compact(&signature.signature[..64], recid)?,
-            )?
+        let pubkey: &[u8; 65] = &Message::from_slice(&digest.secp256k_hash)
+            .and_then(|message| {
+                secp.recover_ecdsa(
+                    &message,
+                    &RecoverableSignature::from_compact(&signature.signature[..64], recid)?,
+                )
+            })
+            .map_err(|e| bad_signature(e.into()))?
             .serialize_uncompressed();
 
         // The address is the last 20 bytes of the Keccak256 hash of the public key
         let address: [u8; 32] = Keccak256::new_with_prefix(&pubkey[1..]).finalize().into();
-        let address: [u8; 20] = address[address.len() - 20..].try_into()?;
+        let address: [u8; 20] = address[address.len() - 20..]
+            .try_into()
+            .map_err(|e: std::array::TryFromSliceError| bad_signature(e.into()))?;
 
         // Confirm the recovered address matches an address in the guardian set.
         if guardian_set.keys.get(signer_id) == Some(&address) {
@@ -231,10 +457,13 @@ fn verify_vaa<'a>(
 
     // Check if we have enough correct signatures
     if signatures.len() < quorum {
-        return Err(anyhow!(
-            "Not enough correct signatures. Expected {:?}, received {:?}",
-            quorum,
-            signatures.len()
+        return Err(VaaRejection::new(
+            RejectionReason::QuorumNotMet,
+            anyhow!(
+                "Not enough correct signatures. Expected {:?}, received {:?}",
+                quorum,
+                signatures.len()
+            ),
         ));
     }
 
@@ -247,3 +476,104 @@ fn verify_vaa<'a>(
     )
         .into())
 }
+
+#[cfg(test)]
+mod test {
+    use {
+        super::*,
+        crate::state::{
+            aggregate::test::{create_dummy_price_feed_message, generate_update},
+            test::setup_state,
+        },
+        pythnet_sdk::messages::Message as PythnetMessage,
+    };
+
+    /// Generate a valid VAA with the given sequence and apply the modification to it.
+    fn vaa_bytes(sequence: u64, modify: impl FnOnce(&mut Vaa<&RawMessage>)) -> Vec<u8> {
+        let message = PythnetMessage::PriceFeedMessage(create_dummy_price_feed_message(100, 1, 0));
+        let Some(Update::Vaa(vaa_bytes)) = generate_update(vec![message], 10, sequence).pop()
+        else {
+            panic!("Expected a VAA update");
+        };
+        let mut vaa = serde_wormhole::from_slice::<Vaa<&RawMessage>>(&vaa_bytes).unwrap();
+        modify(&mut vaa);
+        serde_wormhole::to_vec(&vaa).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_rejected_vaas_are_recorded() {
+        let (state, _) = setup_state(10).await;
+
+        assert!(Wormhole::process_message(&*state, vaa_bytes(1, |_| {}))
+            .await
+            .unwrap());
+        assert!(
+            Wormhole::process_message(&*state, vaa_bytes(2, |vaa| vaa.guardian_set_index = 1))
+                .await
+                .is_err()
+        );
+        assert!(Wormhole::process_message(
+            &*state,
+            vaa_bytes(3, |vaa| vaa.emitter_chain = Chain::Solana)
+        )
+        .await
+        .is_err());
+        assert!(Wormhole::process_message(&*state, vec![1, 2, 3])
+            .await
+            .is_err());
+        // The same VAA delivered by another source is only recorded once.
+        assert!(
+            Wormhole::process_message(&*state, vaa_bytes(2, |vaa| vaa.guardian_set_index = 1))
+                .await
+                .is_err()
+        );
+
+        let rejected_vaas = Wormhole::get_rejected_vaas(&*state).await;
+        assert_eq!(
+            rejected_vaas
+                .iter()
+                .map(|rejected_vaa| (rejected_vaa.reason, rejected_vaa.sequence))
+                .collect::<Vec<_>>(),
+            vec![
+                (RejectionReason::UnknownGuardianSet, Some(2)),
+                (RejectionReason::WrongEmitter, Some(3)),
+                (RejectionReason::Malformed, None),
+            ]
+        );
+        assert_eq!(rejected_vaas[1].emitter_chain, Some(1));
+    }
+
+    #[tokio::test]
+    async fn test_store_vaa_deduplicates_by_sequence() {
+        let (state, _) = setup_state(10).await;
+        assert_eq!(Wormhole::latest_observed_vaa_seq(&*state).await, None);
+
+        assert!(Wormhole::store_vaa(&*state, 5, vec![]).await);
+        assert!(!Wormhole::store_vaa(&*state, 5, vec![]).await);
+        assert!(Wormhole::store_vaa(&*state, 4, vec![]).await);
+
+        assert_eq!(Wormhole::latest_observed_vaa_seq(&*state).await, Some(5));
+    }
+
+    #[tokio::test]
+    async fn test_add_guardian_set_only_accepts_newer_sets() {
+        let (state, _) = setup_state(10).await;
+        let guardian_set = |key| GuardianSet {
+            keys: vec![[key; 20]],
+        };
+
+        // The test state starts with the guardian set 0.
+        assert!(Wormhole::add_guardian_set(&*state, 0, guardian_set(1))
+            .await
+            .is_err());
+        assert!(Wormhole::add_guardian_set(&*state, 2, guardian_set(2))
+            .await
+            .is_ok());
+        assert!(Wormhole::add_guardian_set(&*state, 1, guardian_set(3))
+            .await
+            .is_err());
+        assert!(Wormhole::add_guardian_set(&*state, 2, guardian_set(4))
+            .await
+            .is_err());
+    }
+}